@@ -0,0 +1,148 @@
+//NOTE(joh): `malu_asm!` assembles malu code while the Rust code is compiled:
+//
+//    const DOUBLE: &[u8] = malu_asm! { #21; #2; mul; end; };
+//
+//The code goes through the Rust tokenizer first, so it has to be made of Rust tokens. Comments
+//are dropped, everything else keeps its place, so the assembler sees the code as written. An
+//assemble error is reported at the token it points at.
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use vm::asm::Parser;
+
+#[proc_macro]
+pub fn malu_asm(input: TokenStream) -> TokenStream {
+    let mut tokens = Vec::new();
+    let mut spans = Vec::new();
+    flatten(input, &mut tokens, &mut spans);
+    let (source, offsets) = layout(&tokens);
+    match Parser::parse(&source) {
+        Ok(result) => {
+            let bytes = result.code.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", ");
+            format!("&[{bytes}]").parse().unwrap()
+        }
+        Err(error) => {
+            let token = offsets.iter().rposition(|offset| *offset <= error.span.start);
+            compile_error(&error.kind.to_string(), token.map_or_else(Span::call_site, |i| spans[i]))
+        }
+    }
+}
+
+//NOTE(joh): Text of a token and the line and column where it starts and ends
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SourceToken {
+    text: String,
+    start: (usize, usize),
+    end: (usize, usize),
+}
+
+impl SourceToken {
+    fn new(text: String, span: Span) -> Self {
+        Self {
+            text,
+            start: (span.start().line(), span.start().column()),
+            end: (span.end().line(), span.end().column()),
+        }
+    }
+}
+
+//NOTE(joh): Groups are split into their delimiters and contents, like `(1<<12)` in a constant
+//expression. Groups without delimiters come from `macro_rules` fragments.
+fn flatten(input: TokenStream, tokens: &mut Vec<SourceToken>, spans: &mut Vec<Span>) {
+    for tree in input {
+        let (text, span) = match tree {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => {
+                        flatten(group.stream(), tokens, spans);
+                        continue;
+                    }
+                };
+                tokens.push(SourceToken::new(open.to_string(), group.span_open()));
+                spans.push(group.span_open());
+                flatten(group.stream(), tokens, spans);
+                (close.to_string(), group.span_close())
+            }
+            TokenTree::Literal(literal) => {
+                (literal.span().source_text().unwrap_or_else(|| literal.to_string()), literal.span())
+            }
+            tree => (tree.to_string(), tree.span()),
+        };
+        tokens.push(SourceToken::new(text, span));
+        spans.push(span);
+    }
+}
+
+//NOTE(joh): Puts the tokens back where they were in the Rust source, tokens that were next to
+//each other like `#` and `1` stay together. Also returns where each token ended up.
+fn layout(tokens: &[SourceToken]) -> (String, Vec<usize>) {
+    let mut source = String::new();
+    let mut offsets = Vec::with_capacity(tokens.len());
+    let mut position = tokens.first().map_or((0, 0), |t| t.start);
+    for token in tokens {
+        let (line, column) = token.start;
+        if line > position.0 {
+            (position.0..line).for_each(|_| source.push('\n'));
+            (1..column).for_each(|_| source.push(' '));
+        } else if (line, column) < position {
+            //NOTE(joh): Tokens out of order come from macro expansions, keep them apart
+            source.push(' ');
+        } else {
+            (position.1..column).for_each(|_| source.push(' '));
+        }
+        offsets.push(source.len());
+        source.push_str(&token.text);
+        position = token.end;
+    }
+    (source, offsets)
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut message = TokenTree::Literal(Literal::string(message));
+    message.set_span(span);
+    let tokens: [TokenTree; 3] = [
+        Ident::new("compile_error", span).into(),
+        Punct::new('!', Spacing::Alone).into(),
+        Group::new(Delimiter::Parenthesis, message.into()).into(),
+    ];
+    tokens
+        .into_iter()
+        .map(|mut token| {
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_layout() {
+        let token = |text: &str, line, column| SourceToken {
+            text: text.to_string(),
+            start: (line, column),
+            end: (line, column + text.chars().count()),
+        };
+        let tokens = [
+            token("#", 3, 9),
+            token("1", 3, 10),
+            token(";", 3, 11),
+            token("#", 3, 13),
+            token("-", 3, 14),
+            token("2", 3, 15),
+            token(";", 3, 16),
+            token("sub", 4, 9),
+            token(";", 4, 12),
+            token("end", 4, 14),
+        ];
+        let (source, offsets) = layout(&tokens);
+        assert_eq!(source, "#1; #-2;\n        sub; end");
+        assert_eq!(offsets[7], source.find("sub").unwrap());
+
+        let error = Parser::parse(&source).map(|_| ()).unwrap_err();
+        assert_eq!(offsets.iter().rposition(|offset| *offset <= error.span.start), Some(9));
+    }
+}
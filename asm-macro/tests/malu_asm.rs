@@ -0,0 +1,37 @@
+use malu_asm::malu_asm;
+use vm::{asm::Parser, interpreter::Interpreter, testing::NoSyscalls};
+
+const DOUBLE: &[u8] = malu_asm! { #21; #2; mul; end; };
+
+#[test]
+fn matches_parser() {
+    assert_eq!(DOUBLE, &Parser::parse("#21; #2; mul; end;").unwrap().code[..]);
+
+    let code = malu_asm! {
+        .data pair bytes 3 4;
+        .global counter = 40;
+        #@pair; load_8_u 1; // comments are dropped
+        global_get counter; add;
+        #(1<<2); #-1; mul; add;
+        end;
+    };
+    let source = "
+        .data pair bytes 3 4;
+        .global counter = 40;
+        #@pair; load_8_u 1;
+        global_get counter; add;
+        #(1<<2); #-1; mul; add;
+        end;
+    ";
+    assert_eq!(code, &Parser::parse(source).unwrap().code[..]);
+
+    let mut interpreter = Interpreter::from_bytecode(DOUBLE).unwrap();
+    assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), [42]);
+    let mut interpreter = Interpreter::from_bytecode(code).unwrap();
+    assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), [40]);
+}
+
+#[test]
+fn assemble_errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,9 @@
+use malu_asm::malu_asm;
+
+const CODE: &[u8] = malu_asm! {
+    #1; drop;
+    #@missing; jmp;
+    end;
+};
+
+fn main() {}
@@ -0,0 +1,20 @@
+use std::path::{Path, PathBuf};
+
+//NOTE(joh): The header only goes to `OUT_DIR`, so a build never touches the source tree. The
+//checked-in `include/maluvm.h` is regenerated by hand with
+//
+//    cbindgen --config cbindgen.toml --output include/maluvm.h
+fn main() {
+    println!("cargo::rerun-if-changed=src/lib.rs");
+    println!("cargo::rerun-if-changed=cbindgen.toml");
+
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).expect("invalid cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/lib.rs"))
+        .generate()
+        .expect("failed to generate the C header")
+        .write_to_file(out_dir.join("maluvm.h"));
+}
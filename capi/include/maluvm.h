@@ -0,0 +1,110 @@
+#ifndef MALUVM_H
+#define MALUVM_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of the `malu_vm_*` functions. `malu_vm_last_error` describes everything but `Ok`.
+ */
+typedef enum {
+  MALU_STATUS_OK = 0,
+  MALU_STATUS_NULL_POINTER = 1,
+  MALU_STATUS_INVALID_BYTECODE = 2,
+  MALU_STATUS_NOT_LOADED = 3,
+  MALU_STATUS_TRAP = 4,
+  MALU_STATUS_OUT_OF_BOUNDS = 5,
+  MALU_STATUS_BUFFER_TOO_SMALL = 6,
+  MALU_STATUS_WRITE_PROTECTED = 7,
+} MaluStatus;
+
+/**
+ * Opaque handle, created with `malu_vm_new` and freed with `malu_vm_free`.
+ */
+typedef struct MaluVm MaluVm;
+
+/**
+ * Called for the syscall it was registered for. `args` holds `arg_count` values pushed with
+ * `push_arg`, the return value is pushed for the program. `vm` may be used with
+ * `malu_vm_read_mem` and `malu_vm_write_mem` while the callback runs.
+ */
+typedef uint32_t (*MaluSyscallFn)(MaluVm *vm,
+                                  void *user_data,
+                                  uint32_t id,
+                                  const uint32_t *args,
+                                  size_t arg_count);
+
+/**
+ * Creates a VM without a program. Never returns null.
+ */
+MaluVm *malu_vm_new(void);
+
+/**
+ * # Safety
+ * `vm` must come from `malu_vm_new` and not be used afterwards. Null is ignored.
+ */
+void malu_vm_free(MaluVm *vm);
+
+/**
+ * Loads bytecode, replacing the previous program and its state. The bytes are copied.
+ *
+ * # Safety
+ * `vm` must be a live handle, `bytecode` must point to `len` readable bytes. Must not be
+ * called from a syscall callback.
+ */
+MaluStatus malu_vm_load(MaluVm *vm, const uint8_t *bytecode, size_t len);
+
+/**
+ * Runs the program until it ends. The value stack is copied to `results`, which holds
+ * `results_cap` values, and its size is written to `result_count`. Both may be null if the
+ * results are not needed.
+ *
+ * # Safety
+ * `vm` must be a live handle, `results` must point to `results_cap` writable values and
+ * `result_count` must be writable. Must not be called from a syscall callback.
+ */
+MaluStatus malu_vm_run(MaluVm *vm, uint32_t *results, size_t results_cap, size_t *result_count);
+
+/**
+ * Calls `callback` for the syscall `id` from now on, overriding the built in syscalls. Ids
+ * without a callback behave like in the cli.
+ *
+ * # Safety
+ * `vm` must be a live handle. `user_data` is passed to the callback as is.
+ */
+MaluStatus malu_vm_register_syscall(MaluVm *vm,
+                                    uint32_t id,
+                                    MaluSyscallFn callback,
+                                    void *user_data);
+
+/**
+ * Copies `len` bytes of VM memory starting at `addr` to `dest`.
+ *
+ * # Safety
+ * `vm` must be a live handle, `dest` must point to `len` writable bytes.
+ */
+MaluStatus malu_vm_read_mem(MaluVm *vm, uint32_t addr, uint8_t *dest, size_t len);
+
+/**
+ * Copies `len` bytes from `src` into VM memory starting at `addr`. Write protected memory like
+ * the code is refused with `WriteProtected`.
+ *
+ * # Safety
+ * `vm` must be a live handle, `src` must point to `len` readable bytes.
+ */
+MaluStatus malu_vm_write_mem(MaluVm *vm, uint32_t addr, const uint8_t *src, size_t len);
+
+/**
+ * Describes the last failed call on `vm`. The string stays valid until the next failing call.
+ *
+ * # Safety
+ * `vm` must be a live handle.
+ */
+const char *malu_vm_last_error(const MaluVm *vm);
+
+#endif  /* MALUVM_H */
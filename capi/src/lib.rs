@@ -0,0 +1,318 @@
+//NOTE(joh): C API for embedding the VM. Everything goes through an opaque `MaluVm` handle, the
+//header in `include/maluvm.h` is generated from this file with cbindgen, see `build.rs`.
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_void, CString},
+    ptr,
+};
+
+use vm::{
+    config::SyscallGroup,
+    interpreter::{Interpreter, InterpreterErrorType, SyscallHandler},
+    syscall::StdHandler,
+};
+
+/// Result of the `malu_vm_*` functions. `malu_vm_last_error` describes everything but `Ok`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaluStatus {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidBytecode = 2,
+    NotLoaded = 3,
+    Trap = 4,
+    OutOfBounds = 5,
+    BufferTooSmall = 6,
+    WriteProtected = 7,
+}
+
+/// Called for the syscall it was registered for. `args` holds `arg_count` values pushed with
+/// `push_arg`, the return value is pushed for the program. `vm` may be used with
+/// `malu_vm_read_mem` and `malu_vm_write_mem` while the callback runs.
+pub type MaluSyscallFn = Option<
+    unsafe extern "C" fn(vm: *mut MaluVm, user_data: *mut c_void, id: u32, args: *const u32, arg_count: usize) -> u32,
+>;
+
+#[derive(Clone, Copy)]
+struct Syscall {
+    callback: unsafe extern "C" fn(*mut MaluVm, *mut c_void, u32, *const u32, usize) -> u32,
+    user_data: *mut c_void,
+}
+
+/// Opaque handle, created with `malu_vm_new` and freed with `malu_vm_free`.
+pub struct MaluVm {
+    interpreter: Option<Interpreter>,
+    syscalls: HashMap<u32, Syscall>,
+    //NOTE(joh): Syscalls without a callback behave like in the cli
+    std_syscalls: StdHandler,
+    //NOTE(joh): The interpreter borrowed by `run` while a callback runs
+    active: *mut Interpreter,
+    last_error: CString,
+}
+
+//NOTE(joh): These only touch the fields they need through the raw pointer. Callbacks call back
+//in while `malu_vm_run` borrows the interpreter field.
+unsafe fn fail(vm: *mut MaluVm, status: MaluStatus, message: impl ToString) -> MaluStatus {
+    let message = CString::new(message.to_string().replace('\0', "")).unwrap_or_default();
+    unsafe { (*vm).last_error = message };
+    status
+}
+
+unsafe fn interpreter<'a>(vm: *mut MaluVm) -> Option<&'a mut Interpreter> {
+    unsafe {
+        match (*vm).active.is_null() {
+            true => (*vm).interpreter.as_mut(),
+            //NOTE(joh): Only set while a callback runs, `run` does not touch the interpreter then
+            false => Some(&mut *(*vm).active),
+        }
+    }
+}
+
+struct Callbacks {
+    vm: *mut MaluVm,
+}
+
+impl SyscallHandler for Callbacks {
+    fn on_syscall(&mut self, interpreter: &mut Interpreter, id: u32, args: &[u32]) -> u32 {
+        let vm = self.vm;
+        let Some(syscall) = (unsafe { (*vm).syscalls.get(&id).copied() }) else {
+            return unsafe { (*vm).std_syscalls.on_syscall(interpreter, id, args) };
+        };
+        unsafe {
+            (*vm).active = interpreter;
+            let ret = (syscall.callback)(vm, syscall.user_data, id, args.as_ptr(), args.len());
+            (*vm).active = ptr::null_mut();
+            ret
+        }
+    }
+
+    fn syscall_group(&self, id: u32) -> Option<SyscallGroup> {
+        unsafe {
+            match (*self.vm).syscalls.contains_key(&id) {
+                true => None,
+                false => (*self.vm).std_syscalls.syscall_group(id),
+            }
+        }
+    }
+}
+
+/// Creates a VM without a program. Never returns null.
+#[unsafe(no_mangle)]
+pub extern "C" fn malu_vm_new() -> *mut MaluVm {
+    Box::into_raw(Box::new(MaluVm {
+        interpreter: None,
+        syscalls: HashMap::new(),
+        std_syscalls: StdHandler::default(),
+        active: ptr::null_mut(),
+        last_error: CString::default(),
+    }))
+}
+
+/// # Safety
+/// `vm` must come from `malu_vm_new` and not be used afterwards. Null is ignored.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malu_vm_free(vm: *mut MaluVm) {
+    if !vm.is_null() {
+        drop(unsafe { Box::from_raw(vm) });
+    }
+}
+
+/// Loads bytecode, replacing the previous program and its state. The bytes are copied.
+///
+/// # Safety
+/// `vm` must be a live handle, `bytecode` must point to `len` readable bytes. Must not be
+/// called from a syscall callback.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malu_vm_load(vm: *mut MaluVm, bytecode: *const u8, len: usize) -> MaluStatus {
+    if vm.is_null() {
+        return MaluStatus::NullPointer;
+    }
+    if bytecode.is_null() {
+        return unsafe { fail(vm, MaluStatus::NullPointer, "bytecode is null") };
+    }
+    let bytecode = unsafe { std::slice::from_raw_parts(bytecode, len) };
+    match Interpreter::from_bytecode(bytecode) {
+        Ok(interpreter) => {
+            unsafe { (*vm).interpreter = Some(interpreter) };
+            MaluStatus::Ok
+        }
+        Err(e) => unsafe { fail(vm, MaluStatus::InvalidBytecode, e) },
+    }
+}
+
+/// Runs the program until it ends. The value stack is copied to `results`, which holds
+/// `results_cap` values, and its size is written to `result_count`. Both may be null if the
+/// results are not needed.
+///
+/// # Safety
+/// `vm` must be a live handle, `results` must point to `results_cap` writable values and
+/// `result_count` must be writable. Must not be called from a syscall callback.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malu_vm_run(
+    vm: *mut MaluVm,
+    results: *mut u32,
+    results_cap: usize,
+    result_count: *mut usize,
+) -> MaluStatus {
+    if vm.is_null() {
+        return MaluStatus::NullPointer;
+    }
+    let Some(interpreter) = (unsafe { (*vm).interpreter.as_mut() }) else {
+        return unsafe { fail(vm, MaluStatus::NotLoaded, "no bytecode loaded") };
+    };
+    let stack = match interpreter.run(&mut Callbacks { vm }) {
+        Ok(stack) => stack.to_vec(),
+        Err(e) => return unsafe { fail(vm, MaluStatus::Trap, e) },
+    };
+
+    if let Some(count) = unsafe { result_count.as_mut() } {
+        *count = stack.len();
+    }
+    if results.is_null() {
+        return MaluStatus::Ok;
+    }
+    if stack.len() > results_cap {
+        return unsafe { fail(vm, MaluStatus::BufferTooSmall, format!("{} results do not fit", stack.len())) };
+    }
+    unsafe { ptr::copy_nonoverlapping(stack.as_ptr(), results, stack.len()) };
+    MaluStatus::Ok
+}
+
+/// Calls `callback` for the syscall `id` from now on, overriding the built in syscalls. Ids
+/// without a callback behave like in the cli.
+///
+/// # Safety
+/// `vm` must be a live handle. `user_data` is passed to the callback as is.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malu_vm_register_syscall(
+    vm: *mut MaluVm,
+    id: u32,
+    callback: MaluSyscallFn,
+    user_data: *mut c_void,
+) -> MaluStatus {
+    if vm.is_null() {
+        return MaluStatus::NullPointer;
+    }
+    let Some(callback) = callback else {
+        return unsafe { fail(vm, MaluStatus::NullPointer, "callback is null") };
+    };
+    unsafe { (*vm).syscalls.insert(id, Syscall { callback, user_data }) };
+    MaluStatus::Ok
+}
+
+/// Copies `len` bytes of VM memory starting at `addr` to `dest`.
+///
+/// # Safety
+/// `vm` must be a live handle, `dest` must point to `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malu_vm_read_mem(vm: *mut MaluVm, addr: u32, dest: *mut u8, len: usize) -> MaluStatus {
+    if vm.is_null() {
+        return MaluStatus::NullPointer;
+    }
+    if dest.is_null() {
+        return unsafe { fail(vm, MaluStatus::NullPointer, "destination is null") };
+    }
+    let dest = unsafe { std::slice::from_raw_parts_mut(dest, len) };
+    let Some(interpreter) = (unsafe { interpreter(vm) }) else {
+        return unsafe { fail(vm, MaluStatus::NotLoaded, "no bytecode loaded") };
+    };
+    match interpreter.memory.read(addr as usize, dest) {
+        Some(()) => MaluStatus::Ok,
+        None => unsafe { fail(vm, MaluStatus::OutOfBounds, format!("{len} bytes at 0x{addr:04x} are out of bounds")) },
+    }
+}
+
+/// Copies `len` bytes from `src` into VM memory starting at `addr`. Write protected memory like
+/// the code is refused with `WriteProtected`.
+///
+/// # Safety
+/// `vm` must be a live handle, `src` must point to `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malu_vm_write_mem(vm: *mut MaluVm, addr: u32, src: *const u8, len: usize) -> MaluStatus {
+    if vm.is_null() {
+        return MaluStatus::NullPointer;
+    }
+    if src.is_null() {
+        return unsafe { fail(vm, MaluStatus::NullPointer, "source is null") };
+    }
+    let src = unsafe { std::slice::from_raw_parts(src, len) };
+    let Some(interpreter) = (unsafe { interpreter(vm) }) else {
+        return unsafe { fail(vm, MaluStatus::NotLoaded, "no bytecode loaded") };
+    };
+    match interpreter.write_bytes(addr, src) {
+        Ok(()) => MaluStatus::Ok,
+        Err(InterpreterErrorType::WriteToProtectedMemory(_)) => unsafe {
+            fail(vm, MaluStatus::WriteProtected, format!("0x{addr:04x} is write protected"))
+        },
+        Err(_) => unsafe { fail(vm, MaluStatus::OutOfBounds, format!("{len} bytes at 0x{addr:04x} are out of bounds")) },
+    }
+}
+
+/// Describes the last failed call on `vm`. The string stays valid until the next failing call.
+///
+/// # Safety
+/// `vm` must be a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malu_vm_last_error(vm: *const MaluVm) -> *const c_char {
+    match unsafe { vm.as_ref() } {
+        Some(vm) => vm.last_error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::asm;
+
+    unsafe extern "C" fn add_offset(vm: *mut MaluVm, user_data: *mut c_void, _: u32, args: *const u32, count: usize) -> u32 {
+        let args = unsafe { std::slice::from_raw_parts(args, count) };
+        let mut bytes = [0; 2];
+        assert_eq!(unsafe { malu_vm_read_mem(vm, args[0], bytes.as_mut_ptr(), 2) }, MaluStatus::Ok);
+        unsafe { *(user_data as *mut u32) += 1 };
+        bytes[0] as u32 + bytes[1] as u32 + args[1]
+    }
+
+    #[test]
+    fn embed_with_syscall() {
+        let code = "
+            .data pair bytes 3 4;
+            #@pair; push_arg; #10; push_arg; #42; syscall;
+            #@pair; #7; store_8 0;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap().code;
+        let mut calls = 0u32;
+        let mut results = [0u32; 4];
+        let mut count = 0;
+        unsafe {
+            let vm = malu_vm_new();
+            assert_eq!(malu_vm_run(vm, ptr::null_mut(), 0, ptr::null_mut()), MaluStatus::NotLoaded);
+            assert_eq!(malu_vm_load(vm, bytecode.as_ptr(), bytecode.len()), MaluStatus::Ok);
+            let user_data = &mut calls as *mut u32 as *mut c_void;
+            assert_eq!(malu_vm_register_syscall(vm, 42, Some(add_offset), user_data), MaluStatus::Ok);
+            assert_eq!(malu_vm_run(vm, results.as_mut_ptr(), results.len(), &mut count), MaluStatus::Ok);
+            assert_eq!(&results[..count], [17]);
+
+            let pair = asm::DATA_START + bytecode.len() as u32 - asm::BytecodeInfo::total_header_size() as u32 - 2;
+            let mut byte = 0u8;
+            assert_eq!(malu_vm_read_mem(vm, pair, &mut byte, 1), MaluStatus::Ok);
+            assert_eq!(byte, 7);
+            assert_eq!(malu_vm_read_mem(vm, u32::MAX, &mut byte, 1), MaluStatus::OutOfBounds);
+            assert_eq!(malu_vm_write_mem(vm, asm::DATA_START, &byte, 1), MaluStatus::WriteProtected);
+            assert!(!std::ffi::CStr::from_ptr(malu_vm_last_error(vm)).is_empty());
+
+            assert_eq!(malu_vm_load(vm, [0u8; 3].as_ptr(), 3), MaluStatus::InvalidBytecode);
+            malu_vm_free(vm);
+        }
+        assert_eq!(calls, 1);
+    }
+
+    //NOTE(joh): The build script only writes the header to `OUT_DIR`, the checked-in one has to be
+    //regenerated when the API changes
+    #[test]
+    fn header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/maluvm.h"));
+        assert_eq!(include_str!("../include/maluvm.h"), generated, "run cbindgen to update include/maluvm.h");
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use vm::{
+    analyze::analyze_stack,
+    asm::{AssembleError, BytecodeInfo, Parser, DATA_START},
+    config::InterpreterConfig,
+    debug::DebugInfo,
+    debuginfo::split_debug_info,
+    disasm::disassemble,
+    function::split_function_table,
+    interpreter::{Interpreter, InterpreterErrorType},
+    link::{link, LinkError, Object},
+    module::Module,
+    parse::{try_parse_ops_from_bytecode, MaybeRawOp},
+    replay::{decode_records, encode_records, Recorder, ReplayError, Replayer},
+    sandbox::Sandbox,
+    signing::split_signature,
+    symbols::split_symbol_table,
+    syscall::{StdConsole, StdHandler},
+    testing::run_tests,
+    trace::PrintTracer,
+};
+
+const USAGE: &str = "usage:
+    maluvm-cli assemble <file.malu> [-o <out.mbc>] [-I <include dir>]... [-g] [--symbols] [--strip-dead-code] [--const-pool | --object]
+    maluvm-cli link <file.mobj>... [-o <out.mbc>]
+    maluvm-cli run [--trace] [--flamegraph <stacks>] [--sandbox <dir>] [--seed <n>] [--record <trace> | --replay <trace>] <file.mbc | file.malu>
+    maluvm-cli run [options] [--entry <symbol>] <file.mobj>...
+    maluvm-cli test <file.mbc | file.malu>
+    maluvm-cli disasm [--source] <file.mbc>";
+
+const SOURCE_EXTENSION: &str = "malu";
+const BYTECODE_EXTENSION: &str = "mbc";
+const OBJECT_EXTENSION: &str = "mobj";
+//NOTE(joh): Ops between two samples of `--flamegraph`
+const SAMPLE_INTERVAL: u64 = 100;
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Io(PathBuf, std::io::Error),
+    //NOTE(joh): Already rendered with the offending source line
+    Assemble(String),
+    Interpreter(InterpreterErrorType),
+    Link(LinkError),
+    Replay(ReplayError),
+    //NOTE(joh): Already rendered with the source locations
+    Trap(String),
+    TestsFailed { failed: usize, total: usize },
+}
+
+impl From<InterpreterErrorType> for CliError {
+    fn from(value: InterpreterErrorType) -> Self {
+        CliError::Interpreter(value)
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Usage(msg) => write!(f, "{msg}\n\n{USAGE}"),
+            CliError::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            CliError::Assemble(error) => write!(f, "{error}"),
+            CliError::Interpreter(e) => write!(f, "{e}"),
+            CliError::Link(e) => write!(f, "{e}"),
+            CliError::Replay(e) => write!(f, "replay diverged: {e}"),
+            CliError::Trap(trap) => write!(f, "{trap}"),
+            CliError::TestsFailed { failed, total } => write!(f, "{failed} of {total} tests failed"),
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, CliError> {
+    std::fs::read(path).map_err(|e| CliError::Io(path.to_path_buf(), e))
+}
+
+//NOTE(joh): Errors and warnings may point into an included file
+fn source_of(file: Option<&str>, source: &str) -> String {
+    match file {
+        Some(file) => std::fs::read_to_string(file).unwrap_or_default(),
+        None => source.to_string(),
+    }
+}
+
+fn render_assemble_error(error: AssembleError, source: &str) -> CliError {
+    CliError::Assemble(error.render(&source_of(error.file.as_deref(), source), None))
+}
+
+//NOTE(joh): Which optional sections go into the bytecode
+#[derive(Debug, Clone, Copy, Default)]
+struct Sections {
+    debug_info: bool,
+    symbols: bool,
+}
+
+//NOTE(joh): Prints the warnings of the assembler to stderr
+fn assemble_file(
+    path: &Path,
+    use_const_pool: bool,
+    strip_dead_code: bool,
+    sections: Sections,
+    include_dirs: &[PathBuf],
+) -> Result<(Box<[u8]>, DebugInfo), CliError> {
+    let source = std::fs::read_to_string(path).map_err(|e| CliError::Io(path.to_path_buf(), e))?;
+    let mut parser = if use_const_pool { Parser::with_const_pool() } else { Parser::new() };
+    parser.set_source_path(path);
+    parser.set_emit_debug_info(sections.debug_info);
+    parser.set_emit_symbols(sections.symbols);
+    parser.set_strip_dead_code(strip_dead_code);
+    include_dirs.iter().for_each(|dir| parser.add_include_dir(dir));
+    let result = parser
+        .assemble(&source)
+        .map_err(|e| render_assemble_error(e, &source))?;
+    for warning in &result.warnings {
+        eprintln!("{}", warning.render(&source_of(warning.file.as_deref(), &source), None));
+    }
+    let debug_info = result.debug_info.with_file(path.display().to_string());
+    Ok((result.code, debug_info))
+}
+
+fn assemble_object_file(path: &Path, include_dirs: &[PathBuf]) -> Result<Box<[u8]>, CliError> {
+    let source = std::fs::read_to_string(path).map_err(|e| CliError::Io(path.to_path_buf(), e))?;
+    let mut parser = Parser::new();
+    parser.set_source_path(path);
+    include_dirs.iter().for_each(|dir| parser.add_include_dir(dir));
+    let object = parser
+        .assemble_object(&source)
+        .map_err(|e| render_assemble_error(e, &source))?;
+    Ok(object.encode())
+}
+
+fn assemble(args: &[String]) -> Result<(), CliError> {
+    let mut input = None;
+    let mut output = None;
+    let mut use_const_pool = false;
+    let mut strip_dead_code = false;
+    let mut sections = Sections::default();
+    let mut object = false;
+    let mut include_dirs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                let path = args.next().ok_or(CliError::Usage("-o expects a path".to_string()))?;
+                output = Some(PathBuf::from(path));
+            }
+            "-I" => {
+                let dir = args.next().ok_or(CliError::Usage("-I expects a directory".to_string()))?;
+                include_dirs.push(PathBuf::from(dir));
+            }
+            "-g" => sections.debug_info = true,
+            "--symbols" => sections.symbols = true,
+            "--const-pool" => use_const_pool = true,
+            "--strip-dead-code" => strip_dead_code = true,
+            "--object" => object = true,
+            path if input.is_none() => input = Some(PathBuf::from(path)),
+            other => return Err(CliError::Usage(format!("unexpected argument `{other}`"))),
+        }
+    }
+    let input = input.ok_or(CliError::Usage("missing input file".to_string()))?;
+    if object && use_const_pool {
+        return Err(CliError::Usage("objects can not use the const pool".to_string()));
+    }
+    //NOTE(joh): Other objects may jump to any of their code
+    if object && strip_dead_code {
+        return Err(CliError::Usage("objects can not strip dead code".to_string()));
+    }
+
+    let (output, bytes) = if object {
+        let output = output.unwrap_or_else(|| input.with_extension(OBJECT_EXTENSION));
+        (output, assemble_object_file(&input, &include_dirs)?)
+    } else {
+        let output = output.unwrap_or_else(|| input.with_extension(BYTECODE_EXTENSION));
+        let (bytecode, debug_info) = assemble_file(&input, use_const_pool, strip_dead_code, sections, &include_dirs)?;
+        //NOTE(joh): Stack mistakes are only warned about, the bytecode is written anyway
+        for warning in analyze_stack(&bytecode).into_iter().flatten() {
+            eprintln!("warning: {}", warning.render(Some(&debug_info)));
+        }
+        (output, bytecode)
+    };
+    std::fs::write(&output, bytes).map_err(|e| CliError::Io(output, e))
+}
+
+fn link_objects(args: &[String]) -> Result<(), CliError> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                let path = args.next().ok_or(CliError::Usage("-o expects a path".to_string()))?;
+                output = Some(PathBuf::from(path));
+            }
+            path => inputs.push(PathBuf::from(path)),
+        }
+    }
+    let first = inputs.first().ok_or(CliError::Usage("missing object files".to_string()))?;
+    let output = output.unwrap_or_else(|| first.with_extension(BYTECODE_EXTENSION));
+
+    let bytecode = link(&read_objects(&inputs)?).map_err(CliError::Link)?;
+    std::fs::write(&output, bytecode).map_err(|e| CliError::Io(output, e))
+}
+
+fn read_objects(paths: &[PathBuf]) -> Result<Vec<Object>, CliError> {
+    paths
+        .iter()
+        .map(|path| Object::decode(&read(path)?).map_err(CliError::Link))
+        .collect()
+}
+
+//NOTE(joh): Source files are assembled first so traps can point at the source line. Bytecode
+//can only do that if it was assembled with `-g`.
+fn load_program(path: &Path) -> Result<(Vec<u8>, Option<DebugInfo>), CliError> {
+    let program = match path.extension().and_then(|e| e.to_str()) {
+        Some(SOURCE_EXTENSION) => {
+            let (bytecode, debug_info) = assemble_file(path, false, false, Sections::default(), &[])?;
+            (bytecode.into_vec(), Some(debug_info))
+        }
+        _ => {
+            let bytecode = read(path)?;
+            let mut debug_info = split_debug_info(&bytecode).1;
+            //NOTE(joh): Traps also name the closest label if there is a symbol table
+            if let Some(info) = &mut debug_info
+                && let (_, Some(symbols)) = split_symbol_table(&bytecode)
+            {
+                info.labels = symbols.labels;
+            }
+            (bytecode, debug_info)
+        }
+    };
+    Ok(program)
+}
+
+//NOTE(joh): The file syscalls are refused unless a sandbox directory is given. Without a seed the
+//`Random` syscall is seeded from the time. A recording is written even if the program traps, a
+//replay answers the syscalls from it, see `vm::replay`. Object files are linked when they are
+//loaded, so a library object can be run with any program without linking it to disk first.
+//`--flamegraph` writes the sampled stacks folded for flamegraph.pl or inferno, also on a trap.
+fn run(args: &[String]) -> Result<(), CliError> {
+    let mut inputs = Vec::new();
+    let mut entry = None;
+    let mut trace = false;
+    let mut flamegraph = None;
+    let mut sandbox = None;
+    let mut seed = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => trace = true,
+            "--flamegraph" => {
+                let path = args.next().ok_or(CliError::Usage("--flamegraph expects a path".to_string()))?;
+                flamegraph = Some(PathBuf::from(path));
+            }
+            "--sandbox" => {
+                let dir = args.next().ok_or(CliError::Usage("--sandbox expects a directory".to_string()))?;
+                let dir = PathBuf::from(dir);
+                sandbox = Some(Sandbox::new(&dir).map_err(|e| CliError::Io(dir, e))?);
+            }
+            "--seed" => {
+                let value = args.next().and_then(|seed| seed.parse::<u64>().ok());
+                seed = Some(value.ok_or(CliError::Usage("--seed expects a number".to_string()))?);
+            }
+            "--record" => {
+                let path = args.next().ok_or(CliError::Usage("--record expects a path".to_string()))?;
+                record = Some(PathBuf::from(path));
+            }
+            "--replay" => {
+                let path = args.next().ok_or(CliError::Usage("--replay expects a path".to_string()))?;
+                replay = Some(PathBuf::from(path));
+            }
+            "--entry" => {
+                let symbol = args.next().ok_or(CliError::Usage("--entry expects a symbol".to_string()))?;
+                entry = Some(symbol.clone());
+            }
+            path => inputs.push(PathBuf::from(path)),
+        }
+    }
+
+    let is_object = |path: &PathBuf| path.extension().is_some_and(|e| e == OBJECT_EXTENSION);
+    let (mut interpreter, debug_info) = match inputs.as_slice() {
+        [] => return Err(CliError::Usage("missing input file".to_string())),
+        [path] if !is_object(path) && entry.is_none() => {
+            let (bytecode, debug_info) = load_program(path)?;
+            (Interpreter::from_bytecode(&bytecode)?, debug_info)
+        }
+        paths if paths.iter().all(is_object) => (Interpreter::link(&read_objects(paths)?, entry.as_deref())?, None),
+        _ => return Err(CliError::Usage("only object files can be run together or with --entry".to_string())),
+    };
+    if trace {
+        interpreter.set_trace_sink(PrintTracer);
+    }
+    if let Some(debug_info) = &debug_info {
+        interpreter.set_labels(&debug_info.labels);
+    }
+    if flamegraph.is_some() {
+        interpreter.start_sampling(SAMPLE_INTERVAL);
+    }
+    let mut handler = StdHandler::new(StdConsole);
+    handler.sandbox = sandbox;
+    if let Some(seed) = seed {
+        handler.seed(seed);
+    }
+    let result = match (record, replay) {
+        (Some(_), Some(_)) => return Err(CliError::Usage("--record and --replay exclude each other".to_string())),
+        (Some(path), None) => {
+            let mut recorder = Recorder::new(handler);
+            let result = interpreter.run_with_backtrace(&mut recorder).map(<[u32]>::to_vec);
+            let mut trace = Vec::new();
+            encode_records(&recorder.records, &mut trace);
+            std::fs::write(&path, trace).map_err(|e| CliError::Io(path, e))?;
+            result
+        }
+        (None, Some(path)) => {
+            let records = decode_records(&read(&path)?).map_err(|e| CliError::Io(path, e))?;
+            let mut replayer = Replayer::new(handler, records);
+            let result = interpreter.run_with_backtrace(&mut replayer).map(<[u32]>::to_vec);
+            if let Some(error) = replayer.error {
+                return Err(CliError::Replay(error));
+            }
+            result
+        }
+        (None, None) => interpreter.run_with_backtrace(&mut handler).map(<[u32]>::to_vec),
+    };
+    if let Some(path) = flamegraph
+        && let Some(samples) = interpreter.stop_sampling()
+    {
+        let folded = samples.folded(&interpreter.code_symbols());
+        std::fs::write(&path, folded).map_err(|e| CliError::Io(path, e))?;
+    }
+    let result = result.map_err(|trap| CliError::Trap(trap.render(debug_info.as_ref())))?;
+    println!("=> {result:?}");
+    Ok(())
+}
+
+//NOTE(joh): Runs every `test_*` label, see `vm::testing`
+fn test(path: &Path) -> Result<(), CliError> {
+    let (bytecode, debug_info) = load_program(path)?;
+    let module = Module::from_bytecode(&bytecode)?;
+    let results = run_tests(
+        &module,
+        debug_info.as_ref(),
+        &InterpreterConfig::default(),
+        &mut StdHandler::new(StdConsole),
+    )?;
+    for result in &results {
+        println!("{}", result.render(debug_info.as_ref()));
+    }
+    let failed = results.iter().filter(|r| !r.passed()).count();
+    match failed {
+        0 => {
+            println!("{} tests passed", results.len());
+            Ok(())
+        }
+        failed => Err(CliError::TestsFailed { failed, total: results.len() }),
+    }
+}
+
+//NOTE(joh): Runs the program once per execution mode, output of the program is discarded
+fn bench(path: &Path) -> Result<(), CliError> {
+    let (bytecode, _) = load_program(path)?;
+    let time = |decoded: bool| -> Result<(Duration, u64), CliError> {
+        let mut interpreter = Interpreter::from_bytecode(&bytecode)?;
+        let mut handler = StdHandler::new(String::new());
+        let start = Instant::now();
+        match decoded {
+            true => interpreter.run_decoded(&mut handler)?,
+            false => interpreter.run(&mut handler)?,
+        };
+        Ok((start.elapsed(), interpreter.executed_ops))
+    };
+    let (bytes, ops) = time(false)?;
+    let (decoded, _) = time(true)?;
+    println!("{ops} ops");
+    println!("bytes:   {bytes:?}");
+    println!("decoded: {decoded:?} ({:.2}x)", bytes.as_secs_f64() / decoded.as_secs_f64());
+    Ok(())
+}
+
+//NOTE(joh): Prints source that assembles back to the same bytecode
+fn disasm_source(path: &Path) -> Result<(), CliError> {
+    let bytecode = read(path)?;
+    let source = disassemble(&bytecode).map_err(|e| CliError::Io(path.to_path_buf(), e))?;
+    print!("{source}");
+    Ok(())
+}
+
+fn disasm(path: &Path) -> Result<(), CliError> {
+    let bytecode = read(path)?;
+    let io_error = |e| CliError::Io(path.to_path_buf(), e);
+    let info = BytecodeInfo::decode(&mut Cursor::new(&bytecode[..])).map_err(io_error)?;
+    let (_, functions) = split_function_table(split_signature(&bytecode).0);
+    let functions = functions.unwrap_or_default();
+
+    let code_start = BytecodeInfo::total_header_size();
+    let code = bytecode
+        .get(code_start..code_start + info.code_size_bytes as usize)
+        .ok_or(CliError::Interpreter(InterpreterErrorType::InvalidBytecodeHeader))?;
+
+    let mut addr = DATA_START;
+    for op in try_parse_ops_from_bytecode(&mut Cursor::new(code)) {
+        let op = op.map_err(io_error)?;
+        if let Some(function) = functions.iter().find(|f| f.addr == addr) {
+            println!("{}({}):", function.name, function.arity);
+        }
+        let entry = if addr == info.code_start_offset { " <entry>" } else { "" };
+        match &op {
+            MaybeRawOp::Op(raw_op) => match &raw_op.arg {
+                Some(arg) => println!("0x{addr:04x}: {} {arg}{entry}", raw_op.name()),
+                None => println!("0x{addr:04x}: {}{entry}", raw_op.name()),
+            },
+            MaybeRawOp::Unknown(opcode) => println!("0x{addr:04x}: <unknown 0x{opcode:02x}>{entry}"),
+        }
+        addr += match &op {
+            MaybeRawOp::Op(raw_op) => raw_op.size_bytes() as u32,
+            MaybeRawOp::Unknown(_) => 1,
+        };
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.split_first() {
+        Some((command, rest)) => match (command.as_str(), rest) {
+            ("assemble", rest) => assemble(rest),
+            ("link", rest) => link_objects(rest),
+            ("run", rest) => run(rest),
+            ("test", [path]) => test(Path::new(path)),
+            ("bench", [path]) => bench(Path::new(path)),
+            ("disasm", [path]) => disasm(Path::new(path)),
+            ("disasm", [flag, path]) if flag == "--source" => disasm_source(Path::new(path)),
+            ("test" | "bench" | "disasm", _) => Err(CliError::Usage(format!("`{command}` expects exactly one file"))),
+            (other, _) => Err(CliError::Usage(format!("unknown command `{other}`"))),
+        },
+        None => Err(CliError::Usage("missing command".to_string())),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
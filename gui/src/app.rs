@@ -1,19 +1,199 @@
-use std::{collections::HashMap, io::Cursor};
+use std::{collections::BTreeSet, io::Cursor, path::PathBuf};
 
 use egui::ScrollArea;
 use vm::{
-    asm::{self, RawOp, DATA_START},
-    interpreter::{self, Interpreter, InterpreterErrorType, SyscallHandler}, parse::{try_parse_ops_from_bytecode, MaybeRawOp},
+    analyze::{basic_blocks, BasicBlock},
+    asm::{self, BytecodeInfo, DATA_START},
+    clock::{ClockMode, DEFAULT_NANOS_PER_OP},
+    config::{Capabilities, SyscallGroup},
+    debug::DebugInfo,
+    debuginfo::split_debug_info,
+    function::{split_function_table, Function},
+    gas::GasTable,
+    reload::{function_range, function_source, is_function_label, reload_function, HotReloadError},
+    snapshot::VmSnapshot,
+    symbols::split_symbol_table,
+    syscall::StdHandler,
+    trap::Trap,
+    interpreter::{Interpreter, InterpreterErrorType, MAX_ARGS}, parse::{try_parse_ops_from_bytecode, MaybeRawOp},
 };
 
-use crate::code::{self, select_label, show_mem_op, value_table, Editor};
+use crate::{
+    code::{cfg_view, coverage_summary, profiler_panel, select_label, show_mem_op, value_table, Editor},
+    input::GuiConsole,
+};
+
+//NOTE(joh): Number of ops that can be stepped back
+const HISTORY_CAPACITY: usize = 100_000;
+//NOTE(joh): A line that loops on itself would otherwise never give control back
+const STEP_LINE_MAX_OPS: usize = 1_000_000;
+//NOTE(joh): Ops executed per frame while a program runs, the UI stays responsive in between
+const RUN_SLICE_OPS: u64 = 200_000;
+
+const SOURCE_EXTENSION: &str = "malu";
+const BYTECODE_EXTENSION: &str = "mbc";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RunState {
+    #[default]
+    Idle,
+    Running,
+    Paused,
+}
+
+//NOTE(joh): Where a run pauses, besides the end of the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RunTarget {
+    #[default]
+    End,
+    Breakpoint,
+    //NOTE(joh): Until the return stack is at most this deep, see `step_frame`
+    Return(usize),
+}
 
 pub struct CompiledCode {
     pub interpreter: Interpreter,
     pub labels: Box<[(String, u32)]>,
+    pub functions: Box<[Function]>,
+    pub debug_info: DebugInfo,
+    pub trap: Option<Trap>,
+    pub source: String,
     pub results: Vec<u32>,
     pub ops: Vec<(MaybeRawOp, u32)>,
+    pub blocks: Vec<BasicBlock>,
+    //NOTE(joh): Address the code table scrolls to in the next frame
+    pub scroll_to: Option<u32>,
+    pub breakpoints: BTreeSet<u32>,
+    //NOTE(joh): Set by "save state", compiling again drops it
+    pub saved_state: Option<VmSnapshot>,
+    pub run_state: RunState,
+    pub run_target: RunTarget,
 }
+
+impl CompiledCode {
+    //NOTE(joh): Called every frame, continues a program started with "run"
+    fn run_slice(&mut self, env: &mut Env) {
+        if self.run_state != RunState::Running {
+            return;
+        }
+        if self.run_target != RunTarget::End {
+            if self.run_to_target(env, RUN_SLICE_OPS) && self.run_state == RunState::Running {
+                self.run_state = RunState::Paused;
+                //NOTE(joh): Resuming after a step runs the program on
+                if let RunTarget::Return(_) = self.run_target {
+                    self.run_target = RunTarget::End;
+                }
+            }
+            return;
+        }
+        match self.interpreter.run_for(env, RUN_SLICE_OPS) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.run_state = RunState::Idle;
+                self.interpreter.value_stack.clone_into(&mut self.results);
+            }
+            Err(error) => {
+                self.run_state = RunState::Idle;
+                self.trap = Some(Trap::capture(&self.interpreter, error));
+            }
+        }
+    }
+
+    fn start(&mut self, target: RunTarget) {
+        self.trap = None;
+        self.run_state = RunState::Running;
+        self.run_target = target;
+    }
+
+    //NOTE(joh): Aborts the run, the program starts over the next time
+    fn stop(&mut self) -> Result<(), InterpreterErrorType> {
+        self.run_state = RunState::Idle;
+        let bytecode = self.interpreter.bytecode.clone();
+        self.interpreter.reset_all(&bytecode)
+    }
+
+    //NOTE(joh): Executes at most `max_ops` ops, true once the program ended or `run_target` is
+    //reached. Always executes at least one op so it doesn't get stuck on the current breakpoint.
+    fn run_to_target(&mut self, env: &mut Env, max_ops: u64) -> bool {
+        self.interpreter.running = true;
+        for _ in 0..max_ops {
+            if let Err(error) = self.interpreter.exec_next_op(env) {
+                self.run_state = RunState::Idle;
+                self.trap = Some(Trap::capture(&self.interpreter, error));
+                return true;
+            }
+            if !self.interpreter.running {
+                self.run_state = RunState::Idle;
+                self.interpreter.value_stack.clone_into(&mut self.results);
+                return true;
+            }
+            if self.reached_target() {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn reached_target(&self) -> bool {
+        match self.run_target {
+            RunTarget::End => false,
+            RunTarget::Breakpoint => self.breakpoints.contains(&self.interpreter.pc),
+            RunTarget::Return(depth) => {
+                self.interpreter.return_stack.len() <= depth || self.breakpoints.contains(&self.interpreter.pc)
+            }
+        }
+    }
+
+    //NOTE(joh): Runs in slices like `run_slice` and pauses on the next breakpoint
+    fn run_to_breakpoint(&mut self) {
+        self.start(RunTarget::Breakpoint);
+    }
+
+    //NOTE(joh): Runs until the pc maps to another source line. Calls are stepped into.
+    fn step_line(&mut self, env: &mut Env) {
+        let line_of = |code: &Self| code.debug_info.resolve_line(code.interpreter.pc).map(|e| (e.file, e.line));
+        let start = line_of(self);
+        self.trap = None;
+        self.interpreter.running = true;
+        for _ in 0..STEP_LINE_MAX_OPS {
+            if let Err(error) = self.interpreter.exec_next_op(env) {
+                self.trap = Some(Trap::capture(&self.interpreter, error));
+                return;
+            }
+            if !self.interpreter.running {
+                self.interpreter.value_stack.clone_into(&mut self.results);
+                return;
+            }
+            if line_of(self) != start || self.breakpoints.contains(&self.interpreter.pc) {
+                return;
+            }
+        }
+    }
+
+    //NOTE(joh): Steps over the next op or out of the current function, stopping on breakpoints.
+    //A step that takes longer than `STEP_LINE_MAX_OPS` goes on in slices like `run_slice`.
+    fn step_frame(&mut self, env: &mut Env, out: bool) {
+        self.trap = None;
+        let previous = self.run_target;
+        self.run_target = RunTarget::Return(self.interpreter.return_stack.len().saturating_sub(out as usize));
+        if self.run_to_target(env, STEP_LINE_MAX_OPS as u64) {
+            self.run_target = previous;
+        } else {
+            self.run_state = RunState::Running;
+        }
+    }
+
+    //NOTE(joh): Only lines of the main file can be shown, and only while the editor still
+    //holds the source the program was compiled from
+    fn active_line(&self, editor_code: &str) -> Option<usize> {
+        if self.source != editor_code {
+            return None;
+        }
+        let entry = self.debug_info.resolve_line(self.interpreter.pc)?;
+        (entry.file == 0).then_some(entry.line as usize)
+    }
+}
+#[allow(dead_code)]
 pub enum AppError {
     InterpreterError(InterpreterErrorType),
     LabelDoesNotExist(String),
@@ -24,21 +204,13 @@ impl From<InterpreterErrorType> for AppError {
     }
 }
 
-pub struct Env {
-    log: String,
-}
-impl Default for Env {
-    fn default() -> Self {
-        Self { log: Default::default() }
-    }
-}
+pub type Env = StdHandler<GuiConsole>;
+#[derive(Default)]
 pub struct TemplateApp {
-    // Example stuff:
-    label: String,
-    value: f32,
     editor: Editor,
     code: Option<CompiledCode>,
     label_menu: bool,
+    cfg_window: bool,
     selected_label: Option<usize>,
     jump_dest: Option<usize>,
 
@@ -47,64 +219,36 @@ pub struct TemplateApp {
 
     selected_local_slot_slider: usize,
     selected_local_slot: Option<usize>,
+    //NOTE(joh): Arguments for calling a label from the label panel
+    call_args: String,
+    call_error: Option<String>,
+    //NOTE(joh): Last failed action from the menu bar, shown under it until it is dismissed
+    menu_error: Option<String>,
     env: Env, 
-
-}
-#[allow(non_upper_case_globals)]
-pub mod syscall {
-    pub const PrintDebugString: u32  = 0x00;   
-}
-
-#[derive(Debug, Copy, Clone)]
-pub enum EnvError {
-    InvalidMemAddr = 1,
-    InvalidStringData = 2, 
-
-    Unknown = 99,
-}
-impl EnvError {
-    pub fn as_return_code(result: Result<(), EnvError>) -> u32 {
-        match result {
-            Ok(_) => 0,
-            Err(e) => e as u32,
-        } 
-    }
-}
-impl From<InterpreterErrorType> for EnvError {
-    fn from(value: InterpreterErrorType) -> Self {
-        match value {
-            InterpreterErrorType::InvalidStringData(_) => Self::InvalidStringData,
-            InterpreterErrorType::AddrOutOfBounds(_) => Self::InvalidMemAddr,
-            _ => EnvError::Unknown
-        }
-    }
-}
-impl Env {
-    fn print_debug_string(&mut self, interpreter: &mut Interpreter, addr: u32, len: u32) -> Result<(), EnvError> {
-        let string_data = interpreter.read_str(addr, len)?;
-        self.log.push_str(string_data);
-        Ok(())
-    }
-}
-impl SyscallHandler for Env {
-    fn on_syscall(&mut self, interpreter: &mut Interpreter, id: u32, args: &[u32]) -> u32 {
-        match id {
-            syscall::PrintDebugString => {
-                let addr = args[0];
-                let len = args[1];
-
-                EnvError::as_return_code(self.print_debug_string(interpreter, addr, len))
-            }
-            _ => 0  
-        }
-    }
+    //NOTE(joh): Texture of the framebuffer, see `display_window`
+    display: Option<egui::TextureHandle>,
+    capabilities: Capabilities,
+    use_const_pool: bool,
+    //NOTE(joh): Every run starts the `Random` syscall with this seed, otherwise it keeps going
+    fixed_seed: Option<u64>,
+    //NOTE(joh): Where the editor source was last saved to or loaded from
+    source_path: Option<PathBuf>,
+    #[cfg(target_arch = "wasm32")]
+    files: crate::web::FilePicker,
 }
 impl TemplateApp {
     fn parse_ops(&mut self) -> Result<(), std::io::Error> {
         //TODO: Das ist schreklich
         if let Some(code) = &mut self.code {
-            let mut reader = Cursor::new(code.interpreter.inital_bytecode()); 
+            let bytecode = &code.interpreter.bytecode;
+            let info = BytecodeInfo::decode(&mut Cursor::new(&bytecode[..]))?;
+            let code_start = BytecodeInfo::total_header_size();
+            let code_section = bytecode
+                .get(code_start..code_start + info.code_size_bytes as usize)
+                .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+            let mut reader = Cursor::new(code_section);
             let mut current_offset = DATA_START; 
+            code.ops.clear();
             for op in try_parse_ops_from_bytecode(&mut reader) {
                 let op = op?;
                 code.ops.push((op.clone(), current_offset));
@@ -113,6 +257,8 @@ impl TemplateApp {
                     MaybeRawOp::Unknown(_) => 1,
                 };
             };
+            //NOTE(joh): Bytecode the validator rejects only has no CFG
+            code.blocks = basic_blocks(bytecode).unwrap_or_default();
         };
         Ok(())
     }
@@ -120,61 +266,310 @@ impl TemplateApp {
     fn compile(&mut self) -> Result<(), InterpreterErrorType> {
         //TODO: Error Handling
         let text = &self.editor.code;
-        let bytecode = asm::Parser::parse(&text).unwrap();
+        let mut parser = if self.use_const_pool {
+            asm::Parser::with_const_pool()
+        } else {
+            asm::Parser::new()
+        };
+        if let Some(path) = &self.source_path {
+            parser.set_source_path(path);
+        }
+        //NOTE(joh): Exported bytecode keeps the line table and the label names
+        parser.set_emit_debug_info(true);
+        parser.set_emit_symbols(true);
+        let bytecode = parser.assemble(text).unwrap();
+        let source = text.clone();
+        self.load_program(&bytecode.code, bytecode.labels, bytecode.debug_info, source)
+    }
+
+    //NOTE(joh): Replaces the loaded program. Imported bytecode has no source, labels and debug
+    //info only if they are embedded in it.
+    fn load_program(
+        &mut self,
+        bytecode: &[u8],
+        labels: Box<[(String, u32)]>,
+        debug_info: DebugInfo,
+        source: String,
+    ) -> Result<(), InterpreterErrorType> {
         self.selected_label = None;
+        self.env.console.clear_input();
+        self.env.framebuffer = None;
+        if let Some(seed) = self.fixed_seed {
+            self.env.seed(seed);
+        }
+        let functions = split_function_table(bytecode).1.unwrap_or_default();
 
         match self.code {
             Some(ref mut code) => {
-                code.interpreter.reset_all(&bytecode.code).unwrap();
-                code.labels = bytecode.labels;
-                Ok(())
+                code.interpreter.reset_all(bytecode)?;
+                code.interpreter.config.capabilities = self.capabilities;
+                code.labels = labels;
+                code.functions = functions;
+                code.debug_info = debug_info;
+                code.trap = None;
+                code.source = source;
+                code.run_state = RunState::Idle;
             }
             None => {
-                let interpreter = Interpreter::from_bytecode(&bytecode.code)?;
+                let mut interpreter = Interpreter::from_bytecode(bytecode)?;
+                interpreter.config.capabilities = self.capabilities;
+                interpreter.config = interpreter.config.with_gas_table(GasTable::default());
+                interpreter.start_recording(HISTORY_CAPACITY);
                 let code = CompiledCode {
                     interpreter,
-                    labels: bytecode.labels,
+                    labels,
+                    functions,
+                    debug_info,
+                    trap: None,
+                    source,
                     results: Vec::new(),
-                    ops: Vec::new()
+                    ops: Vec::new(),
+                    blocks: Vec::new(),
+                    scroll_to: None,
+                    breakpoints: BTreeSet::new(),
+                    saved_state: None,
+                    run_state: RunState::Idle,
+                    run_target: RunTarget::End,
                 };
                 self.code = Some(code);
-                self.parse_ops()?;
+            }
+        }
+        self.parse_ops()?;
+        Ok(())
+    }
+
+    //NOTE(joh): The native dialogs block until they are closed. Cancelling is not an error.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_source(&mut self, save_as: bool) -> Result<(), InterpreterErrorType> {
+        let path = match &self.source_path {
+            Some(path) if !save_as => path.clone(),
+            _ => {
+                let dialog = rfd::FileDialog::new()
+                    .add_filter("malu source", &[SOURCE_EXTENSION])
+                    .set_file_name(format!("program.{SOURCE_EXTENSION}"));
+                let Some(path) = dialog.save_file() else {
+                    return Ok(());
+                };
+                path
+            }
+        };
+        std::fs::write(&path, &self.editor.code)?;
+        self.source_path = Some(path);
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_source(&mut self) -> Result<(), InterpreterErrorType> {
+        let dialog = rfd::FileDialog::new().add_filter("malu source", &[SOURCE_EXTENSION]);
+        let Some(path) = dialog.pick_file() else {
+            return Ok(());
+        };
+        self.editor.code = std::fs::read_to_string(&path)?;
+        self.source_path = Some(path);
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn export_bytecode(&self) -> Result<(), InterpreterErrorType> {
+        let Some(code) = &self.code else {
+            return Ok(());
+        };
+        let dialog = rfd::FileDialog::new()
+            .add_filter("malu bytecode", &[BYTECODE_EXTENSION])
+            .set_file_name(self.bytecode_file_name());
+        let Some(path) = dialog.save_file() else {
+            return Ok(());
+        };
+        std::fs::write(path, &code.interpreter.bytecode)?;
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn import_bytecode(&mut self) -> Result<(), InterpreterErrorType> {
+        let dialog = rfd::FileDialog::new().add_filter("malu bytecode", &[BYTECODE_EXTENSION]);
+        let Some(path) = dialog.pick_file() else {
+            return Ok(());
+        };
+        let bytecode = std::fs::read(path)?;
+        self.load_bytecode(&bytecode)
+    }
+
+    fn bytecode_file_name(&self) -> String {
+        self.source_path
+            .as_ref()
+            .and_then(|p| p.with_extension(BYTECODE_EXTENSION).file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or(format!("program.{BYTECODE_EXTENSION}"))
+    }
+
+    fn load_bytecode(&mut self, bytecode: &[u8]) -> Result<(), InterpreterErrorType> {
+        let mut debug_info = split_debug_info(bytecode).1.unwrap_or_default();
+        let symbols = split_symbol_table(bytecode).1.unwrap_or_default();
+        //NOTE(joh): The label panel shows positions relative to the start of the code
+        let labels = symbols.labels.iter().map(|(name, addr)| (name.clone(), addr.saturating_sub(DATA_START))).collect();
+        debug_info.labels = symbols.labels;
+        debug_info.data_labels = symbols.data_labels;
+        self.load_program(bytecode, labels, debug_info, String::new())
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn file_menu(&mut self, ui: &mut egui::Ui) {
+        let result = if ui.button("Save").clicked() {
+            self.save_source(false)
+        } else if ui.button("Save as…").clicked() {
+            self.save_source(true)
+        } else if ui.button("Load").clicked() {
+            self.load_source()
+        } else {
+            Ok(())
+        };
+        ui.separator();
+        let result = result.and_then(|_| {
+            if ui.add_enabled(self.code.is_some(), egui::Button::new("Export bytecode")).clicked() {
+                self.export_bytecode()
+            } else if ui.button("Import bytecode").clicked() {
+                self.import_bytecode()
+            } else {
                 Ok(())
             }
+        });
+        if let Err(e) = result {
+            report_error(&mut self.menu_error, "file operation failed", e);
         }
     }
 
+    //NOTE(joh): Loading finishes in `receive_files`, saving downloads the file
+    #[cfg(target_arch = "wasm32")]
+    fn file_menu(&mut self, ui: &mut egui::Ui) {
+        let source_name = self
+            .source_path
+            .as_ref()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or(format!("program.{SOURCE_EXTENSION}"));
+        let result = if ui.button("Save").clicked() {
+            crate::web::download(&source_name, self.editor.code.as_bytes())
+        } else if ui.button("Load").clicked() {
+            self.files.pick_source(ui.ctx(), SOURCE_EXTENSION);
+            Ok(())
+        } else {
+            Ok(())
+        };
+        ui.separator();
+        let result = result.and_then(|_| {
+            if ui.add_enabled(self.code.is_some(), egui::Button::new("Export bytecode")).clicked()
+                && let Some(code) = &self.code
+            {
+                crate::web::download(&self.bytecode_file_name(), &code.interpreter.bytecode)
+            } else if ui.button("Import bytecode").clicked() {
+                self.files.pick_bytecode(ui.ctx(), BYTECODE_EXTENSION);
+                Ok(())
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            report_error(&mut self.menu_error, "download failed", format!("{e:?}"));
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn receive_files(&mut self) {
+        use crate::web::PickedFile;
+        while let Some(file) = self.files.poll() {
+            match file {
+                PickedFile::Source { name, text } => {
+                    self.editor.code = text;
+                    self.source_path = Some(name.into());
+                }
+                PickedFile::Bytecode(bytecode) => {
+                    if let Err(e) = self.load_bytecode(&bytecode) {
+                        report_error(&mut self.menu_error, "import failed", e);
+                    }
+                }
+            }
+        }
+    }
+
+    //NOTE(joh): Patches changed functions into the running program. Adding or removing
+    //labels changes the function boundaries, that needs a full compile. Either all changes are
+    //applied or, if one of them fails, none.
+    fn apply_code_changes(&mut self) -> Result<(), HotReloadError> {
+        let Some(code) = &mut self.code else {
+            return Ok(());
+        };
+        let new_source = &self.editor.code;
+        let memory = code.interpreter.memory.clone();
+        let instructions = code.interpreter.instructions.clone();
+        let mut debug_info = code.debug_info.clone();
+        let mut replaced = Vec::new();
+
+        let names: Vec<String> = debug_info
+            .labels
+            .iter()
+            .map(|(name, _)| name.clone())
+            .filter(|name| is_function_label(name))
+            .collect();
+        let result = names.iter().try_for_each(|name| {
+            let old = function_source(&code.source, name);
+            let new = function_source(new_source, name).ok_or(HotReloadError::LabelMismatch)?;
+            if old != Some(new) {
+                replaced.extend(function_range(&debug_info, name));
+                reload_function(&mut code.interpreter, &mut debug_info, name, new)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            code.interpreter.memory = memory;
+            code.interpreter.instructions = instructions;
+            return Err(e);
+        }
+
+        //NOTE(joh): Ops of the replaced code start elsewhere now
+        code.breakpoints.retain(|addr| !replaced.iter().any(|(start, end)| (start..end).contains(&addr)));
+        code.debug_info = debug_info;
+        code.source = new_source.clone();
+        Ok(())
+    }
+
+    //NOTE(joh): The program runs in slices from `update`, see `CompiledCode::run_slice`
     fn compile_run(&mut self) -> Result<(), InterpreterErrorType> {
         self.compile()?;
-        let code = self.code.as_mut().unwrap();
-        code.interpreter.run(&mut self.env).unwrap().clone_into(&mut code.results);
-        
+        self.code.as_mut().unwrap().start(RunTarget::End);
         Ok(())
     }
-}
-impl Default for TemplateApp {
-    fn default() -> Self {
-        Self {
-            // Example stuff:
-            label: "Hello World!".to_owned(),
-            value: 2.7,
-            editor: Default::default(),
-            code: None,
-            label_menu: false,
-            selected_label: None,
-            jump_dest: None,
-            selected_global_slot_slider: 0,
-            selected_global_slot: None,
-            selected_local_slot_slider: 0, 
-            selected_local_slot: None,
-            env: Default::default(),
-        }
+
+    //NOTE(joh): Shows the framebuffer the program set with the `SetFramebuffer` syscall, scaled by
+    //whole pixels to fit the window. The texture is uploaded again every frame.
+    fn display_window(&mut self, ctx: &egui::Context) {
+        let (Some(code), Some(framebuffer)) = (&self.code, self.env.framebuffer) else {
+            self.display = None;
+            return;
+        };
+        let Some(pixels) = framebuffer.pixels(&code.interpreter.memory) else {
+            return;
+        };
+        let size = [framebuffer.width as usize, framebuffer.height as usize];
+        let image = egui::ColorImage::from_rgba_unmultiplied(size, &pixels);
+        let texture = match &mut self.display {
+            Some(texture) => {
+                texture.set(image, egui::TextureOptions::NEAREST);
+                texture
+            }
+            None => self.display.insert(ctx.load_texture("display", image, egui::TextureOptions::NEAREST)),
+        };
+        egui::Window::new("🖼 Display").default_size([256.0, 256.0]).show(ctx, |ui| {
+            let available = ui.available_size();
+            let scale = (available.x / size[0] as f32)
+                .min(available.y / size[1] as f32)
+                .floor()
+                .max(1.0);
+            let size = egui::vec2(size[0] as f32, size[1] as f32) * scale;
+            ui.image(egui::load::SizedTexture::new(texture.id(), size));
+        });
     }
 }
-
 impl TemplateApp {
     /// Called once before the first frame.
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
         // This is also where you can customize the look and feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
 
@@ -185,11 +580,23 @@ impl TemplateApp {
 }
 
 impl eframe::App for TemplateApp {
-    /// Called by the framework to save state before shutdown.
-
     /// Called each time the UI needs repainting, which may be many times per second.
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         //TODO: (joh): Nutze Arena hier
+        #[cfg(target_arch = "wasm32")]
+        self.receive_files();
+        if let Some(code) = &mut self.code
+            && code.run_state == RunState::Running
+        {
+            //NOTE(joh): Input only reaches the program while it runs, typing into the editor does not
+            let (events, focused) = ctx.input(|i| (i.events.clone(), i.focused));
+            self.env.console.receive(&events);
+            if !focused {
+                self.env.console.release_keys();
+            }
+            code.run_slice(&mut self.env);
+            ctx.request_repaint();
+        }
 
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
@@ -198,10 +605,10 @@ impl eframe::App for TemplateApp {
         egui::Window::new("Code")
             .show(ctx, |ui| self.editor.ui(ui));
         */
-        if let Some(code) = &self.code
+        if let Some(_code) = &self.code
             && self.label_menu
         {
-            egui::Window::new("Labels").show(ctx, |ui| {});
+            egui::Window::new("Labels").show(ctx, |_ui| {});
         }
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
@@ -209,48 +616,122 @@ impl eframe::App for TemplateApp {
             egui::MenuBar::new().ui(ui, |ui| {
                 // NOTE: no File->Quit on web pages!
                 let is_web = cfg!(target_arch = "wasm32");
-                if !is_web {
-                    ui.menu_button("File", |ui| {
-                        ui.button("Save");
-                        ui.button("Load");
+                ui.menu_button("File", |ui| {
+                    self.file_menu(ui);
+                    if !is_web {
+                        ui.separator();
                         if ui.button("Quit").clicked() {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
+                    }
+                });
+                ui.menu_button("Run", |ui| {
+                    if ui.button("Compile").clicked() {
+                        println!("code: {}", self.editor.code);
+                        _ = self.compile();
+                    }
+
+                    if ui.button("Compile & Run").clicked() {
+                        self.compile_run().unwrap();
+                    }
+
+                    if self.code.is_some() {
+                        if ui.button("Apply code changes").clicked()
+                            && let Err(e) = self.apply_code_changes()
+                        {
+                            report_error(&mut self.menu_error, "hot reload failed", e);
+                        }
+                        _ = ui.button("Call");
+                    }
+                    if let Some(code) = &mut self.code {
+                        match code.run_state {
+                            RunState::Paused => {
+                                if ui.button("Resume").clicked() {
+                                    code.run_state = RunState::Running;
+                                }
+                            }
+                            state => {
+                                let running = state == RunState::Running;
+                                if ui.add_enabled(running, egui::Button::new("Pause")).clicked() {
+                                    code.run_state = RunState::Paused;
+                                }
+                            }
+                        }
+                        if ui.add_enabled(code.run_state != RunState::Idle, egui::Button::new("Stop")).clicked() {
+                            if let Err(e) = code.stop() {
+                                report_error(&mut self.menu_error, "stop failed", e);
+                            }
+                            if let Some(seed) = self.fixed_seed {
+                                self.env.seed(seed);
+                            }
+                        }
+                        _ = ui.button("Next");
+                    }
+                    ui.separator();
+                    ui.menu_button("Capabilities", |ui| {
+                        for group in SyscallGroup::ALL {
+                            if ui.checkbox(self.capabilities.flag_mut(group), group.name()).changed()
+                                && let Some(code) = &mut self.code
+                            {
+                                code.interpreter.config.capabilities = self.capabilities;
+                            }
+                        }
                     });
-                    ui.menu_button("Run", |ui| {
-                        if ui.button("Compile").clicked() {
-                            println!("code: {}", self.editor.code);
-                            self.compile();
+                });
+                if let Some(_code) = &self.code {
+                    ui.menu_button("Program", |ui| {
+                        if ui.button("Labels").clicked() {
+                            self.label_menu = true;
                         }
-
-                        if ui.button("Compile & Run").clicked() {
-                            self.compile_run().unwrap();
+                        if ui.button("CFG").clicked() {
+                            self.cfg_window = true;
                         }
+                    });
+                };
 
-                        if self.code.is_some() {
-                            ui.button("Call");
-                            ui.button("Pause");
-                            ui.button("Stop");
-                            ui.button("Next");
+                ui.menu_button("Settings", |ui| {
+                    ui.menu_button("Color Scheme", |ui| {
+                        egui::widgets::global_theme_preference_buttons(ui);
+                    });
+                    ui.menu_button("Clock", |ui| {
+                        let mode = self.env.clock.mode();
+                        if ui.radio(mode == ClockMode::Real, "Real").clicked() {
+                            self.env.clock.set_mode(ClockMode::Real);
+                        }
+                        let is_virtual = matches!(mode, ClockMode::Virtual { .. });
+                        if ui.radio(is_virtual, "Virtual (deterministic)").clicked() {
+                            self.env.clock.set_mode(ClockMode::Virtual {
+                                nanos_per_op: DEFAULT_NANOS_PER_OP,
+                                epoch_secs: 0,
+                            });
                         }
                     });
-                    if let Some(code) = &self.code {
-                        ui.menu_button("Program", |ui| {
-                            if ui.button("Labels").clicked() {
-                                self.label_menu = true;
-                            }
-                        });
-                    };
-
-                    ui.menu_button("Settings", |ui| {
-                        ui.menu_button("Color Scheme", |ui| {
-                            egui::widgets::global_theme_preference_buttons(ui);
-                        });
+                    ui.menu_button("Random", |ui| {
+                        let mut fixed = self.fixed_seed.is_some();
+                        if ui.checkbox(&mut fixed, "Fixed seed (deterministic)").changed() {
+                            self.fixed_seed = fixed.then(|| self.env.random_seed());
+                        }
+                        if let Some(seed) = &mut self.fixed_seed
+                            && ui.add(egui::DragValue::new(seed).prefix("seed ")).changed()
+                        {
+                            self.env.seed(*seed);
+                        }
                     });
-                    ui.menu_button("Help", |ui| {});
-                    ui.add_space(30.0);
-                }
+                    ui.checkbox(&mut self.use_const_pool, "Constant pool");
+                });
+                ui.menu_button("Help", |_ui| {});
+                ui.add_space(30.0);
             });
+            if let Some(error) = &self.menu_error {
+                let mut dismissed = false;
+                ui.horizontal(|ui| {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                    dismissed = ui.small_button("✖").clicked();
+                });
+                if dismissed {
+                    self.menu_error = None;
+                }
+            }
         });
 
         if let Some(code) = &mut self.code {
@@ -260,21 +741,74 @@ impl eframe::App for TemplateApp {
                 ScrollArea::vertical().show(ui, |ui| {
                         ui.collapsing("⎈ Controls", |ui| {
                             ui.label(format!("PC: 0x{:04x}", code.interpreter.pc));
+                            ui.label(format!(
+                                "Gas: {} ({} ops)",
+                                code.interpreter.gas_used, code.interpreter.executed_ops
+                            ));
+                            if let Some(loc) = code.debug_info.resolve_pc(code.interpreter.pc) {
+                                ui.label(format!("at {loc}"));
+                            }
+                            if code.run_state == RunState::Running {
+                                ui.horizontal(|ui| {
+                                    ui.spinner();
+                                    ui.label("still running");
+                                });
+                            }
+                            ui.horizontal(|ui| {
+                                if ui.button("▶ run").clicked() {
+                                    code.start(RunTarget::End);
+                                }
+                                _ = ui.button("⏮ reset");
+                                let can_step_back = code.interpreter.can_step_back();
+                                if ui.add_enabled(can_step_back, egui::Button::new("⏪ back")).clicked() {
+                                    code.interpreter.step_back();
+                                    code.trap = None;
+                                }
+                                if ui.button("⏩ next").clicked()
+                                    && let Err(error) = code.interpreter.exec_next_op(&mut self.env)
+                                {
+                                    code.trap = Some(Trap::capture(&code.interpreter, error));
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                let has_lines = !code.debug_info.lines.is_empty();
+                                if ui.add_enabled(has_lines, egui::Button::new("↧ step line")).clicked() {
+                                    code.step_line(&mut self.env);
+                                }
+                                if ui.button("⏭ run to breakpoint").clicked() {
+                                    code.run_to_breakpoint();
+                                }
+                            });
                             ui.horizontal(|ui| {
-                                ui.button("▶ run");
-                                ui.button("⏮ reset");
-                                if ui.button("⏩ next").clicked() {
-                                    code.interpreter.exec_next_op(&mut self.env).unwrap();
+                                if ui.button("↷ step over").clicked() {
+                                    code.step_frame(&mut self.env, false);
+                                }
+                                if ui.button("↥ step out").clicked() {
+                                    code.step_frame(&mut self.env, true);
                                 }
                             });
+                            ui.horizontal(|ui| {
+                                if ui.button("💾 save state").clicked() {
+                                    code.saved_state = Some(code.interpreter.snapshot());
+                                }
+                                if let Some(snapshot) = &code.saved_state
+                                    && ui.button("📂 load state").clicked()
+                                {
+                                    code.interpreter.restore(snapshot);
+                                    code.trap = None;
+                                }
+                            });
+                            if let Some(trap) = &code.trap {
+                                ui.colored_label(ui.visuals().error_fg_color, trap.render(Some(&code.debug_info)));
+                            }
                             ui.separator();
                         });
 
                         
                         ui.collapsing("⛃ Value Stack", |ui| {
                             let stack = &code.interpreter.value_stack;
-                            if stack.len() > 0 {
-                                value_table(ui, stack, None, None);
+                            if !stack.is_empty() {
+                                value_table(ui, stack, None, &[], None);
                                 ui.separator();
                             }
                         });
@@ -294,12 +828,22 @@ impl eframe::App for TemplateApp {
                             }
                             ui.separator();
 
-                            value_table(ui, &code.interpreter.globals, Some("Slot"), self.selected_global_slot);
+                            value_table(
+                                ui,
+                                &code.interpreter.globals,
+                                Some("Slot"),
+                                &code.debug_info.globals,
+                                self.selected_global_slot,
+                            );
                             ui.separator();
                             
                         });
                         ui.collapsing("Ｓ Frames", |ui| {
-                            for (i, frame) in code.interpreter.return_stack.iter().enumerate() {
+                            let return_stack = &code.interpreter.return_stack;
+                            for (i, frame) in return_stack.iter().enumerate() {
+                                //NOTE(joh): A frame continues where the frame above it returns to
+                                let pc = return_stack.get(i + 1).map_or(code.interpreter.pc, |f| f.return_addr);
+                                let names = code.debug_info.local_names(pc);
                                 ui.collapsing(format!("{}: @0x{:04x}", i, frame.return_addr), |ui| {
                                     let slider_response = ui.add(
                                         egui::Slider::new(&mut self.selected_local_slot_slider, 0..=63)
@@ -314,38 +858,125 @@ impl eframe::App for TemplateApp {
                                     }
                                     ui.separator();
 
-                                    value_table(ui, &frame.locals, Some("Slot"), self.selected_local_slot);
+                                    value_table(ui, &frame.locals, Some("Slot"), names, self.selected_local_slot);
                                     ui.separator();
                                 });
                             } 
                         });
                         ui.collapsing("🏷 Labels", |ui| {
-                            if let Some(index) = select_label(ui, &code) {
-                                let (name, position) = code.labels.get(index).unwrap();
+                            if let Some(index) = select_label(ui, code) {
+                                let (_name, position) = code.labels.get(index).unwrap();
                                 self.jump_dest = Some(*position as usize);
                             }
                             ui.separator();
                             ui.horizontal(|ui| {
                                 if let Some(dest) = self.jump_dest {
                                     ui.label(format!("Jump to: 0x{:04x}", dest));
-                                    ui.button("jump");
-                                    ui.button("call");
+                                    _ = ui.button("jump");
+                                    if ui.button("call").clicked() {
+                                        self.call_error = call_label(code, &mut self.env, dest, &self.call_args).err();
+                                    }
+                                }
+                            });
+                            if self.jump_dest.is_some() {
+                                ui.horizontal(|ui| {
+                                    ui.label("Args:");
+                                    ui.text_edit_singleline(&mut self.call_args)
+                                        .on_hover_text(format!("Up to {MAX_ARGS} values, e.g. `1, -2, 0x10`"));
+                                });
+                            }
+                            if let Some(error) = &self.call_error {
+                                ui.colored_label(ui.visuals().error_fg_color, error);
+                            }
+                        });
+                        ui.collapsing("⏱ Profiler", |ui| {
+                            let mut profiling = code.interpreter.profile().is_some();
+                            ui.horizontal(|ui| {
+                                if ui.checkbox(&mut profiling, "Profile").changed() {
+                                    match profiling {
+                                        true => code.interpreter.start_profiling(),
+                                        false => _ = code.interpreter.stop_profiling(),
+                                    }
+                                }
+                                if ui.add_enabled(profiling, egui::Button::new("clear")).clicked() {
+                                    code.interpreter.start_profiling();
+                                }
+                            });
+                            profiler_panel(ui, code);
+                            ui.separator();
+                            let mut covering = code.interpreter.coverage().is_some();
+                            ui.horizontal(|ui| {
+                                if ui.checkbox(&mut covering, "Coverage").changed() {
+                                    match covering {
+                                        true => code.interpreter.start_coverage(),
+                                        false => _ = code.interpreter.stop_coverage(),
+                                    }
+                                }
+                                if ui.add_enabled(covering, egui::Button::new("clear")).clicked() {
+                                    code.interpreter.start_coverage();
                                 }
                             });
+                            coverage_summary(ui, code);
+                            ui.separator();
+                            let mut tracing = code.interpreter.execution_trace().is_some();
+                            ui.horizontal(|ui| {
+                                if ui.checkbox(&mut tracing, "Trace").changed() {
+                                    match tracing {
+                                        true => code.interpreter.start_trace(),
+                                        false => _ = code.interpreter.stop_trace(),
+                                    }
+                                }
+                                let trace = code.interpreter.execution_trace().filter(|t| !t.ops().is_empty());
+                                if ui
+                                    .add_enabled(trace.is_some(), egui::Button::new("save json"))
+                                    .on_hover_text("For chrome://tracing or Perfetto")
+                                    .clicked()
+                                    && let Some(trace) = trace
+                                    && let Err(e) = save_file("trace.json", trace.to_chrome_json().as_bytes())
+                                {
+                                    report_error(&mut self.menu_error, "saving the trace failed", e);
+                                }
+                                if ui.add_enabled(trace.is_some(), egui::Button::new("save csv")).clicked()
+                                    && let Some(trace) = trace
+                                    && let Err(e) = save_file("trace.csv", trace.to_csv().as_bytes())
+                                {
+                                    report_error(&mut self.menu_error, "saving the trace failed", e);
+                                }
+                            });
+                            if let Some(trace) = code.interpreter.execution_trace() {
+                                ui.label(format!("{} ops in the last run", trace.ops().len()));
+                            }
+                            ui.separator();
                         });
-                        if code.results.len() > 0 {
+                        if !code.functions.is_empty() {
+                            ui.collapsing("ƒ Functions", |ui| {
+                                for function in &code.functions {
+                                    ui.label(format!("{}({}) @0x{:04x}", function.name, function.arity, function.addr));
+                                }
+                                ui.separator();
+                            });
+                        }
+                        if !code.results.is_empty() {
                             ui.collapsing("✔ Results", |ui| {
-                                ui.push_id(0, |ui| value_table(ui, &code.results, None, None));
+                                ui.push_id(0, |ui| value_table(ui, &code.results, None, &[], None));
                                 ui.separator();
                             });
                         }
                     });
                 });
         }
+        self.display_window(ctx);
+        if let Some(code) = &mut self.code {
+            egui::Window::new("CFG")
+                .open(&mut self.cfg_window)
+                .default_size([600.0, 400.0])
+                .show(ctx, |ui| cfg_view(ui, code));
+        }
         egui::CentralPanel::default()
             .show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
             ui.heading("🖮 Editor");
+            self.editor.active_line = self.code.as_ref().and_then(|code| code.active_line(&self.editor.code));
             self.editor.ui(ui);
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
@@ -354,7 +985,7 @@ impl eframe::App for TemplateApp {
             });
         });
 
-        if let Some(code) = &self.code {
+        if let Some(code) = &mut self.code {
             egui::SidePanel::right("main_right_side").show(ctx, |ui| {
                 ui.heading("⚡ Code");
                 show_mem_op(ui, code);
@@ -364,7 +995,7 @@ impl eframe::App for TemplateApp {
                 .resizable(true)
                 .show(ctx, |ui| {
                 ui.heading("📝 Log");
-                let text_response = ui.text_edit_multiline(&mut self.env.log.as_str());
+                let _text_response = ui.text_edit_multiline(&mut self.env.console.output.as_str());
                 
             });
         };
@@ -372,6 +1003,37 @@ impl eframe::App for TemplateApp {
     }
 }
 
+//NOTE(joh): Values are separated by spaces or commas, negative and 0x hex values are accepted
+fn parse_call_args(text: &str) -> Result<Vec<u32>, String> {
+    let args = text
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| {
+            let value = match arg.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => arg.parse::<u32>().ok().or_else(|| arg.parse::<i32>().ok().map(|v| v as u32)),
+            };
+            value.ok_or_else(|| format!("invalid argument `{arg}`"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if args.len() > MAX_ARGS {
+        return Err(format!("at most {MAX_ARGS} arguments can be passed"));
+    }
+    Ok(args)
+}
+
+//NOTE(joh): Runs the label at `position` (relative to the code start) as a function. The
+//returned values replace the results, the program state is left as it was.
+fn call_label(code: &mut CompiledCode, env: &mut Env, position: usize, args: &str) -> Result<(), String> {
+    let args = parse_call_args(args)?;
+    code.trap = None;
+    match code.interpreter.call(DATA_START + position as u32, &args, env) {
+        Ok(results) => code.results = results,
+        Err(trap) => code.trap = Some(trap),
+    }
+    Ok(())
+}
+
 fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
     ui.horizontal(|ui| {
         ui.spacing_mut().item_spacing.x = 0.0;
@@ -385,3 +1047,24 @@ fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
         ui.label(".");
     });
 }
+
+//NOTE(joh): Asks where to save it, in the browser it is downloaded instead
+#[cfg(not(target_arch = "wasm32"))]
+fn save_file(file_name: &str, contents: &[u8]) -> Result<(), String> {
+    let Some(path) = rfd::FileDialog::new().set_file_name(file_name).save_file() else {
+        return Ok(());
+    };
+    std::fs::write(path, contents).map_err(|e| e.to_string())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_file(file_name: &str, contents: &[u8]) -> Result<(), String> {
+    crate::web::download(file_name, contents).map_err(|e| format!("{e:?}"))
+}
+
+//NOTE(joh): The GUI has no console, so errors of menu actions are shown under the menu bar. Takes
+//the field instead of the app so it also works while the loaded code is borrowed.
+fn report_error(menu_error: &mut Option<String>, action: &str, error: impl std::fmt::Display) {
+    log::error!("{action}: {error}");
+    *menu_error = Some(format!("{action}: {error}"));
+}
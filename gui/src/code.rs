@@ -1,37 +1,100 @@
 
-use std::io::Cursor;
+use std::{collections::VecDeque, ops::Range};
 
-use egui::{ahash::HashMap, text::LayoutJob, Color32, ScrollArea, TextFormat, TextStyle};
+use egui::{
+    text::LayoutJob, vec2, Align, Align2, Color32, FontId, Rect, ScrollArea, Sense, Stroke, TextFormat, Vec2,
+};
 use egui_extras::{Column, TableBuilder};
-use vm::{asm::{self, Op, RawOp}, parse::{try_parse_ops_from_bytecode, MaybeRawOp}};
+use vm::{
+    analyze::{BasicBlock, EdgeKind},
+    asm::{opcode, RawOp, DATA_START},
+    lexer::{tokenize, TokenKind},
+    parse::MaybeRawOp,
+};
 
 use crate::app::CompiledCode;
 
+//NOTE(joh): Rows per table of the profiler panel
+const PROFILER_ROWS: usize = 20;
+//NOTE(joh): Size of a block in the CFG view and the space between two of them
+const CFG_BLOCK_SIZE: Vec2 = vec2(140.0, 36.0);
+const CFG_SPACING: Vec2 = vec2(30.0, 40.0);
+
 pub struct Editor {
     pub code: String,
+    //NOTE(joh): Line of the op at the pc, starting at 1
+    pub active_line: Option<usize>,
 }
 
 impl Default for Editor {
     fn default() -> Self {
         Self {
             code: include_str!("../assets/asm/code_example.malu").into(),
+            active_line: None,
+        }
+    }
+}
+fn token_color(kind: Option<TokenKind>, dark_mode: bool) -> Color32 {
+    let (dark, light) = match kind {
+        Some(TokenKind::Opcode) => (Color32::from_rgb(86, 156, 214), Color32::from_rgb(0, 0, 200)),
+        Some(TokenKind::Directive) => (Color32::from_rgb(197, 134, 192), Color32::from_rgb(140, 0, 140)),
+        Some(TokenKind::Label) => (Color32::from_rgb(220, 220, 120), Color32::from_rgb(150, 110, 0)),
+        Some(TokenKind::LabelRef) => (Color32::from_rgb(78, 201, 176), Color32::from_rgb(0, 120, 110)),
+        Some(TokenKind::Const) => (Color32::from_rgb(215, 186, 125), Color32::from_rgb(170, 90, 0)),
+        Some(TokenKind::Number) => (Color32::from_rgb(181, 206, 168), Color32::from_rgb(9, 134, 88)),
+        Some(TokenKind::String) => (Color32::from_rgb(206, 145, 120), Color32::from_rgb(163, 21, 21)),
+        Some(TokenKind::Comment) => (Color32::from_rgb(106, 153, 85), Color32::from_rgb(0, 128, 0)),
+        Some(TokenKind::Separator) | Some(TokenKind::Ident) | None => (Color32::LIGHT_GRAY, Color32::DARK_GRAY),
+    };
+    if dark_mode { dark } else { light }
+}
+
+//NOTE(joh): Byte range of the 1-based `line` including its newline
+fn line_range(code: &str, line: usize) -> Option<Range<usize>> {
+    let start = match line.checked_sub(1)? {
+        0 => 0,
+        n => code.match_indices('\n').nth(n - 1)?.0 + 1,
+    };
+    let end = code[start..].find('\n').map_or(code.len(), |i| start + i + 1);
+    Some(start..end)
+}
+
+//NOTE(joh): `lexer::tokenize` is separate from the parser of the assembler, its tests check that
+//both agree on where the ops are
+fn highlight(ui: &egui::Ui, code: &str, active_line: Option<usize>) -> LayoutJob {
+    let font_id = FontId::monospace(egui::TextStyle::Monospace.resolve(ui.style()).size);
+    let dark_mode = ui.visuals().dark_mode;
+    let active = active_line.and_then(|line| line_range(code, line)).unwrap_or(0..0);
+    let background = ui.visuals().selection.bg_fill.gamma_multiply(0.4);
+
+    let mut job = LayoutJob::default();
+    //NOTE(joh): Spans are split at the active line so only it gets the background
+    let mut append = |span: Range<usize>, kind| {
+        let clamp = |i: usize| i.clamp(span.start, span.end);
+        let cuts = [span.start, clamp(active.start), clamp(active.end), span.end];
+        for part in cuts.windows(2).filter(|w| w[0] < w[1]) {
+            let mut format = TextFormat::simple(font_id.clone(), token_color(kind, dark_mode));
+            if active.contains(&part[0]) {
+                format.background = background;
+            }
+            job.append(&code[part[0]..part[1]], 0.0, format);
         }
+    };
+    let mut end = 0;
+    for token in tokenize(code) {
+        append(end..token.span.start, None);
+        append(token.span.clone(), Some(token.kind));
+        end = token.span.end;
     }
+    append(end..code.len(), None);
+    job
 }
+
 impl Editor {
     pub fn ui(&mut self, ui: &mut egui::Ui) {
-        //TODO: Syntax Highlighting für asm
-        let mut theme =
-            egui_extras::syntax_highlighting::CodeTheme::from_memory(ui.ctx(), ui.style());
-
+        let active_line = self.active_line;
         let mut layouter = |ui: &egui::Ui, buf: &dyn egui::TextBuffer, wrap_width: f32| {
-            let mut layout_job = egui_extras::syntax_highlighting::highlight(
-                ui.ctx(),
-                ui.style(),
-                &theme,
-                buf.as_str(),
-                "rs",
-            );
+            let mut layout_job = highlight(ui, buf.as_str(), active_line);
             layout_job.wrap.max_width = wrap_width;
             
             ui.fonts_mut(|f| f.layout_job(layout_job))
@@ -54,7 +117,14 @@ impl Editor {
     }
 }
 
-pub fn value_table(ui: &mut egui::Ui, results: &[u32], index_name: Option<&str>, row_index: Option<usize>) {
+//NOTE(joh): `names` label the first rows, e.g. the `.local` and `.global` names from the debug info
+pub fn value_table(
+    ui: &mut egui::Ui,
+    results: &[u32],
+    index_name: Option<&str>,
+    names: &[String],
+    row_index: Option<usize>,
+) {
     let text_height = egui::TextStyle::Body
         .resolve(ui.style())
         .size
@@ -102,7 +172,10 @@ pub fn value_table(ui: &mut egui::Ui, results: &[u32], index_name: Option<&str>,
 
             if index_name.is_some() {
                 row.col(|ui| {
-                    ui.label(format!("{}", row_index));
+                    match names.get(row_index) {
+                        Some(name) => ui.label(format!("{row_index} {name}")),
+                        None => ui.label(format!("{}", row_index)),
+                    };
                 });
     
             }
@@ -119,7 +192,7 @@ pub fn value_table(ui: &mut egui::Ui, results: &[u32], index_name: Option<&str>,
     });
 }
 
-pub fn select_label<'a>(ui: &mut egui::Ui, code: &'a CompiledCode) -> Option<usize> {
+pub fn select_label(ui: &mut egui::Ui, code: &CompiledCode) -> Option<usize> {
     let mut selected = None;
     let text_height = egui::TextStyle::Body
         .resolve(ui.style())
@@ -161,7 +234,7 @@ pub fn select_label<'a>(ui: &mut egui::Ui, code: &'a CompiledCode) -> Option<usi
                 ui.label(row_index.to_string());
             });
             row.col(|ui| {
-                ui.label(format!("{}", name));
+                ui.label(name.to_string());
             });
             row.col(|ui| {
                 ui.label(format!("0x{:04x}", position));
@@ -176,7 +249,219 @@ pub fn select_label<'a>(ui: &mut egui::Ui, code: &'a CompiledCode) -> Option<usi
     selected
 }
 
-pub fn show_mem_op(ui: &mut egui::Ui, code: &CompiledCode) {
+//NOTE(joh): Brighter the more often the op ran compared to the hottest one
+fn heat_color(count: u64, max_count: u64) -> Color32 {
+    let heat = count as f32 / max_count.max(1) as f32;
+    Color32::from_rgba_unmultiplied(255, 96, 0, (32.0 + 160.0 * heat) as u8)
+}
+
+//NOTE(joh): Hot instructions, opcodes and functions of the running profile
+pub fn profiler_panel(ui: &mut egui::Ui, code: &CompiledCode) {
+    let Some(profile) = code.interpreter.profile() else {
+        return;
+    };
+    let total = profile.total_ops().max(1);
+    ui.label(format!("{} ops executed", profile.total_ops()));
+    let name_at = |addr: u32| match code.ops.binary_search_by_key(&addr, |(_, offset)| *offset) {
+        Ok(i) => match &code.ops[i].0 {
+            MaybeRawOp::Op(op) => op.name(),
+            MaybeRawOp::Unknown(_) => "???",
+        },
+        Err(_) => "???",
+    };
+    let function_name = |addr: u32| {
+        code.labels
+            .iter()
+            .find(|(_, position)| *position + DATA_START == addr)
+            .map_or(format!("0x{addr:04x}"), |(name, _)| name.clone())
+    };
+
+    ui.strong("Hot instructions");
+    egui::Grid::new("profiler_hot").striped(true).show(ui, |ui| {
+        for (addr, count) in profile.hot_instructions().into_iter().take(PROFILER_ROWS) {
+            ui.label(format!("0x{addr:04x}"));
+            ui.label(name_at(addr));
+            ui.label(count.to_string());
+            ui.label(format!("{:.1}%", 100.0 * count as f64 / total as f64));
+            ui.end_row();
+        }
+    });
+    ui.strong("Opcodes");
+    egui::Grid::new("profiler_opcodes").striped(true).show(ui, |ui| {
+        for (op, count) in profile.opcodes().into_iter().take(PROFILER_ROWS) {
+            ui.label(opcode::Names.get(op as usize).copied().unwrap_or("???"));
+            ui.label(count.to_string());
+            ui.label(format!("{:.1}%", 100.0 * count as f64 / total as f64));
+            ui.end_row();
+        }
+    });
+    let functions = profile.functions();
+    if !functions.is_empty() {
+        ui.strong("Functions");
+        egui::Grid::new("profiler_functions").striped(true).show(ui, |ui| {
+            for (addr, function) in functions.into_iter().take(PROFILER_ROWS) {
+                ui.label(function_name(addr));
+                ui.label(format!("{} calls", function.calls));
+                ui.label(format!("{:.3} ms", function.time.as_secs_f64() * 1000.0));
+                ui.end_row();
+            }
+        });
+    }
+}
+
+//NOTE(joh): Covered ops out of all ops of the program
+pub fn coverage_summary(ui: &mut egui::Ui, code: &CompiledCode) {
+    let Some(coverage) = code.interpreter.coverage() else {
+        return;
+    };
+    let total = code.interpreter.instructions.count();
+    let covered = coverage.count();
+    ui.label(format!(
+        "{covered}/{total} ops covered ({:.1}%)",
+        100.0 * covered as f64 / total.max(1) as f64
+    ));
+}
+
+fn edge_color(kind: EdgeKind) -> Color32 {
+    match kind {
+        EdgeKind::Fallthrough => Color32::GRAY,
+        EdgeKind::Jump => Color32::from_rgb(64, 128, 255),
+        EdgeKind::Branch => Color32::from_rgb(255, 160, 0),
+        EdgeKind::Call => Color32::from_rgb(0, 176, 96),
+    }
+}
+
+//NOTE(joh): Row and column of every block. Blocks that are only called or not reached at all
+//start the first row, the others go one row below the first block that jumps or falls through
+//to them. Blocks no row reaches, like a loop nothing jumps into, get rows at the bottom.
+fn cfg_layout(blocks: &[BasicBlock]) -> Vec<(usize, usize)> {
+    let index = |addr: u32| blocks.binary_search_by_key(&addr, |b| b.start).ok();
+    let successors = |i: usize| {
+        blocks[i]
+            .edges
+            .iter()
+            .filter(|(_, kind)| *kind != EdgeKind::Call)
+            .filter_map(move |(target, _)| index(*target))
+    };
+    let mut entered = vec![false; blocks.len()];
+    for i in 0..blocks.len() {
+        successors(i).for_each(|target| entered[target] = true);
+    }
+
+    let mut rows: Vec<Option<usize>> = vec![None; blocks.len()];
+    let spread = |rows: &mut Vec<Option<usize>>, mut queue: VecDeque<usize>| {
+        while let Some(i) = queue.pop_front() {
+            for target in successors(i) {
+                if rows[target].is_none() {
+                    rows[target] = rows[i].map(|row| row + 1);
+                    queue.push_back(target);
+                }
+            }
+        }
+    };
+    let roots = (0..blocks.len()).filter(|i| !entered[*i]).collect::<VecDeque<_>>();
+    roots.iter().for_each(|i| rows[*i] = Some(0));
+    spread(&mut rows, roots);
+    for i in 0..blocks.len() {
+        if rows[i].is_none() {
+            rows[i] = Some(rows.iter().flatten().max().map_or(0, |row| row + 1));
+            spread(&mut rows, VecDeque::from([i]));
+        }
+    }
+
+    let mut columns = Vec::new();
+    rows.into_iter()
+        .flatten()
+        .map(|row| {
+            if columns.len() <= row {
+                columns.resize(row + 1, 0);
+            }
+            columns[row] += 1;
+            (row, columns[row] - 1)
+        })
+        .collect()
+}
+
+//NOTE(joh): The basic blocks of the program with the block at the pc highlighted. Clicking a
+//block scrolls the code table to its first op.
+pub fn cfg_view(ui: &mut egui::Ui, code: &mut CompiledCode) {
+    if code.blocks.is_empty() {
+        ui.label("No control flow graph, the bytecode does not validate");
+        return;
+    }
+    ui.horizontal(|ui| {
+        for (kind, name) in [
+            (EdgeKind::Fallthrough, "fallthrough"),
+            (EdgeKind::Jump, "jump"),
+            (EdgeKind::Branch, "branch"),
+            (EdgeKind::Call, "call"),
+        ] {
+            ui.colored_label(edge_color(kind), name);
+        }
+    });
+    ui.separator();
+
+    let layout = cfg_layout(&code.blocks);
+    let rows = layout.iter().map(|(row, _)| row + 1).max().unwrap_or(0);
+    let columns = layout.iter().map(|(_, column)| column + 1).max().unwrap_or(0);
+    let cell = CFG_BLOCK_SIZE + CFG_SPACING;
+    let size = vec2(columns as f32 * cell.x, rows as f32 * cell.y);
+    let pc = code.interpreter.pc;
+
+    ScrollArea::both().show(ui, |ui| {
+        let (response, painter) = ui.allocate_painter(size, Sense::hover());
+        let origin = response.rect.min + CFG_SPACING / 2.0;
+        let rect_of = |(row, column): (usize, usize)| {
+            Rect::from_min_size(origin + vec2(column as f32 * cell.x, row as f32 * cell.y), CFG_BLOCK_SIZE)
+        };
+        let index = |addr: u32| code.blocks.binary_search_by_key(&addr, |b| b.start).ok();
+
+        for (block, &position) in code.blocks.iter().zip(&layout) {
+            let from = rect_of(position).center_bottom();
+            for (target, kind) in &block.edges {
+                let Some(target) = index(*target) else {
+                    continue;
+                };
+                let to = rect_of(layout[target]).center_top();
+                painter.arrow(from, to - from, Stroke::new(1.5, edge_color(*kind)));
+            }
+        }
+
+        let visuals = ui.visuals();
+        for (block, &position) in code.blocks.iter().zip(&layout) {
+            let rect = rect_of(position);
+            let fill = match block.contains(pc) {
+                true => visuals.selection.bg_fill,
+                false => visuals.widgets.inactive.bg_fill,
+            };
+            painter.rect_filled(rect, 4.0, fill);
+            let name = code
+                .labels
+                .iter()
+                .find(|(_, position)| *position + DATA_START == block.start)
+                .map_or(format!("0x{:04x}", block.start), |(name, _)| name.clone());
+            painter.text(
+                rect.center(),
+                Align2::CENTER_CENTER,
+                format!("{name}\n{} ops", block.ops),
+                FontId::monospace(12.0),
+                visuals.text_color(),
+            );
+        }
+
+        for (block, &position) in code.blocks.iter().zip(&layout) {
+            let response = ui
+                .interact(rect_of(position), ui.id().with(("cfg_block", block.start)), Sense::click())
+                .on_hover_text(format!("0x{:04x} - 0x{:04x}", block.start, block.last));
+            if response.clicked() {
+                code.scroll_to = Some(block.start);
+            }
+        }
+    });
+}
+
+//NOTE(joh): Clicking a row toggles a breakpoint on that op
+pub fn show_mem_op(ui: &mut egui::Ui, code: &mut CompiledCode) {
     ScrollArea::vertical().id_salt("grid_scroll").show(ui, |ui| {
         let text_height = egui::TextStyle::Body
             .resolve(ui.style())
@@ -184,8 +469,9 @@ pub fn show_mem_op(ui: &mut egui::Ui, code: &CompiledCode) {
             .max(ui.spacing().interact_size.y);
         let pc = code.interpreter.pc;
         let available_height = ui.available_height();
+        let max_count = code.interpreter.profile().map(|p| p.max_ops_at());
 
-        let table = TableBuilder::new(ui)
+        let mut table = TableBuilder::new(ui)
             .striped(true)
             .resizable(false)
             .min_scrolled_height(0.0)
@@ -193,11 +479,27 @@ pub fn show_mem_op(ui: &mut egui::Ui, code: &CompiledCode) {
             .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
             .column(Column::auto())
             .column(Column::auto())
-            .column(Column::auto());
+            .column(Column::auto())
+            .column(Column::auto())
+            .sense(egui::Sense::click());
+        if max_count.is_some() {
+            table = table.column(Column::auto());
+        }
+        if let Some(addr) = code.scroll_to.take()
+            && let Ok(row) = code.ops.binary_search_by_key(&addr, |(_, offset)| *offset)
+        {
+            table = table.scroll_to_row(row, Some(Align::Center));
+        }
         table.header(10.0, |mut header| {
+            header.col(|_ui| {});
             header.col(|ui| {
                 ui.strong("Offset");
             });
+            if max_count.is_some() {
+                header.col(|ui| {
+                    ui.strong("Count");
+                });
+            }
             header.col(|ui| {
                 ui.strong("Opcode");
             });
@@ -210,12 +512,33 @@ pub fn show_mem_op(ui: &mut egui::Ui, code: &CompiledCode) {
                 let (op, offset) = &code.ops[row.index()];
                 row.set_selected(pc as usize == *offset as usize);
                 row.col(|ui| {
+                    if code.breakpoints.contains(offset) {
+                        ui.colored_label(ui.visuals().error_fg_color, "●");
+                    }
+                });
+                row.col(|ui| {
+                    if let Some(coverage) = code.interpreter.coverage() {
+                        let color = match coverage.contains(*offset) {
+                            true => Color32::from_rgba_unmultiplied(0, 160, 64, 64),
+                            false => Color32::from_rgba_unmultiplied(200, 0, 0, 48),
+                        };
+                        ui.painter().rect_filled(ui.max_rect(), 0.0, color);
+                    }
                     ui.label(format!("0x{:04x}", offset));
                 });
+                if let (Some(profile), Some(max_count)) = (code.interpreter.profile(), max_count) {
+                    let count = profile.ops_at(*offset);
+                    row.col(|ui| {
+                        if count > 0 {
+                            ui.painter().rect_filled(ui.max_rect(), 0.0, heat_color(count, max_count));
+                        }
+                        ui.label(count.to_string());
+                    });
+                }
                 row.col(|ui| {
                     match op {
                         MaybeRawOp::Op(raw_op) => {
-                            ui.label(format!("{}", raw_op.name()));
+                            ui.label(raw_op.name().to_string());
                         },
                         MaybeRawOp::Unknown(_) => {
                             ui.label("???");
@@ -237,6 +560,9 @@ pub fn show_mem_op(ui: &mut egui::Ui, code: &CompiledCode) {
 
                     }
                 }
+                if row.response().clicked() && !code.breakpoints.remove(offset) {
+                    code.breakpoints.insert(*offset);
+                }
             }); 
         });  
 
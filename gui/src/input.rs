@@ -0,0 +1,91 @@
+use std::collections::{HashSet, VecDeque};
+
+use egui::{Event, Key};
+use vm::syscall::{key, Console};
+
+//NOTE(joh): Printed strings are collected in the log panel. Keys and typed text come from the
+//egui input events of the frames the program runs in, see `GuiConsole::receive`.
+#[derive(Debug, Default)]
+pub struct GuiConsole {
+    pub output: String,
+    keys: HashSet<u32>,
+    text: VecDeque<char>,
+}
+
+impl GuiConsole {
+    pub fn receive(&mut self, events: &[Event]) {
+        for event in events {
+            match event {
+                Event::Text(text) => self.text.extend(text.chars()),
+                Event::Key { key, pressed, .. } => {
+                    if *key == Key::Enter && *pressed {
+                        self.text.push_back('\n');
+                    }
+                    match (key_code(*key), pressed) {
+                        (Some(code), true) => self.keys.insert(code),
+                        (Some(code), false) => self.keys.remove(&code),
+                        (None, _) => false,
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+
+    //NOTE(joh): Without focus the key up events never arrive
+    pub fn release_keys(&mut self) {
+        self.keys.clear();
+    }
+
+    pub fn clear_input(&mut self) {
+        self.keys.clear();
+        self.text.clear();
+    }
+}
+
+impl Console for GuiConsole {
+    fn write(&mut self, s: &str) -> std::io::Result<()> {
+        self.output.push_str(s);
+        Ok(())
+    }
+
+    fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+        let Some(end) = self.text.iter().position(|c| *c == '\n') else {
+            return Ok(0);
+        };
+        let start = line.len();
+        line.extend(self.text.drain(..=end));
+        Ok(line.len() - start)
+    }
+
+    fn key_down(&mut self, key: u32) -> bool {
+        self.keys.contains(&key)
+    }
+
+    fn read_char(&mut self) -> Option<char> {
+        self.text.pop_front()
+    }
+}
+
+fn key_code(key: Key) -> Option<u32> {
+    let code = match key {
+        Key::ArrowLeft => key::Left,
+        Key::ArrowRight => key::Right,
+        Key::ArrowUp => key::Up,
+        Key::ArrowDown => key::Down,
+        Key::Enter => key::Enter,
+        Key::Escape => key::Escape,
+        Key::Backspace => key::Backspace,
+        Key::Tab => key::Tab,
+        Key::Space => key::Space,
+        _ => {
+            //NOTE(joh): Letters and digits are named by a single character, e.g. `A` or `7`
+            let mut name = key.name().chars();
+            match (name.next(), name.next()) {
+                (Some(c), None) if c.is_ascii_alphanumeric() => c.to_ascii_lowercase() as u32,
+                _ => return None,
+            }
+        }
+    };
+    Some(code)
+}
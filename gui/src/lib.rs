@@ -2,4 +2,7 @@
 
 mod app;
 mod code;
+mod input;
+#[cfg(target_arch = "wasm32")]
+pub mod web;
 pub use app::TemplateApp;
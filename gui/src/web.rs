@@ -0,0 +1,127 @@
+//NOTE(joh): Browser side of the playground. There is no file system, files are picked with the
+//browser's file input and saved as downloads.
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use eframe::wasm_bindgen::{JsCast as _, JsValue};
+
+use crate::TemplateApp;
+
+//NOTE(joh): Mounts the app on the canvas, `main` of the trunk build calls this
+pub fn start(canvas_id: &'static str) {
+    // Redirect `log` message to `console.log` and friends:
+    eframe::WebLogger::init(log::LevelFilter::Debug).ok();
+
+    let web_options = eframe::WebOptions::default();
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let document = web_sys::window()
+            .expect("No window")
+            .document()
+            .expect("No document");
+
+        let canvas = document
+            .get_element_by_id(canvas_id)
+            .unwrap_or_else(|| panic!("Failed to find {canvas_id}"))
+            .dyn_into::<web_sys::HtmlCanvasElement>()
+            .unwrap_or_else(|_| panic!("{canvas_id} was not a HtmlCanvasElement"));
+
+        let start_result = eframe::WebRunner::new()
+            .start(
+                canvas,
+                web_options,
+                Box::new(|cc| Ok(Box::new(TemplateApp::new(cc)))),
+            )
+            .await;
+
+        // Remove the loading text and spinner:
+        if let Some(loading_text) = document.get_element_by_id("loading_text") {
+            match start_result {
+                Ok(_) => {
+                    loading_text.remove();
+                }
+                Err(e) => {
+                    loading_text.set_inner_html(
+                        "<p> The app has crashed. See the developer console for details. </p>",
+                    );
+                    panic!("Failed to start eframe: {e:?}");
+                }
+            }
+        }
+    });
+}
+
+pub enum PickedFile {
+    Source { name: String, text: String },
+    Bytecode(Vec<u8>),
+}
+
+//NOTE(joh): The browser dialogs are async, picked files arrive in a later frame
+pub struct FilePicker {
+    sender: Sender<PickedFile>,
+    receiver: Receiver<PickedFile>,
+}
+
+impl Default for FilePicker {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        Self { sender, receiver }
+    }
+}
+
+impl FilePicker {
+    pub fn pick_source(&self, ctx: &egui::Context, extension: &str) {
+        let dialog = rfd::AsyncFileDialog::new().add_filter("malu source", &[extension]);
+        self.pick(ctx, dialog, |name, bytes| PickedFile::Source {
+            name,
+            text: String::from_utf8_lossy(&bytes).into_owned(),
+        });
+    }
+
+    pub fn pick_bytecode(&self, ctx: &egui::Context, extension: &str) {
+        let dialog = rfd::AsyncFileDialog::new().add_filter("malu bytecode", &[extension]);
+        self.pick(ctx, dialog, |_, bytes| PickedFile::Bytecode(bytes));
+    }
+
+    fn pick(
+        &self,
+        ctx: &egui::Context,
+        dialog: rfd::AsyncFileDialog,
+        into_file: impl FnOnce(String, Vec<u8>) -> PickedFile + 'static,
+    ) {
+        let sender = self.sender.clone();
+        let ctx = ctx.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let Some(handle) = dialog.pick_file().await else {
+                return;
+            };
+            let bytes = handle.read().await;
+            if sender.send(into_file(handle.file_name(), bytes)).is_ok() {
+                ctx.request_repaint();
+            }
+        });
+    }
+
+    pub fn poll(&self) -> Option<PickedFile> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+//NOTE(joh): Saves by clicking a temporary link to a blob of the content
+pub fn download(file_name: &str, bytes: &[u8]) -> Result<(), JsValue> {
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+    let options = web_sys::BlobPropertyBag::new();
+    options.set_type("application/octet-stream");
+    let blob = web_sys::Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob)?;
+
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or("No document")?;
+    let link = document
+        .create_element("a")?
+        .dyn_into::<web_sys::HtmlAnchorElement>()?;
+    link.set_href(&url);
+    link.set_download(file_name);
+    link.click();
+    web_sys::Url::revoke_object_url(&url)
+}
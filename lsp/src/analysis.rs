@@ -0,0 +1,209 @@
+use std::{ops::Range, path::Path};
+
+use vm::{
+    analyze::analyze_stack,
+    asm::{opcode, Parser},
+    lexer::{tokenize, Token, TokenKind},
+};
+
+use crate::docs;
+
+//NOTE(joh): Everything here works on byte offsets into the document, the server converts them
+//from and to LSP positions with `position` and `offset`.
+pub struct Diagnostic {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+//NOTE(joh): The assembler stops at the first error, so there is at most one. Errors in included
+//files are reported at the start of the document.
+pub fn diagnostics(text: &str, path: Option<&Path>) -> Option<Diagnostic> {
+    let mut parser = Parser::new();
+    if let Some(path) = path {
+        parser.set_source_path(path);
+    }
+    let error = parser.assemble(text).err()?;
+    let in_document = match (&error.file, path) {
+        (None, _) => true,
+        (Some(file), Some(path)) => Path::new(file) == path,
+        (Some(_), None) => false,
+    };
+    Some(match in_document {
+        true => Diagnostic {
+            span: error.span.start.min(text.len())..error.span.end.min(text.len()),
+            message: error.kind.to_string(),
+        },
+        false => Diagnostic {
+            span: 0..0,
+            message: error.to_string(),
+        },
+    })
+}
+
+//NOTE(joh): The warnings of the assembler and the stack mistakes found by `vm::analyze` in code
+//that assembles. Warnings in included files are left out.
+pub fn warnings(text: &str, path: Option<&Path>) -> Vec<Diagnostic> {
+    let mut parser = Parser::new();
+    if let Some(path) = path {
+        parser.set_source_path(path);
+    }
+    let Ok(result) = parser.assemble(text) else {
+        return Vec::new();
+    };
+    let in_document = |file: &Option<String>| match (file, path) {
+        (None, _) => true,
+        (Some(file), Some(path)) => Path::new(file) == path,
+        (Some(_), None) => false,
+    };
+    let assembler = result
+        .warnings
+        .iter()
+        .filter(|w| in_document(&w.file))
+        .map(|w| Diagnostic {
+            span: w.span.start.min(text.len())..w.span.end.min(text.len()),
+            message: w.kind.to_string(),
+        })
+        .collect::<Vec<_>>();
+    let Ok(warnings) = analyze_stack(&result.code) else {
+        return assembler;
+    };
+    let tokens = tokenize(text);
+    let stack = warnings
+        .into_iter()
+        .filter_map(|warning| {
+            let entry = result.debug_info.resolve_line(warning.addr).filter(|e| e.file == 0)?;
+            let line_start = text
+                .split_inclusive('\n')
+                .take(entry.line as usize - 1)
+                .map(str::len)
+                .sum::<usize>();
+            let start = (line_start + entry.column as usize - 1).min(text.len());
+            let span = token_at(&tokens, start).map_or(start..start, |t| t.span.clone());
+            Some(Diagnostic { span, message: warning.kind.to_string() })
+        });
+    assembler.into_iter().chain(stack).collect()
+}
+
+fn token_at(tokens: &[Token], offset: usize) -> Option<&Token> {
+    //NOTE(joh): A cursor right behind a token still belongs to it, unless the next token starts there
+    tokens
+        .iter()
+        .find(|t| t.span.contains(&offset))
+        .or_else(|| tokens.iter().find(|t| t.span.end == offset))
+}
+
+//NOTE(joh): Code labels `:name:` and the names of `.data` and `.string` declarations
+fn definitions<'a>(text: &'a str, tokens: &[Token]) -> Vec<(&'a str, Range<usize>)> {
+    let mut definitions = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Label => {
+                let span = token.span.start + 1..token.span.end.saturating_sub(1).max(token.span.start + 1);
+                definitions.push((&text[span.clone()], span));
+            }
+            TokenKind::Directive if matches!(&text[token.span.clone()], ".data" | ".string") => {
+                if let Some(name) = tokens.get(i + 1).filter(|t| t.kind == TokenKind::Ident) {
+                    definitions.push((&text[name.span.clone()], name.span.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+    definitions
+}
+
+//NOTE(joh): Jumps from a reference like `@name`, `.name` or `@name.len` to where the label is
+//defined. Labels of included files are not found.
+pub fn definition(text: &str, offset: usize) -> Option<Range<usize>> {
+    let tokens = tokenize(text);
+    let token = token_at(&tokens, offset)?;
+    let name = match token.kind {
+        TokenKind::LabelRef => &text[token.span.start + 1..token.span.end],
+        TokenKind::Label => return Some(token.span.clone()),
+        _ => return None,
+    };
+    let definitions = definitions(text, &tokens);
+    let find = |name: &str| definitions.iter().find(|(n, _)| *n == name).map(|(_, span)| span.clone());
+    find(name).or_else(|| find(name.strip_suffix(".len")?))
+}
+
+//NOTE(joh): All mnemonics starting with the word in front of the cursor
+pub fn completions(text: &str, offset: usize) -> Vec<(&'static str, &'static str)> {
+    let before = text.get(..offset).unwrap_or(text);
+    let start = before
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(0, |i| i + 1);
+    let prefix = &before[start..];
+    opcode::Names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| name.starts_with(prefix))
+        .map(|(op, name)| (*name, docs::summary(op as u8)))
+        .collect()
+}
+
+pub fn hover(text: &str, offset: usize) -> Option<(String, Range<usize>)> {
+    let tokens = tokenize(text);
+    let token = token_at(&tokens, offset).filter(|t| t.kind == TokenKind::Opcode)?;
+    let op = docs::opcode_by_name(&text[token.span.clone()])?;
+    Some((docs::hover(op), token.span.clone()))
+}
+
+//NOTE(joh): LSP positions count columns in UTF-16 code units
+pub fn position(text: &str, offset: usize) -> (u32, u32) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].encode_utf16().count();
+    (line as u32, column as u32)
+}
+
+pub fn offset(text: &str, line: u32, column: u32) -> usize {
+    let line_start = match line {
+        0 => 0,
+        line => text
+            .match_indices('\n')
+            .nth(line as usize - 1)
+            .map_or(text.len(), |(i, _)| i + 1),
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= column as usize || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_features() {
+        let text = ".string msg \"hi\";\n:main:\n#@msg.len; drop;\n#@main; jmp;\n";
+        let reference = text.find("@msg").unwrap() + 2;
+        assert_eq!(definition(text, reference).map(|s| s.start), text.find("msg"));
+        let jmp_target = text.find("@main").unwrap();
+        assert_eq!(definition(text, jmp_target).map(|s| &text[s]), Some("main"));
+
+        let (docs, span) = hover(text, text.find("drop").unwrap()).unwrap();
+        assert!(docs.starts_with("**drop**"));
+        assert_eq!(&text[span], "drop");
+
+        let names = completions("#1; loa", 7).into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert!(names.contains(&"load_8_u") && names.iter().all(|n| n.starts_with("loa")));
+
+        let error = diagnostics("#1; frobnicate;", None).unwrap();
+        assert_eq!(error.span, 4..14);
+        let warnings = warnings("#1;\n  add; end;", None);
+        assert_eq!((warnings.len(), warnings[0].span.clone()), (1, 6..9));
+        let dead = super::warnings("end; :dead: nop;", None);
+        assert_eq!(dead.iter().map(|w| w.span.start).collect::<Vec<_>>(), [5, 12]);
+
+        let text = "äb\n𝄞c";
+        assert_eq!(position(text, text.find('c').unwrap()), (1, 2));
+        assert_eq!(offset(text, 1, 2), text.find('c').unwrap());
+    }
+}
@@ -0,0 +1,176 @@
+use std::io::Cursor;
+
+use vm::{
+    asm::{opcode, RawArg, OP_ALIASES},
+    parse::{try_parse_op, MaybeRawOp},
+    validate::stack_effect,
+};
+
+//NOTE(joh): What the op does, in the order the values are popped. Stack effect and encoding are
+//taken from the validator and the decoder, see `hover`.
+#[allow(non_upper_case_globals)]
+pub fn summary(op: u8) -> &'static str {
+    use opcode::*;
+    match op {
+        DbgHalt => "Pauses execution like a breakpoint, running again continues after it.",
+        Nop => "Does nothing.",
+        Unreachable => "Traps.",
+        Drop => "Pops a value and discards it.",
+        Const => "Pushes the immediate.",
+        Jmp => "Pops an address and jumps to it.",
+        JmpIf => "Pops an address, then a condition. Jumps to the address if the condition is not 0.",
+        Branch => "Pops an offset and jumps to the address of this op plus the offset.",
+        BranchIf => "Pops an offset, then a condition. Branches by the offset if the condition is not 0.",
+        LocalGet => "Pushes the local of the current frame.",
+        LocalSet => "Pops a value into the local of the current frame.",
+        LocalTee => "Stores the top of the stack into the local of the current frame without popping it.",
+        GlobalGet => "Pushes the global.",
+        GlobalSet => "Pops a value into the global.",
+        GlobalTee => "Stores the top of the stack into the global without popping it.",
+        Eq => "Pops b, then a. Pushes 1 if a == b, else 0.",
+        Eqz => "Pops a value. Pushes 1 if it is 0, else 0.",
+        Add => "Pops b, then a. Pushes a + b, wrapping on overflow.",
+        Sub => "Pops b, then a. Pushes a - b, wrapping on overflow.",
+        Divs => "Pops b, then a. Pushes the signed quotient a / b. A zero divisor traps.",
+        Divu => "Pops b, then a. Pushes the unsigned quotient a / b. A zero divisor traps.",
+        Mul => "Pops b, then a. Pushes a * b. Traps if the product does not fit into a signed 32 bit value.",
+        MulWrap => "Pops b, then a. Pushes the low 32 bits of a * b.",
+        Neg => "Pops a value and pushes its two's complement negation.",
+        Gt => "Pops b, then a. Pushes 1 if a > b as unsigned values, else 0.",
+        Lt => "Pops b, then a. Pushes 1 if a < b as unsigned values, else 0.",
+        Ge => "Pops b, then a. Pushes 1 if a >= b as unsigned values, else 0.",
+        Le => "Pops b, then a. Pushes 1 if a <= b as unsigned values, else 0.",
+        Shiftr => "Pops b, then a. Pushes a shifted right by b bits, filling with zeros.",
+        Shiftl => "Pops b, then a. Pushes a shifted left by b bits.",
+        And => "Pops b, then a. Pushes the bitwise and.",
+        Or => "Pops b, then a. Pushes the bitwise or.",
+        Xor => "Pops b, then a. Pushes the bitwise xor.",
+        Call => "Pops an address and calls it. Values passed with `push_arg` become the locals of the new frame.",
+        Return => "Returns to the caller. A `.func` with a return count has to leave exactly that many values. The program ends when the bottom frame returns.",
+        Store8 => "Pops a value, then an address. Stores the low byte at the address plus the immediate.",
+        Store16 => "Pops a value, then an address. Stores the low 16 bits at the address plus the immediate.",
+        Store32 => "Pops a value, then an address. Stores it at the address plus the immediate.",
+        Load8u => "Pops an address. Pushes the byte at the address plus the immediate, zero extended.",
+        Load8s => "Pops an address. Pushes the byte at the address plus the immediate, sign extended.",
+        Load16s => "Pops an address. Pushes the 16 bits at the address plus the immediate, sign extended.",
+        Load16u => "Pops an address. Pushes the 16 bits at the address plus the immediate, zero extended.",
+        Load32s | Load32u => "Pops an address. Pushes the 32 bits at the address plus the immediate.",
+        End => "Ends the program.",
+        PushArg => "Pops a value and passes it to the next `call` or `syscall`.",
+        DbgAssert => "Pops a condition. Stops with a failed assertion if it is 0.",
+        Syscall => "Pops a syscall id and calls it with the values passed by `push_arg`. Pushes its result.",
+        ConstPool => "Pushes the entry of the constant pool.",
+        BrTable => "Pops an index and jumps to that target, or to the default if it is out of range.",
+        Const64 => "Pushes the 64 bit immediate, low half first.",
+        Add64 => "Pops the 64 bit values b, then a. Pushes a + b, wrapping on overflow.",
+        Sub64 => "Pops the 64 bit values b, then a. Pushes a - b, wrapping on overflow.",
+        Mul64 => "Pops the 64 bit values b, then a. Pushes a * b. Traps if the product does not fit into a signed 64 bit value.",
+        MulWrap64 => "Pops the 64 bit values b, then a. Pushes the low 64 bits of a * b.",
+        Divs64 => "Pops the 64 bit values b, then a. Pushes the signed quotient. A zero divisor traps.",
+        Divu64 => "Pops the 64 bit values b, then a. Pushes the unsigned quotient. A zero divisor traps.",
+        Eq64 => "Pops the 64 bit values b, then a. Pushes 1 if a == b, else 0.",
+        Lts64 => "Pops the 64 bit values b, then a. Pushes 1 if a < b as signed values, else 0.",
+        Ltu64 => "Pops the 64 bit values b, then a. Pushes 1 if a < b as unsigned values, else 0.",
+        Gts64 => "Pops the 64 bit values b, then a. Pushes 1 if a > b as signed values, else 0.",
+        Gtu64 => "Pops the 64 bit values b, then a. Pushes 1 if a > b as unsigned values, else 0.",
+        And64 => "Pops the 64 bit values b, then a. Pushes the bitwise and.",
+        Or64 => "Pops the 64 bit values b, then a. Pushes the bitwise or.",
+        Xor64 => "Pops the 64 bit values b, then a. Pushes the bitwise xor.",
+        Shiftl64 => "Pops the 64 bit values b, then a. Pushes a shifted left by b bits.",
+        Shiftr64 => "Pops the 64 bit values b, then a. Pushes a shifted right by b bits, filling with zeros.",
+        ExtendU32 => "Pops a value and pushes it zero extended to 64 bits.",
+        ExtendS32 => "Pops a value and pushes it sign extended to 64 bits.",
+        Wrap64 => "Pops a 64 bit value and pushes its low 32 bits.",
+        Load64 => "Pops an address. Pushes the 64 bits at the address plus the immediate.",
+        Store64 => "Pops a 64 bit value, then an address. Stores it at the address plus the immediate.",
+        FConst => "Pushes the bit pattern of the float immediate.",
+        FAdd => "Pops the floats b, then a. Pushes a + b.",
+        FSub => "Pops the floats b, then a. Pushes a - b.",
+        FMul => "Pops the floats b, then a. Pushes a * b.",
+        FDiv => "Pops the floats b, then a. Pushes a / b.",
+        FLt => "Pops the floats b, then a. Pushes 1 if a < b, else 0.",
+        FGt => "Pops the floats b, then a. Pushes 1 if a > b, else 0.",
+        FFromI32 => "Pops a signed value and pushes it converted to a float.",
+        I32FromF => "Pops a float and pushes it converted to a signed value. Saturates, NaN becomes 0.",
+        ShiftrS => "Pops b, then a. Pushes a shifted right by b bits, keeping the sign.",
+        Extend8S32 => "Pops a value and pushes its low 8 bits sign extended.",
+        Extend8U32 => "Pops a value and pushes its low 8 bits zero extended.",
+        Extend16S32 => "Pops a value and pushes its low 16 bits sign extended.",
+        Extend16U32 => "Pops a value and pushes its low 16 bits zero extended.",
+        CallIf => "Pops an address, then a condition. Calls the address if the condition is not 0, else drops the args.",
+        CallIndirect => "Pops an index into the function table and calls that function. The args have to match its arity.",
+        MemSize => "Pushes the size of the memory in bytes.",
+        MemGrow => "Pops a page count and grows the memory by that many pages. Pushes the old size.",
+        RemS => "Pops b, then a. Pushes the signed remainder of a / b. A zero divisor traps.",
+        RemU => "Pops b, then a. Pushes the unsigned remainder of a / b. A zero divisor traps.",
+        Rotl => "Pops b, then a. Pushes a rotated left by b modulo 32 bits.",
+        Rotr => "Pops b, then a. Pushes a rotated right by b modulo 32 bits.",
+        Clz => "Pops a value and pushes its number of leading zero bits.",
+        Ctz => "Pops a value and pushes its number of trailing zero bits.",
+        Popcnt => "Pops a value and pushes its number of set bits.",
+        Bswap => "Pops a value and pushes it with its bytes in reverse order, to read or write big endian data.",
+        Bswap16 => "Pops a value and pushes its low two bytes swapped, the upper bytes are cleared.",
+        Dup => "Pushes a copy of the top of the stack.",
+        Swap => "Swaps the two values on top of the stack.",
+        Select => "Pops a condition, then b, then a. Pushes a if the condition is not 0, else b.",
+        MemCopy => "Pops a length, then a source, then a destination address. Copies the bytes, the ranges may overlap.",
+        MemFill => "Pops a length, then a byte value, then a destination address. Fills the bytes with the value.",
+        Yield => "Pops a value and suspends the program, handing the value to the host. Running again continues after it.",
+        PushMem => "Pops a size and reserves that many bytes on the memory stack, rounded up to 8. Pushes their address, the new `__SP__`. Traps when the stack is full.",
+        PopMem => "Pops a size and releases that many bytes of the memory stack, rounded up to 8. Traps when more is released than was reserved.",
+        LoadMem8u => "Pops an address and pushes the byte at it in the memory the immediate indexes, zero extended. Memory 0 is the one of the program.",
+        LoadMem32 => "Pops an address and pushes the 32 bit value at it in the memory the immediate indexes. Memory 0 is the one of the program.",
+        StoreMem8 => "Pops a value, then an address. Stores the low byte of the value in the memory the immediate indexes.",
+        StoreMem32 => "Pops a value, then an address. Stores the value in the memory the immediate indexes.",
+        AtomicLoad => "Pops an address and pushes the word at it in the shared memory the immediate indexes. The address has to be a multiple of 4.",
+        AtomicStore => "Pops a value, then an address. Stores the value in the shared memory the immediate indexes.",
+        AtomicAdd => "Pops a value, then an address. Adds the value to the word in the shared memory the immediate indexes in one step and pushes the old word.",
+        Cmpxchg => "Pops a new value, then an expected one, then an address. Stores the new value if the word in the shared memory the immediate indexes is the expected one. Pushes the old word.",
+        AtomicLoad32 => "Pops an address and pushes the 32 bit value at the address plus the immediate offset. Traps if it is not a multiple of 4.",
+        AtomicStore32 => "Pops a value, then an address. Stores the value at the address plus the immediate offset. Traps if it is not a multiple of 4.",
+        AtomicRmwAdd => "Pops a value, then an address. Adds the value to the 32 bit value at the address plus the immediate offset and pushes the old one.",
+        AtomicCmpxchg => "Pops a new value, then an expected one, then an address. Stores the new value at the address plus the immediate offset if the old one is the expected one. Pushes the old value.",
+        CallHost => "Calls the host function declared with `.host` with the values passed by `push_arg`. Pushes its result.",
+        _ => "",
+    }
+}
+
+//NOTE(joh): The decoder decides the immediate, so probe it with a zeroed buffer
+fn immediate(op: u8) -> Option<RawArg> {
+    let mut probe = vec![op];
+    probe.resize(16, 0);
+    match try_parse_op(&mut Cursor::new(probe)) {
+        Ok(MaybeRawOp::Op(op)) => op.arg,
+        _ => None,
+    }
+}
+
+fn encoding(op: u8) -> String {
+    let immediate = match immediate(op) {
+        None => return format!("`{op:02x}`, 1 byte"),
+        Some(RawArg::Register(_)) => "a 1 byte index",
+        Some(RawArg::Num(_)) => "a 4 byte little endian immediate",
+        Some(RawArg::Wide(_)) => "an 8 byte little endian immediate",
+        Some(RawArg::Table { .. }) => "a 4 byte target count, the 4 byte targets and a 4 byte default",
+    };
+    format!("`{op:02x}` followed by {immediate}")
+}
+
+pub fn opcode_by_name(name: &str) -> Option<u8> {
+    let alias = OP_ALIASES.iter().find(|(alias, _)| *alias == name).map(|(_, op)| *op);
+    alias.or_else(|| opcode::Names.iter().position(|n| *n == name).map(|op| op as u8))
+}
+
+//NOTE(joh): Markdown shown when hovering an opcode
+pub fn hover(op: u8) -> String {
+    let name = opcode::Names[op as usize];
+    let stack = match stack_effect(op) {
+        Some((pops, pushes)) => format!("pops {pops}, pushes {pushes}"),
+        None => "depends on the callee".to_string(),
+    };
+    format!(
+        "**{name}**\n\n{}\n\nStack: {stack}  \nEncoding: {}",
+        summary(op),
+        encoding(op)
+    )
+}
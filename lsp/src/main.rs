@@ -0,0 +1,215 @@
+//NOTE(joh): Language server for malu assembly, speaks LSP over stdin and stdout. Documents are
+//assembled with the same `Parser` as the cli, so the diagnostics match what `assemble` reports.
+use std::{collections::HashMap, error::Error, path::PathBuf};
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, PublishDiagnostics},
+    request::{Completion, GotoDefinition, HoverRequest},
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range,
+    ServerCapabilities, TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+};
+
+mod analysis;
+mod docs;
+
+//NOTE(joh): Open documents by uri, always the full text since the server asks for full syncs. Keyed
+//by the string since `Uri` caches parts of itself.
+type Documents = HashMap<String, String>;
+
+fn range(text: &str, span: std::ops::Range<usize>) -> Range {
+    let (start_line, start_column) = analysis::position(text, span.start);
+    let (end_line, end_column) = analysis::position(text, span.end);
+    Range::new(Position::new(start_line, start_column), Position::new(end_line, end_column))
+}
+
+//NOTE(joh): Only `file` uris have a path, it is needed to resolve `%include`
+fn path(uri: &Uri) -> Option<PathBuf> {
+    let encoded = uri.as_str().strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = encoded
+            .get(i + 1..i + 3)
+            .filter(|_| encoded[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            None => {
+                bytes.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn send_diagnostics(
+    connection: &Connection,
+    uri: Uri,
+    diagnostics: Vec<lsp_types::Diagnostic>,
+) -> Result<(), Box<dyn Error>> {
+    use lsp_types::notification::Notification as _;
+    let params = PublishDiagnosticsParams { uri, diagnostics, version: None };
+    let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+    connection.sender.send(Message::Notification(notification))?;
+    Ok(())
+}
+
+fn check_document(connection: &Connection, uri: Uri, text: &str) -> Result<(), Box<dyn Error>> {
+    let path = path(&uri);
+    let errors = analysis::diagnostics(text, path.as_deref()).map(|d| (d, DiagnosticSeverity::ERROR));
+    let warnings = analysis::warnings(text, path.as_deref())
+        .into_iter()
+        .map(|d| (d, DiagnosticSeverity::WARNING));
+    let diagnostics = errors
+        .into_iter()
+        .chain(warnings)
+        .map(|(d, severity)| lsp_types::Diagnostic {
+            range: range(text, d.span),
+            severity: Some(severity),
+            source: Some("malu".to_string()),
+            message: d.message,
+            ..Default::default()
+        })
+        .collect();
+    send_diagnostics(connection, uri, diagnostics)
+}
+
+fn handle_notification(
+    connection: &Connection,
+    documents: &mut Documents,
+    notification: Notification,
+) -> Result<(), Box<dyn Error>> {
+    use lsp_types::notification::Notification as _;
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params = notification.extract::<lsp_types::DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)?;
+            let document = params.text_document;
+            check_document(connection, document.uri.clone(), &document.text)?;
+            documents.insert(document.uri.as_str().to_string(), document.text);
+        }
+        DidChangeTextDocument::METHOD => {
+            let params =
+                notification.extract::<lsp_types::DidChangeTextDocumentParams>(DidChangeTextDocument::METHOD)?;
+            //NOTE(joh): With full syncs the last change holds the whole text
+            if let Some(change) = params.content_changes.into_iter().last() {
+                check_document(connection, params.text_document.uri.clone(), &change.text)?;
+                documents.insert(params.text_document.uri.as_str().to_string(), change.text);
+            }
+        }
+        DidCloseTextDocument::METHOD => {
+            let params = notification.extract::<lsp_types::DidCloseTextDocumentParams>(DidCloseTextDocument::METHOD)?;
+            documents.remove(params.text_document.uri.as_str());
+            send_diagnostics(connection, params.text_document.uri, Vec::new())?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+//NOTE(joh): The document and the byte offset of the position, `None` for unknown documents
+fn document_offset<'a>(documents: &'a Documents, position: &TextDocumentPositionParams) -> Option<(&'a str, usize)> {
+    let text = documents.get(position.text_document.uri.as_str())?;
+    let offset = analysis::offset(text, position.position.line, position.position.character);
+    Some((text, offset))
+}
+
+fn handle_request(documents: &Documents, request: Request) -> Result<Response, Box<dyn Error>> {
+    use lsp_types::request::Request as _;
+    let id = request.id.clone();
+    let response = match request.method.as_str() {
+        GotoDefinition::METHOD => {
+            let params = request.extract::<GotoDefinitionParams>(GotoDefinition::METHOD)?.1;
+            let position = params.text_document_position_params;
+            let location = document_offset(documents, &position).and_then(|(text, offset)| {
+                let span = analysis::definition(text, offset)?;
+                Some(GotoDefinitionResponse::Scalar(Location::new(
+                    position.text_document.uri.clone(),
+                    range(text, span),
+                )))
+            });
+            Response::new_ok(id, location)
+        }
+        HoverRequest::METHOD => {
+            let params = request.extract::<HoverParams>(HoverRequest::METHOD)?.1;
+            let hover = document_offset(documents, &params.text_document_position_params).and_then(|(text, offset)| {
+                let (docs, span) = analysis::hover(text, offset)?;
+                Some(Hover {
+                    contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value: docs }),
+                    range: Some(range(text, span)),
+                })
+            });
+            Response::new_ok(id, hover)
+        }
+        Completion::METHOD => {
+            let params = request.extract::<CompletionParams>(Completion::METHOD)?.1;
+            let items = document_offset(documents, &params.text_document_position)
+                .map(|(text, offset)| analysis::completions(text, offset))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, summary)| CompletionItem {
+                    label: name.to_string(),
+                    kind: Some(CompletionItemKind::KEYWORD),
+                    detail: Some(summary.to_string()),
+                    ..Default::default()
+                })
+                .collect();
+            Response::new_ok(id, CompletionResponse::Array(items))
+        }
+        method => Response::new_err(
+            id,
+            lsp_server::ErrorCode::MethodNotFound as i32,
+            format!("unsupported request `{method}`"),
+        ),
+    };
+    Ok(response)
+}
+
+fn serve(connection: &Connection) -> Result<(), Box<dyn Error>> {
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut documents = Documents::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let id: RequestId = request.id.clone();
+                let response = handle_request(&documents, request)
+                    .unwrap_or_else(|e| Response::new_err(id, lsp_server::ErrorCode::InvalidParams as i32, e.to_string()));
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => handle_notification(connection, &mut documents, notification)?,
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn main() -> std::process::ExitCode {
+    let (connection, io_threads) = Connection::stdio();
+    let result = serve(&connection);
+    drop(connection);
+    let result = result.and_then(|()| Ok(io_threads.join()?));
+    match result {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("malu-lsp: {e}");
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
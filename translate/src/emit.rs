@@ -0,0 +1,146 @@
+use vm::{
+    asm::{opcode, DATA_START},
+    mem::push_le,
+};
+
+use crate::TranslateError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+//NOTE(joh): Addresses only known once all code is emitted
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    Label(Label),
+    //NOTE(joh): Where the wasm memory starts, the value is an offset into it
+    Memory(u32),
+    //NOTE(joh): Offset into the data section
+    Data(u32),
+    PageLimit,
+}
+
+//NOTE(joh): Resolved values of the fixups, see `Emitter::finish`
+pub struct Layout {
+    pub memory_base: u32,
+    pub data_start: u32,
+    pub page_limit: u32,
+}
+
+//NOTE(joh): Writes ops straight into a code buffer. Immediates referring to labels or the memory
+//layout are patched in `finish`.
+#[derive(Default)]
+pub struct Emitter {
+    code: Vec<u8>,
+    op_count: u32,
+    labels: Vec<Option<u32>>,
+    fixups: Vec<(usize, Fixup)>,
+}
+
+impl Emitter {
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.addr());
+    }
+
+    pub fn addr(&self) -> u32 {
+        DATA_START + self.code.len() as u32
+    }
+
+    pub fn label_addr(&self, label: Label) -> Option<u32> {
+        self.labels[label.0]
+    }
+
+    pub fn op_count(&self) -> u32 {
+        self.op_count
+    }
+
+    pub fn op(&mut self, opcode: u8) {
+        self.code.push(opcode);
+        self.op_count += 1;
+    }
+
+    pub fn op_u8(&mut self, opcode: u8, arg: u8) {
+        self.op(opcode);
+        self.code.push(arg);
+    }
+
+    pub fn op_u32(&mut self, opcode: u8, arg: u32) {
+        self.op(opcode);
+        push_le(&mut self.code, arg);
+    }
+
+    fn op_fixup(&mut self, opcode: u8, fixup: Fixup) {
+        self.op(opcode);
+        self.fixups.push((self.code.len(), fixup));
+        push_le(&mut self.code, 0u32);
+    }
+
+    pub fn const_32(&mut self, value: u32) {
+        self.op_u32(opcode::Const, value);
+    }
+
+    pub fn const_64(&mut self, value: u64) {
+        self.op(opcode::Const64);
+        push_le(&mut self.code, value);
+    }
+
+    pub fn const_label(&mut self, label: Label) {
+        self.op_fixup(opcode::Const, Fixup::Label(label));
+    }
+
+    pub fn const_data(&mut self, offset: u32) {
+        self.op_fixup(opcode::Const, Fixup::Data(offset));
+    }
+
+    pub fn const_page_limit(&mut self) {
+        self.op_fixup(opcode::Const, Fixup::PageLimit);
+    }
+
+    //NOTE(joh): Either a constant or a load/store whose offset is relative to the wasm memory
+    pub fn op_memory(&mut self, opcode: u8, offset: u32) {
+        self.op_fixup(opcode, Fixup::Memory(offset));
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.const_label(label);
+        self.op(opcode::Jmp);
+    }
+
+    pub fn jmp_if(&mut self, label: Label) {
+        self.const_label(label);
+        self.op(opcode::JmpIf);
+    }
+
+    pub fn br_table(&mut self, targets: &[Label], default: Label) {
+        self.op(opcode::BrTable);
+        push_le(&mut self.code, targets.len() as u32);
+        for label in targets.iter().chain([&default]) {
+            self.fixups.push((self.code.len(), Fixup::Label(*label)));
+            push_le(&mut self.code, 0u32);
+        }
+    }
+
+    pub fn code_len(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    pub fn finish(mut self, layout: &Layout) -> Result<Vec<u8>, TranslateError> {
+        for (pos, fixup) in self.fixups {
+            let value = match fixup {
+                Fixup::Label(label) => self.labels[label.0].expect("label was never bound"),
+                Fixup::Memory(offset) => layout
+                    .memory_base
+                    .checked_add(offset)
+                    .ok_or(TranslateError::AddrOutOfRange(offset))?,
+                Fixup::Data(offset) => layout.data_start + offset,
+                Fixup::PageLimit => layout.page_limit,
+            };
+            self.code[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        Ok(self.code)
+    }
+}
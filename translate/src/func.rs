@@ -0,0 +1,868 @@
+use vm::{
+    asm::opcode,
+    interpreter::{MAX_ARGS, MAX_LOCALS},
+};
+use wasmparser::{BlockType, FunctionBody, MemArg, Operator};
+
+use crate::{
+    emit::{Emitter, Label},
+    slot_count, Module, Signature, TranslateError, Ty, MEMORY_PAGES_GLOBAL,
+};
+
+const SIGN_BIT: u32 = 0x8000_0000;
+
+struct Block {
+    params: Vec<Ty>,
+    results: Vec<Ty>,
+    //NOTE(joh): Stack height below the params of the block
+    height: usize,
+    //NOTE(joh): The start of a loop, the end of any other block
+    target: Label,
+    is_loop: bool,
+    else_label: Option<Label>,
+}
+
+//NOTE(joh): Translates a single function body. Params and locals live in the locals of the
+//frame, 64 bit values take two of them. The locals after them are scratch space for ops that
+//need to shuffle the stack, their values never outlive the translation of a single op.
+pub(crate) struct FunctionTranslator<'a> {
+    module: &'a Module,
+    emit: &'a mut Emitter,
+    index: u32,
+    locals: Vec<(Ty, u8)>,
+    scratch: usize,
+    stack: Vec<Ty>,
+    blocks: Vec<Block>,
+    //NOTE(joh): Set after an unconditional branch, counts the blocks entered in the dead code
+    dead: Option<usize>,
+}
+
+impl<'a> FunctionTranslator<'a> {
+    pub fn new(module: &'a Module, emit: &'a mut Emitter, index: u32) -> Self {
+        Self {
+            module,
+            emit,
+            index,
+            locals: Vec::new(),
+            scratch: 0,
+            stack: Vec::new(),
+            blocks: Vec::new(),
+            dead: None,
+        }
+    }
+
+    pub fn translate(mut self, body: &FunctionBody<'_>) -> Result<(), TranslateError> {
+        let signature = self.module.signature(self.index);
+        let mut declared = signature.params.clone();
+        for local in body.get_locals_reader()? {
+            let (count, ty) = local?;
+            let ty = Ty::from_wasm(ty)?;
+            if declared.len() + count as usize > MAX_LOCALS {
+                return Err(TranslateError::TooManyLocals(self.index));
+            }
+            declared.extend(std::iter::repeat_n(ty, count as usize));
+        }
+        let mut slot = 0;
+        for ty in declared {
+            if slot + ty.slots() > MAX_LOCALS {
+                return Err(TranslateError::TooManyLocals(self.index));
+            }
+            self.locals.push((ty, slot as u8));
+            slot += ty.slots();
+        }
+        self.scratch = slot;
+
+        self.emit.bind(self.module.functions[self.index as usize].label);
+        let end = self.emit.new_label();
+        self.blocks.push(Block {
+            params: Vec::new(),
+            results: signature.results.clone(),
+            height: 0,
+            target: end,
+            is_loop: false,
+            else_label: None,
+        });
+
+        let mut reader = body.get_operators_reader()?;
+        while !reader.eof() {
+            self.translate_op(reader.read()?)?;
+        }
+        Ok(())
+    }
+
+    fn scratch(&self, i: usize) -> Result<u8, TranslateError> {
+        match self.scratch + i {
+            slot if slot < MAX_LOCALS => Ok(slot as u8),
+            _ => Err(TranslateError::TooManyLocals(self.index)),
+        }
+    }
+
+    fn pop(&mut self) -> Ty {
+        self.stack.pop().expect("validated wasm keeps the stack balanced")
+    }
+
+    fn pop_n(&mut self, n: usize) {
+        self.stack.truncate(self.stack.len() - n);
+    }
+
+    fn pop_push(&mut self, n: usize, ty: Ty) {
+        self.pop_n(n);
+        self.stack.push(ty);
+    }
+
+    fn local_get(&mut self, slot: u8) {
+        self.emit.op_u8(opcode::LocalGet, slot);
+    }
+
+    fn local_set(&mut self, slot: u8) {
+        self.emit.op_u8(opcode::LocalSet, slot);
+    }
+
+    fn save(&mut self, ty: Ty, slot: u8) {
+        if ty == Ty::I64 {
+            self.local_set(slot + 1);
+        }
+        self.local_set(slot);
+    }
+
+    fn load(&mut self, ty: Ty, slot: u8) {
+        self.local_get(slot);
+        if ty == Ty::I64 {
+            self.local_get(slot + 1);
+        }
+    }
+
+    fn ops(&mut self, ops: &[u8]) {
+        ops.iter().for_each(|op| self.emit.op(*op));
+    }
+
+    fn block_type(&self, ty: BlockType) -> Result<(Vec<Ty>, Vec<Ty>), TranslateError> {
+        match ty {
+            BlockType::Empty => Ok((Vec::new(), Vec::new())),
+            BlockType::Type(ty) => Ok((Vec::new(), vec![Ty::from_wasm(ty)?])),
+            BlockType::FuncType(index) => {
+                let Signature { params, results } = &self.module.types[index as usize];
+                Ok((params.clone(), results.clone()))
+            }
+        }
+    }
+
+    fn push_block(&mut self, ty: BlockType, is_loop: bool, else_label: Option<Label>) -> Result<(), TranslateError> {
+        let (params, results) = self.block_type(ty)?;
+        let target = self.emit.new_label();
+        if is_loop {
+            self.emit.bind(target);
+        }
+        self.blocks.push(Block {
+            height: self.stack.len() - params.len(),
+            params,
+            results,
+            target,
+            is_loop,
+            else_label,
+        });
+        Ok(())
+    }
+
+    //NOTE(joh): Keeps the top `keep` slots and drops `junk` slots below them
+    fn shuffle(&mut self, keep: usize, junk: usize) -> Result<(), TranslateError> {
+        if junk == 0 {
+            return Ok(());
+        }
+        for i in (0..keep).rev() {
+            let slot = self.scratch(i)?;
+            self.local_set(slot);
+        }
+        (0..junk).for_each(|_| self.emit.op(opcode::Drop));
+        for i in 0..keep {
+            let slot = self.scratch(i)?;
+            self.local_get(slot);
+        }
+        Ok(())
+    }
+
+    //NOTE(joh): The label to jump to and the slots to keep and drop when branching out of a block
+    fn branch_target(&self, depth: u32) -> (Label, usize, usize) {
+        let block = &self.blocks[self.blocks.len() - 1 - depth as usize];
+        let carried = match block.is_loop {
+            true => &block.params,
+            false => &block.results,
+        };
+        let junk = &self.stack[block.height..self.stack.len() - carried.len()];
+        (block.target, slot_count(carried), slot_count(junk))
+    }
+
+    fn branch(&mut self, depth: u32) -> Result<(), TranslateError> {
+        let (target, keep, junk) = self.branch_target(depth);
+        self.shuffle(keep, junk)?;
+        self.emit.jmp(target);
+        Ok(())
+    }
+
+    //NOTE(joh): Moves the args on top of the stack to the arg stack, keeping their order
+    fn push_args(&mut self, params: &[Ty]) -> Result<(), TranslateError> {
+        let slots = slot_count(params);
+        if slots > MAX_ARGS {
+            return Err(TranslateError::TooManyArgs(self.index));
+        }
+        for i in (0..slots).rev() {
+            let slot = self.scratch(i)?;
+            self.local_set(slot);
+        }
+        for i in 0..slots {
+            let slot = self.scratch(i)?;
+            self.local_get(slot);
+            self.emit.op(opcode::PushArg);
+        }
+        self.pop_n(params.len());
+        Ok(())
+    }
+
+    fn mem_offset(&self, memarg: &MemArg) -> Result<u32, TranslateError> {
+        if memarg.memory != 0 {
+            return Err(TranslateError::Unsupported("multiple memories".to_string()));
+        }
+        u32::try_from(memarg.offset).map_err(|_| TranslateError::AddrOutOfRange(u32::MAX))
+    }
+
+    fn load_op(&mut self, memarg: &MemArg, opcode: u8, extend: Option<u8>, ty: Ty) -> Result<(), TranslateError> {
+        let offset = self.mem_offset(memarg)?;
+        self.emit.op_memory(opcode, offset);
+        if let Some(extend) = extend {
+            self.emit.op(extend);
+        }
+        self.pop_push(1, ty);
+        Ok(())
+    }
+
+    fn store_op(&mut self, memarg: &MemArg, opcode: u8, wrap: bool) -> Result<(), TranslateError> {
+        let offset = self.mem_offset(memarg)?;
+        if wrap {
+            self.emit.op(opcode::Wrap64);
+        }
+        self.emit.op_memory(opcode, offset);
+        self.pop_n(2);
+        Ok(())
+    }
+
+    fn helper(&self) -> Result<&crate::Helpers, TranslateError> {
+        self.module
+            .helpers
+            .as_ref()
+            .ok_or_else(|| TranslateError::Unsupported("memory ops without a memory".to_string()))
+    }
+
+    //NOTE(joh): Calls a helper with three args, the first one being an address into the wasm memory
+    fn call_mem_helper(&mut self, helper: Label, second_is_addr: bool) -> Result<(), TranslateError> {
+        let (second, len) = (self.scratch(0)?, self.scratch(1)?);
+        self.local_set(len);
+        self.local_set(second);
+        self.emit.op_memory(opcode::Const, 0);
+        self.ops(&[opcode::Add, opcode::PushArg]);
+        self.local_get(second);
+        if second_is_addr {
+            self.emit.op_memory(opcode::Const, 0);
+            self.emit.op(opcode::Add);
+        }
+        self.emit.op(opcode::PushArg);
+        self.local_get(len);
+        self.emit.op(opcode::PushArg);
+        self.emit.const_label(helper);
+        self.emit.op(opcode::Call);
+        self.pop_n(3);
+        Ok(())
+    }
+
+    //NOTE(joh): Flips the sign bit of both operands, unsigned comparisons then order them as signed
+    fn flip_signs(&mut self) -> Result<(), TranslateError> {
+        let b = self.scratch(0)?;
+        self.local_set(b);
+        self.emit.const_32(SIGN_BIT);
+        self.emit.op(opcode::Xor);
+        self.local_get(b);
+        self.emit.const_32(SIGN_BIT);
+        self.emit.op(opcode::Xor);
+        Ok(())
+    }
+
+    fn rem_64(&mut self, signed: bool) -> Result<(), TranslateError> {
+        let (a, b) = (self.scratch(0)?, self.scratch(2)?);
+        self.save(Ty::I64, b);
+        self.save(Ty::I64, a);
+        let (minus_one, end) = (self.emit.new_label(), self.emit.new_label());
+        if signed {
+            self.load(Ty::I64, b);
+            self.emit.const_64(u64::MAX);
+            self.emit.op(opcode::Eq64);
+            self.emit.jmp_if(minus_one);
+        }
+        self.load(Ty::I64, a);
+        self.load(Ty::I64, a);
+        self.load(Ty::I64, b);
+        self.emit.op(if signed { opcode::Divs64 } else { opcode::Divu64 });
+        self.load(Ty::I64, b);
+        self.ops(&[opcode::MulWrap64, opcode::Sub64]);
+        if signed {
+            self.emit.jmp(end);
+            self.emit.bind(minus_one);
+            self.emit.const_64(0);
+        }
+        self.emit.bind(end);
+        self.pop_n(1);
+        Ok(())
+    }
+
+    //NOTE(joh): `(x << n) | (x >> -n)`, shifts only use the low bits of the amount
+    fn rotate_64(&mut self, left: bool) -> Result<(), TranslateError> {
+        let (x, n) = (self.scratch(0)?, self.scratch(2)?);
+        let (first, second) =
+            if left { (opcode::Shiftl64, opcode::Shiftr64) } else { (opcode::Shiftr64, opcode::Shiftl64) };
+        self.save(Ty::I64, n);
+        self.save(Ty::I64, x);
+        self.load(Ty::I64, x);
+        self.load(Ty::I64, n);
+        self.emit.op(first);
+        self.load(Ty::I64, x);
+        self.emit.const_64(0);
+        self.load(Ty::I64, n);
+        self.ops(&[opcode::Sub64, second, opcode::Or64]);
+        self.pop_n(1);
+        Ok(())
+    }
+
+    fn shr_s_64(&mut self) -> Result<(), TranslateError> {
+        let (x, n) = (self.scratch(0)?, self.scratch(2)?);
+        let (positive, end) = (self.emit.new_label(), self.emit.new_label());
+        self.save(Ty::I64, n);
+        self.save(Ty::I64, x);
+        self.load(Ty::I64, x);
+        self.emit.const_64(0);
+        self.ops(&[opcode::Lts64, opcode::Eqz]);
+        self.emit.jmp_if(positive);
+        //NOTE(joh): Shifting the complement in zeros shifts the original in ones
+        self.load(Ty::I64, x);
+        self.emit.const_64(u64::MAX);
+        self.emit.op(opcode::Xor64);
+        self.load(Ty::I64, n);
+        self.emit.op(opcode::Shiftr64);
+        self.emit.const_64(u64::MAX);
+        self.emit.op(opcode::Xor64);
+        self.emit.jmp(end);
+        self.emit.bind(positive);
+        self.load(Ty::I64, x);
+        self.load(Ty::I64, n);
+        self.emit.op(opcode::Shiftr64);
+        self.emit.bind(end);
+        self.pop_n(1);
+        Ok(())
+    }
+
+    //NOTE(joh): Counts both halves, `low_first` counts the low half first like ctz does
+    fn count_64(&mut self, count: u8, low_first: bool) -> Result<(), TranslateError> {
+        let (high, low) = (self.scratch(2)?, self.scratch(3)?);
+        let (first, second) = if low_first { (low, high) } else { (high, low) };
+        let (whole, end) = (self.emit.new_label(), self.emit.new_label());
+        self.local_set(high);
+        self.local_set(low);
+        self.local_get(first);
+        self.emit.op(opcode::Eqz);
+        self.emit.jmp_if(whole);
+        self.local_get(first);
+        self.emit.op(count);
+        self.emit.jmp(end);
+        self.emit.bind(whole);
+        self.local_get(second);
+        self.emit.op(count);
+        self.emit.const_32(32);
+        self.emit.op(opcode::Add);
+        self.emit.bind(end);
+        self.emit.op(opcode::ExtendU32);
+        Ok(())
+    }
+
+    fn popcnt_64(&mut self) -> Result<(), TranslateError> {
+        let high = self.scratch(0)?;
+        self.local_set(high);
+        self.emit.op(opcode::Popcnt);
+        self.local_get(high);
+        self.ops(&[opcode::Popcnt, opcode::Add, opcode::ExtendU32]);
+        Ok(())
+    }
+
+    //NOTE(joh): Pushes whether the floats in scratch 0 and 1 are equal. Neither one being
+    //smaller or greater is not enough, NaN is unequal to everything.
+    fn float_eq(&mut self) -> Result<(), TranslateError> {
+        let (a, b) = (self.scratch(0)?, self.scratch(1)?);
+        self.local_get(a);
+        self.local_get(b);
+        self.emit.op(opcode::FLt);
+        self.local_get(a);
+        self.local_get(b);
+        self.ops(&[opcode::FGt, opcode::Or, opcode::Eqz]);
+        for slot in [a, b] {
+            self.local_get(slot);
+            self.emit.const_32(!SIGN_BIT);
+            self.emit.op(opcode::And);
+            self.emit.const_32(f32::INFINITY.to_bits());
+            self.ops(&[opcode::Gt, opcode::Eqz, opcode::And]);
+        }
+        Ok(())
+    }
+
+    fn float_cmp(&mut self, cmp: Option<u8>, negate: bool) -> Result<(), TranslateError> {
+        let (a, b) = (self.scratch(0)?, self.scratch(1)?);
+        self.local_set(b);
+        self.local_set(a);
+        self.float_eq()?;
+        if let Some(cmp) = cmp {
+            self.local_get(a);
+            self.local_get(b);
+            self.ops(&[cmp, opcode::Or]);
+        }
+        if negate {
+            self.emit.op(opcode::Eqz);
+        }
+        self.pop_push(2, Ty::I32);
+        Ok(())
+    }
+
+    //NOTE(joh): `i32_from_f` saturates, the trapping conversion checks the range first
+    fn trunc_f32(&mut self) -> Result<(), TranslateError> {
+        let x = self.scratch(0)?;
+        let in_range = self.emit.new_label();
+        self.emit.op_u8(opcode::LocalTee, x);
+        self.emit.op_u32(opcode::FConst, 2147483648f32.to_bits());
+        self.ops(&[opcode::FLt, opcode::Eqz]);
+        self.local_get(x);
+        self.emit.op_u32(opcode::FConst, (-2147483648f32).to_bits());
+        self.ops(&[opcode::FLt, opcode::Or, opcode::Eqz]);
+        self.emit.jmp_if(in_range);
+        self.emit.op(opcode::Unreachable);
+        self.emit.bind(in_range);
+        self.local_get(x);
+        self.emit.op(opcode::I32FromF);
+        self.pop_push(1, Ty::I32);
+        Ok(())
+    }
+
+    fn translate_op(&mut self, op: Operator<'_>) -> Result<(), TranslateError> {
+        if let Some(depth) = &mut self.dead {
+            match op {
+                Operator::Block { .. } | Operator::Loop { .. } | Operator::If { .. } => {
+                    *depth += 1;
+                    return Ok(());
+                }
+                Operator::End | Operator::Else if *depth > 0 => {
+                    if matches!(op, Operator::End) {
+                        *depth -= 1;
+                    }
+                    return Ok(());
+                }
+                Operator::End | Operator::Else => {}
+                _ => return Ok(()),
+            }
+        }
+
+        use Operator as O;
+        use Ty::*;
+        match op {
+            O::Nop => {}
+            O::Unreachable => {
+                self.emit.op(opcode::Unreachable);
+                self.dead = Some(0);
+            }
+            O::Block { blockty } => self.push_block(blockty, false, None)?,
+            O::Loop { blockty } => self.push_block(blockty, true, None)?,
+            O::If { blockty } => {
+                self.pop();
+                let else_label = self.emit.new_label();
+                self.emit.op(opcode::Eqz);
+                self.emit.jmp_if(else_label);
+                self.push_block(blockty, false, Some(else_label))?;
+            }
+            O::Else => {
+                let block = self.blocks.last_mut().expect("else outside of a block");
+                let else_label = block.else_label.take().expect("else outside of an if");
+                let (target, height) = (block.target, block.height);
+                self.stack.truncate(height);
+                self.stack.extend_from_slice(&block.params);
+                if self.dead.is_none() {
+                    self.emit.jmp(target);
+                }
+                self.emit.bind(else_label);
+                self.dead = None;
+            }
+            O::End => {
+                let block = self.blocks.pop().expect("end outside of a block");
+                if let Some(else_label) = block.else_label {
+                    self.emit.bind(else_label);
+                }
+                if !block.is_loop {
+                    self.emit.bind(block.target);
+                }
+                self.stack.truncate(block.height);
+                self.stack.extend_from_slice(&block.results);
+                self.dead = None;
+                if self.blocks.is_empty() {
+                    self.emit.op(opcode::Return);
+                }
+            }
+            O::Br { relative_depth } => {
+                self.branch(relative_depth)?;
+                self.dead = Some(0);
+            }
+            O::BrIf { relative_depth } => {
+                self.pop();
+                match self.branch_target(relative_depth) {
+                    (target, _, 0) => self.emit.jmp_if(target),
+                    _ => {
+                        let skip = self.emit.new_label();
+                        self.emit.op(opcode::Eqz);
+                        self.emit.jmp_if(skip);
+                        self.branch(relative_depth)?;
+                        self.emit.bind(skip);
+                    }
+                }
+            }
+            O::BrTable { targets } => {
+                self.pop();
+                let depths = targets.targets().collect::<Result<Vec<_>, _>>()?;
+                let mut labels = Vec::new();
+                let mut trampolines = Vec::new();
+                for depth in depths.iter().chain([&targets.default()]) {
+                    match self.branch_target(*depth) {
+                        (target, _, 0) => labels.push(target),
+                        _ => {
+                            let trampoline = self.emit.new_label();
+                            trampolines.push((trampoline, *depth));
+                            labels.push(trampoline);
+                        }
+                    }
+                }
+                let default = labels.pop().expect("br_table has a default target");
+                self.emit.br_table(&labels, default);
+                for (trampoline, depth) in trampolines {
+                    self.emit.bind(trampoline);
+                    self.branch(depth)?;
+                }
+                self.dead = Some(0);
+            }
+            O::Return => {
+                let keep = slot_count(&self.blocks[0].results);
+                let junk = slot_count(&self.stack) - keep;
+                self.shuffle(keep, junk)?;
+                self.emit.op(opcode::Return);
+                self.dead = Some(0);
+            }
+            O::Call { function_index } => {
+                let Signature { params, results } = self.module.signature(function_index);
+                self.push_args(params)?;
+                self.emit.const_label(self.module.functions[function_index as usize].label);
+                self.emit.op(opcode::Call);
+                self.stack.extend_from_slice(results);
+            }
+            O::CallIndirect { type_index, .. } => {
+                let Signature { params, results } = &self.module.types[type_index as usize];
+                let index = self.scratch(slot_count(params))?;
+                self.pop();
+                self.local_set(index);
+                self.push_args(params)?;
+                self.local_get(index);
+                self.emit.op(opcode::CallIndirect);
+                self.stack.extend_from_slice(results);
+            }
+            O::Drop => {
+                let ty = self.pop();
+                (0..ty.slots()).for_each(|_| self.emit.op(opcode::Drop));
+            }
+            O::Select | O::TypedSelect { .. } => {
+                self.pop();
+                let ty = self.pop();
+                if ty.slots() == 1 {
+                    self.emit.op(opcode::Select);
+                } else {
+                    let (second, end) = (self.emit.new_label(), self.emit.new_label());
+                    self.emit.op(opcode::Eqz);
+                    self.emit.jmp_if(second);
+                    (0..ty.slots()).for_each(|_| self.emit.op(opcode::Drop));
+                    self.emit.jmp(end);
+                    self.emit.bind(second);
+                    self.shuffle(ty.slots(), ty.slots())?;
+                    self.emit.bind(end);
+                }
+            }
+
+            O::LocalGet { local_index } => {
+                let (ty, slot) = self.locals[local_index as usize];
+                self.load(ty, slot);
+                self.stack.push(ty);
+            }
+            O::LocalSet { local_index } => {
+                let (ty, slot) = self.locals[local_index as usize];
+                self.save(ty, slot);
+                self.pop();
+            }
+            O::LocalTee { local_index } => match self.locals[local_index as usize] {
+                (I64, slot) => {
+                    self.local_set(slot + 1);
+                    self.emit.op_u8(opcode::LocalTee, slot);
+                    self.local_get(slot + 1);
+                }
+                (_, slot) => self.emit.op_u8(opcode::LocalTee, slot),
+            },
+            O::GlobalGet { global_index } => {
+                let (ty, id) = self.module.globals[global_index as usize];
+                self.emit.op_u8(opcode::GlobalGet, id);
+                if ty == I64 {
+                    self.emit.op_u8(opcode::GlobalGet, id + 1);
+                }
+                self.stack.push(ty);
+            }
+            O::GlobalSet { global_index } => {
+                let (ty, id) = self.module.globals[global_index as usize];
+                if ty == I64 {
+                    self.emit.op_u8(opcode::GlobalSet, id + 1);
+                }
+                self.emit.op_u8(opcode::GlobalSet, id);
+                self.pop();
+            }
+
+            O::I32Load { memarg } | O::F32Load { memarg } => {
+                let ty = if matches!(op, O::F32Load { .. }) { F32 } else { I32 };
+                self.load_op(&memarg, opcode::Load32u, None, ty)?
+            }
+            O::I32Load8S { memarg } => self.load_op(&memarg, opcode::Load8s, None, I32)?,
+            O::I32Load8U { memarg } => self.load_op(&memarg, opcode::Load8u, None, I32)?,
+            O::I32Load16S { memarg } => self.load_op(&memarg, opcode::Load16s, None, I32)?,
+            O::I32Load16U { memarg } => self.load_op(&memarg, opcode::Load16u, None, I32)?,
+            O::I64Load { memarg } => self.load_op(&memarg, opcode::Load64, None, I64)?,
+            O::I64Load8S { memarg } => self.load_op(&memarg, opcode::Load8s, Some(opcode::ExtendS32), I64)?,
+            O::I64Load8U { memarg } => self.load_op(&memarg, opcode::Load8u, Some(opcode::ExtendU32), I64)?,
+            O::I64Load16S { memarg } => self.load_op(&memarg, opcode::Load16s, Some(opcode::ExtendS32), I64)?,
+            O::I64Load16U { memarg } => self.load_op(&memarg, opcode::Load16u, Some(opcode::ExtendU32), I64)?,
+            O::I64Load32S { memarg } => self.load_op(&memarg, opcode::Load32u, Some(opcode::ExtendS32), I64)?,
+            O::I64Load32U { memarg } => self.load_op(&memarg, opcode::Load32u, Some(opcode::ExtendU32), I64)?,
+            O::I32Store { memarg } | O::F32Store { memarg } => self.store_op(&memarg, opcode::Store32, false)?,
+            O::I32Store8 { memarg } => self.store_op(&memarg, opcode::Store8, false)?,
+            O::I32Store16 { memarg } => self.store_op(&memarg, opcode::Store16, false)?,
+            O::I64Store { memarg } => self.store_op(&memarg, opcode::Store64, false)?,
+            O::I64Store8 { memarg } => self.store_op(&memarg, opcode::Store8, true)?,
+            O::I64Store16 { memarg } => self.store_op(&memarg, opcode::Store16, true)?,
+            O::I64Store32 { memarg } => self.store_op(&memarg, opcode::Store32, true)?,
+            O::I32AtomicLoad { memarg } => self.load_op(&memarg, opcode::AtomicLoad32, None, I32)?,
+            O::I32AtomicStore { memarg } => self.store_op(&memarg, opcode::AtomicStore32, false)?,
+            O::I32AtomicRmwAdd { memarg } => {
+                let offset = self.mem_offset(&memarg)?;
+                self.emit.op_memory(opcode::AtomicRmwAdd, offset);
+                self.pop_push(2, I32);
+            }
+            O::I32AtomicRmwCmpxchg { memarg } => {
+                let offset = self.mem_offset(&memarg)?;
+                self.emit.op_memory(opcode::AtomicCmpxchg, offset);
+                self.pop_push(3, I32);
+            }
+            //NOTE(joh): Every op is sequentially consistent already
+            O::AtomicFence => {}
+            O::MemorySize { .. } => {
+                self.helper()?;
+                self.emit.op_u8(opcode::GlobalGet, MEMORY_PAGES_GLOBAL);
+                self.stack.push(I32);
+            }
+            O::MemoryGrow { .. } => {
+                let grow = self.helper()?.grow;
+                self.emit.op(opcode::PushArg);
+                self.emit.const_label(grow);
+                self.emit.op(opcode::Call);
+            }
+            O::MemoryCopy { .. } => {
+                let copy = self.helper()?.copy;
+                self.call_mem_helper(copy, true)?;
+            }
+            O::MemoryFill { .. } => {
+                let fill = self.helper()?.fill;
+                self.call_mem_helper(fill, false)?;
+            }
+
+            O::I32Const { value } => {
+                self.emit.const_32(value as u32);
+                self.stack.push(I32);
+            }
+            O::I64Const { value } => {
+                self.emit.const_64(value as u64);
+                self.stack.push(I64);
+            }
+            O::F32Const { value } => {
+                self.emit.op_u32(opcode::FConst, value.bits());
+                self.stack.push(F32);
+            }
+
+            O::I32Eqz => self.emit.op(opcode::Eqz),
+            O::I32Eq | O::I32Ne | O::I32LtU | O::I32GtU | O::I32LeU | O::I32GeU => {
+                let cmp = match op {
+                    O::I32LtU => opcode::Lt,
+                    O::I32GtU => opcode::Gt,
+                    O::I32LeU => opcode::Le,
+                    O::I32GeU => opcode::Ge,
+                    _ => opcode::Eq,
+                };
+                self.emit.op(cmp);
+                if matches!(op, O::I32Ne) {
+                    self.emit.op(opcode::Eqz);
+                }
+                self.pop_push(2, I32);
+            }
+            O::I32LtS | O::I32GtS | O::I32LeS | O::I32GeS => {
+                self.flip_signs()?;
+                self.emit.op(match op {
+                    O::I32LtS => opcode::Lt,
+                    O::I32GtS => opcode::Gt,
+                    O::I32LeS => opcode::Le,
+                    _ => opcode::Ge,
+                });
+                self.pop_push(2, I32);
+            }
+            O::I32Add | O::I32Sub | O::I32Mul | O::I32DivS | O::I32DivU | O::I32RemS | O::I32RemU | O::I32And
+            | O::I32Or | O::I32Xor | O::I32Shl | O::I32ShrU | O::I32ShrS | O::I32Rotl | O::I32Rotr => {
+                self.emit.op(match op {
+                    O::I32Add => opcode::Add,
+                    O::I32Sub => opcode::Sub,
+                    //NOTE(joh): Wasm multiplication wraps
+                    O::I32Mul => opcode::MulWrap,
+                    O::I32DivS => opcode::Divs,
+                    O::I32DivU => opcode::Divu,
+                    O::I32RemS => opcode::RemS,
+                    O::I32RemU => opcode::RemU,
+                    O::I32And => opcode::And,
+                    O::I32Or => opcode::Or,
+                    O::I32Xor => opcode::Xor,
+                    O::I32Shl => opcode::Shiftl,
+                    O::I32ShrU => opcode::Shiftr,
+                    O::I32Rotl => opcode::Rotl,
+                    O::I32Rotr => opcode::Rotr,
+                    _ => opcode::ShiftrS,
+                });
+                self.pop_n(1);
+            }
+            O::I32Clz => self.emit.op(opcode::Clz),
+            O::I32Ctz => self.emit.op(opcode::Ctz),
+            O::I32Popcnt => self.emit.op(opcode::Popcnt),
+            O::I32Extend8S => self.emit.op(opcode::Extend8S32),
+            O::I32Extend16S => self.emit.op(opcode::Extend16S32),
+            O::I32WrapI64 => {
+                self.emit.op(opcode::Wrap64);
+                self.pop_push(1, I32);
+            }
+
+            O::I64Eqz => {
+                self.emit.const_64(0);
+                self.emit.op(opcode::Eq64);
+                self.pop_push(1, I32);
+            }
+            O::I64Eq | O::I64Ne | O::I64LtS | O::I64LtU | O::I64GtS | O::I64GtU | O::I64LeS | O::I64LeU
+            | O::I64GeS | O::I64GeU => {
+                let (cmp, negate) = match op {
+                    O::I64Eq => (opcode::Eq64, false),
+                    O::I64Ne => (opcode::Eq64, true),
+                    O::I64LtS => (opcode::Lts64, false),
+                    O::I64LtU => (opcode::Ltu64, false),
+                    O::I64GtS => (opcode::Gts64, false),
+                    O::I64GtU => (opcode::Gtu64, false),
+                    O::I64LeS => (opcode::Gts64, true),
+                    O::I64LeU => (opcode::Gtu64, true),
+                    O::I64GeS => (opcode::Lts64, true),
+                    _ => (opcode::Ltu64, true),
+                };
+                self.emit.op(cmp);
+                if negate {
+                    self.emit.op(opcode::Eqz);
+                }
+                self.pop_push(2, I32);
+            }
+            O::I64Add | O::I64Sub | O::I64Mul | O::I64DivS | O::I64DivU | O::I64And | O::I64Or | O::I64Xor
+            | O::I64Shl | O::I64ShrU => {
+                self.emit.op(match op {
+                    O::I64Add => opcode::Add64,
+                    O::I64Sub => opcode::Sub64,
+                    O::I64Mul => opcode::MulWrap64,
+                    O::I64DivS => opcode::Divs64,
+                    O::I64DivU => opcode::Divu64,
+                    O::I64And => opcode::And64,
+                    O::I64Or => opcode::Or64,
+                    O::I64Xor => opcode::Xor64,
+                    O::I64Shl => opcode::Shiftl64,
+                    _ => opcode::Shiftr64,
+                });
+                self.pop_n(1);
+            }
+            O::I64ShrS => self.shr_s_64()?,
+            O::I64RemS => self.rem_64(true)?,
+            O::I64RemU => self.rem_64(false)?,
+            O::I64Rotl => self.rotate_64(true)?,
+            O::I64Rotr => self.rotate_64(false)?,
+            O::I64Clz => self.count_64(opcode::Clz, false)?,
+            O::I64Ctz => self.count_64(opcode::Ctz, true)?,
+            O::I64Popcnt => self.popcnt_64()?,
+            O::I64Extend8S => self.ops(&[opcode::Wrap64, opcode::Extend8S32, opcode::ExtendS32]),
+            O::I64Extend16S => self.ops(&[opcode::Wrap64, opcode::Extend16S32, opcode::ExtendS32]),
+            O::I64Extend32S => self.ops(&[opcode::Wrap64, opcode::ExtendS32]),
+            O::I64ExtendI32S | O::I64ExtendI32U => {
+                self.emit.op(match op {
+                    O::I64ExtendI32S => opcode::ExtendS32,
+                    _ => opcode::ExtendU32,
+                });
+                self.pop_push(1, I64);
+            }
+
+            O::F32Add | O::F32Sub | O::F32Mul | O::F32Div => {
+                self.emit.op(match op {
+                    O::F32Add => opcode::FAdd,
+                    O::F32Sub => opcode::FSub,
+                    O::F32Mul => opcode::FMul,
+                    _ => opcode::FDiv,
+                });
+                self.pop_n(1);
+            }
+            O::F32Lt | O::F32Gt => {
+                self.emit.op(if matches!(op, O::F32Lt) { opcode::FLt } else { opcode::FGt });
+                self.pop_push(2, I32);
+            }
+            O::F32Eq => self.float_cmp(None, false)?,
+            O::F32Ne => self.float_cmp(None, true)?,
+            O::F32Le => self.float_cmp(Some(opcode::FLt), false)?,
+            O::F32Ge => self.float_cmp(Some(opcode::FGt), false)?,
+            O::F32Neg => {
+                self.emit.const_32(SIGN_BIT);
+                self.emit.op(opcode::Xor);
+            }
+            O::F32Abs => {
+                self.emit.const_32(!SIGN_BIT);
+                self.emit.op(opcode::And);
+            }
+            O::F32Copysign => {
+                let sign = self.scratch(0)?;
+                self.emit.const_32(SIGN_BIT);
+                self.emit.op(opcode::And);
+                self.local_set(sign);
+                self.emit.const_32(!SIGN_BIT);
+                self.emit.op(opcode::And);
+                self.local_get(sign);
+                self.emit.op(opcode::Or);
+                self.pop_n(1);
+            }
+            O::F32ConvertI32S => {
+                self.emit.op(opcode::FFromI32);
+                self.pop_push(1, F32);
+            }
+            O::I32TruncSatF32S => {
+                self.emit.op(opcode::I32FromF);
+                self.pop_push(1, I32);
+            }
+            O::I32TruncF32S => self.trunc_f32()?,
+            O::I32ReinterpretF32 => self.pop_push(1, I32),
+            O::F32ReinterpretI32 => self.pop_push(1, F32),
+
+            op => return Err(TranslateError::Unsupported(format!("{op:?}"))),
+        }
+        Ok(())
+    }
+}
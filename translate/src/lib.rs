@@ -0,0 +1,688 @@
+//NOTE(joh): Translates WebAssembly modules into malu bytecode. Supported are modules without
+//imports using i32, i64 and f32 values, a single memory and a single function table. Anything
+//else is rejected with `TranslateError::Unsupported` instead of producing a program that behaves
+//differently.
+//
+//Functions become malu functions, wasm locals map to frame locals and wasm globals to globals
+//1 and up. Global 0 holds the number of wasm pages. The wasm memory is placed after the loaded
+//bytecode, see `Translation::memory_base`, and the entry point grows it to its initial size and
+//copies the data segments there. Table 0 becomes the function table of the bytecode, so
+//`call_indirect` indexes it directly. Exported functions keep their names in the symbol table
+//and can be called by name with `Interpreter::call`.
+//
+//The i32 atomics of the threads proposal map to the atomic ops. They are sequentially consistent
+//and trap on unaligned addresses, shared memories are treated like the others.
+//
+//Memory accesses are only checked against the size of the malu memory, which can be larger than
+//the wasm memory. `call_indirect` only checks the number of argument slots, not their types.
+use std::fmt::Display;
+
+use vm::{
+    asm::{opcode, BytecodeInfo, ENTRY_LABEL_NAME},
+    config::DEFAULT_GLOBALS,
+    function::{encode_function_table, Function},
+    mem::PAGE_SIZE,
+    symbols::{encode_symbol_table, SymbolTable},
+};
+use wasmparser::{
+    BinaryReaderError, ConstExpr, DataKind, ElementItems, ElementKind, Encoding, ExternalKind, FunctionBody, Operator,
+    Parser, Payload, ValType,
+};
+
+use crate::{
+    emit::{Emitter, Label, Layout},
+    func::FunctionTranslator,
+};
+
+mod emit;
+mod func;
+
+pub(crate) const MEMORY_PAGES_GLOBAL: u8 = 0;
+const WASM_PAGE_SIZE: u32 = 65536;
+//NOTE(joh): The wasm memory starts at the next multiple of this after the loaded bytecode
+const MEMORY_ALIGN: u32 = 16;
+
+#[derive(Debug)]
+pub enum TranslateError {
+    Wasm(BinaryReaderError),
+    Unsupported(String),
+    TooManyLocals(u32),
+    TooManyArgs(u32),
+    TooManyGlobals,
+    AddrOutOfRange(u32),
+    MissingEntry(String),
+    EntryTakesArgs(String),
+}
+
+impl Display for TranslateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranslateError::Wasm(e) => write!(f, "invalid wasm module: {e}"),
+            TranslateError::Unsupported(feature) => write!(f, "unsupported wasm feature: {feature}"),
+            TranslateError::TooManyLocals(index) => write!(f, "function {index} uses too many locals"),
+            TranslateError::TooManyArgs(index) => write!(f, "function {index} passes too many args"),
+            TranslateError::TooManyGlobals => write!(f, "the module uses too many globals"),
+            TranslateError::AddrOutOfRange(addr) => write!(f, "address 0x{addr:08x} is out of range"),
+            TranslateError::MissingEntry(name) => write!(f, "no exported function `{name}`"),
+            TranslateError::EntryTakesArgs(name) => write!(f, "entry point `{name}` takes arguments"),
+        }
+    }
+}
+
+impl std::error::Error for TranslateError {}
+
+impl From<BinaryReaderError> for TranslateError {
+    fn from(e: BinaryReaderError) -> Self {
+        TranslateError::Wasm(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ty {
+    I32,
+    I64,
+    F32,
+}
+
+impl Ty {
+    pub fn from_wasm(ty: ValType) -> Result<Self, TranslateError> {
+        match ty {
+            ValType::I32 => Ok(Ty::I32),
+            ValType::I64 => Ok(Ty::I64),
+            ValType::F32 => Ok(Ty::F32),
+            ValType::F64 => Err(TranslateError::Unsupported("f64".to_string())),
+            ValType::V128 => Err(TranslateError::Unsupported("simd".to_string())),
+            ValType::Ref(_) => Err(TranslateError::Unsupported("reference types".to_string())),
+        }
+    }
+
+    pub fn slots(self) -> usize {
+        match self {
+            Ty::I64 => 2,
+            _ => 1,
+        }
+    }
+}
+
+pub(crate) fn slot_count(types: &[Ty]) -> usize {
+    types.iter().map(|ty| ty.slots()).sum()
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Signature {
+    pub params: Vec<Ty>,
+    pub results: Vec<Ty>,
+}
+
+pub(crate) struct FunctionInfo {
+    pub type_index: u32,
+    pub label: Label,
+}
+
+//NOTE(joh): Malu functions backing the memory ops, only emitted if the module has a memory
+pub(crate) struct Helpers {
+    pub grow: Label,
+    pub copy: Label,
+    pub fill: Label,
+}
+
+pub(crate) struct Module {
+    pub types: Vec<Signature>,
+    pub functions: Vec<FunctionInfo>,
+    //NOTE(joh): The type and the first global id of each wasm global
+    pub globals: Vec<(Ty, u8)>,
+    pub helpers: Option<Helpers>,
+}
+
+impl Module {
+    pub fn signature(&self, function: u32) -> &Signature {
+        &self.types[self.functions[function as usize].type_index as usize]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Translation {
+    pub code: Box<[u8]>,
+    //NOTE(joh): Address of wasm address 0 once the bytecode is loaded
+    pub memory_base: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Translator {
+    entry: Option<String>,
+}
+
+//NOTE(joh): The parts of the module the code depends on, gathered before translating bodies
+#[derive(Default)]
+struct Sections<'a> {
+    types: Vec<Signature>,
+    functions: Vec<u32>,
+    table_size: Option<u32>,
+    memory: Option<(u32, Option<u32>)>,
+    globals: Vec<(Ty, ConstExpr<'a>)>,
+    exports: Vec<(&'a str, u32)>,
+    start: Option<u32>,
+    elements: Vec<(ConstExpr<'a>, Vec<u32>)>,
+    data: Vec<(ConstExpr<'a>, &'a [u8])>,
+    bodies: Vec<FunctionBody<'a>>,
+}
+
+fn unsupported<T>(feature: &str) -> Result<T, TranslateError> {
+    Err(TranslateError::Unsupported(feature.to_string()))
+}
+
+//NOTE(joh): Constant expressions are limited to a single constant
+fn const_op<'a>(expr: &ConstExpr<'a>) -> Result<Operator<'a>, TranslateError> {
+    let mut reader = expr.get_operators_reader();
+    let op = reader.read()?;
+    match reader.read()? {
+        Operator::End => Ok(op),
+        _ => unsupported("extended constant expressions"),
+    }
+}
+
+fn const_offset(expr: &ConstExpr<'_>) -> Result<u32, TranslateError> {
+    match const_op(expr)? {
+        Operator::I32Const { value } => Ok(value as u32),
+        _ => unsupported("offsets that are not constants"),
+    }
+}
+
+impl<'a> Sections<'a> {
+    fn read(wasm: &'a [u8]) -> Result<Self, TranslateError> {
+        let mut sections = Sections::default();
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::Version { encoding: Encoding::Component, .. } => return unsupported("components"),
+                Payload::TypeSection(reader) => {
+                    for ty in reader.into_iter_err_on_gc_types() {
+                        let ty = ty?;
+                        let convert = |types: &[ValType]| types.iter().map(|t| Ty::from_wasm(*t)).collect::<Result<_, _>>();
+                        sections.types.push(Signature {
+                            params: convert(ty.params())?,
+                            results: convert(ty.results())?,
+                        });
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    if let Some(import) = reader.into_imports().next() {
+                        let import = import?;
+                        return unsupported(&format!("import `{}.{}`", import.module, import.name));
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for type_index in reader {
+                        sections.functions.push(type_index?);
+                    }
+                }
+                Payload::TableSection(reader) => {
+                    for table in reader {
+                        let table = table?;
+                        if sections.table_size.is_some() {
+                            return unsupported("multiple tables");
+                        }
+                        if table.ty.table64 || !table.ty.element_type.is_func_ref() {
+                            return unsupported("tables that do not hold functions");
+                        }
+                        let size = u32::try_from(table.ty.initial).or(unsupported("huge tables"))?;
+                        sections.table_size = Some(size);
+                    }
+                }
+                Payload::MemorySection(reader) => {
+                    for memory in reader {
+                        let memory = memory?;
+                        if sections.memory.is_some() {
+                            return unsupported("multiple memories");
+                        }
+                        //NOTE(joh): Shared memories are fine, the program runs on one thread
+                        if memory.memory64 || memory.page_size_log2.is_some() {
+                            return unsupported("memories other than 32 bit ones with 64KiB pages");
+                        }
+                        let initial = u32::try_from(memory.initial).or(unsupported("huge memories"))?;
+                        let maximum = memory.maximum.map(|m| m.min(u32::MAX as u64) as u32);
+                        sections.memory = Some((initial, maximum));
+                    }
+                }
+                Payload::TagSection(_) => return unsupported("exceptions"),
+                Payload::GlobalSection(reader) => {
+                    for global in reader {
+                        let global = global?;
+                        sections.globals.push((Ty::from_wasm(global.ty.content_type)?, global.init_expr));
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.kind == ExternalKind::Func {
+                            sections.exports.push((export.name, export.index));
+                        }
+                    }
+                }
+                Payload::StartSection { func, .. } => sections.start = Some(func),
+                Payload::ElementSection(reader) => {
+                    for element in reader {
+                        let element = element?;
+                        let offset = match element.kind {
+                            ElementKind::Active { table_index: None | Some(0), offset_expr } => offset_expr,
+                            ElementKind::Declared => continue,
+                            _ => return unsupported("passive element segments"),
+                        };
+                        let mut functions = Vec::new();
+                        match element.items {
+                            ElementItems::Functions(reader) => {
+                                for function in reader {
+                                    functions.push(function?);
+                                }
+                            }
+                            ElementItems::Expressions(_, reader) => {
+                                for expr in reader {
+                                    match const_op(&expr?)? {
+                                        Operator::RefFunc { function_index } => functions.push(function_index),
+                                        _ => return unsupported("null elements"),
+                                    }
+                                }
+                            }
+                        }
+                        sections.elements.push((offset, functions));
+                    }
+                }
+                Payload::DataSection(reader) => {
+                    for data in reader {
+                        let data = data?;
+                        match data.kind {
+                            DataKind::Active { memory_index: 0, offset_expr } => sections.data.push((offset_expr, data.data)),
+                            _ => return unsupported("passive data segments"),
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => sections.bodies.push(body),
+                _ => {}
+            }
+        }
+        Ok(sections)
+    }
+}
+
+impl Translator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //NOTE(joh): The exported function called after the start function, `_start` if it exists by default
+    pub fn with_entry(mut self, name: impl Into<String>) -> Self {
+        self.entry = Some(name.into());
+        self
+    }
+
+    pub fn translate(&self, wasm: &[u8]) -> Result<Translation, TranslateError> {
+        wasmparser::validate(wasm)?;
+        let sections = Sections::read(wasm)?;
+
+        let mut emit = Emitter::default();
+        let mut globals = Vec::new();
+        let mut next_global = MEMORY_PAGES_GLOBAL as usize + 1;
+        for (ty, _) in &sections.globals {
+            if next_global + ty.slots() > DEFAULT_GLOBALS {
+                return Err(TranslateError::TooManyGlobals);
+            }
+            globals.push((*ty, next_global as u8));
+            next_global += ty.slots();
+        }
+        let module = Module {
+            types: sections.types.clone(),
+            functions: sections
+                .functions
+                .iter()
+                .map(|type_index| FunctionInfo { type_index: *type_index, label: emit.new_label() })
+                .collect(),
+            globals,
+            helpers: sections.memory.map(|_| Helpers {
+                grow: emit.new_label(),
+                copy: emit.new_label(),
+                fill: emit.new_label(),
+            }),
+        };
+
+        for (index, body) in sections.bodies.iter().enumerate() {
+            FunctionTranslator::new(&module, &mut emit, index as u32).translate(body)?;
+        }
+        if let Some(helpers) = &module.helpers {
+            emit_helpers(&mut emit, helpers);
+        }
+
+        //NOTE(joh): Empty table slots call a function that traps
+        let mut table = vec![None; sections.table_size.unwrap_or(0) as usize];
+        for (offset, functions) in &sections.elements {
+            let offset = const_offset(offset)? as usize;
+            let slots = table
+                .get_mut(offset..offset + functions.len())
+                .ok_or(TranslateError::AddrOutOfRange(offset as u32))?;
+            slots.iter_mut().zip(functions).for_each(|(slot, f)| *slot = Some(*f));
+        }
+        let null_function = emit.addr();
+        if table.contains(&None) {
+            emit.op(opcode::Unreachable);
+        }
+
+        let entry = match &self.entry {
+            Some(name) => Some(find_export(&sections, name).ok_or_else(|| TranslateError::MissingEntry(name.clone()))?),
+            None => find_export(&sections, "_start"),
+        };
+        let entry_addr = emit.addr();
+        let mut data = Vec::new();
+        if let (Some((initial, _)), Some(helpers)) = (sections.memory, &module.helpers) {
+            emit.const_32(initial);
+            emit.op(opcode::PushArg);
+            emit.const_label(helpers.grow);
+            emit.op(opcode::Call);
+            emit.op(opcode::Drop);
+            for (offset, bytes) in &sections.data {
+                emit.op_memory(opcode::Const, const_offset(offset)?);
+                emit.op(opcode::PushArg);
+                emit.const_data(data.len() as u32);
+                emit.op(opcode::PushArg);
+                emit.const_32(bytes.len() as u32);
+                emit.op(opcode::PushArg);
+                emit.const_label(helpers.copy);
+                emit.op(opcode::Call);
+                data.extend_from_slice(bytes);
+            }
+        }
+        for ((ty, init), (_, id)) in sections.globals.iter().zip(&module.globals) {
+            match (const_op(init)?, ty) {
+                (Operator::I32Const { value }, _) => emit.const_32(value as u32),
+                (Operator::F32Const { value }, _) => emit.const_32(value.bits()),
+                (Operator::I64Const { value }, _) => {
+                    emit.const_64(value as u64);
+                    emit.op_u8(opcode::GlobalSet, id + 1);
+                }
+                _ => return unsupported("globals initialized from other globals"),
+            }
+            emit.op_u8(opcode::GlobalSet, *id);
+        }
+        for function in sections.start.into_iter().chain(entry) {
+            if !module.signature(function).params.is_empty() {
+                let name = sections.exports.iter().find(|(_, f)| *f == function).map_or("start", |(n, _)| n);
+                return Err(TranslateError::EntryTakesArgs(name.to_string()));
+            }
+            emit.const_label(module.functions[function as usize].label);
+            emit.op(opcode::Call);
+        }
+        emit.op(opcode::End);
+
+        let label_addr = |function: u32| {
+            emit.label_addr(module.functions[function as usize].label)
+                .expect("every function is translated")
+        };
+        let mut trailer = Vec::new();
+        if !table.is_empty() {
+            let functions = table
+                .iter()
+                .map(|slot| match slot {
+                    Some(f) => Function {
+                        name: format!("f{f}"),
+                        addr: label_addr(*f),
+                        arity: slot_count(&module.signature(*f).params) as u8,
+                        returns: None,
+                    },
+                    None => Function { name: "null".to_string(), addr: null_function, arity: 0, returns: None },
+                })
+                .collect::<Vec<_>>();
+            encode_function_table(&functions, &mut trailer);
+        }
+        let mut labels = vec![(ENTRY_LABEL_NAME.to_string(), entry_addr)];
+        labels.extend(sections.exports.iter().map(|(name, f)| (name.to_string(), label_addr(*f))));
+        encode_symbol_table(&SymbolTable { labels, data_labels: Vec::new() }, &mut trailer);
+
+        let info = BytecodeInfo {
+            code_size_bytes: emit.code_len(),
+            instruction_count: emit.op_count(),
+            code_start_offset: entry_addr,
+            data_section_size: data.len() as u32,
+        };
+        //NOTE(joh): The magic is not part of the loaded image
+        let image_size = info.total_size() + trailer.len() - 4;
+        let memory_base = (image_size as u32).next_multiple_of(MEMORY_ALIGN);
+        let max_pages = sections.memory.and_then(|(_, max)| max).unwrap_or(u32::MAX);
+        let layout = Layout {
+            memory_base,
+            data_start: info.data_start_addr(),
+            page_limit: max_pages.min((u32::MAX - memory_base) / WASM_PAGE_SIZE),
+        };
+
+        let mut code = info.to_bytecode();
+        code.extend_from_slice(&emit.finish(&layout)?);
+        code.extend_from_slice(&data);
+        code.extend_from_slice(&trailer);
+        Ok(Translation { code: code.into_boxed_slice(), memory_base })
+    }
+}
+
+fn find_export(sections: &Sections<'_>, name: &str) -> Option<u32> {
+    sections.exports.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+}
+
+//NOTE(joh): `grow(pages)` returns the old page count or -1 like `memory.grow`, `copy(dst, src,
+//len)` and `fill(dst, value, len)` wrap `mem_copy` and `mem_fill`. They take malu addresses.
+fn emit_helpers(emit: &mut Emitter, helpers: &Helpers) {
+    let local_get = |emit: &mut Emitter, slot| emit.op_u8(opcode::LocalGet, slot);
+    let local_set = |emit: &mut Emitter, slot| emit.op_u8(opcode::LocalSet, slot);
+
+    let (fail, grown) = (emit.new_label(), emit.new_label());
+    emit.bind(helpers.grow);
+    emit.op_u8(opcode::GlobalGet, MEMORY_PAGES_GLOBAL);
+    local_get(emit, 0);
+    emit.op(opcode::Add);
+    local_set(emit, 1);
+    local_get(emit, 1);
+    emit.op_u8(opcode::GlobalGet, MEMORY_PAGES_GLOBAL);
+    emit.op(opcode::Lt);
+    emit.jmp_if(fail);
+    local_get(emit, 1);
+    emit.const_page_limit();
+    emit.op(opcode::Gt);
+    emit.jmp_if(fail);
+    local_get(emit, 1);
+    emit.const_32(WASM_PAGE_SIZE.trailing_zeros());
+    emit.op(opcode::Shiftl);
+    emit.op_memory(opcode::Const, 0);
+    emit.op(opcode::Add);
+    local_set(emit, 2);
+    local_get(emit, 2);
+    emit.op(opcode::MemSize);
+    emit.op(opcode::Le);
+    emit.jmp_if(grown);
+    local_get(emit, 2);
+    emit.op(opcode::MemSize);
+    emit.op(opcode::Sub);
+    emit.const_32(PAGE_SIZE as u32 - 1);
+    emit.op(opcode::Add);
+    emit.const_32(PAGE_SIZE.trailing_zeros());
+    emit.op(opcode::Shiftr);
+    emit.op(opcode::MemGrow);
+    emit.op(opcode::Drop);
+    emit.bind(grown);
+    emit.op_u8(opcode::GlobalGet, MEMORY_PAGES_GLOBAL);
+    local_get(emit, 1);
+    emit.op_u8(opcode::GlobalSet, MEMORY_PAGES_GLOBAL);
+    emit.op(opcode::Return);
+    emit.bind(fail);
+    emit.const_32(u32::MAX);
+    emit.op(opcode::Return);
+
+    for (label, op) in [(helpers.copy, opcode::MemCopy), (helpers.fill, opcode::MemFill)] {
+        emit.bind(label);
+        (0..3).for_each(|slot| local_get(emit, slot));
+        emit.op(op);
+        emit.op(opcode::Return);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm::{
+        interpreter::{Interpreter, InterpreterErrorType},
+        testing::NoSyscalls,
+    };
+
+    use super::*;
+
+    fn translate(wat: &str) -> Translation {
+        Translator::new().translate(&wat::parse_str(wat).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn recursion_and_exports() {
+        let translation = translate(
+            r#"(module
+                (func $fac (export "fac") (param i64) (result i64)
+                    local.get 0
+                    i64.eqz
+                    if (result i64)
+                        i64.const 1
+                    else
+                        local.get 0
+                        local.get 0
+                        i64.const 1
+                        i64.sub
+                        call $fac
+                        i64.mul
+                    end)
+                (func (export "_start") (result i64)
+                    (call $fac (i64.const 20))))"#,
+        );
+        let mut interpreter = Interpreter::from_bytecode(&translation.code).unwrap();
+        let fac_20 = 2432902008176640000u64;
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), [fac_20 as u32, (fac_20 >> 32) as u32]);
+        assert_eq!(interpreter.call("fac", &[5, 0], &mut NoSyscalls()).unwrap(), [120, 0]);
+    }
+
+    #[test]
+    fn memory_and_globals() {
+        let translation = translate(
+            r#"(module
+                (memory 1 4)
+                (global $bonus (mut i32) (i32.const 40))
+                (data (i32.const 16) "\01\02\03\04\05")
+                (func (export "_start") (result i32 i32 i32 i32 i32)
+                    (local $i i32) (local $sum i32)
+                    (block $done
+                        (loop $next
+                            (br_if $done (i32.ge_u (local.get $i) (i32.const 5)))
+                            (local.set $sum (i32.add (local.get $sum) (i32.load8_u offset=16 (local.get $i))))
+                            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                            (br $next)))
+                    (global.set $bonus (i32.add (global.get $bonus) (i32.const 2)))
+                    (memory.copy (i32.const 100) (i32.const 16) (i32.const 5))
+                    (memory.fill (i32.const 105) (i32.const 9) (i32.const 3))
+                    (i32.store (i32.const 200) (i32.load (i32.const 101)))
+                    (i32.add (local.get $sum) (global.get $bonus))
+                    (memory.grow (i32.const 1))
+                    (memory.grow (i32.const 10))
+                    (memory.size)
+                    (i32.load (i32.const 104))))"#,
+        );
+        let mut interpreter = Interpreter::from_bytecode(&translation.code).unwrap();
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), [57, 1, u32::MAX, 2, 0x09090905]);
+        assert_eq!(interpreter.memory.read_le::<u32>(translation.memory_base as usize + 200), Some(0x05040302));
+        assert!(interpreter.memory.len() >= (translation.memory_base + 2 * WASM_PAGE_SIZE) as usize);
+    }
+
+    #[test]
+    fn atomics() {
+        let translation = translate(
+            r#"(module
+                (memory 1 1 shared)
+                (func (export "counter") (param i32) (result i32 i32 i32 i32)
+                    (i32.atomic.store offset=4 (i32.const 0) (i32.const 40))
+                    (i32.atomic.rmw.add offset=4 (i32.const 0) (local.get 0))
+                    (atomic.fence)
+                    (i32.atomic.rmw.cmpxchg (i32.const 4) (i32.const 0) (i32.const 1))
+                    (i32.atomic.rmw.cmpxchg (i32.const 4) (i32.const 42) (i32.const 7))
+                    (i32.atomic.load (i32.const 4)))
+                (func (export "unaligned") (result i32)
+                    (i32.atomic.load (i32.const 2))))"#,
+        );
+        let mut interpreter = Interpreter::from_bytecode(&translation.code).unwrap();
+        assert_eq!(interpreter.call("counter", &[2], &mut NoSyscalls()).unwrap(), [40, 42, 42, 7]);
+        let trap = interpreter.call("unaligned", &[], &mut NoSyscalls()).unwrap_err();
+        assert!(matches!(trap.error, InterpreterErrorType::UnalignedAtomic(addr) if addr == translation.memory_base + 2));
+    }
+
+    #[test]
+    fn control_flow_and_emulated_ops() {
+        let translation = translate(
+            r#"(module
+                (type $binop (func (param i32 i32) (result i32)))
+                (table 3 funcref)
+                (elem (i32.const 0) $add $sub)
+                (func $add (type $binop) (i32.add (local.get 0) (local.get 1)))
+                (func $sub (type $binop) (i32.sub (local.get 0) (local.get 1)))
+                (func (export "apply") (param i32 i32 i32) (result i32)
+                    (call_indirect (type $binop) (local.get 1) (local.get 2) (local.get 0)))
+                (func (export "classify") (param i32) (result i32)
+                    block $two (result i32)
+                        block $one (result i32)
+                            block $zero (result i32)
+                                i32.const 7
+                                i32.const 10
+                                local.get 0
+                                br_table $zero $one $two
+                            end
+                            i32.const 1
+                            i32.add
+                            return
+                        end
+                        i32.const 2
+                        i32.add
+                        return
+                    end
+                    i32.const 3
+                    i32.add)
+                (func (export "misc") (result i32 i32 i32 i32 i32 i32 i64 i32 i64 i32 i32)
+                    (i32.lt_s (i32.const -1) (i32.const 1))
+                    (i32.rem_s (i32.const -7) (i32.const 2))
+                    (i32.rotl (i32.const 0x80000001) (i32.const 1))
+                    (i32.clz (i32.const 0x00f00000))
+                    (i32.ctz (i32.const 0x00f00000))
+                    (select (i32.const 4) (i32.const 5) (i32.const 0))
+                    (i64.shr_s (i64.const -16) (i64.const 2))
+                    (f32.le (f32.const 1.5) (f32.const 1.5))
+                    (i64.popcnt (i64.const -1))
+                    (i32.trunc_f32_s (f32.const -3.7))
+                    (i32.rem_s (i32.const 0x80000000) (i32.const -1))))"#,
+        );
+        let mut interpreter = Interpreter::from_bytecode(&translation.code).unwrap();
+        let mut call = |name, args: &[u32]| interpreter.call(name, args, &mut NoSyscalls());
+        assert_eq!(call("apply", &[0, 5, 3]).unwrap(), [8]);
+        assert_eq!(call("apply", &[1, 5, 3]).unwrap(), [2]);
+        assert!(call("apply", &[2, 5, 3]).is_err());
+        assert!(call("apply", &[7, 5, 3]).is_err());
+        assert_eq!(call("classify", &[0]).unwrap(), [11]);
+        assert_eq!(call("classify", &[1]).unwrap(), [12]);
+        assert_eq!(call("classify", &[9]).unwrap(), [13]);
+        assert_eq!(
+            call("misc", &[]).unwrap(),
+            [1, -1i32 as u32, 3, 8, 20, 5, -4i32 as u32, u32::MAX, 1, 64, 0, -3i32 as u32, 0]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_modules() {
+        let translate = |wat: &str| Translator::new().translate(&wat::parse_str(wat).unwrap());
+        let f64_add = r#"(module (func (param f64) (result f64) (f64.add (local.get 0) (local.get 0))))"#;
+        assert!(matches!(translate(f64_add), Err(TranslateError::Unsupported(_))));
+        let import = r#"(module (import "env" "log" (func (param i32))))"#;
+        assert!(matches!(translate(import), Err(TranslateError::Unsupported(_))));
+        let sqrt = r#"(module (func (param f32) (result f32) (f32.sqrt (local.get 0))))"#;
+        assert!(matches!(translate(sqrt), Err(TranslateError::Unsupported(_))));
+        assert!(matches!(Translator::new().translate(b"\0asm"), Err(TranslateError::Wasm(_))));
+
+        let wasm = wat::parse_str(r#"(module (func (export "main")))"#).unwrap();
+        assert!(matches!(
+            Translator::new().with_entry("start").translate(&wasm),
+            Err(TranslateError::MissingEntry(_))
+        ));
+        assert!(Translator::new().with_entry("main").translate(&wasm).is_ok());
+    }
+}
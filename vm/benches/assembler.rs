@@ -0,0 +1,23 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use vm::asm::Parser;
+
+mod common;
+
+fn assemble(c: &mut Criterion) {
+    let source = common::large_source(2000);
+    let mut group = c.benchmark_group("assembler");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("large_source", |b| b.iter(|| Parser::parse(&source).unwrap()));
+    group.bench_function("large_source_with_debug_info", |b| {
+        b.iter(|| {
+            let mut parser = Parser::new();
+            parser.set_emit_debug_info(true);
+            parser.set_emit_symbols(true);
+            parser.assemble(&source).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, assemble);
+criterion_main!(benches);
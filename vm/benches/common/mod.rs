@@ -0,0 +1,19 @@
+//NOTE(joh): `functions` small functions with a loop each, all called from the entry point
+pub fn large_source(functions: usize) -> String {
+    let mut source = String::from(":__ENTRY__:\n");
+    for i in 0..functions {
+        source += &format!("#{i}; push_arg; #@f{i}; call; drop;\n");
+    }
+    source += "end;\n";
+    for i in 0..functions {
+        source += &format!(
+            ":f{i}:
+            local_get 0; #3; mul; local_set 1;
+            :f{i}_loop:
+            local_get 1; #1; sub; local_tee 1;
+            #0; gt; #@f{i}_loop; jmp_if;
+            local_get 0; #0x{i:x}; xor; return;\n"
+        );
+    }
+    source
+}
@@ -0,0 +1,25 @@
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use vm::{
+    asm::{BytecodeInfo, Parser},
+    module::Module,
+    parse::try_parse_ops_from_bytecode,
+};
+
+mod common;
+
+fn decode(c: &mut Criterion) {
+    let bytecode = Parser::parse(&common::large_source(2000)).unwrap().code;
+    let code = &bytecode[BytecodeInfo::total_header_size()..];
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes(bytecode.len() as u64));
+    group.bench_function("parse_ops", |b| {
+        b.iter(|| try_parse_ops_from_bytecode(&mut Cursor::new(code)).count())
+    });
+    group.bench_function("module", |b| b.iter(|| Module::from_bytecode(&bytecode).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
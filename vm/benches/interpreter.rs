@@ -0,0 +1,76 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use vm::{
+    asm::Parser,
+    interpreter::Interpreter,
+    module::Module,
+    syscall::StdHandler,
+};
+
+const FIBONACCI: &str = "
+    #80; push_arg; #@fib; call;
+    end;
+    :fib:
+    #0; local_set 1; #1; local_set 2;
+    :step:
+    local_get 1; local_get 2; add;
+    local_get 2; local_set 1; local_set 2;
+    local_get 0; #1; sub; local_tee 0;
+    #1; gt; #@step; jmp_if;
+    local_get 2; return;
+";
+
+//NOTE(joh): Fills 8 KiB word by word and copies them to another buffer
+const MEMORY_COPY: &str = "
+    #0; local_set 0;
+    :fill:
+    local_get 0; #1024; add; local_get 0; store_32 0;
+    local_get 0; #4; add; local_tee 0;
+    #8192; lt; #@fill; jmp_if;
+    #0; local_set 0;
+    :copy:
+    local_get 0; #32768; add;
+    local_get 0; load_32_u 1024;
+    store_32 0;
+    local_get 0; #4; add; local_tee 0;
+    #8192; lt; #@copy; jmp_if;
+    #32768; load_32_u 8188;
+    end;
+";
+
+const RECURSION: &str = "
+    #20; push_arg; #@fib; call;
+    end;
+    :fib:
+    local_get 0; #2; lt; #@base; jmp_if;
+    local_get 0; #1; sub; push_arg; #@fib; call;
+    local_get 0; #2; sub; push_arg; #@fib; call;
+    add; return;
+    :base:
+    local_get 0; return;
+";
+
+//NOTE(joh): Each program runs from a fresh instance, instantiating is not measured
+fn interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interpreter");
+    for (name, source) in [("fibonacci", FIBONACCI), ("memory_copy", MEMORY_COPY), ("recursion", RECURSION)] {
+        let module = Module::from_bytecode(&Parser::parse(source).unwrap().code).unwrap();
+        group.bench_with_input(BenchmarkId::new("bytes", name), &module, |b, module| {
+            b.iter_batched(
+                || Interpreter::instantiate(module).unwrap(),
+                |mut interpreter| interpreter.run(&mut StdHandler::new(String::new())).unwrap().to_vec(),
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("decoded", name), &module, |b, module| {
+            b.iter_batched(
+                || Interpreter::instantiate(module).unwrap(),
+                |mut interpreter| interpreter.run_decoded(&mut StdHandler::new(String::new())).unwrap().to_vec(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
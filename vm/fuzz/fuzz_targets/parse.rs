@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vm::asm::Parser;
+
+fuzz_target!(|source: &str| {
+    _ = Parser::parse(source);
+});
@@ -0,0 +1,45 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vm::{
+    asm::{BytecodeInfo, DATA_START},
+    config::{InterpreterConfig, Quota},
+    interpreter::{Interpreter, SyscallHandler},
+};
+
+//NOTE(joh): The std syscalls read the clock and the random generator, runs have to be repeatable
+struct NoSyscalls;
+impl SyscallHandler for NoSyscalls {
+    fn on_syscall(&mut self, _: &mut Interpreter, _: u32, _: &[u32]) -> u32 {
+        0
+    }
+}
+
+//NOTE(joh): The input is the code section behind a valid header. Both execution modes have to
+//agree on the outcome.
+fuzz_target!(|code: &[u8]| {
+    let info = BytecodeInfo {
+        code_size_bytes: code.len() as u32,
+        instruction_count: 0,
+        code_start_offset: DATA_START,
+        data_section_size: 0,
+    };
+    let mut bytecode = info.to_bytecode();
+    bytecode.extend_from_slice(code);
+
+    let config = InterpreterConfig::default()
+        .with_quota(Quota {
+            fuel: Some(10_000),
+            memory_bytes: Some(1 << 22),
+            ..Quota::default()
+        })
+        .with_max_call_depth(256);
+    let Ok(mut bytes) = Interpreter::from_bytecode_with_config(&bytecode, config.clone()) else {
+        return;
+    };
+    let mut decoded = Interpreter::from_bytecode_with_config(&bytecode, config).unwrap();
+    let expected = bytes.run(&mut NoSyscalls).map(|r| r.to_vec()).map_err(|e| e.to_string());
+    let result = decoded.run_decoded(&mut NoSyscalls).map(|r| r.to_vec()).map_err(|e| e.to_string());
+    assert_eq!(expected, result);
+    assert_eq!(bytes.pc, decoded.pc);
+});
@@ -0,0 +1,187 @@
+use crate::{
+    interpreter::Interpreter,
+    mem::{LeBytes, Memory},
+    prelude::*,
+    syscall::SyscallError,
+};
+
+//NOTE(joh): A value with a fixed layout in guest memory, `SIZE` bytes without padding and every
+//field little endian. Implemented for the integers and by `abi_struct!`.
+pub trait Pod: Sized + Copy {
+    const SIZE: usize;
+
+    //NOTE(joh): `bytes` must be exactly `SIZE` long
+    fn from_guest(bytes: &[u8]) -> Self;
+    fn to_guest(&self, dest: &mut [u8]);
+}
+
+macro_rules! impl_pod_for_le_bytes {
+    ($($t: ty),+) => {
+        $(impl Pod for $t {
+            const SIZE: usize = <$t as LeBytes>::SIZE;
+
+            fn from_guest(bytes: &[u8]) -> Self {
+                <$t>::from_le_slice(bytes)
+            }
+
+            fn to_guest(&self, dest: &mut [u8]) {
+                self.write_le_slice(dest);
+            }
+        })+
+    };
+}
+impl_pod_for_le_bytes!(u8, i8, u16, i16, u32, i32, u64);
+
+//NOTE(joh): Declares a struct and implements `Pod` for it. The fields are laid out in the order
+//they are declared, without padding, so the guest side can use the offsets of the declaration:
+//
+//    vm::abi_struct! {
+//        pub struct Rect { pub x: i32, pub y: i32, pub width: u32, pub height: u32 }
+//    }
+//
+//Fields can be any `Pod`, including other structs declared this way.
+#[macro_export]
+macro_rules! abi_struct {
+    ($(#[$meta: meta])* $vis: vis struct $name: ident { $($field_vis: vis $field: ident: $ty: ty),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        $vis struct $name {
+            $($field_vis $field: $ty),*
+        }
+
+        impl $crate::abi::Pod for $name {
+            const SIZE: usize = 0 $(+ <$ty as $crate::abi::Pod>::SIZE)*;
+
+            fn from_guest(bytes: &[u8]) -> Self {
+                let mut offset = 0;
+                $(
+                    let end = offset + <$ty as $crate::abi::Pod>::SIZE;
+                    let $field = <$ty as $crate::abi::Pod>::from_guest(&bytes[offset..end]);
+                    offset = end;
+                )*
+                _ = offset;
+                Self { $($field),* }
+            }
+
+            fn to_guest(&self, dest: &mut [u8]) {
+                let mut offset = 0;
+                $(
+                    let end = offset + <$ty as $crate::abi::Pod>::SIZE;
+                    $crate::abi::Pod::to_guest(&self.$field, &mut dest[offset..end]);
+                    offset = end;
+                )*
+                _ = offset;
+            }
+        }
+    };
+}
+
+pub fn read<T: Pod>(memory: &Memory, addr: u32) -> Result<T, SyscallError> {
+    let bytes = memory.slice(addr as usize, T::SIZE).ok_or(SyscallError::InvalidMemAddr)?;
+    Ok(T::from_guest(&bytes))
+}
+
+//NOTE(joh): The writes go through `Interpreter::write_bytes`, so they can't touch write protected
+//memory like the code
+pub fn write<T: Pod>(interpreter: &mut Interpreter, addr: u32, value: &T) -> Result<(), SyscallError> {
+    let mut bytes = vec![0; T::SIZE];
+    value.to_guest(&mut bytes);
+    Ok(interpreter.write_bytes(addr, &bytes)?)
+}
+
+//NOTE(joh): `count` values stored one after another, e.g. an array the program passed by address
+//and length
+pub fn read_slice<T: Pod>(memory: &Memory, addr: u32, count: u32) -> Result<Vec<T>, SyscallError> {
+    let len = (count as usize).checked_mul(T::SIZE).ok_or(SyscallError::InvalidMemAddr)?;
+    let bytes = memory.slice(addr as usize, len).ok_or(SyscallError::InvalidMemAddr)?;
+    Ok(bytes.chunks_exact(T::SIZE).map(T::from_guest).collect())
+}
+
+//NOTE(joh): Nothing is written if the values don't fit
+pub fn write_slice<T: Pod>(interpreter: &mut Interpreter, addr: u32, values: &[T]) -> Result<(), SyscallError> {
+    let mut bytes = vec![0; values.len() * T::SIZE];
+    for (value, dest) in values.iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+        value.to_guest(dest);
+    }
+    Ok(interpreter.write_bytes(addr, &bytes)?)
+}
+
+pub fn read_u32s(memory: &Memory, addr: u32, count: u32) -> Result<Vec<u32>, SyscallError> {
+    read_slice(memory, addr, count)
+}
+
+pub fn write_u32s(interpreter: &mut Interpreter, addr: u32, values: &[u32]) -> Result<(), SyscallError> {
+    write_slice(interpreter, addr, values)
+}
+
+//NOTE(joh): A u32 byte length followed by the utf-8 bytes
+pub fn read_prefixed_str(memory: &Memory, addr: u32) -> Result<String, SyscallError> {
+    let len = read::<u32>(memory, addr)?;
+    let start = addr.checked_add(size_of::<u32>() as u32).ok_or(SyscallError::InvalidMemAddr)?;
+    let bytes = memory.slice(start as usize, len as usize).ok_or(SyscallError::InvalidMemAddr)?;
+    String::from_utf8(bytes.into_owned()).map_err(|_| SyscallError::InvalidStringData)
+}
+
+//NOTE(joh): Returns the number of bytes written including the length. Strings longer than
+//`capacity` bytes are refused instead of being cut, which could split a character.
+pub fn write_prefixed_str(interpreter: &mut Interpreter, addr: u32, s: &str, capacity: u32) -> Result<u32, SyscallError> {
+    if s.len() > capacity as usize {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let mut bytes = Vec::with_capacity(size_of::<u32>() + s.len());
+    bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(s.as_bytes());
+    interpreter.write_bytes(addr, &bytes)?;
+    Ok(bytes.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{opcode, Parser, DATA_START},
+        mem::PAGE_SIZE,
+    };
+
+    abi_struct! {
+        struct Point { x: i32, y: i32 }
+    }
+
+    abi_struct! {
+        struct Sprite { position: Point, id: u16, flags: u8 }
+    }
+
+    #[test]
+    fn marshal_structs_and_strings() {
+        assert_eq!(Sprite::SIZE, 11);
+        let mut interpreter = Interpreter::from_bytecode(&Parser::parse("end;").unwrap().code).unwrap();
+        let sprite = Sprite { position: Point { x: -3, y: 7 }, id: 0x1234, flags: 1 };
+        //NOTE(joh): Across a page boundary
+        let addr = PAGE_SIZE as u32 - 5;
+        write(&mut interpreter, addr, &sprite).unwrap();
+        let memory = &interpreter.memory;
+        assert_eq!(read::<Sprite>(memory, addr), Ok(sprite));
+        assert_eq!(memory.read_le::<i32>(addr as usize), Some(-3));
+        assert_eq!(memory.read_le::<u16>(addr as usize + 8), Some(0x1234));
+
+        let end = interpreter.memory.len() as u32;
+        write_u32s(&mut interpreter, 0x100, &[1, 2, 3]).unwrap();
+        let memory = &interpreter.memory;
+        assert_eq!(read_u32s(memory, 0x104, 2), Ok(vec![2, 3]));
+        assert_eq!(read_slice::<Point>(memory, 0x100, 1), Ok(vec![Point { x: 1, y: 2 }]));
+        assert_eq!(read_u32s(memory, end - 4, 2), Err(SyscallError::InvalidMemAddr));
+        assert_eq!(write_u32s(&mut interpreter, u32::MAX, &[1]), Err(SyscallError::InvalidMemAddr));
+        //NOTE(joh): The code is write protected
+        assert_eq!(write_u32s(&mut interpreter, DATA_START, &[1]), Err(SyscallError::InvalidMemAddr));
+        assert_eq!(interpreter.memory.get(DATA_START as usize), Some(opcode::End));
+
+        assert_eq!(write_prefixed_str(&mut interpreter, 0x140, "grüße", 16), Ok(11));
+        assert_eq!(read_prefixed_str(&interpreter.memory, 0x140).as_deref(), Ok("grüße"));
+        assert_eq!(write_prefixed_str(&mut interpreter, 0x140, "too long", 4), Err(SyscallError::InvalidArgument));
+        let memory = &mut interpreter.memory;
+        memory.write_le(0x140, 3u32).unwrap();
+        assert_eq!(read_prefixed_str(memory, 0x140), Err(SyscallError::InvalidStringData));
+        memory.write_le(0x140, u32::MAX).unwrap();
+        assert_eq!(read_prefixed_str(memory, 0x140), Err(SyscallError::InvalidMemAddr));
+    }
+}
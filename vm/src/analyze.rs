@@ -0,0 +1,331 @@
+use alloc::collections::BTreeSet;
+use core::fmt::Display;
+
+use crate::{
+    asm::opcode,
+    debug::DebugInfo,
+    function::{split_function_table, Function},
+    prelude::*,
+    signing::split_signature,
+    validate::{const_value, decode_code, ends_flow, is_control_flow, op_targets, stack_effect, Code, ValidationError},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackWarningKind {
+    //NOTE(joh): The op pops more values than the function pushed so far
+    Underflow { height: usize, pops: usize },
+    //NOTE(joh): A `return` of a function that declares `returns` leaves another number of values
+    ReturnHeight { expected: usize, found: usize },
+    //NOTE(joh): Two paths reach the op with different stack heights
+    Merge { expected: usize, found: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackWarning {
+    pub addr: u32,
+    pub kind: StackWarningKind,
+}
+
+impl Display for StackWarningKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Underflow { height, pops } => write!(f, "pops {pops} values but only {height} are on the stack"),
+            Self::ReturnHeight { expected, found } => {
+                write!(f, "returns with {found} values on the stack but the function declares {expected}")
+            }
+            Self::Merge { expected, found } => {
+                write!(f, "stack height is {found} on one path and {expected} on another")
+            }
+        }
+    }
+}
+
+impl Display for StackWarning {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "0x{:04x}: {}", self.addr, self.kind)
+    }
+}
+
+impl StackWarning {
+    //NOTE(joh): Like `Trap::render`, with the source line if the debug info knows it
+    pub fn render(&self, debug_info: Option<&DebugInfo>) -> String {
+        let location = debug_info
+            .and_then(|d| d.resolve_pc(self.addr))
+            .map_or_else(|| format!("0x{:04x}", self.addr), |loc| loc.to_string());
+        format!("{location}: {}", self.kind)
+    }
+}
+
+//NOTE(joh): Finds stack mistakes `validate` lets through. Every function of the function table
+//is walked on its own starting with an empty stack, the code from the entry point as well. Unlike
+//`validate` a path goes on after a call if the callee is in the function table and declares its
+//`returns`. Only malformed bytecode is an error, the stack mistakes are warnings sorted by
+//address.
+pub fn analyze_stack(bytecode: &[u8]) -> Result<Vec<StackWarning>, ValidationError> {
+    let (bytecode, _) = split_signature(bytecode);
+    let (bytecode, functions) = split_function_table(bytecode);
+    let functions = functions.unwrap_or_default();
+    let (info, const_pool, code) = decode_code(bytecode)?;
+
+    let mut walk = Walk {
+        code: &code,
+        const_pool: &const_pool,
+        functions: &functions,
+        warnings: Vec::new(),
+    };
+    walk.walk(info.code_start_offset, None);
+    for function in functions.iter() {
+        walk.walk(function.addr, function.returns);
+    }
+    let mut warnings = walk.warnings;
+    warnings.sort_by_key(|w| w.addr);
+    warnings.dedup();
+    Ok(warnings)
+}
+
+struct Walk<'a> {
+    code: &'a Code,
+    const_pool: &'a [u32],
+    functions: &'a [Function],
+    warnings: Vec<StackWarning>,
+}
+
+impl Walk<'_> {
+    fn walk(&mut self, entry: u32, returns: Option<u8>) {
+        let mut heights: HashMap<u32, usize> = HashMap::new();
+        let mut pending = vec![(entry, 0)];
+
+        while let Some((addr, height)) = pending.pop() {
+            let Some(&i) = self.code.index.get(&addr) else {
+                continue;
+            };
+            if let Some(&expected) = heights.get(&addr) {
+                if expected != height {
+                    self.warn(addr, StackWarningKind::Merge { expected, found: height });
+                }
+                continue;
+            }
+            heights.insert(addr, height);
+
+            let op = &self.code.ops[i].1;
+            if op.opcode == opcode::Return
+                && let Some(expected) = returns
+                && height != expected as usize
+            {
+                self.warn(addr, StackWarningKind::ReturnHeight { expected: expected as usize, found: height });
+            }
+            let Some((pops, pushes)) = self.stack_effect(i) else {
+                continue;
+            };
+            let Some(rest) = height.checked_sub(pops) else {
+                self.warn(addr, StackWarningKind::Underflow { height, pops });
+                continue;
+            };
+            let height = rest + pushes;
+
+            //NOTE(joh): The callee runs in its own walk, only the jumps stay in this one
+            if !matches!(op.opcode, opcode::Call | opcode::CallIf) {
+                pending.extend(op_targets(self.code, i, self.const_pool).into_iter().map(|t| (t, height)));
+            }
+            if !ends_flow(op.opcode)
+                && let Some((next, _)) = self.code.ops.get(i + 1)
+            {
+                pending.push((*next, height));
+            }
+        }
+    }
+
+    //NOTE(joh): Calls pop their target and push what the callee declares. A `call_if` that does
+    //not call pushes nothing, so only callees without results keep the height known.
+    fn stack_effect(&self, i: usize) -> Option<(usize, usize)> {
+        let op = &self.code.ops[i].1;
+        let returns = |function: Option<&Function>| function.and_then(|f| f.returns).map(|r| r as usize);
+        let callee = || {
+            let target = *op_targets(self.code, i, self.const_pool).first()?;
+            self.functions.iter().find(|f| f.addr == target)
+        };
+        match op.opcode {
+            opcode::Call => Some((1, returns(callee())?)),
+            opcode::CallIf => returns(callee()).filter(|r| *r == 0).map(|_| (2, 0)),
+            opcode::CallIndirect => {
+                let index = i.checked_sub(1).and_then(|prev| const_value(&self.code.ops[prev].1, self.const_pool))?;
+                Some((1, returns(self.functions.get(index as usize))?))
+            }
+            other => stack_effect(other),
+        }
+    }
+
+    fn warn(&mut self, addr: u32, kind: StackWarningKind) {
+        self.warnings.push(StackWarning { addr, kind });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    //NOTE(joh): A conditional jump, the fallthrough edge is the other way
+    Branch,
+    Call,
+}
+
+//NOTE(joh): Ops that run one after another, only the first one is jumped to and only the last
+//one jumps. The edges go out of the last op, to the first op of another block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: u32,
+    //NOTE(joh): Address of the last op
+    pub last: u32,
+    pub ops: usize,
+    pub edges: Vec<(u32, EdgeKind)>,
+}
+
+impl BasicBlock {
+    pub fn contains(&self, addr: u32) -> bool {
+        (self.start..=self.last).contains(&addr)
+    }
+}
+
+//NOTE(joh): The control flow graph of all ops in address order. Like in `validate` only targets
+//pushed as a constant right before the op are known, computed jumps have no edge. An
+//`call_indirect` gets one if the index is a constant and the function table has it.
+pub fn basic_blocks(bytecode: &[u8]) -> Result<Vec<BasicBlock>, ValidationError> {
+    let (bytecode, _) = split_signature(bytecode);
+    let (bytecode, functions) = split_function_table(bytecode);
+    let functions = functions.unwrap_or_default();
+    let (info, const_pool, code) = decode_code(bytecode)?;
+
+    let targets = (0..code.ops.len())
+        .map(|i| {
+            let op = &code.ops[i].1;
+            let mut targets = op_targets(&code, i, &const_pool);
+            if op.opcode == opcode::CallIndirect
+                && let Some(index) = i.checked_sub(1).and_then(|prev| const_value(&code.ops[prev].1, &const_pool))
+                && let Some(function) = functions.get(index as usize)
+            {
+                targets.push(function.addr);
+            }
+            targets
+        })
+        .collect::<Vec<_>>();
+
+    let mut leaders = BTreeSet::from([info.code_start_offset]);
+    leaders.extend(functions.iter().map(|f| f.addr));
+    for (i, (_, op)) in code.ops.iter().enumerate() {
+        if is_control_flow(op.opcode) {
+            leaders.extend(&targets[i]);
+            leaders.extend(code.ops.get(i + 1).map(|(next, _)| *next));
+        }
+    }
+
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    for (i, (addr, op)) in code.ops.iter().enumerate() {
+        match blocks.last_mut() {
+            Some(block) if !leaders.contains(addr) => {
+                block.last = *addr;
+                block.ops += 1;
+            }
+            _ => blocks.push(BasicBlock { start: *addr, last: *addr, ops: 1, edges: Vec::new() }),
+        }
+        let next = code.ops.get(i + 1).map(|(next, _)| *next);
+        if next.is_some_and(|next| !leaders.contains(&next)) {
+            continue;
+        }
+        let kind = match op.opcode {
+            opcode::JmpIf | opcode::BranchIf => EdgeKind::Branch,
+            opcode::Call | opcode::CallIf | opcode::CallIndirect => EdgeKind::Call,
+            _ => EdgeKind::Jump,
+        };
+        let block = blocks.last_mut().unwrap();
+        block.edges.extend(targets[i].iter().filter(|t| code.index.contains_key(*t)).map(|t| (*t, kind)));
+        if !ends_flow(op.opcode)
+            && let Some(next) = next
+        {
+            block.edges.push((next, EdgeKind::Fallthrough));
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{Parser, DATA_START};
+
+    fn warnings(code: &str) -> Vec<StackWarningKind> {
+        let bytecode = Parser::parse(code).unwrap().code;
+        analyze_stack(&bytecode).unwrap().into_iter().map(|w| w.kind).collect()
+    }
+
+    #[test]
+    fn stack_warnings() {
+        let code = "
+            .func pair 0 2;
+            #1; #2;
+            return;
+            .func short 0 2;
+            #1;
+            return;
+            .func takes 1 0;
+            drop;
+            return;
+            :__ENTRY__:
+            #@pair; call; add; #@short; call; add;
+            end;
+        ";
+        assert_eq!(
+            warnings(code),
+            [
+                StackWarningKind::ReturnHeight { expected: 2, found: 1 },
+                StackWarningKind::Underflow { height: 0, pops: 1 },
+            ]
+        );
+        assert_eq!(
+            warnings(":loop: #1; #1; #@loop; jmp_if; end;"),
+            [StackWarningKind::Merge { expected: 0, found: 1 }]
+        );
+        //NOTE(joh): The result of the call is known, so the path goes on to the underflow
+        assert_eq!(
+            warnings(".func one 0 1; #1; return; :__ENTRY__: #@one; call; add; end;"),
+            [StackWarningKind::Underflow { height: 1, pops: 2 }]
+        );
+        assert!(warnings("#@f; call; drop; end; :f: #1; return;").is_empty());
+
+        let parsed = Parser::parse("#1;\nadd;\nend;").unwrap();
+        let warning = analyze_stack(&parsed.code).unwrap()[0];
+        assert_eq!(warning.addr, DATA_START + 5);
+        let debug_info = parsed.debug_info.with_file("add.malu");
+        assert_eq!(
+            warning.render(Some(&debug_info)),
+            "add.malu:2: pops 2 values but only 1 are on the stack"
+        );
+    }
+
+    #[test]
+    fn control_flow_graph() {
+        let code = "
+            .func double 1 1;
+            #2; mul;
+            return;
+            :__ENTRY__:
+            #3; #@double; call;
+            :loop:
+            #1; sub; dup; #@loop; jmp_if;
+            end;
+        ";
+        let parsed = Parser::parse(code).unwrap();
+        let label = |name: &str| parsed.labels.iter().find(|(n, _)| n == name).unwrap().1 + DATA_START;
+        let blocks = basic_blocks(&parsed.code).unwrap();
+        let starts = blocks.iter().map(|b| b.start).collect::<Vec<_>>();
+        assert_eq!(starts.len(), 4);
+        assert_eq!(starts[0], label("double"));
+        assert_eq!(blocks[0].edges, []);
+        assert_eq!(blocks[1].start, label("__ENTRY__"));
+        assert_eq!(blocks[1].edges, [(label("double"), EdgeKind::Call), (label("loop"), EdgeKind::Fallthrough)]);
+        assert_eq!(blocks[2].start, label("loop"));
+        assert_eq!(blocks[2].ops, 5);
+        assert_eq!(blocks[2].edges, [(label("loop"), EdgeKind::Branch), (blocks[3].start, EdgeKind::Fallthrough)]);
+        assert!(blocks[2].contains(blocks[2].last) && !blocks[2].contains(blocks[3].start));
+        assert_eq!((blocks[3].ops, &blocks[3].edges[..]), (1, &[][..]));
+    }
+}
@@ -1,8 +1,34 @@
-use crate::interpreter::{Interpreter, InterpreterErrorType};
-use core::fmt::{self, Display};
-use std::{
-    collections::HashMap,
-    num::{ParseIntError, TryFromIntError},
+use alloc::{borrow::Cow, collections::BTreeSet};
+use core::{
+    fmt::Display,
+    num::{ParseFloatError, ParseIntError, TryFromIntError},
+    ops::Range,
+};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+//NOTE(joh): Without std sources are only named, there are no files to `%include`
+#[cfg(not(feature = "std"))]
+type PathBuf = String;
+
+use crate::{
+    config::STACK_POINTER_GLOBAL,
+    debug::{DebugInfo, LineEntry},
+    debuginfo::encode_debug_info,
+    exports::encode_exports,
+    expr::{self, ExprError},
+    flow::{self, FlowError},
+    function::{decode_returns, encode_function_table, Function},
+    globals::encode_global_inits,
+    host::{encode_host_imports, HostImport},
+    interpreter::{MAX_ARGS, MAX_LOCALS},
+    lexer::blank_comments,
+    link::{Object, Relocation, RelocationTarget, Section, Symbol},
+    mem::push_le,
+    prelude::*,
+    symbols::{encode_symbol_table, SymbolTable},
+    testing::TEST_PREFIX,
+    validate::{ends_flow, op_targets, Code},
 };
 
 #[derive(Debug, Clone)]
@@ -10,6 +36,7 @@ pub enum AssembleErrorKind {
     MissingDelimiter,
     UnknownOperation,
     UnableToParseInt(ParseIntError),
+    UnableToParseFloat(ParseFloatError),
     IntSize(TryFromIntError),
     MissingArgument,
     TooManyArguments,
@@ -17,6 +44,80 @@ pub enum AssembleErrorKind {
     LabelAlreadyExists(String),
     UnexpectedRegisterId(i32),
     UnexpectedImmArgSize,
+    UnknownDirective(String),
+    UnknownEscape(char),
+    MacroAlreadyDefined(String),
+    UnterminatedMacro(String),
+    RecursiveMacro(String),
+    ExpansionTooLarge,
+    IncludeNotFound(String),
+    IncludeCycle(String),
+    Io(String),
+    ArityMismatch {
+        name: String,
+        expected: u8,
+        found: u8,
+    },
+    InvalidExpression(String),
+    InvalidAlignment(u32),
+    UnknownGlobal(String),
+    GlobalAlreadyDefined(String),
+    GlobalInObject,
+    UnknownLocal(String),
+    LocalAlreadyDefined(String),
+    ImportWithoutObject(String),
+    UnknownHostFn(String),
+    HostFnAlreadyDefined(String),
+    HostFnInObject,
+    ControlFlow(FlowError),
+    TooLarge,
+}
+
+impl Display for AssembleErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AssembleErrorKind::MissingDelimiter => write!(f, "missing delimiter"),
+            AssembleErrorKind::UnknownOperation => write!(f, "unknown operation"),
+            AssembleErrorKind::UnableToParseInt(e) => write!(f, "unable to parse integer: {e}"),
+            AssembleErrorKind::UnableToParseFloat(e) => write!(f, "unable to parse float: {e}"),
+            AssembleErrorKind::IntSize(e) => write!(f, "integer out of range: {e}"),
+            AssembleErrorKind::MissingArgument => write!(f, "missing argument"),
+            AssembleErrorKind::TooManyArguments => write!(f, "too many arguments"),
+            AssembleErrorKind::UnknownLabel(label) => write!(f, "unknown label `{label}`"),
+            AssembleErrorKind::LabelAlreadyExists(label) => write!(f, "label `{label}` is already defined"),
+            AssembleErrorKind::UnexpectedRegisterId(id) => write!(f, "register id {id} is out of range"),
+            AssembleErrorKind::UnexpectedImmArgSize => write!(f, "unexpected argument type"),
+            AssembleErrorKind::UnknownDirective(name) => write!(f, "unknown directive `{name}`"),
+            AssembleErrorKind::UnknownEscape(c) => write!(f, "unknown escape sequence `\\{c}`"),
+            AssembleErrorKind::MacroAlreadyDefined(name) => write!(f, "macro `{name}` is already defined"),
+            AssembleErrorKind::UnterminatedMacro(name) => write!(f, "macro `{name}` is missing `%end`"),
+            AssembleErrorKind::RecursiveMacro(name) => write!(f, "macro `{name}` expands recursively"),
+            AssembleErrorKind::ExpansionTooLarge => {
+                write!(f, "macros and defines expand to more than {MAX_EXPANSION_LEN} bytes")
+            }
+            AssembleErrorKind::IncludeNotFound(path) => write!(f, "unable to find included file `{path}`"),
+            AssembleErrorKind::IncludeCycle(path) => write!(f, "`{path}` includes itself"),
+            AssembleErrorKind::Io(e) => write!(f, "{e}"),
+            AssembleErrorKind::ArityMismatch { name, expected, found } => {
+                write!(f, "`{name}` takes {expected} arguments but {found} were pushed")
+            }
+            AssembleErrorKind::InvalidExpression(expr) => write!(f, "invalid expression `{expr}`"),
+            AssembleErrorKind::InvalidAlignment(n) => write!(f, "alignment {n} is not a power of two"),
+            AssembleErrorKind::UnknownGlobal(name) => write!(f, "unknown global `{name}`"),
+            AssembleErrorKind::GlobalAlreadyDefined(name) => write!(f, "global `{name}` is already defined"),
+            AssembleErrorKind::GlobalInObject => write!(f, "objects can't declare initialized globals"),
+            AssembleErrorKind::UnknownLocal(name) => write!(f, "unknown local `{name}`"),
+            AssembleErrorKind::LocalAlreadyDefined(name) => write!(f, "local `{name}` is already defined in this function"),
+            AssembleErrorKind::ImportWithoutObject(name) => {
+                write!(f, "`{name}` can only be imported when assembling an object")
+            }
+            AssembleErrorKind::UnknownHostFn(name) => write!(f, "unknown host function `{name}`"),
+            AssembleErrorKind::HostFnAlreadyDefined(name) => write!(f, "host function `{name}` is already defined"),
+            AssembleErrorKind::HostFnInObject => write!(f, "objects can't import host functions"),
+            AssembleErrorKind::ControlFlow(e) => write!(f, "{e}"),
+            AssembleErrorKind::TooLarge => write!(f, "the program does not fit into the address space"),
+        }
+    }
 }
 
 impl From<ParseIntError> for AssembleErrorKind {
@@ -25,27 +126,151 @@ impl From<ParseIntError> for AssembleErrorKind {
     }
 }
 
+impl From<ParseFloatError> for AssembleErrorKind {
+    fn from(value: ParseFloatError) -> Self {
+        AssembleErrorKind::UnableToParseFloat(value)
+    }
+}
+
 impl From<TryFromIntError> for AssembleErrorKind {
     fn from(value: TryFromIntError) -> Self {
         AssembleErrorKind::IntSize(value)
     }
 }
 
+//NOTE(joh): `line` and `column` start at 0, `span` is the byte range of the offending
+//statement in the source. `file` is set for errors in included files and the main file
+//if its path is known.
 #[derive(Debug, Clone)]
 pub struct AssembleError {
-    kind: AssembleErrorKind,
-    line: usize,
+    pub kind: AssembleErrorKind,
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub span: Range<usize>,
 }
-impl<'src> AssembleError {
+impl AssembleError {
     pub fn new(state: &Parser, kind: AssembleErrorKind) -> Self {
-        AssembleError {
-            kind,
-            line: state.line,
+        let (file, line, column, span) = state.statement_location();
+        AssembleError { kind, file, line, column, span }
+    }
+
+    //NOTE(joh): Renders the offending source line with the span underlined. `source` has
+    //to be the contents of `self.file`, or the code that was assembled if it is not set.
+    pub fn render(&self, source: &str, file: Option<&str>) -> String {
+        let file = self.file.as_deref().or(file).unwrap_or("<source>");
+        let header = format!("error: {}\n --> {file}:{}:{}", self.kind, self.line + 1, self.column + 1);
+        render_source_line(header, source, self.line, self.column, &self.span)
+    }
+}
+
+fn render_source_line(header: String, source: &str, line: usize, column: usize, span: &Range<usize>) -> String {
+    let line_start = span.start.saturating_sub(column);
+    let Some(rest) = source.get(line_start..) else {
+        return header;
+    };
+    let line_number = (line + 1).to_string();
+    let line = rest.lines().next().unwrap_or_default();
+    let Some(before) = line.get(..column) else {
+        return header;
+    };
+    let underlined = line
+        .get(column..(span.end - line_start).min(line.len()))
+        .map_or(0, |s| s.trim_end().chars().count())
+        .max(1);
+
+    let gutter = " ".repeat(line_number.len());
+    format!(
+        "{header}\n{gutter} |\n{line_number} | {line}\n{gutter} | {}{}",
+        " ".repeat(before.chars().count()),
+        "^".repeat(underlined)
+    )
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
         }
+        write!(f, "{}:{}: {}", self.line + 1, self.column + 1, self.kind)
     }
 }
-const STATEMENT_SEP: char = ';';
-const ENTRY_LABEL_NAME: &'static str = "__ENTRY__";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleWarningKind {
+    //NOTE(joh): `ops` ops in a row that no path from the entry point, a function, an export or
+    //a referenced label reaches
+    UnreachableCode { ops: usize },
+    UnusedLabel(String),
+}
+
+impl Display for AssembleWarningKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnreachableCode { ops: 1 } => write!(f, "op is never reached"),
+            Self::UnreachableCode { ops } => write!(f, "{ops} ops are never reached"),
+            Self::UnusedLabel(name) => write!(f, "label `{name}` is never referenced"),
+        }
+    }
+}
+
+//NOTE(joh): Something that assembles but is likely a mistake, located like `AssembleError`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleWarning {
+    pub kind: AssembleWarningKind,
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub span: Range<usize>,
+}
+
+impl AssembleWarning {
+    fn new(state: &Parser, kind: AssembleWarningKind) -> Self {
+        let (file, line, column, span) = state.statement_location();
+        Self { kind, file, line, column, span }
+    }
+
+    //NOTE(joh): See `AssembleError::render`
+    pub fn render(&self, source: &str, file: Option<&str>) -> String {
+        let file = self.file.as_deref().or(file).unwrap_or("<source>");
+        let header = format!("warning: {}\n --> {file}:{}:{}", self.kind, self.line + 1, self.column + 1);
+        render_source_line(header, source, self.line, self.column, &self.span)
+    }
+}
+
+impl Display for AssembleWarning {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
+        }
+        write!(f, "{}:{}: {}", self.line + 1, self.column + 1, self.kind)
+    }
+}
+
+impl core::error::Error for AssembleError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match &self.kind {
+            AssembleErrorKind::UnableToParseInt(e) => Some(e),
+            AssembleErrorKind::UnableToParseFloat(e) => Some(e),
+            AssembleErrorKind::IntSize(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+pub(crate) const STATEMENT_SEP: char = ';';
+pub(crate) const MACRO_PREFIX: char = '%';
+//NOTE(joh): Guards against macros that (indirectly) expand to themselves
+const MAX_MACRO_DEPTH: usize = 32;
+//NOTE(joh): The depth alone still lets a few nested macros that invoke the next one several times
+//expand exponentially, so the expanded source has a size limit as well
+const MAX_EXPANSION_LEN: usize = 16 << 20;
+pub const ENTRY_LABEL_NAME: &str = "__ENTRY__";
+//NOTE(joh): Other names an op can be written with, the disassembler uses the one in `opcode::Names`
+pub const OP_ALIASES: [(&str, u8); 1] = [("jump_table", opcode::BrTable)];
+//NOTE(joh): Name of `STACK_POINTER_GLOBAL` in `global_get` and friends
+pub const STACK_POINTER_NAME: &str = "__SP__";
+//NOTE(joh): Pool indices are encoded as a single byte
+pub const MAX_CONST_POOL_ENTRIES: usize = u8::MAX as usize + 1;
 pub const BYTECODE_HEADER: [u8; 4] = [b'm', b'a', b'l', b'u'];
 
 //TODO (joh): This has to be updated manually each time the bytecode header definiton changes.
@@ -82,6 +307,8 @@ pub mod opcode {
     pub const Sub: u8 = 0x12;
     pub const Divs: u8 = 0x13;
     pub const Divu: u8 = 0x14;
+    //NOTE(joh): Traps with `IntegerOverflow` if the product doesn't fit into a signed 32 bit
+    //value, `mul_wrap` keeps the low 32 bits instead. The same goes for the 64 bit variants.
     pub const Mul: u8 = 0x15;
     pub const Neg: u8 = 0x16;
     pub const Gt: u8 = 0x17;
@@ -108,8 +335,110 @@ pub mod opcode {
     pub const PushArg: u8 = 0x2c;
     pub const DbgAssert: u8 = 0x2d;
     pub const Syscall: u8 = 0x2e;
-
-    pub const Names: [&'static str; Syscall as usize + 1] = [
+    pub const ConstPool: u8 = 0x2f;
+    pub const BrTable: u8 = 0x30;
+    //NOTE(joh): 64 bit values take up two stack slots, the low half is pushed first
+    pub const Const64: u8 = 0x31;
+    pub const Add64: u8 = 0x32;
+    pub const Sub64: u8 = 0x33;
+    pub const Mul64: u8 = 0x34;
+    pub const Divs64: u8 = 0x35;
+    pub const Divu64: u8 = 0x36;
+    pub const Eq64: u8 = 0x37;
+    pub const Lts64: u8 = 0x38;
+    pub const Ltu64: u8 = 0x39;
+    pub const Gts64: u8 = 0x3a;
+    pub const Gtu64: u8 = 0x3b;
+    pub const And64: u8 = 0x3c;
+    pub const Or64: u8 = 0x3d;
+    pub const Xor64: u8 = 0x3e;
+    pub const Shiftl64: u8 = 0x3f;
+    pub const Shiftr64: u8 = 0x40;
+    pub const ExtendU32: u8 = 0x41;
+    pub const ExtendS32: u8 = 0x42;
+    pub const Wrap64: u8 = 0x43;
+    pub const Load64: u8 = 0x44;
+    pub const Store64: u8 = 0x45;
+    //NOTE(joh): Floats are stored as their bit pattern in a single slot
+    pub const FConst: u8 = 0x46;
+    pub const FAdd: u8 = 0x47;
+    pub const FSub: u8 = 0x48;
+    pub const FMul: u8 = 0x49;
+    pub const FDiv: u8 = 0x4a;
+    pub const FLt: u8 = 0x4b;
+    pub const FGt: u8 = 0x4c;
+    pub const FFromI32: u8 = 0x4d;
+    pub const I32FromF: u8 = 0x4e;
+    pub const ShiftrS: u8 = 0x4f;
+    //NOTE(joh): Sign or zero extend the low 8/16 bits of the value to 32 bits
+    pub const Extend8S32: u8 = 0x50;
+    pub const Extend8U32: u8 = 0x51;
+    pub const Extend16S32: u8 = 0x52;
+    pub const Extend16U32: u8 = 0x53;
+    //NOTE(joh): Pops the address, then the condition. The args are dropped if the call is not taken.
+    pub const CallIf: u8 = 0x54;
+    //NOTE(joh): Pops an index into the function table of the bytecode, see `.func`
+    pub const CallIndirect: u8 = 0x55;
+    //NOTE(joh): Pushes the size of the memory in bytes
+    pub const MemSize: u8 = 0x56;
+    //NOTE(joh): Pops a page count and grows the memory by that many pages. Pushes the old size,
+    //which is the address of the new memory. Growing past the memory quota traps, growing past
+    //the 32 bit address space leaves the memory as it is and pushes `u32::MAX`.
+    pub const MemGrow: u8 = 0x57;
+    //NOTE(joh): Remainder of the division, traps on a zero divisor. `MIN rem_s -1` is 0.
+    pub const RemS: u8 = 0x58;
+    pub const RemU: u8 = 0x59;
+    //NOTE(joh): The rotate amount is taken modulo 32. The bit counts of zero are 32 and 0.
+    pub const Rotl: u8 = 0x5a;
+    pub const Rotr: u8 = 0x5b;
+    pub const Clz: u8 = 0x5c;
+    pub const Ctz: u8 = 0x5d;
+    pub const Popcnt: u8 = 0x5e;
+    pub const Dup: u8 = 0x5f;
+    pub const Swap: u8 = 0x60;
+    //NOTE(joh): Pops the condition, then b, then a. Pushes a if the condition is not 0, else b.
+    pub const Select: u8 = 0x61;
+    //NOTE(joh): Pop the length, then the source address or the byte value, then the destination.
+    //The copy handles overlapping ranges, either range being out of bounds traps.
+    pub const MemCopy: u8 = 0x62;
+    pub const MemFill: u8 = 0x63;
+    //NOTE(joh): Pops a value and suspends the program, the host gets the value from
+    //`Interpreter::run_until_yield` and continues after the op by running again
+    pub const Yield: u8 = 0x64;
+    //NOTE(joh): Calls the host function the immediate indexes in the host imports, see `.host`
+    pub const CallHost: u8 = 0x65;
+    //NOTE(joh): Pop a size and move the stack pointer of the memory stack down or up by it,
+    //rounded up to 8 bytes. `push_mem` pushes the new stack pointer, the address of the reserved
+    //bytes. Leaving the reserved region traps instead of running into the data or the heap.
+    pub const PushMem: u8 = 0x66;
+    pub const PopMem: u8 = 0x67;
+    //NOTE(joh): Reverse the byte order, for big endian data. `bswap_16` swaps the low two bytes
+    //and clears the rest, e.g. after a `load_16_u`.
+    pub const Bswap: u8 = 0x68;
+    pub const Bswap16: u8 = 0x69;
+    //NOTE(joh): Like the loads and stores, but in the memory the immediate indexes. 0 is the
+    //memory of the program, the others are created by the host, see `Interpreter::add_memory`.
+    pub const LoadMem8u: u8 = 0x6a;
+    pub const LoadMem32: u8 = 0x6b;
+    pub const StoreMem8: u8 = 0x6c;
+    pub const StoreMem32: u8 = 0x6d;
+    //NOTE(joh): Atomic accesses to aligned words of the shared memory the immediate indexes, see
+    //`Interpreter::add_shared_memory`. `atomic_add` and `cmpxchg` push the old value.
+    pub const AtomicLoad: u8 = 0x6e;
+    pub const AtomicStore: u8 = 0x6f;
+    pub const AtomicAdd: u8 = 0x70;
+    pub const Cmpxchg: u8 = 0x71;
+    //NOTE(joh): The same on the memory of the program, with an offset immediate like the loads and
+    //stores. One instance runs on one thread, so they only differ from `load_32_u` and `store_32`
+    //in trapping on addresses that are not a multiple of 4, like wasm atomics do.
+    pub const AtomicLoad32: u8 = 0x72;
+    pub const AtomicStore32: u8 = 0x73;
+    pub const AtomicRmwAdd: u8 = 0x74;
+    pub const AtomicCmpxchg: u8 = 0x75;
+    pub const MulWrap: u8 = 0x76;
+    pub const MulWrap64: u8 = 0x77;
+
+    pub const Names: [&str; MulWrap64 as usize + 1] = [
         "dbg_halt",
         "nop", 
         "unreachable", 
@@ -157,6 +486,79 @@ pub mod opcode {
         "push_arg",
         "dbg_assert",
         "syscall",
+        "const_pool",
+        "br_table",
+        "const_64",
+        "add_64",
+        "sub_64",
+        "mul_64",
+        "div_s_64",
+        "div_u_64",
+        "eq_64",
+        "lt_s_64",
+        "lt_u_64",
+        "gt_s_64",
+        "gt_u_64",
+        "and_64",
+        "or_64",
+        "xor_64",
+        "shift_l_64",
+        "shift_r_64",
+        "extend_u_32",
+        "extend_s_32",
+        "wrap_64",
+        "load_64",
+        "store_64",
+        "f_const",
+        "f_add",
+        "f_sub",
+        "f_mul",
+        "f_div",
+        "f_lt",
+        "f_gt",
+        "f_from_i32",
+        "i32_from_f",
+        "shift_r_s",
+        "extend_8_32_s",
+        "extend_8_32_u",
+        "extend_16_32_s",
+        "extend_16_32_u",
+        "call_if",
+        "call_indirect",
+        "mem_size",
+        "mem_grow",
+        "rem_s",
+        "rem_u",
+        "rot_l",
+        "rot_r",
+        "clz",
+        "ctz",
+        "popcnt",
+        "dup",
+        "swap",
+        "select",
+        "mem_copy",
+        "mem_fill",
+        "yield",
+        "call_host",
+        "push_mem",
+        "pop_mem",
+        "bswap",
+        "bswap_16",
+        "load_mem_8_u",
+        "load_mem_32",
+        "store_mem_8",
+        "store_mem_32",
+        "atomic_load",
+        "atomic_store",
+        "atomic_add",
+        "cmpxchg",
+        "atomic_load_32",
+        "atomic_store_32",
+        "atomic_rmw_add",
+        "atomic_cmpxchg",
+        "mul_wrap",
+        "mul_wrap_64",
     ];
 
     pub struct StoreArgs {
@@ -170,18 +572,33 @@ macro_rules! op_name {
     };
 }
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RawArg {
     Register(u8),
     Num(u32),
+    Wide(u64),
+    Table { targets: Box<[u32]>, default: u32 },
 }
 impl RawArg {
     pub fn from_arg_type(arg: &ArgType<'_>, parser: &mut Parser) -> Result<Self, AssembleError> {
         match arg {
             ArgType::AbsLabelRef(l) => Ok(RawArg::Num(parser.get_abs_label_addr(l)? as u32)),
             ArgType::OffLabelRef(l) => Ok(RawArg::Num(parser.get_off_label_addr(l)? as u32)),
+            ArgType::Expr(expr) => Ok(RawArg::Num(parser.eval_expr(expr)? as u32)),
             ArgType::Number(n) => Ok(RawArg::Num(*n as u32)),
             ArgType::Register(r) => Ok(RawArg::Register(*r)),
-            ArgType::String((_, n)) => Ok(RawArg::Num(*n + DATA_START + parser.op_size_bytes as u32)) 
+            ArgType::Wide(n) => Ok(RawArg::Wide(*n)),
+            ArgType::Float(n) => Ok(RawArg::Num(n.to_bits())),
+            ArgType::String((_, n)) => Ok(RawArg::Num(*n + parser.get_data_start_addr())),
+            ArgType::Table { targets, default } => {
+                let mut resolve = |arg: &ArgType<'_>| match RawArg::from_arg_type(arg, parser)? {
+                    RawArg::Num(n) => Ok(n),
+                    _ => Err(AssembleError::new(parser, AssembleErrorKind::UnexpectedImmArgSize)),
+                };
+                let targets = targets.iter().map(&mut resolve).collect::<Result<_, _>>()?;
+                let default = resolve(default)?;
+                Ok(RawArg::Table { targets, default })
+            }
         }
     }
 
@@ -189,20 +606,40 @@ impl RawArg {
         match self {
             RawArg::Register(_) => size_of::<u8>(),
             RawArg::Num(_) => size_of::<u32>(),
+            RawArg::Wide(_) => size_of::<u64>(),
+            RawArg::Table { targets, .. } => table_size_bytes(targets.len()),
         }
     }
     pub fn encode(&self, buffer: &mut Vec<u8>) {
         match self {
             Self::Register(r) => buffer.push(*r),
-            Self::Num(n) => buffer.extend_from_slice(&n.to_le_bytes()),
+            Self::Num(n) => push_le(buffer, *n),
+            Self::Wide(n) => push_le(buffer, *n),
+            Self::Table { targets, default } => {
+                push_le(buffer, targets.len() as u32);
+                targets.iter().for_each(|t| push_le(buffer, *t));
+                push_le(buffer, *default);
+            }
         }
     }
 }
+
+//NOTE(joh): A table is encoded as the target count, the targets and the default target
+pub const fn table_size_bytes(target_count: usize) -> usize {
+    (target_count + 2) * size_of::<u32>()
+}
 impl Display for RawArg {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             RawArg::Register(r) => write!(f, "r{r}"),
             RawArg::Num(n) => write!(f, "0x{:04x}", n),
+            RawArg::Wide(n) => write!(f, "0x{:08x}", n),
+            RawArg::Table { targets, default } => {
+                for target in targets {
+                    write!(f, "0x{:04x} ", target)?;
+                }
+                write!(f, "default 0x{:04x}", default)
+            }
         }
     }
 } 
@@ -217,6 +654,7 @@ impl Op<'_> {
     }
 }
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RawOp {
     pub opcode: u8,
     pub arg: Option<RawArg>,
@@ -249,23 +687,48 @@ impl RawOp {
     }
 }
 
+//NOTE(joh): What `parse_ops` emits. Padding stays a byte count until the code is encoded.
+#[derive(PartialEq, Debug, Clone)]
+pub enum CodeElem {
+    Op(RawOp),
+    Padding(u32),
+}
+impl CodeElem {
+    pub fn encode(&self, dest: &mut Vec<u8>) {
+        match self {
+            CodeElem::Op(op) => op.encode(dest),
+            CodeElem::Padding(len) => dest.resize(dest.len() + *len as usize, opcode::Nop),
+        }
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        match self {
+            CodeElem::Op(op) => op.size_bytes(),
+            CodeElem::Padding(len) => *len as usize,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Label<'src> {
     pub name: &'src str,
     pub position: usize,
 }
 
-impl<'src> std::fmt::Display for Label<'src> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<'src> core::fmt::Display for Label<'src> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}: {:#05x}", self.name, self.position)
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BytecodeInfo {
     pub code_size_bytes: u32,
     pub instruction_count: u32,
     pub code_start_offset: u32,
-    pub lit_data_section_size: u32,
+    //NOTE(joh): String literals and `.data` declarations, placed right after the code
+    pub data_section_size: u32,
 }
 
 impl BytecodeInfo {
@@ -274,18 +737,23 @@ impl BytecodeInfo {
         4 * size_of::<u32>() + size_of_val(&BYTECODE_HEADER)
     }
     pub fn total_size(&self) -> usize {
-        self.lit_data_section_size as usize + self.code_size_bytes as usize + Self::total_header_size()
+        self.data_section_size as usize + self.code_size_bytes as usize + Self::total_header_size()
+    }
+
+    //NOTE(joh): Where the data section ends up in memory once the bytecode is loaded
+    pub fn data_start_addr(&self) -> u32 {
+        DATA_START + self.code_size_bytes
     }
 
-    fn to_bytecode(&self) -> Vec<u8> {
+    pub fn to_bytecode(&self) -> Vec<u8> {
         let mut buffer = Vec::with_capacity(self.total_size());
         
         buffer.extend_from_slice(&BYTECODE_HEADER);
 
-        buffer.extend_from_slice(&(self.code_size_bytes).to_le_bytes());
-        buffer.extend_from_slice(&(self.instruction_count).to_le_bytes());
-        buffer.extend_from_slice(&(self.code_start_offset).to_le_bytes());
-        buffer.extend_from_slice(&(self.lit_data_section_size).to_le_bytes()); 
+        push_le(&mut buffer, self.code_size_bytes);
+        push_le(&mut buffer, self.instruction_count);
+        push_le(&mut buffer, self.code_start_offset);
+        push_le(&mut buffer, self.data_section_size);
 
         
         buffer
@@ -295,19 +763,14 @@ impl BytecodeInfo {
 macro_rules! impl_parse_num {
     ($fn_name: ident, $type: ty) => {
         pub fn $fn_name(&self, str: &str) -> Result<$type, AssembleError> {
-            if str.len() == 1 {
-                Ok(<$type>::from_str_radix(str, 10)
-                    .map_err(|e| AssembleError::new(self, e.into()))?)
+            let result = if let Some(hex) = str.strip_prefix("0x") {
+                <$type>::from_str_radix(hex, 16)
+            } else if let Some(bin) = str.strip_prefix("0b") {
+                <$type>::from_str_radix(bin, 2)
             } else {
-                match &str[0..2] {
-                    "0x" => Ok(<$type>::from_str_radix(&str[2..], 16)
-                        .map_err(|e| AssembleError::new(self, e.into()))?),
-                    "0b" => Ok(<$type>::from_str_radix(&str[2..], 2)
-                        .map_err(|e| AssembleError::new(self, e.into()))?),
-                    _ => Ok(<$type>::from_str_radix(str, 10)
-                        .map_err(|e| AssembleError::new(self, e.into()))?),
-                }
-            }
+                str.parse::<$type>()
+            };
+            result.map_err(|e| AssembleError::new(self, e.into()))
         }
     };
 }
@@ -315,160 +778,1509 @@ macro_rules! impl_parse_num {
 #[derive(Debug, Eq, PartialEq, PartialOrd, Clone, Copy)]
 pub struct LabelId(usize);
 
+#[derive(Debug, Clone, Copy)]
+enum Padding {
+    Align(u32),
+    Bytes(u32),
+}
+
+//NOTE(joh): Label and string addresses are only known once all ops are parsed, so the
+//pool stores what a constant refers to and resolves the values in `parse_ops`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstPoolKey {
+    Number(u32),
+    AbsLabel(String),
+    String(u32),
+}
+
 impl Display for LabelId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+//NOTE(joh): Line, line start and span of a statement
+type SourceLocation = (usize, usize, Range<usize>);
+
+//NOTE(joh): Parameters are substituted as whole words when the macro is expanded
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: String,
+}
+
+#[derive(Clone)]
 pub struct Parser {
     op_count: usize,
     op_size_bytes: usize,
     line: usize,
+    //NOTE(joh): Byte offsets into the source, only used for error reporting
+    source_len: usize,
+    line_start: usize,
+    span: Range<usize>,
+    //NOTE(joh): line, line_start and span of each elem, so errors found in `parse_ops` point at the right place
+    elem_locations: Vec<SourceLocation>,
     labels: HashMap<String, u32>,
+    //NOTE(joh): Positions of the numeric labels like `:1:`, in the order they are defined. They
+    //may be defined any number of times and are referenced with `@1f` and `@1b`.
+    local_labels: HashMap<String, Vec<u32>>,
+    //NOTE(joh): Offset of the op whose argument is resolved, local label references are relative to it
+    op_offset: u32,
     string_literals: HashMap<String, u32>, 
     data: Vec<u8>,
+    //NOTE(joh): Offsets into `data`. Label references inside `.data` words are patched in `parse_ops`
+    data_labels: HashMap<String, u32>,
+    data_relocs: Vec<(usize, String)>,
+    //NOTE(joh): Size in bytes of each `.data` and `.string` entry, referenced as `@name.len`
+    data_lens: HashMap<String, u32>,
+    //NOTE(joh): Set by `.align` and `.pad`, applied to the next op, label or data entry
+    padding: Option<Padding>,
+    //NOTE(joh): Largest alignment of a data entry, the code is padded so the data starts aligned to it
+    data_align: u32,
+    use_const_pool: bool,
+    const_pool: Vec<(ConstPoolKey, u32)>,
+    line_table: Vec<LineEntry>,
+    emit_debug_info: bool,
+    emit_symbols: bool,
+    origin: u32,
+    external_labels: HashMap<String, u32>,
+    //NOTE(joh): `%define` constants and `%macro` bodies, expanded before `parse_elems`
+    defines: HashMap<String, String>,
+    macros: HashMap<String, Macro>,
+    //NOTE(joh): Index 0 is the main source, the rest are `%include`d files. `file` is the
+    //one currently being expanded.
+    source_files: Vec<Option<PathBuf>>,
+    file: usize,
+    include_dirs: Vec<PathBuf>,
+    include_stack: Vec<PathBuf>,
+    //NOTE(joh): File, line and line start of each line of the expanded source
+    line_map: Vec<(usize, usize, usize)>,
+    //NOTE(joh): Arity of `.func` declarations. Calls of a constant target are checked against
+    //them in `parse_ops`, once all functions are known.
+    functions: Vec<(String, u8, Option<u8>)>,
+    pushed_args: Option<u8>,
+    call_target: Option<String>,
+    call_sites: Vec<(String, u8, SourceLocation)>,
+    //NOTE(joh): `.global` declarations in order, the position is the index of the global. The
+    //values may refer to labels, so they are evaluated in `parse_ops`.
+    globals: Vec<(String, String, SourceLocation)>,
+    global_inits: Vec<u32>,
+    //NOTE(joh): `.local` names by slot, each `.func` starts a new scope at its position. Names
+    //are only looked up in the last scope.
+    local_scopes: Vec<(u32, Vec<String>)>,
+    //NOTE(joh): `.export` declarations, resolved to their addresses in `parse_ops`
+    exports: Vec<(String, SourceLocation)>,
+    export_table: Vec<(String, u32)>,
+    //NOTE(joh): `.import` declarations. Once an object imports anything, every symbol it takes from
+    //other objects has to be imported.
+    imports: Vec<String>,
+    //NOTE(joh): `.host` declarations, the position is the index `call_host` refers to
+    host_imports: Vec<HostImport>,
+    //NOTE(joh): Only set when assembling an object. Absolute addresses are recorded here so the
+    //linker can move them, unknown labels are left to the linker instead of being an error.
+    relocations: Option<Vec<Relocation>>,
+    //NOTE(joh): Ops written in the source are counted so a second pass can leave out the
+    //unreachable ones, see `set_strip_dead_code`. `op_sources` has the count of every op that
+    //ends up in the code, `None` for padding.
+    strip_dead_code: bool,
+    strip_ops: BTreeSet<usize>,
+    source_ops: usize,
+    op_sources: Vec<Option<usize>>,
+    label_sites: Vec<(String, SourceLocation)>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParseResult {
     pub code: Box<[u8]>, 
     pub labels: Box<[(String, u32)]>,
+    pub debug_info: DebugInfo,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub warnings: Vec<AssembleWarning>,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<'src> Parser {
+impl Parser {
     impl_parse_num!(parse_u32, u32);
     impl_parse_num!(parse_i32, i32);
     impl_parse_num!(parse_u8, u8);
+    impl_parse_num!(parse_i64, i64);
+    impl_parse_num!(parse_u64, u64);
 
     pub fn new() -> Self {
         Self {
             line: 0,
+            source_len: 0,
+            line_start: 0,
+            span: 0..0,
+            elem_locations: Vec::new(),
             op_count: 0,
             op_size_bytes: 0,
             labels: HashMap::new(),
+            local_labels: HashMap::new(),
+            op_offset: 0,
             string_literals: HashMap::new(),
-            data: Vec::new() 
+            data: Vec::new(),
+            data_labels: HashMap::new(),
+            data_relocs: Vec::new(),
+            data_lens: HashMap::new(),
+            padding: None,
+            data_align: 1,
+            use_const_pool: false,
+            const_pool: Vec::new(),
+            line_table: Vec::new(),
+            emit_debug_info: false,
+            emit_symbols: false,
+            origin: DATA_START,
+            external_labels: HashMap::new(),
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            source_files: vec![None],
+            file: 0,
+            include_dirs: Vec::new(),
+            include_stack: Vec::new(),
+            line_map: Vec::new(),
+            functions: Vec::new(),
+            pushed_args: Some(0),
+            call_target: None,
+            call_sites: Vec::new(),
+            globals: Vec::new(),
+            global_inits: Vec::new(),
+            exports: Vec::new(),
+            export_table: Vec::new(),
+            imports: Vec::new(),
+            host_imports: Vec::new(),
+            local_scopes: Vec::new(),
+            relocations: None,
+            strip_dead_code: false,
+            strip_ops: BTreeSet::new(),
+            source_ops: 0,
+            op_sources: Vec::new(),
+            label_sites: Vec::new(),
+        }
+    }
+
+    //NOTE(joh): Assembles code that will be placed at `origin` inside an already loaded
+    //program. Labels not defined in the code are looked up in `external_labels` (absolute
+    //addresses). Only the code and data after the header are meaningful in the output.
+    pub fn with_origin(origin: u32, external_labels: HashMap<String, u32>) -> Self {
+        Self {
+            origin,
+            external_labels,
+            ..Self::new()
+        }
+    }
+
+    //NOTE(joh): Replaces the 32 bit immediates of constants with indices into a constant pool
+    //appended to the bytecode
+    pub fn with_const_pool() -> Self {
+        Self {
+            use_const_pool: true,
+            ..Self::new()
         }
     }
 
-    pub fn parse(code: &'src str) -> Result<ParseResult, AssembleError> {
-        let mut parser = Self::new();
+    //NOTE(joh): Path of the assembled code, `%include`s are resolved relative to it
+    pub fn set_source_path(&mut self, path: impl Into<PathBuf>) {
+        self.source_files[0] = Some(path.into());
+    }
+
+    //NOTE(joh): Appends the line table to the bytecode, see `debuginfo`
+    pub fn set_emit_debug_info(&mut self, emit: bool) {
+        self.emit_debug_info = emit;
+    }
+
+    //NOTE(joh): Appends the label names to the bytecode, see `symbols`
+    pub fn set_emit_symbols(&mut self, emit: bool) {
+        self.emit_symbols = emit;
+    }
+
+    //NOTE(joh): Leaves the ops out that `AssembleWarningKind::UnreachableCode` warns about. The
+    //code is assembled twice, the second time without them. Code only reached from a label that
+    //is only referenced by unreachable code stays in.
+    pub fn set_strip_dead_code(&mut self, strip: bool) {
+        self.strip_dead_code = strip;
+    }
+
+    //NOTE(joh): Searched in order when an include is not found next to the including file
+    pub fn add_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_dirs.push(dir.into());
+    }
+
+    pub fn parse(code: &str) -> Result<ParseResult, AssembleError> {
+        Self::new().assemble(code)
+    }
 
-        let elems = parser.parse_elems(code)?;
+    pub fn parse_with_const_pool(code: &str) -> Result<ParseResult, AssembleError> {
+        Self::with_const_pool().assemble(code)
+    }
+
+    //NOTE(joh): Assembles a relocatable object for `link::link` instead of a flat image. The
+    //const pool is not supported, its indices could not be merged.
+    pub fn assemble_object(mut self, code: &str) -> Result<Object, AssembleError> {
+        self.relocations = Some(Vec::new());
+        self.use_const_pool = false;
+
+        let code = self.expand_macros(code)?;
+        let code = self.lower_control_flow(&code)?;
+        let elems = self.parse_elems(&code)?;
+        //NOTE(joh): Global indices are absolute, they could not be merged when linking
+        if let Some((_, _, location)) = self.globals.first() {
+            (self.line, self.line_start, self.span) = location.clone();
+            return Err(AssembleError::new(&self, AssembleErrorKind::GlobalInObject));
+        }
+        //NOTE(joh): Same for the indices of host imports
+        if !self.host_imports.is_empty() {
+            return Err(AssembleError::new(&self, AssembleErrorKind::HostFnInObject));
+        }
+        let ops = self.parse_ops(&elems)?;
+
+        let mut code = Vec::with_capacity(self.op_size_bytes);
+        ops.iter().for_each(|o| o.encode(&mut code));
+
+        let exported = |name: &String| {
+            name == ENTRY_LABEL_NAME
+                || self.functions.iter().any(|(f, ..)| f == name)
+                || self.export_table.iter().any(|(e, _)| e == name)
+        };
+        let mut symbols = self.labels
+            .iter()
+            .filter(|(name, _)| exported(name))
+            .map(|(name, offset)| Symbol { name: name.clone(), section: Section::Code, offset: *offset })
+            .chain(self.data_labels.iter().map(|(name, offset)| Symbol {
+                name: name.clone(),
+                section: Section::Data,
+                offset: *offset,
+            }))
+            .collect::<Vec<_>>();
+        symbols.sort_by(|a, b| (a.section as u8, a.offset, &a.name).cmp(&(b.section as u8, b.offset, &b.name)));
+
+        Ok(Object {
+            code,
+            data: core::mem::take(&mut self.data),
+            instruction_count: self.op_count as u32,
+            symbols,
+            functions: core::mem::take(&mut self.functions),
+            relocations: self.relocations.take().unwrap_or_default(),
+            exports: self.export_table.drain(..).map(|(name, _)| name).collect(),
+            imports: core::mem::take(&mut self.imports),
+        })
+    }
+
+    pub fn assemble(self, code: &str) -> Result<ParseResult, AssembleError> {
+        let stripped = self.strip_dead_code.then(|| self.clone());
+        let (mut result, dead_ops) = self.assemble_once(code)?;
+        if let Some(mut parser) = stripped
+            && !dead_ops.is_empty()
+        {
+            parser.strip_ops = dead_ops;
+            let warnings = core::mem::take(&mut result.warnings);
+            result = parser.assemble_once(code)?.0;
+            result.warnings = warnings;
+        }
+        Ok(result)
+    }
+
+    //NOTE(joh): Also returns the indices of the unreachable ops written in the source
+    fn assemble_once(mut self, code: &str) -> Result<(ParseResult, BTreeSet<usize>), AssembleError> {
+        let parser = &mut self;
+
+        let code = parser.expand_macros(code)?;
+        let code = parser.lower_control_flow(&code)?;
+        let elems = parser.parse_elems(&code)?;
+        let referenced = parser.referenced_labels(&elems);
+        let locations = parser.elem_locations.clone();
         let ops = parser.parse_ops(&elems)?;
+        let (warnings, dead_ops) = parser.find_dead_code(&elems, &ops, &locations, &referenced);
         let mut labels: Vec<(String, u32)> = parser.labels.iter()
             .map(|(k, v)| (k.to_string(), *v))
             .collect::<Vec<_>>();
 
-        labels.sort_by(|(_, v1), (_, v2)| v1.cmp(v2)); 
+        labels.sort_by_key(|(_, v1)| *v1); 
+
+        let code_start = parser.get_code_start_addr();
+        let data_start = parser.get_data_start_addr();
+        let mut data_labels = parser.data_labels.iter()
+            .map(|(name, offset)| (name.clone(), offset + data_start))
+            .collect::<Vec<_>>();
+        data_labels.sort_by_key(|(_, addr)| *addr);
+
+        let debug_info = DebugInfo {
+            file: parser.source_file_name(0),
+            includes: parser.source_files[1..]
+                .iter()
+                .filter_map(|f| f.as_deref().map(path_name))
+                .collect(),
+            lines: core::mem::take(&mut parser.line_table),
+            labels: labels.iter().map(|(name, pos)| (name.clone(), pos + code_start)).collect(),
+            data_labels,
+            code_end: code_start + parser.op_size_bytes as u32,
+            globals: parser.globals.iter().map(|(name, _, _)| name.clone()).collect(),
+            locals: core::mem::take(&mut parser.local_scopes)
+                .into_iter()
+                .map(|(pos, names)| (pos + code_start, names))
+                .collect(),
+        };
             
+        let mut code = parser.as_bytecode(&ops);
+        if parser.emit_symbols || parser.emit_debug_info {
+            let mut buffer = code.into_vec();
+            if parser.emit_symbols {
+                let symbols = SymbolTable {
+                    labels: debug_info.labels.clone(),
+                    data_labels: debug_info.data_labels.clone(),
+                };
+                encode_symbol_table(&symbols, &mut buffer);
+            }
+            if parser.emit_debug_info {
+                encode_debug_info(&debug_info, &mut buffer);
+            }
+            code = buffer.into_boxed_slice();
+        }
         let res = ParseResult {
-            code: parser.as_bytecode(&ops),
+            code,
             labels: labels.into_boxed_slice(),
+            debug_info,
+            warnings,
         };
-        Ok(res)
+        Ok((res, dead_ops))
+    }
+
+    //NOTE(joh): Names referenced by ops, `.data` words, globals and exports, before `parse_ops`
+    //consumes the `.data` references
+    fn referenced_labels(&self, elems: &[Elem<'_>]) -> BTreeSet<String> {
+        fn collect(arg: &ArgType<'_>, names: &mut BTreeSet<String>) {
+            match arg {
+                ArgType::AbsLabelRef(name) | ArgType::OffLabelRef(name) => _ = names.insert(name.to_string()),
+                ArgType::Expr(expr) => names.extend(expr::labels(expr).into_iter().map(str::to_string)),
+                ArgType::Table { targets, default } => {
+                    targets.iter().chain([&**default]).for_each(|target| collect(target, names))
+                }
+                _ => {}
+            }
+        }
+        let mut names = BTreeSet::new();
+        for elem in elems {
+            match elem {
+                Elem::Op(Op { arg: Some(arg), .. }) | Elem::Const(arg) => collect(arg, &mut names),
+                _ => {}
+            }
+        }
+        names.extend(self.data_relocs.iter().map(|(_, name)| name.clone()));
+        for (_, value, _) in &self.globals {
+            names.extend(expr::labels(value).into_iter().map(str::to_string));
+        }
+        names.extend(self.exports.iter().map(|(name, _)| name.clone()));
+        names
+    }
+
+    //NOTE(joh): Builds the control flow graph of the assembled ops. Computed jumps are only
+    //known to go to referenced labels, so every referenced label is treated as reachable.
+    fn find_dead_code(
+        &mut self,
+        elems: &[Elem<'_>],
+        ops: &[CodeElem],
+        locations: &[SourceLocation],
+        referenced: &BTreeSet<String>,
+    ) -> (Vec<AssembleWarning>, BTreeSet<usize>) {
+        let code_start = self.get_code_start_addr();
+        let mut code = Code { ops: Vec::with_capacity(ops.len()), index: HashMap::new() };
+        let mut addr = code_start;
+        for op in ops {
+            code.index.insert(addr, code.ops.len());
+            //NOTE(joh): Padding only falls through, a single `nop` stands in for it
+            let raw = match op {
+                CodeElem::Op(op) => op.clone(),
+                CodeElem::Padding(_) => RawOp { opcode: opcode::Nop, arg: None },
+            };
+            code.ops.push((addr, raw));
+            addr += op.size_bytes() as u32;
+        }
+        let const_pool = self.const_pool.iter().map(|(_, value)| *value).collect::<Vec<_>>();
+
+        let mut roots = vec![self.get_bytecode_info().code_start_offset];
+        roots.extend(self.function_table().iter().map(|f| f.addr));
+        for name in referenced {
+            let positions = match local_label_ref(name) {
+                Some((local, _)) => self.local_labels.get(local).map_or(&[][..], Vec::as_slice),
+                None => self.labels.get(name).map_or(&[][..], core::slice::from_ref),
+            };
+            roots.extend(positions.iter().map(|pos| pos + code_start));
+        }
+        let mut reached = vec![false; code.ops.len()];
+        let mut pending = roots;
+        while let Some(addr) = pending.pop() {
+            let Some(&i) = code.index.get(&addr) else {
+                continue;
+            };
+            if core::mem::replace(&mut reached[i], true) {
+                continue;
+            }
+            pending.extend(op_targets(&code, i, &const_pool));
+            if !ends_flow(code.ops[i].1.opcode)
+                && let Some((next, _)) = code.ops.get(i + 1)
+            {
+                pending.push(*next);
+            }
+        }
+
+        let mut warnings = Vec::new();
+        let mut dead_ops = BTreeSet::new();
+        let mut run: Option<(usize, usize)> = None;
+        let mut op = 0;
+        for (e, elem) in elems.iter().enumerate() {
+            if matches!(elem, Elem::Label(_)) {
+                continue;
+            }
+            let source = self.op_sources.get(op).copied().flatten();
+            let dead = source.filter(|_| !reached[op]);
+            op += 1;
+            match (dead, &mut run) {
+                (Some(source), Some((_, count))) => {
+                    dead_ops.insert(source);
+                    *count += 1;
+                }
+                (Some(source), None) => {
+                    dead_ops.insert(source);
+                    run = Some((e, 1));
+                }
+                //NOTE(joh): Padding doesn't end a run
+                (None, _) if source.is_none() => {}
+                (None, _) => self.warn_unreachable(&mut warnings, run.take(), locations),
+            }
+        }
+        self.warn_unreachable(&mut warnings, run, locations);
+
+        let functions = self.function_table();
+        for (name, location) in core::mem::take(&mut self.label_sites) {
+            if referenced.contains(&name)
+                || name == ENTRY_LABEL_NAME
+                || name.starts_with(TEST_PREFIX)
+                || name.starts_with(flow::LABEL_PREFIX)
+                || functions.iter().any(|f| f.name == name)
+            {
+                continue;
+            }
+            (self.line, self.line_start, self.span) = location;
+            warnings.push(AssembleWarning::new(self, AssembleWarningKind::UnusedLabel(name)));
+        }
+        warnings.sort_by_key(|w| (w.file.clone(), w.span.start));
+        (warnings, dead_ops)
+    }
+
+    fn warn_unreachable(
+        &mut self,
+        warnings: &mut Vec<AssembleWarning>,
+        run: Option<(usize, usize)>,
+        locations: &[SourceLocation],
+    ) {
+        if let Some((elem, ops)) = run
+            && let Some(location) = locations.get(elem)
+        {
+            (self.line, self.line_start, self.span) = location.clone();
+            warnings.push(AssembleWarning::new(self, AssembleWarningKind::UnreachableCode { ops }));
+        }
     }
 
     pub fn try_push_label(&mut self, name: &str, position: u32) -> Result<LabelId, AssembleError> {
-        match self.labels.get(name) {
+        let id = LabelId(self.labels.len() + self.local_labels.values().map(Vec::len).sum::<usize>());
+        if is_local_label(name) {
+            self.local_labels.entry(name.to_string()).or_default().push(position);
+            return Ok(id);
+        }
+        match self.labels.get(name).or(self.data_labels.get(name)) {
             Some(_) => Err(AssembleError::new(
                 self,
                 AssembleErrorKind::LabelAlreadyExists(name.to_string()),
             )),
             None => {
-                let id = self.labels.len();
                 _ = self.labels.insert(name.to_string(), position);
 
-                Ok(LabelId(id))
+                Ok(id)
+            }
+        }
+    }
+
+    pub fn get_string_literal_addr(&mut self, str: &str) -> u32 {
+        match self.string_literals.get(str) {
+            Some(addr) => *addr,
+            None => self.push_string_literal(str)
+        }
+    }
+
+    pub fn const_pool_index(&mut self, arg: &ArgType<'_>) -> Option<u8> {
+        let key = match arg {
+            ArgType::Number(n) => ConstPoolKey::Number(*n as u32),
+            //NOTE(joh): Local labels depend on the position of the op as well
+            ArgType::AbsLabelRef(l) if local_label_ref(l).is_some() => return None,
+            ArgType::AbsLabelRef(l) => ConstPoolKey::AbsLabel(l.to_string()),
+            ArgType::String((_, offset)) => ConstPoolKey::String(*offset),
+            //NOTE(joh): Offset labels depend on the position of the op
+            ArgType::OffLabelRef(_) | ArgType::Expr(_) | ArgType::Register(_) | ArgType::Wide(_) | ArgType::Float(_) | ArgType::Table { .. } => {
+                return None;
+            }
+        };
+        let index = match self.const_pool.iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None if self.const_pool.len() < MAX_CONST_POOL_ENTRIES => {
+                self.const_pool.push((key, 0));
+                self.const_pool.len() - 1
+            }
+            None => return None,
+        };
+        Some(index as u8)
+    }
+
+    //NOTE(joh): Turns a constant into a `const_pool` op if the pool is enabled and has room left
+    fn pool_const<'src>(&mut self, arg: &ArgType<'src>) -> Option<Op<'src>> {
+        if !self.use_const_pool {
+            return None;
+        }
+        let index = self.const_pool_index(arg)?;
+        Some(Op {
+            opcode: opcode::ConstPool,
+            arg: Some(ArgType::Register(index)),
+        })
+    }
+
+    fn resolve_const_pool(&mut self) -> Result<(), AssembleError> {
+        for i in 0..self.const_pool.len() {
+            let value = match &self.const_pool[i].0 {
+                ConstPoolKey::Number(n) => *n,
+                ConstPoolKey::AbsLabel(l) => self.get_abs_label_addr(l)? as u32,
+                ConstPoolKey::String(offset) => *offset + self.get_data_start_addr(),
+            };
+            self.const_pool[i].1 = value;
+        }
+        Ok(())
+    }
+
+    pub fn push_string_literal(&mut self, str: &str) -> u32 {
+        let offset = self.data.len();
+
+        push_le(&mut self.data, str.len() as u32);
+        self.data.extend_from_slice(str.as_bytes());
+        self.string_literals.insert(str.to_string(), offset as u32);
+        
+        offset as u32
+    }
+
+    pub fn try_push_data_label(&mut self, name: &str) -> Result<(), AssembleError> {
+        if self.labels.contains_key(name) || self.data_labels.contains_key(name) {
+            return Err(AssembleError::new(
+                self,
+                AssembleErrorKind::LabelAlreadyExists(name.to_string()),
+            ));
+        }
+        self.pad_data()?;
+        self.data_labels.insert(name.to_string(), self.data.len() as u32);
+        Ok(())
+    }
+
+    //NOTE(joh): Data offsets are aligned, the start of the data is aligned once all code is known
+    fn pad_data(&mut self) -> Result<(), AssembleError> {
+        let len = match self.padding.take() {
+            Some(Padding::Align(align)) => {
+                self.data_align = self.data_align.max(align);
+                self.data.len().next_multiple_of(align as usize) - self.data.len()
+            }
+            Some(Padding::Bytes(len)) => len as usize,
+            None => return Ok(()),
+        };
+        self.check_fits(len)?;
+        self.data.resize(self.data.len() + len, 0);
+        Ok(())
+    }
+
+    //NOTE(joh): Code is padded with `nop`s, alignment is relative to the absolute address. The
+    //padding is a single elem, it is only expanded into bytes when the code is encoded.
+    fn pad_code<'src>(&mut self, elems: &mut Vec<Elem<'src>>, padding: Padding) -> Result<(), AssembleError> {
+        let addr = self.get_code_start_addr() as usize + self.op_size_bytes;
+        let len = match padding {
+            Padding::Align(align) => addr.next_multiple_of(align as usize) - addr,
+            Padding::Bytes(len) => len as usize,
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        self.check_fits(len)?;
+        elems.push(Elem::Padding(len as u32));
+        self.op_sources.push(None);
+        self.op_size_bytes += len;
+        self.op_count += len;
+        Ok(())
+    }
+
+    //NOTE(joh): `len` is added to what was emitted so far, code and data are loaded into the
+    //32 bit address space together
+    fn check_fits(&self, len: usize) -> Result<(), AssembleError> {
+        let end = self.get_code_start_addr() as u64 + self.op_size_bytes as u64 + self.data.len() as u64 + len as u64;
+        if end > u32::MAX as u64 {
+            return Err(AssembleError::new(self, AssembleErrorKind::TooLarge));
+        }
+        Ok(())
+    }
+
+    fn resolve_data_relocs(&mut self) -> Result<(), AssembleError> {
+        for (offset, label) in core::mem::take(&mut self.data_relocs) {
+            let addr = self.get_abs_label_addr(&label)? as u32;
+            self.record_relocation(self.op_size_bytes + offset, &ArgType::AbsLabelRef(&label));
+            self.data[offset..offset + size_of::<u32>()].copy_from_slice(&addr.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    pub fn parse_directive<'src>(&mut self, s: &'src str) -> Result<Option<&'src str>, AssembleError> {
+        //NOTE(joh): Strings may contain the statement separator, so they can't be sliced first
+        if let Some(rest) = s.strip_prefix("string")
+            && rest.starts_with(char::is_whitespace)
+        {
+            return self.parse_string_directive(rest);
+        }
+        let statement = self.slice_until(s, STATEMENT_SEP)?;
+        let (name, args) = next_word(statement.word)
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        match name {
+            "data" => self.parse_data(args)?,
+            "func" => self.parse_func(args)?,
+            "global" => self.parse_global(args)?,
+            "host" => self.parse_host(args)?,
+            "local" => self.parse_local(args)?,
+            "export" => {
+                let name = self.parse_name(args)?;
+                if !self.exports.iter().any(|(n, _)| *n == name) {
+                    let location = (self.line, self.line_start, self.span.clone());
+                    self.exports.push((name, location));
+                }
+            }
+            "import" => {
+                let name = self.parse_name(args)?;
+                if self.relocations.is_none() {
+                    return Err(AssembleError::new(self, AssembleErrorKind::ImportWithoutObject(name)));
+                }
+                if !self.imports.contains(&name) {
+                    self.imports.push(name);
+                }
+            }
+            "align" => {
+                let align = self.parse_u32(args.trim())?;
+                if !align.is_power_of_two() {
+                    return Err(AssembleError::new(self, AssembleErrorKind::InvalidAlignment(align)));
+                }
+                self.padding = Some(Padding::Align(align));
+            }
+            "pad" => self.padding = Some(Padding::Bytes(self.parse_u32(args.trim())?)),
+            _ => {
+                return Err(AssembleError::new(
+                    self,
+                    AssembleErrorKind::UnknownDirective(name.to_string()),
+                ));
+            }
+        }
+        Ok(statement.rest)
+    }
+
+    //NOTE(joh): `.string <name> "text\n";` places the UTF-8 bytes without a length prefix
+    fn parse_string_directive<'src>(&mut self, s: &'src str) -> Result<Option<&'src str>, AssembleError> {
+        let (name, rest) = next_word(s)
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        let rest = rest
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        let string = self.parse_escaped_string(rest)?;
+        let statement = self.slice_until(string.rest.unwrap_or_default(), STATEMENT_SEP)?;
+        if !statement.word.trim().is_empty() {
+            return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+        }
+
+        self.try_push_data_label(name)?;
+        self.data.extend_from_slice(string.word.as_bytes());
+        self.data_lens.insert(name.to_string(), string.word.len() as u32);
+        Ok(statement.rest)
+    }
+
+    //NOTE(joh): Expects the opening quote to be consumed already
+    pub fn parse_escaped_string<'src>(&self, s: &'src str) -> Result<ParseOutput<'src, String>, AssembleError> {
+        let mut string = String::new();
+        let mut chars = s.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    return Ok(ParseOutput {
+                        word: string,
+                        rest: s.get(i + 1..),
+                    });
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('0') => '\0',
+                        Some(c @ ('\\' | '"')) => c,
+                        Some(c) => return Err(AssembleError::new(self, AssembleErrorKind::UnknownEscape(c))),
+                        None => break,
+                    };
+                    string.push(escaped);
+                }
+                c => string.push(c),
+            }
+        }
+        Err(AssembleError::new(self, AssembleErrorKind::MissingDelimiter))
+    }
+
+    //NOTE(joh): `.func <name> <arity> [returns];` declares a label that is called with `arity`
+    //args and, if given, leaves exactly `returns` values on the stack
+    fn parse_func(&mut self, args: &str) -> Result<(), AssembleError> {
+        let mut args = iter_op_args(args);
+        let (Some(name), Some(arity)) = (args.next(), args.next()) else {
+            return Err(AssembleError::new(self, AssembleErrorKind::MissingArgument));
+        };
+        let arity = self.parse_u8(arity)?;
+        let returns = args.next().map(|r| self.parse_u8(r)).transpose()?;
+        if args.next().is_some() || arity as usize > MAX_ARGS || returns.is_some_and(|r| decode_returns(r).is_none()) {
+            return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+        }
+        self.try_push_label(name, self.op_size_bytes as u32)?;
+        self.functions.push((name.to_string(), arity, returns));
+        self.local_scopes.push((self.op_size_bytes as u32, Vec::new()));
+        self.pushed_args = None;
+        Ok(())
+    }
+
+    //NOTE(joh): `.global <name> = <value>;` declares the next global, it has to come before
+    //the ops using its name
+    fn parse_global(&mut self, args: &str) -> Result<(), AssembleError> {
+        let (name, value) = args
+            .split_once('=')
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        if self.globals.iter().any(|(n, _, _)| n == name) {
+            return Err(AssembleError::new(self, AssembleErrorKind::GlobalAlreadyDefined(name.to_string())));
+        }
+        if self.globals.len() >= STACK_POINTER_GLOBAL as usize {
+            return Err(AssembleError::new(self, AssembleErrorKind::UnexpectedRegisterId(self.globals.len() as i32)));
+        }
+        let location = (self.line, self.line_start, self.span.clone());
+        self.globals.push((name.to_string(), value.to_string(), location));
+        Ok(())
+    }
+
+    //NOTE(joh): `.host <name> <arity>;` imports a host function, it has to come before the
+    //`call_host` ops using its name
+    fn parse_host(&mut self, args: &str) -> Result<(), AssembleError> {
+        let mut args = iter_op_args(args);
+        let (Some(name), Some(arity)) = (args.next(), args.next()) else {
+            return Err(AssembleError::new(self, AssembleErrorKind::MissingArgument));
+        };
+        let arity = self.parse_u8(arity)?;
+        if args.next().is_some() || arity as usize > MAX_ARGS {
+            return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+        }
+        if self.host_imports.iter().any(|import| import.name == name) {
+            return Err(AssembleError::new(self, AssembleErrorKind::HostFnAlreadyDefined(name.to_string())));
+        }
+        if self.host_imports.len() > u8::MAX as usize {
+            return Err(AssembleError::new(self, AssembleErrorKind::UnexpectedRegisterId(self.host_imports.len() as i32)));
+        }
+        self.host_imports.push(HostImport { name: name.to_string(), arity });
+        Ok(())
+    }
+
+    fn resolve_globals(&mut self) -> Result<(), AssembleError> {
+        for i in 0..self.globals.len() {
+            let (_, value, location) = self.globals[i].clone();
+            (self.line, self.line_start, self.span) = location;
+            let value = self.eval_expr(&value)? as u32;
+            self.global_inits.push(value);
+        }
+        Ok(())
+    }
+
+    //NOTE(joh): `.local <name>;` names the next slot of the current function, the args of a
+    //`.func` come first. Locals before the first `.func` belong to the code outside of functions.
+    fn parse_local(&mut self, args: &str) -> Result<(), AssembleError> {
+        let mut args = iter_op_args(args);
+        let name = args
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        if args.next().is_some() {
+            return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+        }
+        if self.local_scopes.is_empty() {
+            self.local_scopes.push((0, Vec::new()));
+        }
+        let names = &self.local_scopes.last().unwrap().1;
+        if names.iter().any(|n| n == name) {
+            return Err(AssembleError::new(self, AssembleErrorKind::LocalAlreadyDefined(name.to_string())));
+        }
+        if names.len() >= MAX_LOCALS {
+            return Err(AssembleError::new(self, AssembleErrorKind::UnexpectedRegisterId(names.len() as i32)));
+        }
+        self.local_scopes.last_mut().unwrap().1.push(name.to_string());
+        Ok(())
+    }
+
+    //NOTE(joh): The single name of `.export` and `.import`
+    fn parse_name(&self, args: &str) -> Result<String, AssembleError> {
+        let mut args = iter_op_args(args);
+        let name = args
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        if args.next().is_some() {
+            return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+        }
+        Ok(name.to_string())
+    }
+
+    //NOTE(joh): Only code and data labels of this program can be exported, objects keep the name
+    //and let the linker place it
+    fn resolve_exports(&mut self) -> Result<(), AssembleError> {
+        for i in 0..self.exports.len() {
+            let (name, location) = self.exports[i].clone();
+            (self.line, self.line_start, self.span) = location;
+            if !self.labels.contains_key(&name) && !self.data_labels.contains_key(&name) {
+                return Err(AssembleError::new(self, AssembleErrorKind::UnknownLabel(name)));
+            }
+            let addr = match self.relocations {
+                Some(_) => 0,
+                None => self.get_abs_label_addr(&name)? as u32,
+            };
+            self.export_table.push((name, addr));
+        }
+        Ok(())
+    }
+
+    //NOTE(joh): Counts the args pushed since the last call. Control flow may enter at a label,
+    //so the count is unknown until the next call.
+    fn track_call_site(&mut self, opcode: u8, arg: Option<&ArgType<'_>>) {
+        match opcode {
+            opcode::PushArg => self.pushed_args = self.pushed_args.map(|n| n.saturating_add(1)),
+            opcode::Call | opcode::CallIf | opcode::CallIndirect => {
+                let target = match opcode {
+                    opcode::CallIndirect => self.call_target.take().and_then(|t| t.strip_suffix(".index").map(str::to_string)),
+                    _ => self.call_target.take().filter(|t| !t.ends_with(".index")),
+                };
+                if let (Some(target), Some(count)) = (target, self.pushed_args) {
+                    let location = (self.line, self.line_start, self.span.clone());
+                    self.call_sites.push((target, count, location));
+                }
+                self.pushed_args = Some(0);
+            }
+            opcode::Syscall | opcode::CallHost => self.pushed_args = Some(0),
+            _ => {}
+        }
+        self.call_target = match (opcode, arg) {
+            (opcode::Const, Some(ArgType::AbsLabelRef(label))) => Some(label.to_string()),
+            _ => None,
+        };
+    }
+
+    fn check_call_sites(&mut self) -> Result<(), AssembleError> {
+        for (target, found, location) in core::mem::take(&mut self.call_sites) {
+            let Some((name, expected, _)) = self.functions.iter().find(|(name, ..)| *name == target) else {
+                continue;
+            };
+            if *expected != found {
+                (self.line, self.line_start, self.span) = location;
+                return Err(AssembleError::new(
+                    self,
+                    AssembleErrorKind::ArityMismatch {
+                        name: name.clone(),
+                        expected: *expected,
+                        found,
+                    },
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn function_table(&self) -> Vec<Function> {
+        self.functions
+            .iter()
+            .map(|(name, arity, returns)| Function {
+                name: name.clone(),
+                addr: self.labels.get(name).map_or(0, |pos| pos + self.get_code_start_addr()),
+                arity: *arity,
+                returns: *returns,
+            })
+            .collect()
+    }
+
+    //NOTE(joh): `.data <name> bytes "text" 0x0a ...;` or `.data <name> words 1 @label ...;`
+    fn parse_data(&mut self, args: &str) -> Result<(), AssembleError> {
+        let missing = |parser: &Self| AssembleError::new(parser, AssembleErrorKind::MissingArgument);
+        let (name, args) = next_word(args).ok_or_else(|| missing(self))?;
+        let (kind, mut args) = next_word(args).ok_or_else(|| missing(self))?;
+        self.try_push_data_label(name)?;
+        let start = self.data.len();
+
+        match kind {
+            "bytes" => loop {
+                args = args.trim_start();
+                if let Some(s) = args.strip_prefix('"') {
+                    let string = self.parse_string(s)?;
+                    self.data.extend_from_slice(string.word.as_bytes());
+                    args = string.rest.unwrap_or_default();
+                } else if let Some((word, rest)) = next_word(args) {
+                    let byte = self.parse_u8(word)?;
+                    self.data.push(byte);
+                    args = rest;
+                } else {
+                    break;
+                }
+            },
+            "words" => {
+                for word in iter_op_args(args) {
+                    //NOTE(joh): String arguments would push their literal into the middle of this entry
+                    let arg = match word.starts_with('"') {
+                        true => None,
+                        false => Some(self.parse_arg(word)?),
+                    };
+                    match arg {
+                        Some(ArgType::Number(n)) => push_le(&mut self.data, n),
+                        Some(ArgType::AbsLabelRef(l)) => {
+                            self.data_relocs.push((self.data.len(), l.to_string()));
+                            push_le(&mut self.data, 0_u32);
+                        }
+                        _ => {
+                            return Err(AssembleError::new(
+                                self,
+                                AssembleErrorKind::UnexpectedImmArgSize,
+                            ));
+                        }
+                    }
+                }
+            }
+            _ => {
+                return Err(AssembleError::new(
+                    self,
+                    AssembleErrorKind::UnknownDirective(kind.to_string()),
+                ));
+            }
+        }
+        self.data_lens.insert(name.to_string(), (self.data.len() - start) as u32);
+        Ok(())
+    }
+
+    //NOTE(joh): Definitions are replaced by empty lines and invocations are expanded onto the
+    //line they appear on. Included files are spliced in line by line, `line_map` keeps track
+    //of where each line came from for errors and the line table.
+    pub fn expand_macros<'src>(&mut self, code: &'src str) -> Result<Cow<'src, str>, AssembleError> {
+        if !code.contains(MACRO_PREFIX) {
+            return Ok(Cow::Borrowed(code));
+        }
+        let mut expanded = String::with_capacity(code.len());
+        let mut line_map = Vec::new();
+        #[cfg(feature = "std")]
+        if let Some(path) = self.source_files[0].as_ref().and_then(|p| p.canonicalize().ok()) {
+            self.include_stack.push(path);
+        }
+        self.expand_source(code, 0, &mut expanded, &mut line_map)?;
+        self.include_stack.clear();
+        expanded.pop();
+
+        self.file = 0;
+        self.line = 0;
+        self.line_start = 0;
+        self.span = 0..0;
+        self.line_map = line_map;
+        Ok(Cow::Owned(expanded))
+    }
+
+    fn expand_source(
+        &mut self,
+        code: &str,
+        file: usize,
+        expanded: &mut String,
+        line_map: &mut Vec<(usize, usize, usize)>,
+    ) -> Result<(), AssembleError> {
+        //NOTE(joh): Comments may contain anything, including separators and macro names
+        let code = &*blank_comments(code);
+        let mut open_macro: Option<(String, Macro)> = None;
+        let mut macro_location = (0, 0, 0..0);
+        let mut line_start = 0;
+
+        for (i, line) in code.split('\n').enumerate() {
+            self.file = file;
+            self.line = i;
+            self.line_start = line_start;
+            self.span = line_start..line_start + line.trim_end().len();
+            line_start += line.len() + 1;
+
+            let directive = line.trim_start().strip_prefix(MACRO_PREFIX);
+            if let Some((_, m)) = open_macro.as_mut() {
+                let is_end = directive
+                    .and_then(next_word)
+                    .is_some_and(|(word, _)| word.trim_end_matches(STATEMENT_SEP) == "end");
+                if !is_end {
+                    m.body.push_str(line);
+                    m.body.push('\n');
+                } else if let Some((name, m)) = open_macro.take() {
+                    self.macros.insert(name, m);
+                }
+            } else if let Some(path) = directive.and_then(|d| d.strip_prefix("include")) {
+                self.expand_include(path, expanded, line_map)?;
+                continue;
+            } else if let Some(directive) = directive {
+                open_macro = self.parse_macro_directive(directive)?;
+                macro_location = (self.line, self.line_start, self.span.clone());
+            } else {
+                let mut len = expanded.len();
+                expanded.push_str(&self.expand_line(line, 0, &mut len)?);
+            }
+            expanded.push('\n');
+            line_map.push((file, self.line, self.line_start));
+        }
+        if let Some((name, _)) = open_macro {
+            (self.line, self.line_start, self.span) = macro_location;
+            return Err(AssembleError::new(self, AssembleErrorKind::UnterminatedMacro(name)));
+        }
+        Ok(())
+    }
+
+    //NOTE(joh): `%include "other.malu";` expands the file in place of the directive
+    #[cfg(feature = "std")]
+    fn expand_include(
+        &mut self,
+        s: &str,
+        expanded: &mut String,
+        line_map: &mut Vec<(usize, usize, usize)>,
+    ) -> Result<(), AssembleError> {
+        let s = s
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        let name = self.parse_string(s)?;
+        let statement = self.slice_until(name.rest.unwrap_or_default(), STATEMENT_SEP)?;
+        if !statement.word.trim().is_empty() || !statement.rest.unwrap_or_default().trim().is_empty() {
+            return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+        }
+
+        let path = self.resolve_include(name.word)?;
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if self.include_stack.contains(&canonical) {
+            return Err(AssembleError::new(
+                self,
+                AssembleErrorKind::IncludeCycle(name.word.to_string()),
+            ));
+        }
+        let code = std::fs::read_to_string(&path)
+            .map_err(|e| AssembleError::new(self, AssembleErrorKind::Io(format!("{}: {e}", path.display()))))?;
+
+        let file = match self.source_files.iter().position(|f| f.as_ref() == Some(&path)) {
+            Some(file) => file,
+            None => {
+                self.source_files.push(Some(path));
+                self.source_files.len() - 1
+            }
+        };
+        self.include_stack.push(canonical);
+        self.expand_source(&code, file, expanded, line_map)?;
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn expand_include(
+        &mut self,
+        s: &str,
+        _: &mut String,
+        _: &mut Vec<(usize, usize, usize)>,
+    ) -> Result<(), AssembleError> {
+        let name = s.trim().trim_end_matches(STATEMENT_SEP).trim_matches('"');
+        Err(AssembleError::new(self, AssembleErrorKind::IncludeNotFound(name.to_string())))
+    }
+
+    //NOTE(joh): Relative to the including file first, then the include dirs in order
+    #[cfg(feature = "std")]
+    fn resolve_include(&self, name: &str) -> Result<PathBuf, AssembleError> {
+        let base = self.source_files[self.file]
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+        core::iter::once(base)
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or(AssembleError::new(
+                self,
+                AssembleErrorKind::IncludeNotFound(name.to_string()),
+            ))
+    }
+
+    //NOTE(joh): Maps a line of the expanded source back to its file, line and line start
+    //NOTE(joh): File, line, column and span in that file of the current statement
+    fn statement_location(&self) -> (Option<String>, usize, usize, Range<usize>) {
+        let (file, line, line_start) = self.source_location(self.line);
+        let column = self.span.start.saturating_sub(self.line_start);
+        let span = line_start + column..line_start + column + self.span.len();
+        (self.source_file_name(file), line, column, span)
+    }
+
+    fn source_location(&self, line: usize) -> (usize, usize, usize) {
+        self.line_map
+            .get(line)
+            .copied()
+            .unwrap_or((self.file, line, self.line_start))
+    }
+
+    fn source_file_name(&self, file: usize) -> Option<String> {
+        self.source_files
+            .get(file)?
+            .as_deref()
+            .map(path_name)
+    }
+
+    //NOTE(joh): `%define NAME value;` or `%macro name params...` whose body ends at `%end`
+    fn parse_macro_directive(&mut self, s: &str) -> Result<Option<(String, Macro)>, AssembleError> {
+        let missing = |parser: &Self| AssembleError::new(parser, AssembleErrorKind::MissingArgument);
+        let (name, args) = next_word(s).ok_or_else(|| missing(self))?;
+        match name {
+            "define" => {
+                let statement = self.slice_until(args, STATEMENT_SEP)?;
+                if !statement.rest.unwrap_or_default().trim().is_empty() {
+                    return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+                }
+                let (name, value) = next_word(statement.word).ok_or_else(|| missing(self))?;
+                if value.trim().is_empty() {
+                    return Err(missing(self));
+                }
+                self.check_macro_name(name)?;
+                //NOTE(joh): Earlier defines are substituted right away so they can build on each other
+                let value = self.substitute_defines(value.trim());
+                if value.len() > MAX_EXPANSION_LEN {
+                    return Err(AssembleError::new(self, AssembleErrorKind::ExpansionTooLarge));
+                }
+                self.defines.insert(name.to_string(), value);
+                Ok(None)
+            }
+            "macro" => {
+                let mut words = iter_op_args(args.trim_end().trim_end_matches(STATEMENT_SEP));
+                let name = words.next().ok_or_else(|| missing(self))?;
+                self.check_macro_name(name)?;
+                let params = words.map(str::to_string).collect();
+                Ok(Some((name.to_string(), Macro { params, body: String::new() })))
+            }
+            _ => Err(AssembleError::new(
+                self,
+                AssembleErrorKind::UnknownDirective(name.to_string()),
+            )),
+        }
+    }
+
+    fn check_macro_name(&self, name: &str) -> Result<(), AssembleError> {
+        match self.defines.contains_key(name) || self.macros.contains_key(name) {
+            true => Err(AssembleError::new(
+                self,
+                AssembleErrorKind::MacroAlreadyDefined(name.to_string()),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    //NOTE(joh): Label references share the namespace of defines, so `@NAME` is left alone
+    fn substitute_defines(&self, s: &str) -> String {
+        substitute_words(s, |word, prev| match prev {
+            Some('@' | '.' | ':') => None,
+            _ => self.defines.get(word).map(String::as_str),
+        })
+    }
+
+    //NOTE(joh): `len` is the length of the whole expanded source so far, only the statements that
+    //end up in it are counted, not the bodies they were expanded from
+    fn expand_line(&self, line: &str, depth: usize, len: &mut usize) -> Result<String, AssembleError> {
+        let mut expanded = String::with_capacity(line.len());
+        for statement in split_statements(line) {
+            let statement = self.substitute_defines(statement);
+
+            //NOTE(joh): Labels may precede the op in the same statement
+            let mut op = statement.as_str();
+            while let Some(label) = op.trim_start().strip_prefix(':')
+                && let Some(end) = label.find(':')
+            {
+                op = &label[end + 1..];
+            }
+            let call = op.trim_end().strip_suffix(STATEMENT_SEP).unwrap_or(op);
+            let Some((name, args)) = next_word(call).filter(|(name, _)| self.macros.contains_key(*name)) else {
+                self.grow_expansion(len, statement.len())?;
+                expanded.push_str(&statement);
+                continue;
+            };
+            let m = &self.macros[name];
+            if depth >= MAX_MACRO_DEPTH {
+                return Err(AssembleError::new(
+                    self,
+                    AssembleErrorKind::RecursiveMacro(name.to_string()),
+                ));
+            }
+
+            let args: Vec<&str> = iter_op_args(args).collect();
+            match args.len().cmp(&m.params.len()) {
+                core::cmp::Ordering::Less => {
+                    return Err(AssembleError::new(self, AssembleErrorKind::MissingArgument));
+                }
+                core::cmp::Ordering::Greater => {
+                    return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments));
+                }
+                core::cmp::Ordering::Equal => {}
             }
+            let body = substitute_words(&m.body, |word, prev| match prev {
+                Some('.' | ':') => None,
+                _ => m.params.iter().position(|p| p == word).map(|i| args[i]),
+            });
+
+            //NOTE(joh): The separator of the invocation is dropped, the body brings its own
+            let labels = &statement[..statement.len() - op.len()];
+            self.grow_expansion(len, labels.len())?;
+            expanded.push_str(labels);
+            expanded.push_str(&self.expand_line(&body.replace(['\r', '\n'], " "), depth + 1, len)?);
         }
+        Ok(expanded)
     }
 
-    pub fn get_string_literal_addr(&mut self, str: &'src str) -> u32 {
-        match self.string_literals.get(str) {
-            Some(addr) => *addr,
-            None => self.push_string_literal(str)
+    fn grow_expansion(&self, len: &mut usize, by: usize) -> Result<(), AssembleError> {
+        *len += by;
+        match *len > MAX_EXPANSION_LEN {
+            true => Err(AssembleError::new(self, AssembleErrorKind::ExpansionTooLarge)),
+            false => Ok(()),
         }
     }
 
-    pub fn push_string_literal(&mut self, str: &'src str) -> u32 {
-        println!("pushing string");
-        let offset = self.data.len();
-
-        self.data.extend_from_slice(&(str.len() as u32).to_le_bytes());
-        self.data.extend_from_slice(str.as_bytes());
-        println!("{:?}", self.data);
-        self.string_literals.insert(str.to_string(), offset as u32);
-        
-        offset as u32
+    //NOTE(joh): `if`, `loop` and `block` become jumps, see `flow::lower`. Runs after the macros
+    //are expanded so they may expand to the constructs.
+    pub fn lower_control_flow<'src>(&mut self, code: &'src str) -> Result<Cow<'src, str>, AssembleError> {
+        flow::lower(code).map_err(|(error, span)| {
+            self.line = code[..span.start].matches('\n').count();
+            self.line_start = code[..span.start].rfind('\n').map_or(0, |i| i + 1);
+            self.span = span;
+            AssembleError::new(self, AssembleErrorKind::ControlFlow(error))
+        })
     }
 
-    pub fn parse_elems(&mut self, code: &'src str) -> Result<Box<[Elem<'src>]>, AssembleError> {
+    //NOTE(joh): First pass, records the size of every op and where each label is. Label references
+    //are kept by name, so they may come before the definition.
+    pub fn parse_elems<'src>(&mut self, code: &'src str) -> Result<Box<[Elem<'src>]>, AssembleError> {
         let mut rest = Some(code);
         let mut elems = Vec::new();
-
-        loop {
-            match rest {
-                Some(r) => match r.chars().next() {
-                    Some('\n') | Some('\r') | Some(' ') => rest = self.skip_whitespace(r),
-
-                    Some('#') => {
-                        //TODO: Make this more consistent
-                        let statement = self.slice_until(&r[1..], ';')?;
-                        let arg = self.parse_arg(statement.word)?;
-                        self.op_size_bytes += 5;
-
-                        elems.push(Elem::Const(arg));
-                        self.op_count += 1;
+        self.source_len = code.len();
+
+        while let Some(r) = rest {
+            let start = self.source_len - r.len();
+            self.span = start..start + r.find([STATEMENT_SEP, '\n']).unwrap_or(r.len());
+            if !r.starts_with(['\n', '\r', ' ', '/', '.'])
+                && let Some(padding) = self.padding.take()
+            {
+                self.pad_code(&mut elems, padding)?;
+            }
+            match r.chars().next() {
+                Some('\n') | Some('\r') | Some(' ') => rest = self.skip_whitespace(r),
+                Some('/') if r.starts_with("//") || r.starts_with("/*") => rest = self.skip_comment(r)?,
+
+                Some('#') => {
+                    //TODO: Make this more consistent
+                    let statement = self.slice_until(&r[1..], STATEMENT_SEP)?;
+                    if self.skip_source_op() {
                         rest = statement.rest;
+                        continue;
                     }
-                    Some('*') => {
-                        let arg = r.chars().next();
+                    let arg = self.parse_arg(statement.word)?;
+                    self.track_call_site(opcode::Const, Some(&arg));
+                    self.push_line_entry()?;
+                    match self.pool_const(&arg) {
+                        Some(op) => {
+                            self.op_size_bytes += op.size_bytes();
+                            elems.push(Elem::Op(op));
+                        }
+                        None => {
+                            self.op_size_bytes += 5;
+                            elems.push(Elem::Const(arg));
+                        }
                     }
-                    Some(':') => {
-                        let (label, label_rest) = self.parse_label(&r[1..])?;
-
-                        let id = self.try_push_label(label.name, label.position as u32)?;
-                        elems.push(Elem::Label(id));
-                        rest = label_rest;
+                    self.op_count += 1;
+                    rest = statement.rest;
+                }
+                Some('.') => rest = self.parse_directive(&r[1..])?,
+                Some(':') => {
+                    let (label, label_rest) = self.parse_label(&r[1..])?;
+
+                    let id = self.try_push_label(label.name, label.position as u32)?;
+                    elems.push(Elem::Label(id));
+                    if !is_local_label(label.name) {
+                        let span = self.span.start..self.span.start + label.name.len() + 2;
+                        self.label_sites.push((label.name.to_string(), (self.line, self.line_start, span)));
                     }
-                    Some(_) => {
-                        let (op, op_rest) = self.parse_op(r)?;
-                        self.op_size_bytes += op.size_bytes() as usize;
-
-                        elems.push(Elem::Op(op));
-                        self.op_count += 1;
-
+                    self.pushed_args = None;
+                    rest = label_rest;
+                }
+                Some(_) => {
+                    let (mut op, op_rest) = self.parse_op(r)?;
+                    if self.skip_source_op() {
                         rest = op_rest;
+                        continue;
+                    }
+                    self.track_call_site(op.opcode, op.arg.as_ref());
+                    if op.opcode == opcode::Const
+                        && let Some(pooled) = op.arg.as_ref().and_then(|a| self.pool_const(a))
+                    {
+                        op = pooled;
                     }
-                    None => break,
-                },
+                    self.push_line_entry()?;
+                    self.op_size_bytes += op.size_bytes();
+
+                    elems.push(Elem::Op(op));
+                    self.op_count += 1;
+
+                    rest = op_rest;
+                }
                 None => break,
             }
+            let location = (self.line, self.line_start, self.span.clone());
+            self.elem_locations.resize(elems.len(), location);
+        }
+        if self.data_align > 1 {
+            self.pad_code(&mut elems, Padding::Align(self.data_align))?;
         }
+        self.check_fits(0)?;
         Ok(elems.into())
     }
 
-    pub fn parse_ops(&mut self, elems: &[Elem<'src>]) -> Result<Box<[RawOp]>, AssembleError> {
-        let mut ops = Vec::with_capacity(self.op_count);
+    //NOTE(joh): Counts an op written in the source, true if it is stripped
+    fn skip_source_op(&mut self) -> bool {
+        let index = self.source_ops;
+        self.source_ops += 1;
+        if self.strip_ops.contains(&index) {
+            return true;
+        }
+        self.op_sources.push(Some(index));
+        false
+    }
+
+    //NOTE(joh): Called before the size of the op is added, lines are 1-based
+    fn push_line_entry(&mut self) -> Result<(), AssembleError> {
+        self.check_fits(0)?;
+        let (file, line, _) = self.source_location(self.line);
+        self.line_table.push(LineEntry {
+            addr: self.get_code_start_addr() + self.op_size_bytes as u32,
+            line: line as u32 + 1,
+            column: self.span.start.saturating_sub(self.line_start) as u32 + 1,
+            file: file as u32,
+        });
+        Ok(())
+    }
 
-        for elem in elems {
-            match elem {
-                Elem::Op(op) => ops.push(RawOp::from_op(op, self)?),
-                Elem::Label(_) => {}
-                Elem::Const(arg_type) => ops.push(RawOp {
-                    opcode: opcode::Const,
-                    arg: Some(RawArg::from_arg_type(arg_type, self)?),
-                }),
+    //NOTE(joh): Second pass, every label is known now. The references of the ops, the constant pool
+    //and `.data` words are resolved here, unknown labels are only an error at this point.
+    pub fn parse_ops<'src>(&mut self, elems: &[Elem<'src>]) -> Result<Box<[CodeElem]>, AssembleError> {
+        let mut ops = Vec::with_capacity(elems.len());
+        self.resolve_const_pool()?;
+        self.resolve_data_relocs()?;
+        self.check_call_sites()?;
+        self.resolve_globals()?;
+        self.resolve_exports()?;
+
+        let locations = core::mem::take(&mut self.elem_locations);
+        let mut offset = 0;
+        for (i, elem) in elems.iter().enumerate() {
+            if let Some((line, line_start, span)) = locations.get(i) {
+                self.line = *line;
+                self.line_start = *line_start;
+                self.span = span.clone();
             }
+            self.op_offset = offset as u32;
+            let op = match elem {
+                Elem::Op(op) => {
+                    if let Some(arg) = &op.arg {
+                        self.record_relocation(offset + size_of::<u8>(), arg);
+                    }
+                    CodeElem::Op(RawOp::from_op(op, self)?)
+                }
+                Elem::Label(_) => continue,
+                Elem::Const(arg_type) => {
+                    self.record_relocation(offset + size_of::<u8>(), arg_type);
+                    CodeElem::Op(RawOp {
+                        opcode: opcode::Const,
+                        arg: Some(RawArg::from_arg_type(arg_type, self)?),
+                    })
+                }
+                Elem::Padding(len) => CodeElem::Padding(*len),
+            };
+            offset += op.size_bytes();
+            ops.push(op);
         }
 
         Ok(ops.into_boxed_slice())
     }
 
-    pub fn try_get_label(&self, id: &'src str) -> Result<u32, AssembleError> {
-        self.labels
-            .get(id)
+    //NOTE(joh): `offset` is where the immediate of `arg` ends up, counted from the start of the
+    //code. Mirrors the lookup order of `get_abs_label_addr`.
+    fn record_relocation(&mut self, offset: usize, arg: &ArgType<'_>) {
+        if self.relocations.is_none() {
+            return;
+        }
+        let mut relocations = Vec::new();
+        self.collect_relocations(offset, arg, &mut relocations);
+        if let Some(recorded) = self.relocations.as_mut() {
+            recorded.extend(relocations);
+        }
+    }
+
+    fn collect_relocations(&self, offset: usize, arg: &ArgType<'_>, relocations: &mut Vec<Relocation>) {
+        let target = match arg {
+            ArgType::String(_) => RelocationTarget::Data,
+            ArgType::AbsLabelRef(name) => {
+                let function = name.strip_suffix(".index");
+                if self.data_labels.contains_key(*name) {
+                    RelocationTarget::Data
+                } else if name.strip_suffix(".len").is_some_and(|n| self.data_lens.contains_key(n)) {
+                    return;
+                } else if function.is_some_and(|n| self.functions.iter().any(|(f, ..)| f == n)) {
+                    RelocationTarget::Function
+                } else if self.labels.contains_key(*name) || local_label_ref(name).is_some() {
+                    RelocationTarget::Code
+                } else if let Some(function) = function {
+                    RelocationTarget::SymbolIndex(function.to_string())
+                } else {
+                    RelocationTarget::Symbol(name.to_string())
+                }
+            }
+            //NOTE(joh): Moves with the label it refers to. With several labels it is assumed to be
+            //a distance between them, which doesn't change when linking.
+            ArgType::Expr(expr) => {
+                if let [label] = expr::labels(expr)[..] {
+                    self.collect_relocations(offset, &ArgType::AbsLabelRef(label), relocations);
+                }
+                return;
+            }
+            ArgType::Table { targets, default } => {
+                let start = offset + size_of::<u32>();
+                for (i, target) in targets.iter().chain([&**default]).enumerate() {
+                    self.collect_relocations(start + i * size_of::<u32>(), target, relocations);
+                }
+                return;
+            }
+            _ => return,
+        };
+        relocations.push(Relocation { offset: offset as u32, target });
+    }
+
+    pub fn try_get_label(&self, id: &str) -> Result<u32, AssembleError> {
+        let local = local_label_ref(id).and_then(|(name, forward)| {
+            let positions = self.local_labels.get(name)?;
+            match forward {
+                true => positions.iter().find(|pos| **pos > self.op_offset),
+                false => positions.iter().rev().find(|pos| **pos <= self.op_offset),
+            }
+        });
+        local
+            .or(self.labels.get(id))
             .ok_or(AssembleError::new(
                 self,
                 AssembleErrorKind::UnknownLabel(id.to_string()),
@@ -477,43 +2289,96 @@ impl<'src> Parser {
     }
 
     pub fn get_code_start_addr(&self) -> u32 {
-        DATA_START
+        self.origin
+    }
+
+    pub fn get_data_start_addr(&self) -> u32 {
+        self.get_code_start_addr() + self.op_size_bytes as u32
     }
 
-    pub fn get_abs_label_addr(&self, name: &'src str) -> Result<i32, AssembleError> {
+    pub fn get_abs_label_addr(&self, name: &str) -> Result<i32, AssembleError> {
+        if local_label_ref(name).is_some() {
+            return Ok(self.try_get_label(name)? as i32 + self.get_code_start_addr() as i32);
+        }
+        if let Some(offset) = self.data_labels.get(name) {
+            return Ok((self.get_data_start_addr() + offset) as i32);
+        }
+        if let Some(len) = name.strip_suffix(".len").and_then(|n| self.data_lens.get(n)) {
+            return Ok(*len as i32);
+        }
+        if let Some(index) = name
+            .strip_suffix(".index")
+            .and_then(|n| self.functions.iter().position(|(f, ..)| f == n))
+        {
+            return Ok(index as i32);
+        }
+        if !self.labels.contains_key(name)
+            && let Some(addr) = self.external_labels.get(name)
+        {
+            return Ok(*addr as i32);
+        }
+        let imported = |name: &str| {
+            let name = name.strip_suffix(".index").unwrap_or(name);
+            self.imports.is_empty() || self.imports.iter().any(|i| i == name)
+        };
+        if self.relocations.is_some() && !self.labels.contains_key(name) && imported(name) {
+            return Ok(0);
+        }
         let label = self.try_get_label(name)?;
         Ok(label as i32 + self.get_code_start_addr() as i32)
     }
 
-    pub fn get_off_label_addr(&self, name: &'src str) -> Result<i32, AssembleError> {
-        //NOTE: (joh): Ich glaube das ist falsch
+    //NOTE(joh): `.name` is consumed by the `branch` or `branch_if` right after the constant, so the
+    //distance is counted from there. Constants with an offset are never pooled and always 5 bytes.
+    pub fn get_off_label_addr(&self, name: &str) -> Result<i32, AssembleError> {
         let label = self.try_get_label(name)?;
-        Ok(((self.op_size_bytes as isize) - (label as isize)) as i32)
+        let branch = self.op_offset + (size_of::<u8>() + size_of::<u32>()) as u32;
+        Ok(label.wrapping_sub(branch) as i32)
     }
 
     pub fn get_bytecode_info(&self) -> BytecodeInfo {
         let code_start_offset =
-            self.labels.get(ENTRY_LABEL_NAME).copied().unwrap_or(0) as u32 + self.get_code_start_addr();
+            self.labels.get(ENTRY_LABEL_NAME).copied().unwrap_or(0) + self.get_code_start_addr();
 
         BytecodeInfo {
             code_size_bytes: self.op_size_bytes as u32,
             instruction_count: self.op_count as u32,
             code_start_offset,
-            lit_data_section_size: self.data.len() as u32,
+            data_section_size: self.data.len() as u32,
         }
     }
 
-    pub fn as_bytecode(&self, ops: &'src [RawOp]) -> Box<[u8]> {
+    pub fn as_bytecode(&self, ops: &[CodeElem]) -> Box<[u8]> {
         let info = self.get_bytecode_info();
         let mut buffer = info.to_bytecode();
 
         ops.iter().for_each(|o| o.encode(&mut buffer));
         buffer.extend_from_slice(&self.data);
 
+        //NOTE(joh): The pool is optional and follows the literal data: entry count, then the entries
+        if !self.const_pool.is_empty() {
+            push_le(&mut buffer, self.const_pool.len() as u32);
+            self.const_pool
+                .iter()
+                .for_each(|(_, value)| push_le(&mut buffer, *value));
+        }
+        if !self.functions.is_empty() {
+            encode_function_table(&self.function_table(), &mut buffer);
+        }
+        if !self.global_inits.is_empty() {
+            encode_global_inits(&self.global_inits, &mut buffer);
+        }
+        if !self.host_imports.is_empty() {
+            encode_host_imports(&self.host_imports, &mut buffer);
+        }
+        if !self.export_table.is_empty() {
+            encode_exports(&self.export_table, &mut buffer);
+        }
+
         buffer.into_boxed_slice()
     }
     
-    pub fn slice_until(
+    pub fn slice_until<'src>(
         &self,
         rest: &'src str,
         delim: char,
@@ -534,23 +2399,49 @@ impl<'src> Parser {
         })
     }
 
-    pub fn skip_whitespace(&mut self, rest: &'src str) -> Option<&'src str> {
+    pub fn skip_whitespace<'src>(&mut self, rest: &'src str) -> Option<&'src str> {
         for (i, c) in rest.char_indices() {
             match c {
                 '\r' | ' ' => {}
-                '\n' => self.line += 1,
+                '\n' => {
+                    self.line += 1;
+                    self.line_start = self.source_len.saturating_sub(rest.len()) + i + 1;
+                }
                 _ => return Some(&rest[i..]),
             }
         }
         None
     }
 
-    pub fn parse_string(&self, rest: &'src str) -> Result<ParseOutput<'src, &'src str>, AssembleError> {
+    //NOTE(joh): Newlines in block comments still count for the line numbers
+    pub fn skip_comment<'src>(&mut self, rest: &'src str) -> Result<Option<&'src str>, AssembleError> {
+        let end = match rest.strip_prefix("/*") {
+            Some(block) => {
+                block
+                    .find("*/")
+                    .ok_or(AssembleError::new(self, AssembleErrorKind::MissingDelimiter))?
+                    + 4
+            }
+            None => rest.find('\n').unwrap_or(rest.len()),
+        };
+        let comment_start = self.source_len - rest.len();
+        for (i, _) in rest[..end].match_indices('\n') {
+            self.line += 1;
+            self.line_start = comment_start + i + 1;
+        }
+        Ok(rest.get(end..).filter(|r| !r.is_empty()))
+    }
+
+    pub fn parse_string<'src>(&self, rest: &'src str) -> Result<ParseOutput<'src, &'src str>, AssembleError> {
         self.slice_until(rest, '"')
     }
 
-    pub fn parse_arg(&mut self, s: &'src str) -> Result<ArgType<'src>, AssembleError> {
-        match s.chars().next().unwrap() {
+    pub fn parse_arg<'src>(&mut self, s: &'src str) -> Result<ArgType<'src>, AssembleError> {
+        let first = s
+            .chars()
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        match first {
             '"' => {
                 //TODO (joh): Was ist mit dem Rest?
                 let res = self.parse_string(&s[1..])?; 
@@ -558,6 +2449,10 @@ impl<'src> Parser {
                 Ok(ArgType::String((res.word, addr)))
 
             },
+            _ if expr::is_expr(s) => match expr::labels(s).is_empty() {
+                true => Ok(ArgType::Number(self.eval_expr(s)?)),
+                false => Ok(ArgType::Expr(s)),
+            },
             '@' => Ok(ArgType::AbsLabelRef(&s[1..])),
             '.' => Ok(ArgType::OffLabelRef(&s[1..])),
             _ => {
@@ -566,7 +2461,21 @@ impl<'src> Parser {
             }
         }
     }
-    pub fn arg_register(
+    //NOTE(joh): Labels are looked up like `@name` operands, so expressions with labels can only be
+    //evaluated once all labels are known
+    pub fn eval_expr(&self, s: &str) -> Result<i32, AssembleError> {
+        let value = expr::eval(s, |label| self.get_abs_label_addr(label).map(|addr| addr as u32 as i64))
+            .map_err(|e| match e {
+                ExprError::Syntax => AssembleError::new(self, AssembleErrorKind::InvalidExpression(s.to_string())),
+                ExprError::Label(e) => e,
+            })?;
+        //NOTE(joh): Like number operands, either the signed or the unsigned range is fine
+        i32::try_from(value)
+            .or_else(|_| u32::try_from(value).map(|v| v as i32))
+            .map_err(|e| AssembleError::new(self, e.into()))
+    }
+
+    pub fn arg_register<'src>(
         &mut self,
         args: &mut impl Iterator<Item = &'src str>,
     ) -> Result<ArgType<'src>, AssembleError> {
@@ -575,7 +2484,7 @@ impl<'src> Parser {
             .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
         match self.parse_arg(s)? {
             ArgType::Number(num) => match num {
-                n @ 0..255 => Ok(ArgType::Register(n as u8)),
+                n @ 0..=255 => Ok(ArgType::Register(n as u8)),
                 num => Err(AssembleError::new(
                     self,
                     AssembleErrorKind::UnexpectedRegisterId(num),
@@ -588,18 +2497,126 @@ impl<'src> Parser {
         }
     }
 
-    pub fn arg_const(
+    //NOTE(joh): Like `arg_register`, but names declared with `.local` are allowed as well
+    pub fn arg_local<'src>(
+        &mut self,
+        args: &mut impl Iterator<Item = &'src str>,
+    ) -> Result<ArgType<'src>, AssembleError> {
+        let s = args
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        let names = self.local_scopes.last().map_or(&[][..], |(_, names)| names);
+        if let Some(index) = names.iter().position(|name| name == s) {
+            return Ok(ArgType::Register(index as u8));
+        }
+        if s.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return Err(AssembleError::new(self, AssembleErrorKind::UnknownLocal(s.to_string())));
+        }
+        self.arg_register(&mut core::iter::once(s))
+    }
+
+    //NOTE(joh): Like `arg_register`, but names declared with `.global` are allowed as well
+    pub fn arg_global<'src>(
+        &mut self,
+        args: &mut impl Iterator<Item = &'src str>,
+    ) -> Result<ArgType<'src>, AssembleError> {
+        let s = args
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        if let Some(index) = self.globals.iter().position(|(name, _, _)| name == s) {
+            return Ok(ArgType::Register(index as u8));
+        }
+        if s == STACK_POINTER_NAME {
+            return Ok(ArgType::Register(STACK_POINTER_GLOBAL));
+        }
+        if s.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return Err(AssembleError::new(self, AssembleErrorKind::UnknownGlobal(s.to_string())));
+        }
+        self.arg_register(&mut core::iter::once(s))
+    }
+
+    //NOTE(joh): The args pushed right before are checked against the arity of the import
+    pub fn arg_host_fn<'src>(
+        &mut self,
+        args: &mut impl Iterator<Item = &'src str>,
+    ) -> Result<ArgType<'src>, AssembleError> {
+        let s = args
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        let Some(index) = self.host_imports.iter().position(|import| import.name == s) else {
+            return Err(AssembleError::new(self, AssembleErrorKind::UnknownHostFn(s.to_string())));
+        };
+        let expected = self.host_imports[index].arity;
+        if let Some(found) = self.pushed_args
+            && found != expected
+        {
+            let name = s.to_string();
+            return Err(AssembleError::new(self, AssembleErrorKind::ArityMismatch { name, expected, found }));
+        }
+        Ok(ArgType::Register(index as u8))
+    }
+
+    pub fn arg_float<'src>(
+        &mut self,
+        args: &mut impl Iterator<Item = &'src str>,
+    ) -> Result<ArgType<'src>, AssembleError> {
+        let s = args
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        let num = s
+            .parse::<f32>()
+            .map_err(|e| AssembleError::new(self, e.into()))?;
+        Ok(ArgType::Float(num))
+    }
+
+    pub fn arg_wide<'src>(
+        &mut self,
+        args: &mut impl Iterator<Item = &'src str>,
+    ) -> Result<ArgType<'src>, AssembleError> {
+        let s = args
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        //NOTE(joh): Accept the full unsigned range as well as negative numbers
+        let num = self.parse_i64(s).map(|n| n as u64).or_else(|_| self.parse_u64(s))?;
+        Ok(ArgType::Wide(num))
+    }
+
+    //NOTE(joh): All remaining arguments are targets, the last one is the default
+    pub fn arg_table<'src>(
+        &mut self,
+        args: &mut impl Iterator<Item = &'src str>,
+    ) -> Result<ArgType<'src>, AssembleError> {
+        //NOTE(joh): The last target is the default, it may be marked with `default`
+        let mut args = args.collect::<Vec<_>>();
+        if let Some(i) = args.iter().position(|arg| *arg == "default") {
+            match args.len() - i {
+                1 => return Err(AssembleError::new(self, AssembleErrorKind::MissingArgument)),
+                2 => _ = args.remove(i),
+                _ => return Err(AssembleError::new(self, AssembleErrorKind::TooManyArguments)),
+            }
+        }
+        let mut targets = args.into_iter().map(|s| self.parse_arg(s)).collect::<Result<Vec<_>, _>>()?;
+        let default = targets
+            .pop()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
+        Ok(ArgType::Table {
+            targets,
+            default: Box::new(default),
+        })
+    }
+
+    pub fn arg_const<'src>(
         &mut self,
         args: &mut impl Iterator<Item = &'src str>,
     ) -> Result<ArgType<'src>, AssembleError> {
         let s = args
             .next()
             .ok_or(AssembleError::new(self, AssembleErrorKind::MissingArgument))?;
-        Ok(self.parse_arg(s)?)
+        self.parse_arg(s)
     }
 
-    pub fn parse_op(&mut self, s: &'src str) -> Result<(Op<'src>, Option<&'src str>), AssembleError> {
-        let statement = self.slice_until(s, ';')?;
+    pub fn parse_op<'src>(&mut self, s: &'src str) -> Result<(Op<'src>, Option<&'src str>), AssembleError> {
+        let statement = self.slice_until(s, STATEMENT_SEP)?;
         let mut op_str = iter_op_args(statement.word);
         macro_rules! op {
             ($op: ident, Register) => {
@@ -609,6 +2626,24 @@ impl<'src> Parser {
             ($op: ident, Number) => {
                 Ok((opcode::$op, Some(self.arg_const(&mut op_str)?))) 
             };
+            ($op: ident, Local) => {
+                Ok((opcode::$op, Some(self.arg_local(&mut op_str)?))) 
+            };
+            ($op: ident, Global) => {
+                Ok((opcode::$op, Some(self.arg_global(&mut op_str)?))) 
+            };
+            ($op: ident, Wide) => {
+                Ok((opcode::$op, Some(self.arg_wide(&mut op_str)?))) 
+            };
+            ($op: ident, Float) => {
+                Ok((opcode::$op, Some(self.arg_float(&mut op_str)?))) 
+            };
+            ($op: ident, Table) => {
+                Ok((opcode::$op, Some(self.arg_table(&mut op_str)?))) 
+            };
+            ($op: ident, HostFn) => {
+                Ok((opcode::$op, Some(self.arg_host_fn(&mut op_str)?))) 
+            };
             ($op: ident, None) => {
                 {
                     let a: Option<ArgType<'src>> = None;
@@ -624,7 +2659,13 @@ impl<'src> Parser {
             };
         }
 
-        let op_name = op_str.next().unwrap();
+        let op_name = op_str
+            .next()
+            .ok_or(AssembleError::new(self, AssembleErrorKind::UnknownOperation))?;
+        let op_name = match OP_ALIASES.iter().find(|(alias, _)| *alias == op_name) {
+            Some((_, op)) => opcode::Names[*op as usize],
+            None => op_name,
+        };
         macro_rules! op_p {
             ($(($op: ident, $e: tt)),+) => {
                 match op_name {
@@ -646,12 +2687,12 @@ impl<'src> Parser {
             (JmpIf, None),
             (Branch, None),
             (BranchIf, None),
-            (LocalGet, Register),
-            (LocalSet, Register),
-            (LocalTee, Register),
-            (GlobalGet, Register),
-            (GlobalSet, Register),
-            (GlobalTee, Register),
+            (LocalGet, Local),
+            (LocalSet, Local),
+            (LocalTee, Local),
+            (GlobalGet, Global),
+            (GlobalSet, Global),
+            (GlobalTee, Global),
             (Eq, None),
             (Eqz, None),
             (Add, None),
@@ -683,7 +2724,80 @@ impl<'src> Parser {
             (End, None),
             (PushArg, None),
             (DbgAssert, None),
-            (Syscall, None)
+            (Syscall, None),
+            (ConstPool, Register),
+            (BrTable, Table),
+            (Const64, Wide),
+            (Add64, None),
+            (Sub64, None),
+            (Mul64, None),
+            (Divs64, None),
+            (Divu64, None),
+            (Eq64, None),
+            (Lts64, None),
+            (Ltu64, None),
+            (Gts64, None),
+            (Gtu64, None),
+            (And64, None),
+            (Or64, None),
+            (Xor64, None),
+            (Shiftl64, None),
+            (Shiftr64, None),
+            (ExtendU32, None),
+            (ExtendS32, None),
+            (Wrap64, None),
+            (Load64, Number),
+            (Store64, Number),
+            (FConst, Float),
+            (FAdd, None),
+            (FSub, None),
+            (FMul, None),
+            (FDiv, None),
+            (FLt, None),
+            (FGt, None),
+            (FFromI32, None),
+            (I32FromF, None),
+            (ShiftrS, None),
+            (Extend8S32, None),
+            (Extend8U32, None),
+            (Extend16S32, None),
+            (Extend16U32, None),
+            (CallIf, None),
+            (CallIndirect, None),
+            (MemSize, None),
+            (MemGrow, None),
+            (RemS, None),
+            (RemU, None),
+            (Rotl, None),
+            (Rotr, None),
+            (Clz, None),
+            (Ctz, None),
+            (Popcnt, None),
+            (Dup, None),
+            (Swap, None),
+            (Select, None),
+            (MemCopy, None),
+            (MemFill, None),
+            (Yield, None),
+            (CallHost, HostFn),
+            (PushMem, None),
+            (PopMem, None),
+            (Bswap, None),
+            (Bswap16, None),
+            (LoadMem8u, Register),
+            (LoadMem32, Register),
+            (StoreMem8, Register),
+            (StoreMem32, Register),
+            (AtomicLoad, Register),
+            (AtomicStore, Register),
+            (AtomicAdd, Register),
+            (Cmpxchg, Register),
+            (AtomicLoad32, Number),
+            (AtomicStore32, Number),
+            (AtomicRmwAdd, Number),
+            (AtomicCmpxchg, Number),
+            (MulWrap, None),
+            (MulWrap64, None)
         )?;
         match op_str.next() {
             Some(_) => Err(AssembleError::new(
@@ -694,7 +2808,7 @@ impl<'src> Parser {
         }
     }
 
-    pub fn parse_label(
+    pub fn parse_label<'src>(
         &self,
         s: &'src str,
     ) -> Result<(Label<'src>, Option<&'src str>), AssembleError> {
@@ -710,38 +2824,150 @@ impl<'src> Parser {
     }
 }
 
+fn is_local_label(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit())
+}
+
+//NOTE(joh): `1f` refers to the next `:1:` after the op, `1b` to the last one before or at it
+fn local_label_ref(name: &str) -> Option<(&str, bool)> {
+    let (label, direction) = name.split_at_checked(name.len().checked_sub(1)?)?;
+    match direction {
+        "f" if is_local_label(label) => Some((label, true)),
+        "b" if is_local_label(label) => Some((label, false)),
+        _ => None,
+    }
+}
+
 pub fn iter_op_args(str: &str) -> impl Iterator<Item = &str> {
     str.split_whitespace()
 }
 
+//NOTE(joh): Splits after each statement separator that is not inside a string, the
+//separators are kept
+fn split_statements(line: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            STATEMENT_SEP if !in_string => {
+                statements.push(&line[start..=i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < line.len() {
+        statements.push(&line[start..]);
+    }
+    statements
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+//NOTE(joh): Replaces whole words outside of strings. `lookup` gets the word and the char
+//in front of it.
+fn substitute_words<'a>(s: &str, lookup: impl Fn(&str, Option<char>) -> Option<&'a str>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut prev = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = 0;
+    while let Some(c) = s[i..].chars().next() {
+        if !in_string && is_word_char(c) {
+            let end = s[i..].find(|c| !is_word_char(c)).map_or(s.len(), |e| i + e);
+            let word = &s[i..end];
+            out.push_str(lookup(word, prev).unwrap_or(word));
+            prev = word.chars().last();
+            i = end;
+            continue;
+        }
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ => {}
+        }
+        out.push(c);
+        prev = Some(c);
+        i += c.len_utf8();
+    }
+    out
+}
+
+//NOTE(joh): Splits off the first whitespace separated word
+#[cfg(feature = "std")]
+fn path_name(path: &Path) -> String {
+    path.display().to_string()
+}
+
+#[cfg(not(feature = "std"))]
+fn path_name(path: &str) -> String {
+    path.to_string()
+}
+
+fn next_word(str: &str) -> Option<(&str, &str)> {
+    let str = str.trim_start();
+    if str.is_empty() {
+        return None;
+    }
+    Some(str.split_at(str.find(char::is_whitespace).unwrap_or(str.len())))
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum ArgType<'src> {
     String((&'src str, u32)),
     AbsLabelRef(&'src str),
     OffLabelRef(&'src str),
+    //NOTE(joh): A constant expression with labels, see `Parser::eval_expr`
+    Expr(&'src str),
     Number(i32),
+    Wide(u64),
+    Float(f32),
     Register(u8),
+    Table {
+        targets: Vec<ArgType<'src>>,
+        default: Box<ArgType<'src>>,
+    },
 }
 
 impl<'src> ArgType<'src> {
     pub fn size_bytes(&self) -> usize {
         match self {
-            ArgType::AbsLabelRef(_) | ArgType::OffLabelRef(_) | ArgType::Number(_) => {
+            ArgType::AbsLabelRef(_) | ArgType::OffLabelRef(_) | ArgType::Expr(_) | ArgType::Number(_) => {
                 size_of::<u32>()
             }
             ArgType::Register(_) => size_of::<u8>(),
+            ArgType::Wide(_) => size_of::<u64>(),
+            ArgType::Float(_) => size_of::<f32>(),
             ArgType::String(_) => size_of::<u32>(),
+            ArgType::Table { targets, .. } => table_size_bytes(targets.len()),
         }
     }
 }
 impl<'src> Display for ArgType<'src> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             ArgType::AbsLabelRef(label) => write!(f, "@{label}"),
             ArgType::OffLabelRef(label) => write!(f, ".{label}"),
+            ArgType::Expr(expr) => write!(f, "{expr}"),
             ArgType::Number(num) => write!(f, "{num}"),
+            ArgType::Wide(num) => write!(f, "{num}"),
+            ArgType::Float(num) => write!(f, "{num:?}"),
             ArgType::Register(num) => write!(f, "{num}"),
             ArgType::String((s, addr)) => write!(f, "\"{s}\"(@0x{:04x})", addr),
+            ArgType::Table { targets, default } => {
+                for target in targets {
+                    write!(f, "{target} ")?;
+                }
+                write!(f, "{default}")
+            }
         }
     }
 }
@@ -751,6 +2977,8 @@ pub enum Elem<'src> {
     Op(Op<'src>),
     Const(ArgType<'src>),
     Label(LabelId),
+    //NOTE(joh): A run of `nop`s, in bytes
+    Padding(u32),
 }
 
 #[cfg(test)]
@@ -762,8 +2990,8 @@ mod tests {
             let mut parser = Parser::new();
 
             let elems = parser.parse_elems($code).unwrap();
-            let ops: &[RawOp] = &parser.parse_ops(&elems).unwrap();
-            let expected: &[RawOp] = $expected;
+            let ops: &[CodeElem] = &parser.parse_ops(&elems).unwrap();
+            let expected: &[CodeElem] = $expected;
             assert_eq!(ops, expected)
         };
     }
@@ -779,6 +3007,7 @@ mod tests {
         };
     }
 
+    #[allow(unused_macros)]
     macro_rules! raw_num {
         ($num: expr) => {
             RawArg::Num($num)
@@ -802,16 +3031,16 @@ mod tests {
 
     macro_rules! raw_op {
         ($opcode: ident, $arg: expr) => {
-            RawOp {
+            CodeElem::Op(RawOp {
                 opcode: opcode::$opcode,
                 arg: Some($arg),
-            }
+            })
         };
         ($opcode: ident) => {
-            RawOp {
+            CodeElem::Op(RawOp {
                 opcode: opcode::$opcode,
                 arg: None,
-            }
+            })
         };
     }
 
@@ -902,10 +3131,10 @@ mod tests {
 
         let buffer = parser.as_bytecode(ops);
 
-        assert_eq!(&buffer[0..4], &[b'm', b'a', b'l', b'u']);
+        assert_eq!(&buffer[0..4], b"malu");
 
-        let size_bytes = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
-        let op_count = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        let size_bytes = crate::mem::read_le::<u32>(&buffer, 4).unwrap();
+        let op_count = crate::mem::read_le::<u32>(&buffer, 8).unwrap();
         let expected_size = ops.iter().fold(0, |acc, op| acc + op.size_bytes() as u32);
 
         assert_eq!(op_count, 4);
@@ -924,5 +3153,376 @@ mod tests {
         assert!(matches!(elems[1], Elem::Const(ArgType::Number(5))));
     }
 
+    #[test]
+    fn const_pool_shrinks_bytecode() {
+        let code = "
+            :loop:
+            #0x12345678; #0x12345678; add;
+            #@loop; #@loop; drop; drop;
+            const 0x12345678;
+            end;
+        ";
+        let plain = Parser::parse(code).unwrap();
+        let pooled = Parser::parse_with_const_pool(code).unwrap();
+        assert!(pooled.code.len() < plain.code.len());
+
+        let mut parser = Parser::with_const_pool();
+        let elems = parser.parse_elems(code).unwrap();
+        let ops = parser.parse_ops(&elems).unwrap();
+        assert_eq!(ops[0], raw_op!(ConstPool, raw_reg!(0)));
+        assert_eq!(ops[1], raw_op!(ConstPool, raw_reg!(0)));
+        assert_eq!(ops[3], raw_op!(ConstPool, raw_reg!(1)));
+        assert_eq!(ops[7], raw_op!(ConstPool, raw_reg!(0)));
+        assert_eq!(parser.const_pool.iter().map(|(_, v)| *v).collect::<Vec<_>>(), [0x12345678, DATA_START]);
+    }
+
+    #[test]
+    fn data_section() {
+        let code = r#"
+            .data msg bytes "hi there" 0x0a;
+            .data table words 7 @msg @start -1;
+            :start:
+            #@table;
+            end;
+        "#;
+        let mut parser = Parser::new();
+        let elems = parser.parse_elems(code).unwrap();
+        let ops = parser.parse_ops(&elems).unwrap();
+        let info = parser.get_bytecode_info();
+        let msg = info.data_start_addr();
+        let table = msg + 9;
+
+        assert_eq!(ops[0], raw_op!(Const, raw_num!(table)));
+        assert_eq!(&parser.data[..9], b"hi there\n");
+        let words: Vec<u32> = (0..4).map(|i| crate::mem::read_le(&parser.data, 9 + i * 4).unwrap()).collect();
+        assert_eq!(words, [7, msg, DATA_START, u32::MAX]);
+
+        assert!(matches!(
+            Parser::parse(".data a bytes 1; .data a bytes 2;").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::LabelAlreadyExists(_)
+        ));
+        assert!(matches!(
+            Parser::parse(".data a floats 1;").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::UnknownDirective(_)
+        ));
+    }
+
+    #[test]
+    fn string_directive() {
+        let code = r#"
+            .string greeting "hi; \"there\"\n";
+            .data empty bytes;
+            #@greeting; #@greeting.len; #@empty.len;
+            end;
+        "#;
+        let mut parser = Parser::new();
+        let elems = parser.parse_elems(code).unwrap();
+        let ops = parser.parse_ops(&elems).unwrap();
+        let data_start = parser.get_data_start_addr();
+
+        assert_eq!(&parser.data[..], b"hi; \"there\"\n");
+        assert_eq!(ops[0], raw_op!(Const, raw_num!(data_start)));
+        assert_eq!(ops[1], raw_op!(Const, raw_num!(12)));
+        assert_eq!(ops[2], raw_op!(Const, raw_num!(0)));
+
+        assert!(matches!(
+            Parser::parse(r#".string s "\q";"#).map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::UnknownEscape('q')
+        ));
+        assert!(matches!(
+            Parser::parse(r#".string s "open;"#).map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::MissingDelimiter
+        ));
+    }
+
+    #[test]
+    fn error_location() {
+        let code = "nop;\n  :start:\n    #@missing; end;";
+        let error = Parser::parse(code).map(|_| ()).unwrap_err();
+        assert!(matches!(error.kind, AssembleErrorKind::UnknownLabel(_)));
+        assert_eq!((error.line, error.column), (2, 4));
+        assert_eq!(&code[error.span.clone()], "#@missing");
+        assert_eq!(error.to_string(), "3:5: unknown label `missing`");
+        assert_eq!(
+            error.render(code, Some("test.malu")),
+            "error: unknown label `missing`\n --> test.malu:3:5\n  |\n3 |     #@missing; end;\n  |     ^^^^^^^^^"
+        );
+
+        let error = Parser::parse("nop;\nlocal_get 300;").map(|_| ()).unwrap_err();
+        assert_eq!((error.line, error.column), (1, 0));
+        assert!(error.render("nop;\nlocal_get 300;", None).ends_with("2 | local_get 300;\n  | ^^^^^^^^^^^^^"));
+    }
+
+    #[test]
+    fn macros() {
+        let code = "
+            %define COUNT 3;
+            %define START COUNT;
+            %macro push_sum a b
+                #a; #b;
+                add;
+            %end
+            :start: push_sum START @start; #COUNT;
+            .string msg \"COUNT;\";
+            end;
+        ";
+        let plain = "
+            :start: #3; #@start; add; #3;
+            .string msg \"COUNT;\";
+            end;
+        ";
+        assert_eq!(Parser::parse(code).unwrap().code, Parser::parse(plain).unwrap().code);
+
+        //NOTE(joh): Definitions keep their lines, so errors still point at the source
+        let error = Parser::parse("%macro m\nnop;\n%end\nm; #@missing;").map(|_| ()).unwrap_err();
+        assert!(matches!(error.kind, AssembleErrorKind::UnknownLabel(_)));
+        assert_eq!(error.line, 3);
+
+        let error = Parser::parse("nop;\n%macro m\nnop;").map(|_| ()).unwrap_err();
+        assert!(matches!(error.kind, AssembleErrorKind::UnterminatedMacro(_)));
+        assert_eq!(error.line, 1);
+        assert!(matches!(
+            Parser::parse("%macro m\nm;\n%end\nm;").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::RecursiveMacro(_)
+        ));
+        assert!(matches!(
+            Parser::parse("%macro m a\n#a;\n%end\nm;").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::MissingArgument
+        ));
+
+        //NOTE(joh): Each level invokes the next one four times, 4^30 nops in total
+        let mut code = String::from("%macro m0\nnop;\n%end\n");
+        for i in 1..=30 {
+            code.push_str(&format!("%macro m{i}\nm{0}; m{0}; m{0}; m{0};\n%end\n", i - 1));
+        }
+        code.push_str("m30;");
+        assert!(matches!(
+            Parser::parse(&code).map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::ExpansionTooLarge
+        ));
+        let mut code = String::from("%define D0 nop;\n");
+        for i in 1..=30 {
+            code.push_str(&format!("%define D{i} D{0} D{0} D{0} D{0};\n", i - 1));
+        }
+        assert!(matches!(
+            Parser::parse(&code).map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::ExpansionTooLarge
+        ));
+        assert!(matches!(
+            Parser::parse("%define A 1;\n%define A 2;").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::MacroAlreadyDefined(_)
+        ));
+    }
+
+    #[test]
+    fn const_expressions() {
+        let code = "
+            %define BASE 4096;
+            :start:
+            #BASE; store_32 BASE+4;
+            const (1<<12);
+            #@start + 2*8; #@end-@start;
+            :end:
+            end;
+        ";
+        let plain = "
+            :start:
+            #4096; store_32 4100;
+            const 4096;
+            #32; #25;
+            :end:
+            end;
+        ";
+        assert_eq!(Parser::parse(code).unwrap().code, Parser::parse(plain).unwrap().code);
+
+        let error = |code| Parser::parse(code).map(|_| ()).unwrap_err().kind;
+        assert!(matches!(error("#(1+2;"), AssembleErrorKind::InvalidExpression(_)));
+        assert!(matches!(error("#@missing+1;"), AssembleErrorKind::UnknownLabel(_)));
+        assert!(matches!(error("#1<<40;"), AssembleErrorKind::IntSize(_)));
+        let deep = format!("#{}1{};", "(".repeat(20000), ")".repeat(20000));
+        assert!(matches!(error(&deep), AssembleErrorKind::InvalidExpression(_)));
+    }
+
+    #[test]
+    fn local_labels() {
+        let code = "
+            %macro countdown n
+                #n;
+                :1: #1; sub; dup; #@1b; jmp_if;
+            %end
+            countdown 3; countdown 2;
+            #@1f; jmp;
+            :1: #@1f-@1b; drop;
+            :1:
+            end;
+        ";
+        let plain = "
+            #3;
+            :a: #1; sub; dup; #@a; jmp_if;
+            #2;
+            :b: #1; sub; dup; #@b; jmp_if;
+            #@c; jmp;
+            :c: #@d-@c; drop;
+            :d:
+            end;
+        ";
+        assert_eq!(Parser::parse(code).unwrap().code, Parser::parse(plain).unwrap().code);
+        assert_eq!(Parser::with_const_pool().assemble(code).unwrap().labels.len(), 0);
+
+        let error = Parser::parse(":1: #@1f; jmp;").map(|_| ()).unwrap_err();
+        assert!(matches!(error.kind, AssembleErrorKind::UnknownLabel(label) if label == "1f"));
+    }
+
+    #[test]
+    fn align_and_pad() {
+        let code = "
+            nop;
+            .align 8;
+            :aligned: nop;
+            .pad 2;
+            :padded: #@b; #@w; end;
+            .string b \"abc\";
+            .align 4;
+            .data w words 7;
+        ";
+        let plain = "
+            nop; nop; nop; nop; nop; nop; nop; nop;
+            :aligned: nop;
+            nop; nop;
+            :padded: #@b; #@w; end;
+            nop; nop;
+            .data b bytes \"abc\" 0;
+            .data w words 7;
+        ";
+        let result = Parser::parse(code).unwrap();
+        assert_eq!(result.code, Parser::parse(plain).unwrap().code);
+        let label = |name: &str| result.debug_info.labels.iter().find(|(n, _)| n == name).map(|(_, addr)| *addr);
+        assert_eq!(label("aligned"), Some(24));
+        assert_eq!(label("padded"), Some(27));
+
+        assert!(matches!(
+            Parser::parse(".align 3; nop;").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::InvalidAlignment(3)
+        ));
+    }
+
+    #[test]
+    fn large_padding() {
+        let code = Parser::parse(".pad 20000000; end;").unwrap().code;
+        let header = |at: usize| u32::from_le_bytes(code[at..at + 4].try_into().unwrap());
+        assert_eq!(header(BYTECODE_HEADER.len()), 20000001);
+        assert_eq!(header(BYTECODE_HEADER.len() + 4), 20000001);
+
+        for code in [
+            ".pad 0xffffffff; end;",
+            ".pad 0x80000000; nop; .align 0x80000000; end;",
+            ".pad 0x80000000; nop; .pad 0x80000000; .data d bytes 1;",
+            ".pad 0xfffffff0; #1; #1; #1; #1; end;",
+        ] {
+            let error = Parser::parse(code).map(|_| ()).unwrap_err();
+            assert!(matches!(error.kind, AssembleErrorKind::TooLarge), "{code}");
+        }
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("maluvm_include_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let write = |name: &str, code: &str| std::fs::write(dir.join(name), code).unwrap();
+        write("lib/util.malu", "%define ANSWER 42;\n:helper: #ANSWER; return;");
+        write("lib/broken.malu", "nop;\n  bogus;");
+        write("cycle_a.malu", "%include \"cycle_b.malu\";");
+        write("cycle_b.malu", "%include \"cycle_a.malu\";");
+
+        let assemble = |code: &str| {
+            let mut parser = Parser::new();
+            parser.set_source_path(dir.join("main.malu"));
+            parser.add_include_dir(dir.join("lib"));
+            parser.assemble(code)
+        };
+
+        let result = assemble("%include \"util.malu\";\n#@helper; call; end;").unwrap();
+        let plain = Parser::parse(":helper: #42; return;\n#@helper; call; end;").unwrap();
+        assert_eq!(result.code, plain.code);
+        let debug_info = result.debug_info.with_file("main.malu");
+        let helper = debug_info.resolve_pc(DATA_START).unwrap();
+        assert!(helper.file.unwrap().ends_with("util.malu"));
+        assert_eq!(helper.line, 2);
+        let call = debug_info.resolve_pc(DATA_START + 6).unwrap();
+        assert_eq!((call.file, call.line), (Some("main.malu"), 2));
+
+        let error = assemble("nop;\n%include \"broken.malu\";").map(|_| ()).unwrap_err();
+        assert!(matches!(error.kind, AssembleErrorKind::UnknownOperation));
+        assert!(error.file.as_deref().unwrap().ends_with("broken.malu"));
+        assert_eq!((error.line, error.column), (1, 2));
+        assert!(error.render("nop;\n  bogus;", None).ends_with("2 |   bogus;\n  |   ^^^^^"));
+
+        assert!(matches!(
+            assemble("%include \"cycle_a.malu\";").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::IncludeCycle(_)
+        ));
+        assert!(matches!(
+            assemble("%include \"missing.malu\";").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::IncludeNotFound(_)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn comments() {
+        let code = "
+            // leading comment; #1;
+            #1; /* inline */ #2;
+            /* spans
+               two lines */
+            add; // trailing
+            end;
+        ";
+        assert_eq!(Parser::parse(code).unwrap().code, Parser::parse("#1; #2; add; end;").unwrap().code);
+
+        let error = Parser::parse("/* a\nb */ nop;\n#@missing;").map(|_| ()).unwrap_err();
+        assert_eq!((error.line, error.column), (2, 0));
+        assert!(matches!(
+            Parser::parse("nop; /* open").map(|_| ()).unwrap_err().kind,
+            AssembleErrorKind::MissingDelimiter
+        ));
+
+        //NOTE(joh): Macro names and separators inside comments are not expanded
+        let code = "%macro m\n#1; // m;\n%end\nm; /* m; */ end;";
+        assert_eq!(Parser::parse(code).unwrap().code, Parser::parse("#1; end;").unwrap().code);
+    }
+    #[test]
+    fn dead_code() {
+        let code = "#1; #@skip; jmp;\n#2; drop;\n:skip: #@done; jmp;\n:unused: #3;\ndrop;\n:done: end;";
+        let result = Parser::parse(code).unwrap();
+        let warnings = result.warnings.iter().map(|w| (w.line, w.kind.clone())).collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            [
+                (1, AssembleWarningKind::UnreachableCode { ops: 2 }),
+                (3, AssembleWarningKind::UnusedLabel("unused".to_string())),
+                (3, AssembleWarningKind::UnreachableCode { ops: 2 }),
+            ]
+        );
+        assert_eq!(&code[result.warnings[1].span.clone()], ":unused:");
+
+        let mut parser = Parser::new();
+        parser.set_strip_dead_code(true);
+        let stripped = parser.assemble(code).unwrap();
+        assert_eq!(stripped.warnings, result.warnings);
+        let expected = Parser::parse("#1; #@skip; jmp; :skip: #@done; jmp; :done: end;").unwrap();
+        assert_eq!(stripped.code, expected.code);
+
+        //NOTE(joh): Functions, exports and labels only used as values count as reachable
+        let code = ".func f 0 0; return; .export g; :g: return; :h: return; :__ENTRY__: #@h; drop; end;";
+        assert!(Parser::parse(code).unwrap().warnings.is_empty());
+    }
 
+    //NOTE(joh): Found by the fuzzer, these used to panic or loop forever
+    #[test]
+    fn malformed_input() {
+        for code in ["*", "#咐;", "#;", "#0x;", "nop; ;", "local_get 0x;"] {
+            assert!(Parser::parse(code).is_err(), "{code}");
+        }
+    }
 }
@@ -0,0 +1,334 @@
+use core::fmt::Display;
+
+use crate::{
+    asm::{opcode, BytecodeInfo, RawArg, RawOp, DATA_START, ENTRY_LABEL_NAME},
+    function::{encode_function_table, Function},
+    prelude::*,
+    validate::{validate, ValidationError},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    UnknownLabel(String),
+    LabelAlreadyExists(String),
+    Invalid(ValidationError),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BuildError::UnknownLabel(label) => write!(f, "unknown label `{label}`"),
+            BuildError::LabelAlreadyExists(label) => write!(f, "label `{label}` is already defined"),
+            BuildError::Invalid(e) => write!(f, "invalid bytecode: {e}"),
+        }
+    }
+}
+
+impl core::error::Error for BuildError {}
+
+impl From<ValidationError> for BuildError {
+    fn from(value: ValidationError) -> Self {
+        BuildError::Invalid(value)
+    }
+}
+
+//NOTE(joh): An op whose immediate may be a label that is only known in `build`
+#[derive(Debug, Clone)]
+enum Item {
+    Op(RawOp),
+    Label(String),
+    //NOTE(joh): `const` of the address of a label or data entry
+    ConstLabel(String),
+    Table { targets: Vec<String>, default: String },
+}
+
+//NOTE(joh): Builds bytecode from Rust instead of formatting assembly, e.g.
+//
+//    let mut b = ProgramBuilder::new();
+//    b.const_(5).label("loop").const_(1).sub().dup().jmp_if("loop").end();
+//    let bytecode = b.build()?;
+//
+//Labels are names like in the assembler and may be used before they are defined. Code starts at
+//`__ENTRY__` if it is defined. Ops without a method are added with `op`, `op_u8` and `op_u32`.
+//`build` resolves the labels and validates the bytecode.
+#[derive(Debug, Clone, Default)]
+pub struct ProgramBuilder {
+    items: Vec<Item>,
+    data: Vec<u8>,
+    data_labels: Vec<(String, u32)>,
+    functions: Vec<(String, u8, Option<u8>)>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, op: RawOp) -> &mut Self {
+        self.items.push(Item::Op(op));
+        self
+    }
+
+    pub fn op(&mut self, opcode: u8) -> &mut Self {
+        self.push(RawOp { opcode, arg: None })
+    }
+
+    //NOTE(joh): Ops with a register immediate, e.g. `local_get`
+    pub fn op_u8(&mut self, opcode: u8, arg: u8) -> &mut Self {
+        self.push(RawOp { opcode, arg: Some(RawArg::Register(arg)) })
+    }
+
+    pub fn op_u32(&mut self, opcode: u8, arg: u32) -> &mut Self {
+        self.push(RawOp { opcode, arg: Some(RawArg::Num(arg)) })
+    }
+
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        self.items.push(Item::Label(name.to_string()));
+        self
+    }
+
+    //NOTE(joh): A label that goes into the function table, like `.func`
+    pub fn function(&mut self, name: &str, arity: u8, returns: Option<u8>) -> &mut Self {
+        self.functions.push((name.to_string(), arity, returns));
+        self.label(name)
+    }
+
+    //NOTE(joh): Appended to the data section, `const_label` gets its address
+    pub fn data(&mut self, name: &str, bytes: &[u8]) -> &mut Self {
+        self.data_labels.push((name.to_string(), self.data.len() as u32));
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn const_(&mut self, value: u32) -> &mut Self {
+        self.op_u32(opcode::Const, value)
+    }
+
+    pub fn const_64(&mut self, value: u64) -> &mut Self {
+        self.push(RawOp { opcode: opcode::Const64, arg: Some(RawArg::Wide(value)) })
+    }
+
+    pub fn const_label(&mut self, name: &str) -> &mut Self {
+        self.items.push(Item::ConstLabel(name.to_string()));
+        self
+    }
+
+    pub fn add(&mut self) -> &mut Self {
+        self.op(opcode::Add)
+    }
+
+    pub fn sub(&mut self) -> &mut Self {
+        self.op(opcode::Sub)
+    }
+
+    pub fn mul(&mut self) -> &mut Self {
+        self.op(opcode::Mul)
+    }
+
+    pub fn mul_wrap(&mut self) -> &mut Self {
+        self.op(opcode::MulWrap)
+    }
+
+    pub fn eq(&mut self) -> &mut Self {
+        self.op(opcode::Eq)
+    }
+
+    pub fn lt(&mut self) -> &mut Self {
+        self.op(opcode::Lt)
+    }
+
+    pub fn dup(&mut self) -> &mut Self {
+        self.op(opcode::Dup)
+    }
+
+    pub fn drop(&mut self) -> &mut Self {
+        self.op(opcode::Drop)
+    }
+
+    pub fn local_get(&mut self, slot: u8) -> &mut Self {
+        self.op_u8(opcode::LocalGet, slot)
+    }
+
+    pub fn local_set(&mut self, slot: u8) -> &mut Self {
+        self.op_u8(opcode::LocalSet, slot)
+    }
+
+    pub fn push_arg(&mut self) -> &mut Self {
+        self.op(opcode::PushArg)
+    }
+
+    pub fn syscall(&mut self) -> &mut Self {
+        self.op(opcode::Syscall)
+    }
+
+    pub fn jmp(&mut self, label: &str) -> &mut Self {
+        self.const_label(label).op(opcode::Jmp)
+    }
+
+    pub fn jmp_if(&mut self, label: &str) -> &mut Self {
+        self.const_label(label).op(opcode::JmpIf)
+    }
+
+    pub fn call(&mut self, label: &str) -> &mut Self {
+        self.const_label(label).op(opcode::Call)
+    }
+
+    pub fn br_table(&mut self, targets: &[&str], default: &str) -> &mut Self {
+        self.items.push(Item::Table {
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            default: default.to_string(),
+        });
+        self
+    }
+
+    pub fn return_(&mut self) -> &mut Self {
+        self.op(opcode::Return)
+    }
+
+    pub fn end(&mut self) -> &mut Self {
+        self.op(opcode::End)
+    }
+
+    fn resolve(&self, labels: &HashMap<String, u32>, name: &str) -> Result<u32, BuildError> {
+        labels.get(name).copied().ok_or_else(|| BuildError::UnknownLabel(name.to_string()))
+    }
+
+    //NOTE(joh): The sizes of all ops are known up front, so one pass finds the labels and a
+    //second one fills them in
+    pub fn build(&self) -> Result<Box<[u8]>, BuildError> {
+        let placeholder = |item: &Item| match item {
+            Item::Op(op) => Some(op.clone()),
+            Item::Label(_) => None,
+            Item::ConstLabel(_) => Some(RawOp { opcode: opcode::Const, arg: Some(RawArg::Num(0)) }),
+            Item::Table { targets, .. } => Some(RawOp {
+                opcode: opcode::BrTable,
+                arg: Some(RawArg::Table { targets: vec![0; targets.len()].into(), default: 0 }),
+            }),
+        };
+
+        let mut labels = HashMap::new();
+        let mut addr = DATA_START;
+        for item in &self.items {
+            match item {
+                Item::Label(name) => {
+                    if labels.insert(name.clone(), addr).is_some() {
+                        return Err(BuildError::LabelAlreadyExists(name.clone()));
+                    }
+                }
+                item => addr += placeholder(item).map_or(0, |op| op.size_bytes() as u32),
+            }
+        }
+        let code_end = addr;
+        for (name, offset) in &self.data_labels {
+            if labels.insert(name.clone(), code_end + offset).is_some() {
+                return Err(BuildError::LabelAlreadyExists(name.clone()));
+            }
+        }
+
+        let mut ops = Vec::with_capacity(self.items.len());
+        for item in &self.items {
+            let op = match item {
+                Item::Op(op) => op.clone(),
+                Item::Label(_) => continue,
+                Item::ConstLabel(name) => RawOp {
+                    opcode: opcode::Const,
+                    arg: Some(RawArg::Num(self.resolve(&labels, name)?)),
+                },
+                Item::Table { targets, default } => RawOp {
+                    opcode: opcode::BrTable,
+                    arg: Some(RawArg::Table {
+                        targets: targets.iter().map(|t| self.resolve(&labels, t)).collect::<Result<_, _>>()?,
+                        default: self.resolve(&labels, default)?,
+                    }),
+                },
+            };
+            ops.push(op);
+        }
+
+        let info = BytecodeInfo {
+            code_size_bytes: code_end - DATA_START,
+            instruction_count: ops.len() as u32,
+            code_start_offset: labels.get(ENTRY_LABEL_NAME).copied().unwrap_or(DATA_START),
+            data_section_size: self.data.len() as u32,
+        };
+        let mut buffer = info.to_bytecode();
+        ops.iter().for_each(|op| op.encode(&mut buffer));
+        buffer.extend_from_slice(&self.data);
+        if !self.functions.is_empty() {
+            let functions = self
+                .functions
+                .iter()
+                .map(|(name, arity, returns)| Function {
+                    name: name.clone(),
+                    addr: labels[name],
+                    arity: *arity,
+                    returns: *returns,
+                })
+                .collect::<Vec<_>>();
+            encode_function_table(&functions, &mut buffer);
+        }
+        validate(&buffer)?;
+        Ok(buffer.into_boxed_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::Parser, interpreter::Interpreter, syscall::StdHandler};
+
+    #[test]
+    fn build_program() {
+        let code = "
+            .data table bytes 7 0 0 0;
+            .func double 1 1;
+            local_get 0; #2; mul;
+            return;
+            :__ENTRY__:
+            #5; push_arg; #@double; call;
+            :loop:
+            #1; sub; dup; #@loop; jmp_if;
+            #@table; drop;
+            #1; br_table @done @done;
+            :done:
+            end;
+        ";
+        let mut builder = ProgramBuilder::new();
+        builder
+            .data("table", &[7, 0, 0, 0])
+            .function("double", 1, Some(1))
+            .local_get(0)
+            .const_(2)
+            .mul()
+            .return_()
+            .label(ENTRY_LABEL_NAME)
+            .const_(5)
+            .push_arg()
+            .call("double")
+            .label("loop")
+            .const_(1)
+            .sub()
+            .dup()
+            .jmp_if("loop")
+            .const_label("table")
+            .drop()
+            .const_(1)
+            .br_table(&["done"], "done")
+            .label("done")
+            .end();
+        let bytecode = builder.build().unwrap();
+        assert_eq!(bytecode, Parser::parse(code).unwrap().code);
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        assert_eq!(interpreter.run(&mut StdHandler::new(String::new())).unwrap(), [0]);
+
+        assert_eq!(
+            ProgramBuilder::new().jmp("nowhere").build(),
+            Err(BuildError::UnknownLabel("nowhere".to_string()))
+        );
+        assert_eq!(
+            ProgramBuilder::new().label("a").label("a").end().build(),
+            Err(BuildError::LabelAlreadyExists("a".to_string()))
+        );
+        assert!(matches!(ProgramBuilder::new().add().op(0xff).build(), Err(BuildError::Invalid(_))));
+    }
+}
@@ -0,0 +1,121 @@
+//NOTE(joh): `std::time` panics in the browser, `web_time` goes through the JS clocks there
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(target_arch = "wasm32")]
+pub(crate) use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::interpreter::Interpreter;
+
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+pub const DEFAULT_NANOS_PER_OP: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockMode {
+    //NOTE(joh): Time only advances with executed instructions, so runs are reproducible
+    Virtual { nanos_per_op: u64, epoch_secs: u64 },
+    Real,
+}
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    mode: ClockMode,
+    start: Instant,
+    //NOTE(joh): Virtual time spent in `sleep`, real sleeps show up in the elapsed time instead
+    slept_nanos: u64,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::real()
+    }
+}
+
+impl Clock {
+    pub fn real() -> Self {
+        Self {
+            mode: ClockMode::Real,
+            start: Instant::now(),
+            slept_nanos: 0,
+        }
+    }
+
+    pub fn virtualized(nanos_per_op: u64, epoch_secs: u64) -> Self {
+        Self {
+            mode: ClockMode::Virtual { nanos_per_op, epoch_secs },
+            start: Instant::now(),
+            slept_nanos: 0,
+        }
+    }
+
+    pub fn mode(&self) -> ClockMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ClockMode) {
+        self.mode = mode;
+        self.start = Instant::now();
+        self.slept_nanos = 0;
+    }
+
+    pub fn monotonic_nanos(&self, interpreter: &Interpreter) -> u64 {
+        match self.mode {
+            ClockMode::Virtual { nanos_per_op, .. } => {
+                interpreter.executed_ops.saturating_mul(nanos_per_op).saturating_add(self.slept_nanos)
+            }
+            ClockMode::Real => self.start.elapsed().as_nanos() as u64,
+        }
+    }
+
+    pub fn monotonic_millis(&self, interpreter: &Interpreter) -> u64 {
+        self.monotonic_nanos(interpreter) / NANOS_PER_MILLI
+    }
+
+    //NOTE(joh): A virtual clock just moves forward. The browser can't block, there the caller
+    //has to suspend the program instead.
+    pub fn sleep(&mut self, millis: u64) {
+        match self.mode {
+            ClockMode::Virtual { .. } => {
+                self.slept_nanos = self.slept_nanos.saturating_add(millis.saturating_mul(NANOS_PER_MILLI));
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            ClockMode::Real => std::thread::sleep(std::time::Duration::from_millis(millis)),
+            #[cfg(target_arch = "wasm32")]
+            ClockMode::Real => {}
+        }
+    }
+
+    pub fn wall_clock_secs(&self, interpreter: &Interpreter) -> u64 {
+        match self.mode {
+            ClockMode::Virtual { epoch_secs, .. } => epoch_secs + self.monotonic_nanos(interpreter) / NANOS_PER_SEC,
+            ClockMode::Real => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, testing::NoSyscalls};
+
+    #[test]
+    fn virtual_clock_follows_executed_ops() {
+        let bytecode = asm::Parser::parse("#1; #2; add; drop; end;").unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let clock = Clock::virtualized(NANOS_PER_SEC, 1000);
+
+        assert_eq!(clock.monotonic_nanos(&interpreter), 0);
+        assert_eq!(clock.wall_clock_secs(&interpreter), 1000);
+
+        interpreter.run(&mut NoSyscalls()).unwrap();
+        assert_eq!(interpreter.executed_ops, 5);
+        assert_eq!(clock.monotonic_millis(&interpreter), 5000);
+        assert_eq!(clock.wall_clock_secs(&interpreter), 1005);
+
+        let mut clock = clock;
+        clock.sleep(250);
+        assert_eq!(clock.monotonic_millis(&interpreter), 5250);
+    }
+}
@@ -0,0 +1,270 @@
+use alloc::sync::Arc;
+
+use crate::{gas::GasTable, interpreter::MIN_HEAP_SIZE, signing::SignaturePolicy};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallGroup {
+    Console,
+    Files,
+    Network,
+    Clock,
+    Random,
+    Display,
+    //NOTE(joh): `Alloc`, `Free` and `MemorySize`, the heap grows the memory
+    Memory,
+    //NOTE(joh): `Spawn` and `Join`, see `worker::Spawner`
+    Threads,
+}
+
+impl SyscallGroup {
+    pub const ALL: [SyscallGroup; 8] = [
+        SyscallGroup::Console,
+        SyscallGroup::Files,
+        SyscallGroup::Network,
+        SyscallGroup::Clock,
+        SyscallGroup::Random,
+        SyscallGroup::Display,
+        SyscallGroup::Memory,
+        SyscallGroup::Threads,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyscallGroup::Console => "console",
+            SyscallGroup::Files => "files",
+            SyscallGroup::Network => "network",
+            SyscallGroup::Clock => "clock",
+            SyscallGroup::Random => "random",
+            SyscallGroup::Display => "display",
+            SyscallGroup::Memory => "memory",
+            SyscallGroup::Threads => "threads",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub console: bool,
+    pub files: bool,
+    pub network: bool,
+    pub clock: bool,
+    pub random: bool,
+    pub display: bool,
+    pub memory: bool,
+    pub threads: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Capabilities {
+    pub const fn all() -> Self {
+        Self {
+            console: true,
+            files: true,
+            network: true,
+            clock: true,
+            random: true,
+            display: true,
+            memory: true,
+            threads: true,
+        }
+    }
+
+    pub const fn none() -> Self {
+        Self {
+            console: false,
+            files: false,
+            network: false,
+            clock: false,
+            random: false,
+            display: false,
+            memory: false,
+            threads: false,
+        }
+    }
+
+    pub fn flag_mut(&mut self, group: SyscallGroup) -> &mut bool {
+        match group {
+            SyscallGroup::Console => &mut self.console,
+            SyscallGroup::Files => &mut self.files,
+            SyscallGroup::Network => &mut self.network,
+            SyscallGroup::Clock => &mut self.clock,
+            SyscallGroup::Random => &mut self.random,
+            SyscallGroup::Display => &mut self.display,
+            SyscallGroup::Memory => &mut self.memory,
+            SyscallGroup::Threads => &mut self.threads,
+        }
+    }
+
+    pub fn allows(&self, group: SyscallGroup) -> bool {
+        match group {
+            SyscallGroup::Console => self.console,
+            SyscallGroup::Files => self.files,
+            SyscallGroup::Network => self.network,
+            SyscallGroup::Clock => self.clock,
+            SyscallGroup::Random => self.random,
+            SyscallGroup::Display => self.display,
+            SyscallGroup::Memory => self.memory,
+            SyscallGroup::Threads => self.threads,
+        }
+    }
+
+    pub fn with(mut self, group: SyscallGroup, allowed: bool) -> Self {
+        *self.flag_mut(group) = allowed;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKind {
+    Fuel,
+    Memory,
+    Syscalls,
+    OutputBytes,
+    //NOTE(joh): Only counted with a gas table, see `InterpreterConfig::with_gas_table`
+    Gas,
+}
+
+//NOTE(joh): `None` means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub fuel: Option<u64>,
+    pub memory_bytes: Option<u64>,
+    pub syscalls: Option<u64>,
+    pub output_bytes: Option<u64>,
+    pub gas: Option<u64>,
+}
+
+impl Quota {
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn limit(&self, kind: QuotaKind) -> Option<u64> {
+        match kind {
+            QuotaKind::Fuel => self.fuel,
+            QuotaKind::Memory => self.memory_bytes,
+            QuotaKind::Syscalls => self.syscalls,
+            QuotaKind::OutputBytes => self.output_bytes,
+            QuotaKind::Gas => self.gas,
+        }
+    }
+
+    pub fn allows(&self, kind: QuotaKind, used: u64) -> bool {
+        self.limit(kind).is_none_or(|limit| used <= limit)
+    }
+}
+
+pub const DEFAULT_MEMORY_SIZE: usize = MIN_HEAP_SIZE;
+pub const DEFAULT_MAX_VALUE_STACK: usize = 1 << 20;
+pub const DEFAULT_GLOBALS: usize = 64;
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 4096;
+//NOTE(joh): Global ids are a single byte in the bytecode
+pub const MAX_GLOBALS: usize = u8::MAX as usize + 1;
+//NOTE(joh): The last global holds the stack pointer of the memory stack, see `push_mem`
+pub const STACK_POINTER_GLOBAL: u8 = u8::MAX;
+pub const DEFAULT_MEMORY_STACK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct InterpreterConfig {
+    pub capabilities: Capabilities,
+    pub quota: Quota,
+    pub signature_policy: SignaturePolicy,
+    //NOTE(joh): Minimum size of the memory in bytes. Modules with a larger image keep it.
+    pub memory_size: usize,
+    //NOTE(joh): In values, checked before every op
+    pub max_value_stack: usize,
+    pub globals: usize,
+    //NOTE(joh): In frames, including the one of the entry point
+    pub max_call_depth: usize,
+    //NOTE(joh): Bytes reserved behind the image for `push_mem`, 0 leaves out the memory stack
+    pub memory_stack_size: usize,
+    //NOTE(joh): Make the header and the code of loaded programs read-only, turn it off for
+    //programs that modify their own code
+    pub protect_code: bool,
+    //NOTE(joh): Run `validate::validate` before loading bytecode, so malformed programs fail
+    //before they start instead of in the middle of a run
+    pub validate: bool,
+    //NOTE(joh): Every op adds its cost to `Interpreter::gas_used`, `None` counts no gas
+    pub gas_table: Option<Arc<GasTable>>,
+}
+
+impl Default for InterpreterConfig {
+    fn default() -> Self {
+        Self {
+            capabilities: Capabilities::default(),
+            quota: Quota::default(),
+            signature_policy: SignaturePolicy::default(),
+            memory_size: DEFAULT_MEMORY_SIZE,
+            max_value_stack: DEFAULT_MAX_VALUE_STACK,
+            globals: DEFAULT_GLOBALS,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            memory_stack_size: DEFAULT_MEMORY_STACK_SIZE,
+            protect_code: true,
+            validate: false,
+            gas_table: None,
+        }
+    }
+}
+
+impl InterpreterConfig {
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    pub fn with_signature_policy(mut self, signature_policy: SignaturePolicy) -> Self {
+        self.signature_policy = signature_policy;
+        self
+    }
+
+    pub fn with_memory_size(mut self, bytes: usize) -> Self {
+        self.memory_size = bytes;
+        self
+    }
+
+    pub fn with_max_value_stack(mut self, values: usize) -> Self {
+        self.max_value_stack = values;
+        self
+    }
+
+    pub fn with_max_call_depth(mut self, frames: usize) -> Self {
+        self.max_call_depth = frames;
+        self
+    }
+
+    pub fn with_memory_stack_size(mut self, bytes: usize) -> Self {
+        self.memory_stack_size = bytes;
+        self
+    }
+
+    pub fn with_code_protection(mut self, protect: bool) -> Self {
+        self.protect_code = protect;
+        self
+    }
+
+    pub fn with_validation(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    pub fn with_gas_table(mut self, table: GasTable) -> Self {
+        self.gas_table = Some(Arc::new(table));
+        self
+    }
+
+    //NOTE(joh): Clamped to `MAX_GLOBALS`
+    pub fn with_globals(mut self, count: usize) -> Self {
+        self.globals = count.min(MAX_GLOBALS);
+        self
+    }
+}
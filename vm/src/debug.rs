@@ -0,0 +1,126 @@
+use core::fmt::Display;
+
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LineEntry {
+    pub addr: u32,
+    pub line: u32,
+    //NOTE(joh): Column of the op in the line, starts at 1 like `line`
+    pub column: u32,
+    //NOTE(joh): 0 is `DebugInfo::file`, everything else indexes `DebugInfo::includes` starting at 1
+    pub file: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLoc<'a> {
+    pub file: Option<&'a str>,
+    pub line: u32,
+    //NOTE(joh): Closest label at or before the address and the offset from it
+    pub label: Option<(&'a str, u32)>,
+}
+
+impl Display for SourceLoc<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.file.unwrap_or("<source>"), self.line)?;
+        if let Some((label, offset)) = self.label {
+            write!(f, " (:{label}+0x{offset:02x})")?;
+        }
+        Ok(())
+    }
+}
+
+//NOTE(joh): Addresses are absolute memory addresses, lines start at 1. Both tables are
+//sorted by address so lookups can binary search.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DebugInfo {
+    pub file: Option<String>,
+    //NOTE(joh): Files pulled in with `%include`, in the order they were first included
+    pub includes: Vec<String>,
+    pub lines: Vec<LineEntry>,
+    pub labels: Vec<(String, u32)>,
+    //NOTE(joh): Labels of `.data` declarations, kept apart since they don't mark code
+    pub data_labels: Vec<(String, u32)>,
+    pub code_end: u32,
+    //NOTE(joh): Names of `.global`s by index
+    pub globals: Vec<String>,
+    //NOTE(joh): Names of `.local`s by slot. A scope starts at its address and reaches up to the
+    //next one, each `.func` opens a new scope.
+    pub locals: Vec<(u32, Vec<String>)>,
+}
+
+impl DebugInfo {
+    pub fn with_file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    //NOTE(joh): Entry of the op at `pc`, addresses inside an op map to the op
+    pub fn resolve_line(&self, pc: u32) -> Option<LineEntry> {
+        if pc >= self.code_end {
+            return None;
+        }
+        let index = self.lines.partition_point(|e| e.addr <= pc).checked_sub(1)?;
+        Some(self.lines[index])
+    }
+
+    pub fn resolve_pc(&self, pc: u32) -> Option<SourceLoc<'_>> {
+        let entry = self.resolve_line(pc)?;
+        Some(SourceLoc {
+            file: match entry.file {
+                0 => self.file.as_deref(),
+                i => self.includes.get(i as usize - 1).map(String::as_str),
+            },
+            line: entry.line,
+            label: self.resolve_label(pc),
+        })
+    }
+
+    //NOTE(joh): Names of the locals of the function running at `pc`, slots past the end are unnamed
+    pub fn local_names(&self, pc: u32) -> &[String] {
+        match self.locals.partition_point(|(addr, _)| *addr <= pc).checked_sub(1) {
+            Some(index) if pc < self.code_end => &self.locals[index].1,
+            _ => &[],
+        }
+    }
+
+    pub fn resolve_label(&self, pc: u32) -> Option<(&str, u32)> {
+        let index = self.labels.partition_point(|(_, addr)| *addr <= pc).checked_sub(1)?;
+        let (name, addr) = &self.labels[index];
+        Some((name.as_str(), pc - addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm;
+
+    #[test]
+    fn resolve_pc() {
+        let code = "
+            #1;
+            :inner_loop:
+            nop;
+            local_get 0;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let debug_info = bytecode.debug_info.with_file("loop.malu");
+        let code_start = asm::DATA_START;
+
+        let loc = debug_info.resolve_pc(code_start).unwrap();
+        assert_eq!(loc.line, 2);
+        assert_eq!(loc.label, None);
+
+        let loc = debug_info.resolve_pc(code_start + 6).unwrap();
+        assert_eq!(loc.to_string(), "loop.malu:5 (:inner_loop+0x01)");
+
+        //NOTE(joh): Addresses inside an op map to the op
+        assert_eq!(debug_info.resolve_pc(code_start + 7).unwrap().line, 5);
+        assert_eq!(debug_info.resolve_pc(code_start + 8).unwrap().line, 6);
+        assert_eq!(debug_info.resolve_pc(code_start + 9), None);
+        assert_eq!(debug_info.resolve_pc(0), None);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    debug::{DebugInfo, LineEntry},
+    mem::{push_le, read_le_from},
+    prelude::*,
+    section::split_section,
+};
+
+pub const DEBUG_INFO_MAGIC: [u8; 4] = *b"mdbg";
+
+//NOTE(joh): The section is optional and comes after the function table, right before the
+//signature. It holds the source files (the main file first, empty if unknown), the line table
+//and the end of the code, then the names of globals and locals, followed by the size of the
+//content and `DEBUG_INFO_MAGIC`. Labels are not part of it. Sections written before the names
+//existed end after the code end.
+pub fn encode_debug_info(info: &DebugInfo, buffer: &mut Vec<u8>) {
+    let start = buffer.len();
+    let files = core::iter::once(info.file.as_deref().unwrap_or_default()).chain(info.includes.iter().map(String::as_str));
+    push_le(buffer, info.includes.len() as u32 + 1);
+    for file in files {
+        push_le(buffer, file.len() as u32);
+        buffer.extend_from_slice(file.as_bytes());
+    }
+    push_le(buffer, info.lines.len() as u32);
+    for entry in &info.lines {
+        push_le(buffer, entry.addr);
+        push_le(buffer, entry.line);
+        push_le(buffer, entry.column);
+        push_le(buffer, entry.file);
+    }
+    push_le(buffer, info.code_end);
+    push_names(buffer, &info.globals);
+    push_le(buffer, info.locals.len() as u32);
+    for (addr, names) in &info.locals {
+        push_le(buffer, *addr);
+        push_names(buffer, names);
+    }
+
+    let size = (buffer.len() - start) as u32;
+    push_le(buffer, size);
+    buffer.extend_from_slice(&DEBUG_INFO_MAGIC);
+}
+
+fn push_names(buffer: &mut Vec<u8>, names: &[String]) {
+    push_le(buffer, names.len() as u32);
+    for name in names {
+        push_le(buffer, name.len() as u32);
+        buffer.extend_from_slice(name.as_bytes());
+    }
+}
+
+fn decode_name(reader: &mut &[u8]) -> Result<String, crate::io::Error> {
+    let len: u32 = read_le_from(reader)?;
+    let name = reader
+        .split_off(..len as usize)
+        .ok_or(crate::io::ErrorKind::UnexpectedEof)?;
+    String::from_utf8(name.to_vec()).map_err(|_| crate::io::ErrorKind::InvalidData.into())
+}
+
+fn decode_names(reader: &mut &[u8]) -> Result<Vec<String>, crate::io::Error> {
+    let count: u32 = read_le_from(reader)?;
+    (0..count).map(|_| decode_name(reader)).collect()
+}
+
+pub fn split_debug_info(bytecode: &[u8]) -> (&[u8], Option<DebugInfo>) {
+    split_section(bytecode, DEBUG_INFO_MAGIC, |mut section| match decode_debug_info(&mut section) {
+        Ok(info) if section.is_empty() => Some(info),
+        _ => None,
+    })
+}
+
+fn decode_debug_info(reader: &mut &[u8]) -> Result<DebugInfo, crate::io::Error> {
+    let file_count: u32 = read_le_from(reader)?;
+    let mut files = Vec::new();
+    for _ in 0..file_count {
+        files.push(decode_name(reader)?);
+    }
+    let mut files = files.into_iter();
+    let file = files.next().filter(|f| !f.is_empty());
+
+    let line_count: u32 = read_le_from(reader)?;
+    let mut lines = Vec::new();
+    for _ in 0..line_count {
+        lines.push(LineEntry {
+            addr: read_le_from(reader)?,
+            line: read_le_from(reader)?,
+            column: read_le_from(reader)?,
+            file: read_le_from(reader)?,
+        });
+    }
+
+    let code_end = read_le_from(reader)?;
+
+    let mut globals = Vec::new();
+    let mut locals = Vec::new();
+    if !reader.is_empty() {
+        globals = decode_names(reader)?;
+        let scope_count: u32 = read_le_from(reader)?;
+        for _ in 0..scope_count {
+            locals.push((read_le_from(reader)?, decode_names(reader)?));
+        }
+    }
+
+    Ok(DebugInfo {
+        file,
+        includes: files.collect(),
+        lines,
+        code_end,
+        globals,
+        locals,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, function::split_function_table, interpreter::Interpreter, syscall::StdHandler};
+
+    #[test]
+    fn embedded_line_table() {
+        let code = "
+            #2; push_arg; #@double; call;
+            end;
+            .func double 1;
+            local_get 0;   local_get 0; add;
+            return;
+        ";
+        let mut parser = asm::Parser::new();
+        parser.set_source_path("double.malu");
+        parser.set_emit_debug_info(true);
+        let result = parser.assemble(code).unwrap();
+
+        let (rest, info) = split_debug_info(&result.code);
+        let info = info.unwrap();
+        assert_eq!(info.file.as_deref(), Some("double.malu"));
+        assert_eq!(info.lines, result.debug_info.lines);
+        assert_eq!(info.code_end, result.debug_info.code_end);
+        assert!(info.labels.is_empty());
+
+        let second_get = asm::DATA_START + 13 + 2;
+        let loc = info.resolve_pc(second_get).unwrap();
+        assert_eq!((loc.file, loc.line), (Some("double.malu"), 5));
+        assert_eq!(info.lines.iter().find(|e| e.addr == second_get).unwrap().column, 28);
+
+        //NOTE(joh): The rest of the bytecode reads the same as without the section
+        let plain = asm::Parser::parse(code).unwrap();
+        assert_eq!(rest, &plain.code[..]);
+        assert_eq!(split_function_table(&result.code).1, split_function_table(&plain.code).1);
+        let mut interpreter = Interpreter::from_bytecode(&result.code).unwrap();
+        assert_eq!(interpreter.run(&mut StdHandler::new(String::new())).unwrap(), &[4]);
+        assert_eq!(split_debug_info(&plain.code), (&plain.code[..], None));
+    }
+
+    #[test]
+    fn named_slots() {
+        let code = "
+            .global total = 0;
+            .local scratch;
+            #3; local_set scratch;
+            #2; push_arg; local_get scratch; push_arg; #@mul; call; global_set total;
+            end;
+            .func mul 2;
+            .local a; .local b;
+            local_get a; local_get b; mul;
+            return;
+        ";
+        let plain = "
+            .global total = 0;
+            #3; local_set 0;
+            #2; push_arg; local_get 0; push_arg; #@mul; call; global_set 0;
+            end;
+            .func mul 2;
+            local_get 0; local_get 1; mul;
+            return;
+        ";
+        let mut parser = asm::Parser::new();
+        parser.set_emit_debug_info(true);
+        let result = parser.assemble(code).unwrap();
+        assert_eq!(split_debug_info(&result.code).0, &asm::Parser::parse(plain).unwrap().code[..]);
+
+        let info = split_debug_info(&result.code).1.unwrap();
+        assert_eq!(info.globals, ["total"]);
+        let mul = asm::DATA_START + 25;
+        assert_eq!(info.local_names(asm::DATA_START), ["scratch"]);
+        assert_eq!(info.local_names(mul + 2), ["a", "b"]);
+        assert!(info.local_names(info.code_end).is_empty());
+
+        let error = |code| asm::Parser::parse(code).map(|_| ()).unwrap_err().kind;
+        assert!(matches!(error(".func f 0; .local a; .func g 0; local_get a;"), asm::AssembleErrorKind::UnknownLocal(_)));
+        assert!(matches!(error(".local a; .local a;"), asm::AssembleErrorKind::LocalAlreadyDefined(_)));
+    }
+}
@@ -0,0 +1,619 @@
+use alloc::sync::Arc;
+
+use crate::{
+    io::Cursor,
+    asm::{opcode, BytecodeInfo, RawArg, DATA_START},
+    config::QuotaKind,
+    interpreter::{Frame, Interpreter, InterpreterErrorType, SyscallHandler, MAX_ARGS},
+    module::InstructionMap,
+    parse::{try_parse_ops_from_bytecode, MaybeRawOp},
+    prelude::*,
+};
+
+const NO_OP: u32 = u32::MAX;
+
+//NOTE(joh): An op with its immediate already read. Registers and numbers are zero extended,
+//tables are not decoded, `br_table` always goes through `exec_next_op`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedOp {
+    pub opcode: u8,
+    pub imm: u64,
+    pub addr: u32,
+}
+
+//NOTE(joh): The code section decoded once, ops are found by index instead of reading memory.
+//The bytes it was decoded from are kept so writes to the code can be noticed.
+#[derive(Debug, Clone)]
+pub struct DecodedCode {
+    ops: Vec<DecodedOp>,
+    //NOTE(joh): Op index per address starting at `DATA_START`, `NO_OP` inside immediates
+    index: Vec<u32>,
+    bytes: Vec<u8>,
+    //NOTE(joh): Where the decoded ops end, running past the last op continues there
+    end: u32,
+    instructions: Arc<InstructionMap>,
+}
+
+impl DecodedCode {
+    pub fn decode(interpreter: &Interpreter) -> Self {
+        let code_size = BytecodeInfo::decode(&mut Cursor::new(&interpreter.bytecode[..]))
+            .map_or(0, |info| info.code_size_bytes as usize);
+        let bytes = interpreter
+            .memory
+            .slice(DATA_START as usize, code_size)
+            .map(|bytes| bytes.into_owned())
+            .unwrap_or_default();
+
+        let mut ops = Vec::new();
+        let mut index = vec![NO_OP; bytes.len()];
+        let mut addr = DATA_START;
+        for op in try_parse_ops_from_bytecode(&mut Cursor::new(&bytes[..])) {
+            let Ok(op) = op else {
+                break;
+            };
+            index[(addr - DATA_START) as usize] = ops.len() as u32;
+            let (opcode, imm, size) = match op {
+                MaybeRawOp::Op(raw_op) => {
+                    let imm = match &raw_op.arg {
+                        Some(RawArg::Register(r)) => *r as u64,
+                        Some(RawArg::Num(n)) => *n as u64,
+                        Some(RawArg::Wide(n)) => *n,
+                        Some(RawArg::Table { .. }) | None => 0,
+                    };
+                    (raw_op.opcode, imm, raw_op.size_bytes() as u32)
+                }
+                MaybeRawOp::Unknown(opcode) => (opcode, 0, 1),
+            };
+            ops.push(DecodedOp { opcode, imm, addr });
+            addr += size;
+        }
+        Self {
+            ops,
+            index,
+            bytes,
+            end: addr,
+            instructions: interpreter.instructions.clone(),
+        }
+    }
+
+    pub fn ops(&self) -> &[DecodedOp] {
+        &self.ops
+    }
+
+    pub fn index_of(&self, addr: u32) -> Option<usize> {
+        let slot = *self.index.get(addr.checked_sub(DATA_START)? as usize)?;
+        (slot != NO_OP).then_some(slot as usize)
+    }
+
+    fn overlaps(&self, addr: u32, len: u32) -> bool {
+        addr < DATA_START + self.bytes.len() as u32 && addr.saturating_add(len) > DATA_START
+    }
+
+    //NOTE(joh): Hot reloading and stores into the code invalidate the decoded ops
+    pub fn is_current(&self, interpreter: &Interpreter) -> bool {
+        Arc::ptr_eq(&self.instructions, &interpreter.instructions)
+            && interpreter
+                .memory
+                .slice(DATA_START as usize, self.bytes.len())
+                .is_some_and(|bytes| *bytes == self.bytes[..])
+    }
+}
+
+enum Flow {
+    Next,
+    Jump(u32),
+    Stop,
+    //NOTE(joh): Not handled here or it would fail, `exec_next_op` runs it again from scratch.
+    //Nothing may have been changed before returning this.
+    Slow,
+}
+
+//NOTE(joh): The checks `exec_next_op` does before every op, computed once and refreshed after
+//every op that ran through it since a syscall handler can change the config
+struct Limits {
+    ops: u64,
+    value_stack: usize,
+    memory_ok: bool,
+}
+
+macro_rules! decoded_binop {
+    ($self: ident, $a: ident, $b: ident, $op: expr) => {{
+        let Some(&[$a, $b]) = $self.value_stack.last_chunk::<2>() else {
+            return Flow::Slow;
+        };
+        $self.value_stack.pop();
+        *$self.value_stack.last_mut().unwrap() = $op as u32;
+        Flow::Next
+    }};
+}
+
+macro_rules! decoded_load {
+    ($self: ident, $op: ident, $t: ty, $convert: expr) => {{
+        let Some(addr) = $self.value_stack.last().and_then(|base| ($op.imm as u32).checked_add(*base)) else {
+            return Flow::Slow;
+        };
+        let Some(value) = $self.memory.read_le::<$t>(addr as usize) else {
+            return Flow::Slow;
+        };
+        *$self.value_stack.last_mut().unwrap() = $convert(value);
+        Flow::Next
+    }};
+}
+
+macro_rules! decoded_store {
+    ($self: ident, $code: ident, $op: ident, $t: ty) => {{
+        let Some(&[base, value]) = $self.value_stack.last_chunk::<2>() else {
+            return Flow::Slow;
+        };
+        let Some(addr) = base.checked_add($op.imm as u32) else {
+            return Flow::Slow;
+        };
+        if $code.overlaps(addr, size_of::<$t>() as u32)
+            || !$self.is_writable(addr, size_of::<$t>() as u32)
+            || $self.memory.write_le(addr as usize, value as $t).is_none()
+        {
+            return Flow::Slow;
+        }
+        $self.value_stack.truncate($self.value_stack.len() - 2);
+        Flow::Next
+    }};
+}
+
+impl Interpreter {
+    //NOTE(joh): Same results as `run`, but the code is decoded up front and common ops are
+    //executed from the decoded ops. Everything else, and every op that would trap, goes through
+    //`exec_next_op`. History, traces, fuel, gas, profiling, sampling and coverage need every op
+    //to pass through it, with one of them active this is just `run`.
+    pub fn run_decoded(&mut self, syscall_handler: &mut impl SyscallHandler) -> Result<&[u32], InterpreterErrorType> {
+        let mut code = match self.decoded.take() {
+            Some(code) if code.is_current(self) => code,
+            _ => Arc::new(DecodedCode::decode(self)),
+        };
+        self.decoded = Some(code.clone());
+
+        self.running = true;
+        let mut limits = self.decoded_limits();
+        let mut index = code.index_of(self.pc);
+        while self.running {
+            let Some(current) = &limits else {
+                return self.run(syscall_handler);
+            };
+            let op = index.and_then(|i| code.ops.get(i));
+            let flow = match op {
+                Some(op) if self.executed_ops < current.ops
+                    && self.value_stack.len() <= current.value_stack
+                    && current.memory_ok =>
+                {
+                    self.pc = op.addr;
+                    self.exec_decoded_op(op, &code)
+                }
+                _ => Flow::Slow,
+            };
+            match flow {
+                Flow::Next => {
+                    self.executed_ops += 1;
+                    index = index.map(|i| i + 1);
+                    if index == Some(code.ops.len()) {
+                        self.pc = code.end;
+                        index = None;
+                    }
+                }
+                Flow::Jump(addr) => {
+                    self.executed_ops += 1;
+                    self.pc = addr;
+                    index = code.index_of(addr);
+                }
+                Flow::Stop => self.executed_ops += 1,
+                Flow::Slow => {
+                    if let Some(op) = op {
+                        self.pc = op.addr;
+                    }
+                    let writes = matches!(
+                        self.memory.get(self.pc as usize),
+                        Some(
+                            opcode::Syscall
+                                | opcode::CallHost
+                                | opcode::Store8
+                                | opcode::Store16
+                                | opcode::Store32
+                                | opcode::Store64
+                                | opcode::StoreMem8
+                                | opcode::StoreMem32
+                                | opcode::AtomicStore32
+                                | opcode::AtomicRmwAdd
+                                | opcode::AtomicCmpxchg
+                                | opcode::MemCopy
+                                | opcode::MemFill
+                        )
+                    );
+                    self.exec_next_op(syscall_handler)?;
+                    //NOTE(joh): Self-modifying code is decoded again and continues from there
+                    if writes && !code.is_current(self) {
+                        code = Arc::new(DecodedCode::decode(self));
+                        self.decoded = Some(code.clone());
+                    }
+                    limits = self.decoded_limits();
+                    index = code.index_of(self.pc);
+                }
+            }
+        }
+        Ok(&self.value_stack)
+    }
+
+    fn decoded_limits(&self) -> Option<Limits> {
+        if self.history.is_some()
+            || self.has_trace_sink()
+            || self.fuel().is_some()
+            || self.config.gas_table.is_some()
+            || self.profile().is_some()
+            || self.coverage().is_some()
+            || self.execution_trace().is_some()
+            || self.stack_samples().is_some()
+        {
+            return None;
+        }
+        Some(Limits {
+            ops: self.config.quota.limit(QuotaKind::Fuel).unwrap_or(u64::MAX),
+            value_stack: self.config.max_value_stack,
+            memory_ok: self.check_quota(QuotaKind::Memory, self.memory.len() as u64).is_ok(),
+        })
+    }
+
+    fn exec_decoded_op(&mut self, op: &DecodedOp, code: &DecodedCode) -> Flow {
+        match op.opcode {
+            opcode::Nop => Flow::Next,
+            opcode::End => {
+                self.running = false;
+                Flow::Stop
+            }
+            opcode::Drop => match self.value_stack.pop() {
+                Some(_) => Flow::Next,
+                None => Flow::Slow,
+            },
+            opcode::Dup => match self.value_stack.last() {
+                Some(&value) => {
+                    self.value_stack.push(value);
+                    Flow::Next
+                }
+                None => Flow::Slow,
+            },
+            opcode::Swap | opcode::Select => {
+                let len = self.value_stack.len();
+                match op.opcode {
+                    opcode::Swap if len >= 2 => self.value_stack.swap(len - 2, len - 1),
+                    opcode::Select if len >= 3 => {
+                        let cond = self.value_stack.pop().unwrap();
+                        let b = self.value_stack.pop().unwrap();
+                        if cond == 0 {
+                            self.value_stack[len - 3] = b;
+                        }
+                    }
+                    _ => return Flow::Slow,
+                }
+                Flow::Next
+            }
+            opcode::Const | opcode::FConst => {
+                self.value_stack.push(op.imm as u32);
+                Flow::Next
+            }
+            opcode::Const64 => {
+                self.value_stack.push(op.imm as u32);
+                self.value_stack.push((op.imm >> 32) as u32);
+                Flow::Next
+            }
+            opcode::ConstPool => match self.const_pool.get(op.imm as usize) {
+                Some(value) => {
+                    self.value_stack.push(*value);
+                    Flow::Next
+                }
+                None => Flow::Slow,
+            },
+            opcode::LocalGet => match self.return_stack.last().and_then(|f| f.locals.get(op.imm as usize)) {
+                Some(value) => {
+                    self.value_stack.push(*value);
+                    Flow::Next
+                }
+                None => Flow::Slow,
+            },
+            opcode::LocalSet | opcode::LocalTee => {
+                let Some(value) = self.value_stack.last().copied() else {
+                    return Flow::Slow;
+                };
+                let Some(local) = self.return_stack.last_mut().and_then(|f| f.locals.get_mut(op.imm as usize)) else {
+                    return Flow::Slow;
+                };
+                *local = value;
+                if op.opcode == opcode::LocalSet {
+                    self.value_stack.pop();
+                }
+                Flow::Next
+            }
+            opcode::GlobalGet => match self.global_slot(op.imm as u8) {
+                Ok(slot) => {
+                    self.value_stack.push(self.globals[slot]);
+                    Flow::Next
+                }
+                Err(_) => Flow::Slow,
+            },
+            opcode::GlobalSet | opcode::GlobalTee => {
+                let Some(value) = self.value_stack.last().copied() else {
+                    return Flow::Slow;
+                };
+                let Ok(slot) = self.global_slot(op.imm as u8) else {
+                    return Flow::Slow;
+                };
+                self.globals[slot] = value;
+                if op.opcode == opcode::GlobalSet {
+                    self.value_stack.pop();
+                }
+                Flow::Next
+            }
+            opcode::Eqz => match self.value_stack.last_mut() {
+                Some(value) => {
+                    *value = (*value == 0) as u32;
+                    Flow::Next
+                }
+                None => Flow::Slow,
+            },
+            opcode::Clz | opcode::Ctz | opcode::Popcnt | opcode::Bswap | opcode::Bswap16 => {
+                match self.value_stack.last_mut() {
+                    Some(value) => {
+                        *value = match op.opcode {
+                            opcode::Clz => value.leading_zeros(),
+                            opcode::Ctz => value.trailing_zeros(),
+                            opcode::Bswap => value.swap_bytes(),
+                            opcode::Bswap16 => (*value as u16).swap_bytes() as u32,
+                            _ => value.count_ones(),
+                        };
+                        Flow::Next
+                    }
+                    None => Flow::Slow,
+                }
+            }
+            opcode::Eq => decoded_binop!(self, a, b, a == b),
+            opcode::Add => decoded_binop!(self, a, b, a.wrapping_add(b)),
+            opcode::Sub => decoded_binop!(self, a, b, a.wrapping_sub(b)),
+            //NOTE(joh): An overflow traps in the slow path
+            opcode::Mul => match self.value_stack.last_chunk::<2>() {
+                Some(&[a, b]) if (a as i32).checked_mul(b as i32).is_some() => {
+                    decoded_binop!(self, a, b, a.wrapping_mul(b))
+                }
+                _ => Flow::Slow,
+            },
+            opcode::MulWrap => decoded_binop!(self, a, b, a.wrapping_mul(b)),
+            opcode::Lt => decoded_binop!(self, a, b, a < b),
+            opcode::Gt => decoded_binop!(self, a, b, a > b),
+            opcode::Ge => decoded_binop!(self, a, b, a >= b),
+            opcode::Le => decoded_binop!(self, a, b, a <= b),
+            opcode::And => decoded_binop!(self, a, b, a & b),
+            opcode::Or => decoded_binop!(self, a, b, a | b),
+            opcode::Xor => decoded_binop!(self, a, b, a ^ b),
+            opcode::Shiftl => decoded_binop!(self, a, b, a.wrapping_shl(b)),
+            opcode::Shiftr => decoded_binop!(self, a, b, a.wrapping_shr(b)),
+            opcode::ShiftrS => decoded_binop!(self, a, b, (a as i32).wrapping_shr(b)),
+            opcode::Rotl => decoded_binop!(self, a, b, a.rotate_left(b % 32)),
+            opcode::Rotr => decoded_binop!(self, a, b, a.rotate_right(b % 32)),
+            opcode::Load8u => decoded_load!(self, op, u8, |v: u8| v as u32),
+            opcode::Load8s => decoded_load!(self, op, u8, |v: u8| v as i8 as i32 as u32),
+            opcode::Load16u => decoded_load!(self, op, u16, |v: u16| v as u32),
+            opcode::Load16s => decoded_load!(self, op, i16, |v: i16| v as i32 as u32),
+            opcode::Load32u => decoded_load!(self, op, u32, |v: u32| v),
+            opcode::Load32s => decoded_load!(self, op, i32, |v: i32| v as u32),
+            opcode::Store8 => decoded_store!(self, code, op, u8),
+            opcode::Store16 => decoded_store!(self, code, op, u16),
+            opcode::Store32 => decoded_store!(self, code, op, u32),
+            opcode::Jmp => match self.value_stack.last() {
+                Some(&addr) if self.is_jump_target(addr) => {
+                    self.value_stack.pop();
+                    Flow::Jump(addr)
+                }
+                _ => Flow::Slow,
+            },
+            opcode::JmpIf => {
+                let Some(&[cond, addr]) = self.value_stack.last_chunk::<2>() else {
+                    return Flow::Slow;
+                };
+                if cond != 0 && !self.is_jump_target(addr) {
+                    return Flow::Slow;
+                }
+                self.value_stack.truncate(self.value_stack.len() - 2);
+                match cond {
+                    0 => Flow::Next,
+                    _ => Flow::Jump(addr),
+                }
+            }
+            opcode::PushArg => {
+                if self.args.len() >= MAX_ARGS {
+                    return Flow::Slow;
+                }
+                match self.value_stack.pop() {
+                    Some(arg) => {
+                        self.args.push(arg);
+                        Flow::Next
+                    }
+                    None => Flow::Slow,
+                }
+            }
+            //NOTE(joh): Functions that declare their returns are checked by `call_addr`
+            opcode::Call => match self.value_stack.last() {
+                Some(&addr)
+                    if self.is_jump_target(addr)
+                        && self.return_stack.len() < self.config.max_call_depth
+                        && !self.functions.iter().any(|f| f.addr == addr && f.returns.is_some()) =>
+                {
+                    self.value_stack.pop();
+                    let mut frame = Frame::empty();
+                    frame.return_addr = op.addr + 1;
+                    frame.stack_base = self.value_stack.len() as u32;
+                    frame.locals[..self.args.len()].copy_from_slice(&self.args);
+                    self.return_stack.push(frame);
+                    self.args.clear();
+                    Flow::Jump(addr)
+                }
+                _ => Flow::Slow,
+            },
+            opcode::Return => match self.return_stack.last() {
+                Some(frame) if frame.returns.is_some() => Flow::Slow,
+                Some(frame) if frame.return_addr == 0 => {
+                    self.return_stack.pop();
+                    self.running = false;
+                    Flow::Stop
+                }
+                Some(frame) => {
+                    let addr = frame.return_addr;
+                    self.return_stack.pop();
+                    Flow::Jump(addr)
+                }
+                None => Flow::Slow,
+            },
+            _ => Flow::Slow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, config::{InterpreterConfig, Quota}, syscall::StdHandler};
+
+    type Outcome = Result<Vec<u32>, String>;
+
+    fn run_both(code: &str, config: InterpreterConfig) -> (Outcome, Outcome, u64) {
+        let bytecode = asm::Parser::parse(code).unwrap().code;
+        let mut bytes = Interpreter::from_bytecode_with_config(&bytecode, config.clone()).unwrap();
+        let mut decoded = Interpreter::from_bytecode_with_config(&bytecode, config).unwrap();
+        let expected = bytes.run(&mut StdHandler::new(String::new())).map(|r| r.to_vec()).map_err(|e| e.to_string());
+        let result = decoded
+            .run_decoded(&mut StdHandler::new(String::new()))
+            .map(|r| r.to_vec())
+            .map_err(|e| e.to_string());
+        assert_eq!(bytes.pc, decoded.pc);
+        assert_eq!(bytes.executed_ops, decoded.executed_ops);
+        assert_eq!(bytes.globals, decoded.globals);
+        (expected, result, decoded.executed_ops)
+    }
+
+    #[test]
+    fn matches_byte_interpreter() {
+        let programs = [
+            (
+                "
+                #0; push_arg; #@fib_loop; call;
+                #40; push_arg; #@fib; call;
+                end;
+                :fib_loop:
+                local_get 0; #1; add; local_tee 0;
+                #1000; lt; #@fib_loop; jmp_if;
+                local_get 0; return;
+                :fib:
+                local_get 0; #2; lt; #@base; jmp_if;
+                #0; local_set 1; #1; local_set 2;
+                :step:
+                local_get 1; local_get 2; add;
+                local_get 2; local_set 1; local_set 2;
+                local_get 0; #1; sub; local_tee 0;
+                #1; gt; #@step; jmp_if;
+                local_get 2; return;
+                :base: local_get 0; return;
+                ",
+                Ok(vec![1000, 102334155]),
+            ),
+            (
+                "
+                #200; #0x12345678; store_32 0;
+                #200; load_8_u 1; #200; load_16_s 2;
+                #5; global_set 3; global_get 3; #3; shift_l;
+                #7; #2; div_u; const_64 0x100000000; drop; drop;
+                #@next; jmp_if; unreachable;
+                :next: end;
+                ",
+                Ok(vec![0x56, 0x1234, 40]),
+            ),
+            (
+                "
+                #7; #0; #@skip; jmp_if;
+                :skip: #1; #12345; jmp_if;
+                end;
+                ",
+                Err(InterpreterErrorType::InvalidJumpAddr(12345).to_string()),
+            ),
+            (
+                "
+                #-2147483647; #1; rot_l; #0x00f00000; dup; clz; swap; popcnt; bswap; bswap; bswap_16; bswap_16;
+                #4; #5; #1; select; #6; #7; #0; select; #2; swap; drop;
+                select;
+                end;
+                ",
+                Ok(vec![3, 8, 4]),
+            ),
+            (
+                "#-3; #5; mul; #0x10000; #0x10000; mul_wrap; #0x10000; #0x8000; mul; end;",
+                Err(InterpreterErrorType::IntegerOverflow.to_string()),
+            ),
+            //NOTE(joh): Patches the `unreachable` into a `nop` before reaching it, the code is
+            //writable for all of them
+            (
+                "
+                #@code; #0x01; store_8 0;
+                :code: unreachable;
+                end;
+                ",
+                Ok(vec![]),
+            ),
+        ];
+        for (code, expected) in programs {
+            let (bytes, decoded, _) = run_both(code, InterpreterConfig::default().with_code_protection(false));
+            assert_eq!(bytes, expected, "{code}");
+            assert_eq!(decoded, expected, "{code}");
+        }
+
+        //NOTE(joh): Functions that declare their returns are checked on the way in and out
+        let functions = "
+            .func pair 1 2; local_get 0; local_get 0; return;
+            .func leaky 0 1; #1; #2; return;
+            .func greedy 0 0; drop; return;
+        ";
+        let programs = [
+            (":__ENTRY__: #7; #3; push_arg; #@pair; call; end;", Ok(vec![7, 3, 3])),
+            (
+                ":__ENTRY__: #@leaky; call; end;",
+                Err(InterpreterErrorType::ReturnArityMismatch { expected: 1, found: 2 }.to_string()),
+            ),
+            (
+                ":__ENTRY__: #9; #@greedy; call; end;",
+                Err(InterpreterErrorType::ReturnArityMismatch { expected: 0, found: -1 }.to_string()),
+            ),
+            (
+                ":__ENTRY__: #@pair; local_set 0; local_get 0; call; end;",
+                Err(InterpreterErrorType::ArityMismatch { function: 0, expected: 1, found: 0 }.to_string()),
+            ),
+        ];
+        for (code, expected) in programs {
+            let code = format!("{code} {functions}");
+            let (bytes, decoded, _) = run_both(&code, InterpreterConfig::default());
+            assert_eq!(bytes, expected, "{code}");
+            assert_eq!(decoded, expected, "{code}");
+        }
+
+        //NOTE(joh): Every iteration patches the code again, decoding it again must not grow the stack
+        let patching = "
+            .global n = 0;
+            :loop:
+            #@imm; global_get n; store_8 1;
+            :imm: #0; drop;
+            global_get n; #1; add; global_set n;
+            global_get n; #100000; lt; #@loop; jmp_if;
+            global_get n; end;
+        ";
+        let (expected, result, _) = run_both(patching, InterpreterConfig::default().with_code_protection(false));
+        assert_eq!(expected, Ok(vec![100000]));
+        assert_eq!(result, expected);
+
+        let looping = ":loop: #1; drop; #@loop; jmp;";
+        let config = InterpreterConfig::default().with_quota(Quota { fuel: Some(1000), ..Default::default() });
+        let (expected, result, executed) = run_both(looping, config);
+        assert!(expected.is_err());
+        assert_eq!(expected, result);
+        assert_eq!(executed, 1000);
+    }
+}
@@ -0,0 +1,219 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::fmt::Write;
+
+use crate::{
+    io::{Cursor, ErrorKind},
+    asm::{opcode, BytecodeInfo, RawArg, RawOp, DATA_START, ENTRY_LABEL_NAME},
+    function::split_function_table,
+    host::split_host_imports,
+    parse::{decode_const_pool, try_parse_ops_from_bytecode, MaybeRawOp},
+    prelude::*,
+    signing::split_signature,
+    symbols::split_symbol_table,
+};
+
+//NOTE(joh): Bytes per `.data` line
+const DATA_CHUNK: usize = 16;
+
+fn invalid(msg: String) -> crate::io::Error {
+    crate::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+//NOTE(joh): Constants directly consumed by these ops are code addresses
+fn takes_code_addr(opcode: u8) -> bool {
+    matches!(opcode, opcode::Jmp | opcode::JmpIf | opcode::Call | opcode::CallIf)
+}
+
+//NOTE(joh): Turns bytecode back into malu source that `Parser::parse` assembles to the same
+//bytes. Jump, call and table targets get labels, functions their `.func` declaration. Ops
+//loading from the const pool become plain constants, which changes the layout, so only
+//references through labels stay valid for such bytecode. Labels keep their names if the
+//bytecode has a symbol table, host imports get their `.host` declaration.
+pub fn disassemble(bytecode: &[u8]) -> Result<String, crate::io::Error> {
+    let (bytecode, _) = split_signature(bytecode);
+    let (_, symbols) = split_symbol_table(bytecode);
+    let symbols = symbols.unwrap_or_default();
+    let (_, host_imports) = split_host_imports(bytecode);
+    let host_imports = host_imports.unwrap_or_default();
+    let (bytecode, functions) = split_function_table(bytecode);
+    let functions = functions.unwrap_or_default();
+    let info = BytecodeInfo::decode(&mut Cursor::new(bytecode))?;
+    let const_pool = decode_const_pool(bytecode)?;
+
+    let code_start = BytecodeInfo::total_header_size();
+    let data_start = code_start + info.code_size_bytes as usize;
+    let code = bytecode
+        .get(code_start..data_start)
+        .ok_or_else(|| invalid("truncated code".to_string()))?;
+    let data = bytecode
+        .get(data_start..data_start + info.data_section_size as usize)
+        .ok_or_else(|| invalid("truncated data section".to_string()))?;
+
+    let mut ops = Vec::new();
+    let mut addr = DATA_START;
+    for op in try_parse_ops_from_bytecode(&mut Cursor::new(code)) {
+        match op? {
+            MaybeRawOp::Op(op) => {
+                let size = op.size_bytes() as u32;
+                ops.push((addr, op));
+                addr += size;
+            }
+            MaybeRawOp::Unknown(opcode) => {
+                return Err(invalid(format!("unknown opcode 0x{opcode:02x} at 0x{addr:04x}")));
+            }
+        }
+    }
+    let boundaries = ops.iter().map(|(addr, _)| *addr).collect::<BTreeSet<_>>();
+    if info.code_start_offset != DATA_START && !boundaries.contains(&info.code_start_offset) {
+        return Err(invalid(format!("entry point 0x{:04x} is inside an op", info.code_start_offset)));
+    }
+
+    let const_value = |op: &RawOp| match (op.opcode, &op.arg) {
+        (opcode::Const, Some(RawArg::Num(n))) => Some(*n),
+        (opcode::ConstPool, Some(RawArg::Register(i))) => const_pool.get(*i as usize).copied(),
+        _ => None,
+    };
+
+    let mut labels = functions
+        .iter()
+        .map(|f| (f.addr, f.name.clone()))
+        .collect::<BTreeMap<_, _>>();
+    for (name, addr) in &symbols.labels {
+        if name != ENTRY_LABEL_NAME && boundaries.contains(addr) {
+            labels.entry(*addr).or_insert_with(|| name.clone());
+        }
+    }
+    let mut mark = |target: u32| {
+        if boundaries.contains(&target) {
+            labels.entry(target).or_insert_with(|| format!("L_{target:04x}"));
+        }
+    };
+    for (i, (_, op)) in ops.iter().enumerate() {
+        if let Some(value) = const_value(op)
+            && ops.get(i + 1).is_some_and(|(_, next)| takes_code_addr(next.opcode))
+        {
+            mark(value);
+        }
+        if let Some(RawArg::Table { targets, default }) = &op.arg {
+            targets.iter().chain([default]).for_each(|t| mark(*t));
+        }
+    }
+    let target = |value: u32| match labels.get(&value) {
+        Some(label) => format!("@{label}"),
+        None => (value as i32).to_string(),
+    };
+
+    let mut out = String::new();
+    for import in &host_imports {
+        _ = writeln!(out, ".host {} {};", import.name, import.arity);
+    }
+    for (i, (addr, op)) in ops.iter().enumerate() {
+        if *addr == info.code_start_offset && *addr != DATA_START {
+            _ = writeln!(out, ":{ENTRY_LABEL_NAME}:");
+        }
+        if let Some(function) = functions.iter().find(|f| f.addr == *addr) {
+            match function.returns {
+                Some(returns) => _ = writeln!(out, ".func {} {} {returns};", function.name, function.arity),
+                None => _ = writeln!(out, ".func {} {};", function.name, function.arity),
+            }
+        } else if let Some(label) = labels.get(addr) {
+            _ = writeln!(out, ":{label}:");
+        }
+
+        let is_target = ops.get(i + 1).is_some_and(|(_, next)| takes_code_addr(next.opcode));
+        let line = match (op.opcode, &op.arg) {
+            (opcode::Const | opcode::ConstPool, _) => {
+                let value = const_value(op)
+                    .ok_or_else(|| invalid(format!("invalid constant at 0x{addr:04x}")))?;
+                match is_target {
+                    true => format!("#{}", target(value)),
+                    false => format!("#{}", value as i32),
+                }
+            }
+            (opcode::FConst, Some(RawArg::Num(bits))) => format!("{} {:?}", op.name(), f32::from_bits(*bits)),
+            (_, Some(RawArg::Num(n))) => format!("{} {}", op.name(), *n as i32),
+            (opcode::CallHost, Some(RawArg::Register(i))) => {
+                let import = host_imports
+                    .get(*i as usize)
+                    .ok_or_else(|| invalid(format!("invalid host function at 0x{addr:04x}")))?;
+                format!("{} {}", op.name(), import.name)
+            }
+            (_, Some(RawArg::Register(r))) => format!("{} {r}", op.name()),
+            (_, Some(RawArg::Wide(n))) => format!("{} {n}", op.name()),
+            (_, Some(RawArg::Table { targets, default })) => {
+                let mut line = op.name().to_string();
+                for t in targets {
+                    _ = write!(line, " {}", target(*t));
+                }
+                _ = write!(line, " default {}", target(*default));
+                line
+            }
+            (_, None) => op.name().to_string(),
+        };
+        _ = writeln!(out, "    {line};");
+    }
+
+    //NOTE(joh): Named data starts a new entry, everything else is split into chunks
+    let data_labels = symbols
+        .data_labels
+        .iter()
+        .filter_map(|(name, addr)| Some(((addr.checked_sub(DATA_START)? as usize).checked_sub(code.len())?, name)))
+        .filter(|(offset, _)| *offset < data.len())
+        .collect::<BTreeMap<_, _>>();
+    let mut offset = 0;
+    while offset < data.len() {
+        let next_label = data_labels.range(offset + 1..).next().map_or(data.len(), |(o, _)| *o);
+        let end = next_label.min(offset + DATA_CHUNK);
+        match data_labels.get(&offset) {
+            Some(name) => _ = write!(out, ".data {name} bytes"),
+            None => _ = write!(out, ".data D_{offset:04x} bytes"),
+        }
+        data[offset..end].iter().for_each(|b| _ = write!(out, " {b}"));
+        _ = writeln!(out, ";");
+        offset = end;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Parser;
+
+    #[test]
+    fn disassembly_round_trips() {
+        let code = "
+            .string msg \"hello\";
+            .host log 1;
+            .func add 2;
+            local_get 0; local_get 1; add;
+            return;
+            :__ENTRY__:
+            #2; push_arg; #3; push_arg; #@add; call;
+            #0; global_set 0;
+            :loop:
+            global_get 0; #1; add; global_tee 0;
+            #5; lt; #@loop; jmp_if;
+            #1; br_table @a @b;
+            :a: #-7; end;
+            :b: f_const 1.5; const_64 -3; #@msg; load_8_u 1;
+            #@add.index; drop;
+            #1; push_arg; call_host log; drop;
+            end;
+        ";
+        let original = Parser::parse(code).unwrap().code;
+        let source = disassemble(&original).unwrap();
+        assert!(source.contains(".func add 2;"));
+        assert!(source.contains(":__ENTRY__:"));
+        assert!(source.contains("br_table @L_") && source.contains(" default @L_"));
+        assert!(source.contains("call_host log;"));
+
+        let reassembled = Parser::parse(&source).unwrap().code;
+        assert_eq!(reassembled, original);
+
+        let mut corrupted = original.to_vec();
+        corrupted[BytecodeInfo::total_header_size()] = 0xff;
+        assert!(disassemble(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::{
+    mem::push_le,
+    prelude::*,
+    section::split_section,
+    symbols::{decode_symbols, push_symbols},
+};
+
+pub const EXPORTS_MAGIC: [u8; 4] = *b"mexp";
+
+//NOTE(joh): Names and absolute addresses, in the order they were declared
+pub type Exports = Vec<(String, u32)>;
+
+//NOTE(joh): Names declared with `.export <name>;` and their absolute addresses, the entry points
+//a host can look up in a program. Unlike the symbol table it is always emitted, it sits between
+//the host imports and the symbol table and ends with its size and `EXPORTS_MAGIC`.
+pub fn encode_exports(exports: &[(String, u32)], buffer: &mut Vec<u8>) {
+    let start = buffer.len();
+    push_symbols(buffer, exports);
+    let size = (buffer.len() - start) as u32;
+    push_le(buffer, size);
+    buffer.extend_from_slice(&EXPORTS_MAGIC);
+}
+
+pub fn split_exports(bytecode: &[u8]) -> (&[u8], Option<Exports>) {
+    split_section(bytecode, EXPORTS_MAGIC, decode_exports)
+}
+
+pub(crate) fn decode_exports(mut table: &[u8]) -> Option<Exports> {
+    decode_symbols(&mut table).ok().filter(|_| table.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{AssembleErrorKind, Parser, DATA_START},
+        function::split_function_table,
+        link::{link, LinkError, Object},
+        module::Module,
+    };
+
+    #[test]
+    fn exports_and_imports() {
+        let code = "
+            .export main;
+            .export buffer;
+            .data buffer bytes 0 0;
+            :main:
+            #@helper; call; end;
+            .func helper 0;
+            return;
+        ";
+        let bytecode = Parser::parse(code).unwrap().code;
+        let module = Module::from_bytecode(&bytecode).unwrap();
+        assert_eq!(module.export("main"), Some(DATA_START));
+        assert_eq!(module.export("buffer"), Some(DATA_START + 8));
+        assert_eq!(module.export("helper"), None);
+        assert_eq!(split_exports(&bytecode).1.map(|e| e.len()), Some(2));
+        assert_eq!(split_function_table(&bytecode).1.map(|f| f.len()), Some(1));
+
+        let error = |code| Parser::parse(code).map(|_| ()).unwrap_err().kind;
+        assert!(matches!(error(".export nowhere;"), AssembleErrorKind::UnknownLabel(_)));
+        assert!(matches!(error(".import helper;"), AssembleErrorKind::ImportWithoutObject(_)));
+
+        let lib = Parser::new()
+            .assemble_object(".export square; :square: local_get 0; local_get 0; mul; return;")
+            .unwrap();
+        let prog = Parser::new()
+            .assemble_object(".import square; .func main 0; #3; push_arg; #@square; call; end;")
+            .unwrap();
+        assert_eq!(Object::decode(&prog.encode()), Ok(prog.clone()));
+        let linked = link(&[prog.clone(), lib]).unwrap();
+        let module = Module::from_bytecode(&linked).unwrap();
+        assert_eq!(module.export("square"), Some(DATA_START + prog.code.len() as u32));
+
+        let missing = Parser::new().assemble_object(".import a; .import b; #@a; drop; end;").unwrap();
+        let names = ["a", "b"].map(str::to_string).to_vec();
+        assert_eq!(link(&[missing]), Err(LinkError::UnresolvedSymbols(names)));
+        //NOTE(joh): With an import every other symbol has to be imported as well
+        let strict = Parser::new().assemble_object(".import a; #@a; #@b; end;");
+        assert!(matches!(strict.unwrap_err().kind, AssembleErrorKind::UnknownLabel(_)));
+    }
+}
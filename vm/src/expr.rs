@@ -0,0 +1,170 @@
+use crate::prelude::*;
+
+//NOTE(joh): Constant expressions in operands, e.g. `#@buffer+16;` or `const (1<<12);`. Numbers
+//are written like everywhere else, labels with `@`. From lowest to highest precedence there are
+//`<<` and `>>`, then `+` and `-`, then `*`. Everything is evaluated with 64 bits and wraps.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExprError<E> {
+    Syntax,
+    Label(E),
+}
+
+//NOTE(joh): Anything that is not a plain number or label. A leading `-` is part of the number.
+pub fn is_expr(s: &str) -> bool {
+    s.contains(['+', '*', '(', ')', '<', '>']) || s.get(1..).is_some_and(|rest| rest.contains('-'))
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+//NOTE(joh): The labels the expression refers to, without the `@`
+pub fn labels(s: &str) -> Vec<&str> {
+    s.match_indices('@')
+        .map(|(i, _)| {
+            let rest = &s[i + 1..];
+            &rest[..rest.find(|c| !is_label_char(c)).unwrap_or(rest.len())]
+        })
+        .collect()
+}
+
+//NOTE(joh): Parentheses and `-` nest at most this deep, the evaluation recurses for each level
+pub const MAX_EXPR_DEPTH: usize = 64;
+
+pub fn eval<E>(s: &str, label: impl FnMut(&str) -> Result<i64, E>) -> Result<i64, ExprError<E>> {
+    let mut eval = Eval { rest: s, label, depth: 0 };
+    let value = eval.shift()?;
+    match eval.rest.trim_start() {
+        "" => Ok(value),
+        _ => Err(ExprError::Syntax),
+    }
+}
+
+struct Eval<'a, F> {
+    rest: &'a str,
+    label: F,
+    depth: usize,
+}
+
+impl<'a, E, F: FnMut(&str) -> Result<i64, E>> Eval<'a, F> {
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.trim_start().strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn shift(&mut self) -> Result<i64, ExprError<E>> {
+        let mut value = self.sum()?;
+        loop {
+            if self.eat("<<") {
+                value = value.wrapping_shl(self.sum()? as u32);
+            } else if self.eat(">>") {
+                value = value.wrapping_shr(self.sum()? as u32);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sum(&mut self) -> Result<i64, ExprError<E>> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat("+") {
+                value = value.wrapping_add(self.product()?);
+            } else if self.eat("-") {
+                value = value.wrapping_sub(self.product()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<i64, ExprError<E>> {
+        let mut value = self.unary()?;
+        while self.eat("*") {
+            value = value.wrapping_mul(self.unary()?);
+        }
+        Ok(value)
+    }
+
+    fn nested(&mut self, eval: impl FnOnce(&mut Self) -> Result<i64, ExprError<E>>) -> Result<i64, ExprError<E>> {
+        if self.depth == MAX_EXPR_DEPTH {
+            return Err(ExprError::Syntax);
+        }
+        self.depth += 1;
+        let value = eval(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn unary(&mut self) -> Result<i64, ExprError<E>> {
+        if self.eat("-") {
+            return self.nested(|eval| Ok(eval.unary()?.wrapping_neg()));
+        }
+        if self.eat("(") {
+            let value = self.nested(Self::shift)?;
+            return match self.eat(")") {
+                true => Ok(value),
+                false => Err(ExprError::Syntax),
+            };
+        }
+
+        let rest = self.rest.trim_start();
+        let (is_label, rest) = match rest.strip_prefix('@') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let len = rest.find(|c| !is_label_char(c)).unwrap_or(rest.len());
+        let (word, rest) = rest.split_at(len);
+        self.rest = rest;
+        match is_label {
+            true if !word.is_empty() => (self.label)(word).map_err(ExprError::Label),
+            true => Err(ExprError::Syntax),
+            false => parse_number(word).ok_or(ExprError::Syntax),
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(s: &str) -> Result<i64, ExprError<String>> {
+        eval(s, |label| match label {
+            "buffer" => Ok(0x100),
+            other => Err(other.to_string()),
+        })
+    }
+
+    #[test]
+    fn evaluate() {
+        assert_eq!(eval_with("1+2*3"), Ok(7));
+        assert_eq!(eval_with("(1+2)*3"), Ok(9));
+        assert_eq!(eval_with("1<<4+1"), Ok(32));
+        assert_eq!(eval_with("@buffer+0x10"), Ok(0x110));
+        assert_eq!(eval_with("-(2 - 5) >> 1"), Ok(1));
+        assert_eq!(eval_with("@missing+1"), Err(ExprError::Label("missing".to_string())));
+        assert_eq!(eval_with("(1+2"), Err(ExprError::Syntax));
+        assert_eq!(eval_with("1+"), Err(ExprError::Syntax));
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval_with(&nested(MAX_EXPR_DEPTH)), Ok(1));
+        assert_eq!(eval_with(&nested(20000)), Err(ExprError::Syntax));
+        assert_eq!(eval_with(&"-".repeat(20000)), Err(ExprError::Syntax));
+        assert_eq!(labels("@a+@b.len*2"), ["a", "b.len"]);
+        assert!(is_expr("@a+1") && is_expr("(4)") && !is_expr("-4") && !is_expr("@a.len"));
+    }
+}
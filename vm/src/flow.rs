@@ -0,0 +1,256 @@
+use alloc::borrow::Cow;
+use core::{fmt::Display, ops::Range};
+
+use crate::{
+    asm::opcode,
+    lexer::{tokenize, Token, TokenKind},
+    prelude::*,
+    validate::ends_flow,
+};
+
+//NOTE(joh): Structured control flow, lowered to jumps before the source is parsed:
+//
+//    #1; if { #2; } else { #3; }
+//    loop { ...; #0; eq; break_if; ...; }
+//
+//`if` pops the condition and runs the first block if it is not 0. `loop` repeats its block until
+//`break` leaves it, `continue` starts it over. `block` runs its block once, `break` leaves it
+//early. `break_if` and `continue_if` pop a condition first. `break` goes to the innermost `loop`
+//or `block`, `continue` to the innermost `loop`. The generated labels start with `LABEL_PREFIX`.
+pub const KEYWORDS: [&str; 8] = ["if", "else", "loop", "block", "break", "continue", "break_if", "continue_if"];
+pub const LABEL_PREFIX: &str = "__flow";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowError {
+    //NOTE(joh): `if`, `else`, `loop` or `block` not followed by `{`
+    MissingBrace(&'static str),
+    UnmatchedBrace,
+    UnclosedBlock(&'static str),
+    ElseWithoutIf,
+    //NOTE(joh): `break` or `continue` without a construct it could leave
+    OutsideLoop(&'static str),
+}
+
+impl Display for FlowError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FlowError::MissingBrace(keyword) => write!(f, "`{keyword}` is missing its `{{`"),
+            FlowError::UnmatchedBrace => write!(f, "`}}` without an open block"),
+            FlowError::UnclosedBlock(keyword) => write!(f, "`{keyword}` is missing its `}}`"),
+            FlowError::ElseWithoutIf => write!(f, "`else` has to follow the `}}` of an `if`"),
+            FlowError::OutsideLoop(keyword) => write!(f, "`{keyword}` outside of a loop"),
+        }
+    }
+}
+
+impl core::error::Error for FlowError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Construct {
+    If,
+    //NOTE(joh): Whether the `if` block ended the flow, then it needs no jump over the `else` block
+    Else { then_ends_flow: bool },
+    Loop,
+    Block,
+}
+
+impl Construct {
+    fn keyword(self) -> &'static str {
+        match self {
+            Construct::If => "if",
+            Construct::Else { .. } => "else",
+            Construct::Loop => "loop",
+            Construct::Block => "block",
+        }
+    }
+}
+
+//NOTE(joh): Replaces the constructs with jumps. Newlines stay where they are, so lines still
+//match the source, columns after a construct may not. Errors come with the span of the keyword
+//or brace in `code`.
+pub fn lower(code: &str) -> Result<Cow<'_, str>, (FlowError, Range<usize>)> {
+    let tokens = tokenize(code)
+        .into_iter()
+        .filter(|t| t.kind != TokenKind::Comment)
+        .collect::<Vec<_>>();
+    let text = |token: &Token| &code[token.span.clone()];
+    let is_keyword = |token: &Token| token.kind == TokenKind::Opcode && KEYWORDS.contains(&text(token));
+    let is_brace = |token: &Token, brace: &str| token.kind == TokenKind::Separator && text(token) == brace;
+    if !tokens.iter().any(|t| is_keyword(t) || is_brace(t, "{") || is_brace(t, "}")) {
+        return Ok(Cow::Borrowed(code));
+    }
+
+    let mut lowered = String::with_capacity(code.len());
+    let mut copied = 0;
+    let mut open: Vec<(Construct, usize, Range<usize>)> = Vec::new();
+    let mut count = 0;
+    //NOTE(joh): Whether the last statement jumped away, then a block needs no jump at its end
+    let mut flow_ended = false;
+    let mut i = 0;
+
+    while let Some(token) = tokens.get(i) {
+        let mut end = token.span.end;
+        let replacement = match (token.kind, text(token)) {
+            (TokenKind::Opcode, keyword @ ("if" | "loop" | "block")) => {
+                let construct = match keyword {
+                    "if" => Construct::If,
+                    "loop" => Construct::Loop,
+                    _ => Construct::Block,
+                };
+                match tokens.get(i + 1) {
+                    Some(brace) if is_brace(brace, "{") => {
+                        end = brace.span.end;
+                        i += 1;
+                    }
+                    _ => return Err((FlowError::MissingBrace(construct.keyword()), token.span.clone())),
+                }
+                let id = count;
+                count += 1;
+                open.push((construct, id, token.span.clone()));
+                flow_ended = false;
+                match construct {
+                    Construct::If => format!("eqz; #@{LABEL_PREFIX}{id}_else; jmp_if;"),
+                    Construct::Loop => format!(":{LABEL_PREFIX}{id}_start:"),
+                    _ => String::new(),
+                }
+            }
+            (TokenKind::Separator, "}") => {
+                let Some((construct, id, span)) = open.pop() else {
+                    return Err((FlowError::UnmatchedBrace, token.span.clone()));
+                };
+                let jump = |label: &str| format!("#@{LABEL_PREFIX}{id}_{label}; jmp; ");
+                let has_else = tokens.get(i + 1).is_some_and(|t| t.kind == TokenKind::Opcode && text(t) == "else");
+                let lowered = match construct {
+                    Construct::If if has_else => {
+                        match tokens.get(i + 2) {
+                            Some(brace) if is_brace(brace, "{") => {
+                                end = brace.span.end;
+                                i += 2;
+                            }
+                            _ => return Err((FlowError::MissingBrace("else"), tokens[i + 1].span.clone())),
+                        }
+                        open.push((Construct::Else { then_ends_flow: flow_ended }, id, span));
+                        let jump = if flow_ended { String::new() } else { jump("end") };
+                        format!("{jump}:{LABEL_PREFIX}{id}_else:")
+                    }
+                    Construct::If => format!(":{LABEL_PREFIX}{id}_else:"),
+                    Construct::Else { .. } | Construct::Block => format!(":{LABEL_PREFIX}{id}_end:"),
+                    Construct::Loop => {
+                        let jump = if flow_ended { String::new() } else { jump("start") };
+                        format!("{jump}:{LABEL_PREFIX}{id}_end:")
+                    }
+                };
+                flow_ended = match construct {
+                    Construct::Else { then_ends_flow } => then_ends_flow && flow_ended,
+                    _ => false,
+                };
+                lowered
+            }
+            (TokenKind::Opcode, "else") => return Err((FlowError::ElseWithoutIf, token.span.clone())),
+            (TokenKind::Opcode, keyword @ ("break" | "continue" | "break_if" | "continue_if")) => {
+                let (label, conditional, keyword) = match keyword {
+                    "break" => ("end", false, "break"),
+                    "continue" => ("start", false, "continue"),
+                    "break_if" => ("end", true, "break_if"),
+                    _ => ("start", true, "continue_if"),
+                };
+                let target = open.iter().rev().find(|(construct, ..)| match label {
+                    "end" => matches!(construct, Construct::Loop | Construct::Block),
+                    _ => *construct == Construct::Loop,
+                });
+                let Some((_, id, _)) = target else {
+                    return Err((FlowError::OutsideLoop(keyword), token.span.clone()));
+                };
+                flow_ended = !conditional;
+                let jump = if conditional { "jmp_if" } else { "jmp" };
+                format!("#@{LABEL_PREFIX}{id}_{label}; {jump}")
+            }
+            (TokenKind::Opcode, name) => {
+                flow_ended = opcode::Names.iter().position(|n| *n == name).is_some_and(|op| ends_flow(op as u8));
+                i += 1;
+                continue;
+            }
+            (TokenKind::Const | TokenKind::Label | TokenKind::Directive, _) => {
+                flow_ended = false;
+                i += 1;
+                continue;
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        lowered.push_str(&code[copied..token.span.start]);
+        lowered.push_str(&replacement);
+        //NOTE(joh): `}` and `else {` may be on different lines
+        code[token.span.start..end].matches('\n').for_each(|_| lowered.push('\n'));
+        copied = end;
+        i += 1;
+    }
+    if let Some((construct, _, span)) = open.pop() {
+        return Err((FlowError::UnclosedBlock(construct.keyword()), span));
+    }
+    lowered.push_str(&code[copied..]);
+    Ok(Cow::Owned(lowered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{AssembleErrorKind, AssembleWarningKind, Parser, DATA_START},
+        interpreter::Interpreter,
+        syscall::StdHandler,
+    };
+
+    #[test]
+    fn structured_control_flow() {
+        let code = "
+            #0; local_set 0;
+            #0; local_set 1;
+            loop {
+                local_get 0; #5; eq; break_if;
+                local_get 0; #1; add; local_set 0;
+                // only the even ones
+                local_get 0; #2; rem_u; if { continue; }
+                local_get 1; local_get 0; add; local_set 1;
+            }
+            local_get 1;
+            #1; if { #10; } else { #20; }
+            #0; if {
+                #30;
+            }
+            else {
+                #40;
+            }
+            block { #50; break; #60; }
+            end;
+        ";
+        let parsed = Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&parsed.code).unwrap();
+        assert_eq!(interpreter.run(&mut StdHandler::new(String::new())).unwrap(), [6, 10, 40, 50]);
+        //NOTE(joh): Only the op after the `break`, the generated labels are not unused
+        let warnings = parsed.warnings.iter().map(|w| (&w.kind, w.line)).collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(warnings[0], (AssembleWarningKind::UnreachableCode { ops: 1 }, 18)));
+        //NOTE(joh): Lines stay where they are in the source
+        let block_end = parsed.labels.iter().find(|(name, _)| *name == format!("{LABEL_PREFIX}4_end")).unwrap().1;
+        assert_eq!(parsed.debug_info.resolve_line(block_end + DATA_START).unwrap().line, 20);
+
+        let error = |code| {
+            let error = Parser::parse(code).map(|_| ()).unwrap_err();
+            match error.kind {
+                AssembleErrorKind::ControlFlow(kind) => (kind, error.line),
+                other => panic!("{other}"),
+            }
+        };
+        assert_eq!(error("#1;\n}"), (FlowError::UnmatchedBrace, 1));
+        assert_eq!(error("#1;\nloop {\n#1;"), (FlowError::UnclosedBlock("loop"), 1));
+        assert_eq!(error("#1; if { } #2; else { }"), (FlowError::ElseWithoutIf, 0));
+        assert_eq!(error("if #1;"), (FlowError::MissingBrace("if"), 0));
+        assert_eq!(error("#1; if { } else #2;"), (FlowError::MissingBrace("else"), 0));
+        assert_eq!(error("break;"), (FlowError::OutsideLoop("break"), 0));
+        assert!(Parser::parse("loop { block { continue; } } end;").is_ok());
+        assert_eq!(error("block { continue; }"), (FlowError::OutsideLoop("continue"), 0));
+    }
+}
@@ -0,0 +1,87 @@
+use alloc::borrow::Cow;
+
+use crate::mem::Memory;
+
+pub const BYTES_PER_PIXEL: u32 = 4;
+//NOTE(joh): Per side, keeps the texture of the gui within what every backend supports
+pub const MAX_SIDE: u32 = 2048;
+
+//NOTE(joh): A region of the linear memory shown as an image. It holds `width * height` pixels row
+//by row, each as the bytes r, g, b, a. Programs draw by storing into it or with `Blit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub addr: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Framebuffer {
+    //NOTE(joh): `None` if the region is empty, too large or does not fit into the memory
+    pub fn new(memory: &Memory, addr: u32, width: u32, height: u32) -> Option<Self> {
+        if !(1..=MAX_SIDE).contains(&width) || !(1..=MAX_SIDE).contains(&height) {
+            return None;
+        }
+        let framebuffer = Self { addr, width, height };
+        memory.slice(addr as usize, framebuffer.len_bytes())?;
+        Some(framebuffer)
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        (self.width * self.height * BYTES_PER_PIXEL) as usize
+    }
+
+    //NOTE(joh): `None` once the memory shrank below it, e.g. after loading another program
+    pub fn pixels<'a>(&self, memory: &'a Memory) -> Option<Cow<'a, [u8]>> {
+        memory.slice(self.addr as usize, self.len_bytes())
+    }
+
+    //NOTE(joh): Copies `width * height` pixels stored row by row at `src` to `x`, `y`. Pixels
+    //outside of the framebuffer are cut off, so sprites can be partly off screen. The whole source
+    //has to be in memory.
+    pub fn blit(&self, memory: &mut Memory, src: u32, x: i32, y: i32, width: u32, height: u32) -> Option<()> {
+        let src_len = (width as usize)
+            .checked_mul(height as usize)?
+            .checked_mul(BYTES_PER_PIXEL as usize)?;
+        memory.slice(src as usize, src_len)?;
+
+        let columns = (x as i64).max(0)..(x as i64 + width as i64).min(self.width as i64);
+        let rows = (y as i64).max(0)..(y as i64 + height as i64).min(self.height as i64);
+        if columns.is_empty() {
+            return Some(());
+        }
+        let row_len = (columns.end - columns.start) as usize * BYTES_PER_PIXEL as usize;
+        for row in rows {
+            let from = src as i64 + ((row - y as i64) * width as i64 + columns.start - x as i64) * BYTES_PER_PIXEL as i64;
+            let to = self.addr as i64 + (row * self.width as i64 + columns.start) * BYTES_PER_PIXEL as i64;
+            memory.copy_within(from as usize, to as usize, row_len)?;
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blit_clips() {
+        let mut memory = Memory::zeroed(256);
+        assert_eq!(Framebuffer::new(&memory, 0, 0, 2), None);
+        assert_eq!(Framebuffer::new(&memory, 200, 4, 4), None);
+        let framebuffer = Framebuffer::new(&memory, 0, 4, 4).unwrap();
+
+        //NOTE(joh): A 2x2 sprite, pixel i is filled with i + 1
+        let sprite = (0..16).map(|i| i / 4 + 1).collect::<Vec<u8>>();
+        memory.write(128, &sprite).unwrap();
+        framebuffer.blit(&mut memory, 128, 3, -1, 2, 2).unwrap();
+        framebuffer.blit(&mut memory, 128, 0, 2, 2, 2).unwrap();
+        let pixels = framebuffer.pixels(&memory).unwrap();
+        let pixel = |x: usize, y: usize| pixels[(y * 4 + x) * 4];
+        assert_eq!(pixel(3, 0), 3);
+        assert_eq!([pixel(0, 2), pixel(1, 2), pixel(0, 3), pixel(1, 3)], [1, 2, 3, 4]);
+        assert_eq!(pixels.iter().filter(|b| **b != 0).count(), 5 * 4);
+
+        assert_eq!(framebuffer.blit(&mut memory, 250, 0, 0, 2, 2), None);
+        assert_eq!(framebuffer.blit(&mut memory, 128, 9, 9, 2, 2), Some(()));
+    }
+}
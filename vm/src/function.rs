@@ -0,0 +1,105 @@
+use crate::{
+    mem::{push_le, read_le_from},
+    prelude::*,
+    section::split_section,
+};
+
+pub const FUNCTION_TABLE_MAGIC: [u8; 4] = *b"mfun";
+//NOTE(joh): Stored in place of the return count of functions that don't declare one
+const UNCHECKED_RETURNS: u8 = u8::MAX;
+
+//NOTE(joh): Declared with `.func <name> <arity> [returns];`, `addr` is the absolute address of
+//the first op. Calls and returns of a function with `returns` are checked by the interpreter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub addr: u32,
+    pub arity: u8,
+    pub returns: Option<u8>,
+}
+
+//NOTE(joh): `None` is encoded as `UNCHECKED_RETURNS`, which is never a valid count
+pub fn encode_returns(returns: Option<u8>) -> u8 {
+    returns.unwrap_or(UNCHECKED_RETURNS)
+}
+
+pub fn decode_returns(returns: u8) -> Option<u8> {
+    Some(returns).filter(|r| *r != UNCHECKED_RETURNS)
+}
+
+//NOTE(joh): The table follows the const pool and comes before the signature section. Each
+//entry is the address, arity, return count, name length and name, followed by the size of the
+//entries and `FUNCTION_TABLE_MAGIC`.
+pub fn encode_function_table(functions: &[Function], buffer: &mut Vec<u8>) {
+    let start = buffer.len();
+    for function in functions {
+        push_le(buffer, function.addr);
+        push_le(buffer, function.arity);
+        push_le(buffer, encode_returns(function.returns));
+        push_le(buffer, function.name.len() as u32);
+        buffer.extend_from_slice(function.name.as_bytes());
+    }
+    let size = (buffer.len() - start) as u32;
+    push_le(buffer, size);
+    buffer.extend_from_slice(&FUNCTION_TABLE_MAGIC);
+}
+
+pub fn split_function_table(bytecode: &[u8]) -> (&[u8], Option<Box<[Function]>>) {
+    split_section(bytecode, FUNCTION_TABLE_MAGIC, decode_function_table)
+}
+
+pub(crate) fn decode_function_table(mut entries: &[u8]) -> Option<Box<[Function]>> {
+    let mut functions = Vec::new();
+    while !entries.is_empty() {
+        functions.push(decode_function(&mut entries).ok()?);
+    }
+    Some(functions.into_boxed_slice())
+}
+
+fn decode_function(reader: &mut &[u8]) -> Result<Function, crate::io::Error> {
+    let addr = read_le_from(reader)?;
+    let arity = read_le_from(reader)?;
+    let returns = decode_returns(read_le_from(reader)?);
+    let len: u32 = read_le_from(reader)?;
+    let name = reader
+        .split_off(..len as usize)
+        .ok_or(crate::io::ErrorKind::UnexpectedEof)?;
+    let name = String::from_utf8(name.to_vec()).map_err(|_| crate::io::ErrorKind::InvalidData)?;
+    Ok(Function { name, addr, arity, returns })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn function_table() {
+        let code = "
+            #2; push_arg; #3; push_arg; #@add_two; call;
+            end;
+            .func add_two 2;
+            local_get 0; local_get 1; add;
+            return;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let (content, functions) = split_function_table(&bytecode.code);
+        assert_eq!(
+            functions.unwrap()[..],
+            [Function {
+                name: "add_two".to_string(),
+                addr: asm::DATA_START + 19,
+                arity: 2,
+                returns: None,
+            }]
+        );
+        assert!(content.len() < bytecode.code.len());
+        assert_eq!(split_function_table(content), (content, None));
+
+        let error = asm::Parser::parse("#@f; call; end; .func f 1; return;").map(|_| ()).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            asm::AssembleErrorKind::ArityMismatch { expected: 1, found: 0, .. }
+        ));
+    }
+}
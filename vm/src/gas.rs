@@ -0,0 +1,55 @@
+use crate::asm::opcode;
+
+//NOTE(joh): What every opcode costs, see `InterpreterConfig::with_gas_table`. The default makes
+//memory accesses, calls and syscalls more expensive than plain arithmetic. Costs are per op, a
+//`mem_copy` costs the same no matter how many bytes it copies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasTable {
+    costs: [u32; 256],
+}
+
+impl GasTable {
+    //NOTE(joh): Every op costs the same, with a cost of 1 the gas used is the number of ops
+    pub fn uniform(cost: u32) -> Self {
+        Self { costs: [cost; 256] }
+    }
+
+    pub fn cost(&self, opcode: u8) -> u32 {
+        self.costs[opcode as usize]
+    }
+
+    pub fn set_cost(&mut self, opcode: u8, cost: u32) {
+        self.costs[opcode as usize] = cost;
+    }
+
+    pub fn with_cost(mut self, opcode: u8, cost: u32) -> Self {
+        self.set_cost(opcode, cost);
+        self
+    }
+}
+
+impl Default for GasTable {
+    fn default() -> Self {
+        use opcode::*;
+        let mut table = Self::uniform(1);
+        let weights: [(&[u8], u32); 4] = [
+            (
+                &[
+                    Store8, Store16, Store32, Store64, Load8u, Load8s, Load16s, Load16u, Load32s, Load32u, Load64,
+                    LoadMem8u, LoadMem32, StoreMem8, StoreMem32, AtomicLoad, AtomicStore, AtomicLoad32, AtomicStore32,
+                    PushMem, PopMem,
+                ],
+                3,
+            ),
+            (&[Divs, Divu, RemS, RemU, Divs64, Divu64, FDiv, AtomicAdd, Cmpxchg, AtomicRmwAdd, AtomicCmpxchg], 4),
+            (&[Call, CallIf, CallIndirect, CallHost, Return], 5),
+            (&[Syscall, MemCopy, MemFill, MemGrow], 10),
+        ];
+        for (opcodes, cost) in weights {
+            for &op in opcodes {
+                table.set_cost(op, cost);
+            }
+        }
+        table
+    }
+}
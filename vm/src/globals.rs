@@ -0,0 +1,75 @@
+use crate::{
+    mem::{push_le, read_le_from},
+    prelude::*,
+    section::split_section,
+};
+
+pub const GLOBALS_MAGIC: [u8; 4] = *b"mglb";
+
+//NOTE(joh): Initial values of the globals declared with `.global <name> = <value>;`, global `i`
+//starts with `values[i]`. The section sits between the function table and the host imports, it
+//holds the values followed by their size in bytes and `GLOBALS_MAGIC`.
+pub fn encode_global_inits(values: &[u32], buffer: &mut Vec<u8>) {
+    values.iter().for_each(|value| push_le(buffer, *value));
+    push_le(buffer, size_of_val(values) as u32);
+    buffer.extend_from_slice(&GLOBALS_MAGIC);
+}
+
+pub fn split_global_inits(bytecode: &[u8]) -> (&[u8], Option<Box<[u32]>>) {
+    split_section(bytecode, GLOBALS_MAGIC, decode_global_inits)
+}
+
+pub(crate) fn decode_global_inits(mut values: &[u8]) -> Option<Box<[u32]>> {
+    if !values.len().is_multiple_of(size_of::<u32>()) {
+        return None;
+    }
+    (0..values.len() / size_of::<u32>())
+        .map(|_| read_le_from(&mut values).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, function::split_function_table, interpreter::Interpreter, syscall::StdHandler};
+
+    #[test]
+    fn global_initializers() {
+        let code = "
+            .global counter = 40;
+            .global base = @table+4;
+            global_get counter; #2; add; global_set counter;
+            global_get base; drop;
+            global_get 1; dbg_assert;
+            end;
+            .func table 0;
+            return;
+        ";
+        let mut parser = asm::Parser::new();
+        parser.set_emit_symbols(true);
+        let bytecode = parser.assemble(code).unwrap().code;
+        let table = asm::DATA_START + 17;
+        assert_eq!(split_global_inits(&bytecode).1.as_deref(), Some(&[40, table + 4][..]));
+        //NOTE(joh): The function table in front of it is still found
+        assert_eq!(split_function_table(&bytecode).1.map(|f| f.len()), Some(1));
+
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        interpreter.run(&mut StdHandler::new(String::new())).unwrap();
+        assert_eq!(interpreter.globals[..2], [42, table + 4]);
+        assert!(!interpreter.assertion_failed);
+        interpreter.reset_all(&bytecode).unwrap();
+        assert_eq!(interpreter.globals[..2], [40, table + 4]);
+
+        let plain = asm::Parser::parse("end;").unwrap().code;
+        assert_eq!(split_global_inits(&plain), (&plain[..], None));
+        //NOTE(joh): Data that ends like the section does not initialize anything
+        let data = asm::Parser::parse("global_get 0; end; .data d bytes 7 0 0 0 4 0 0 0 109 103 108 98;").unwrap().code;
+        assert_eq!(split_global_inits(&data), (&data[..], None));
+        let mut interpreter = Interpreter::from_bytecode(&data).unwrap();
+        assert_eq!(interpreter.run(&mut StdHandler::new(String::new())).unwrap(), [0]);
+
+        let error = |code| asm::Parser::parse(code).map(|_| ()).unwrap_err().kind;
+        assert!(matches!(error("global_get missing;"), asm::AssembleErrorKind::UnknownGlobal(_)));
+        assert!(matches!(error(".global a = 1; .global a = 2;"), asm::AssembleErrorKind::GlobalAlreadyDefined(_)));
+    }
+}
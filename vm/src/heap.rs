@@ -0,0 +1,214 @@
+use crate::{
+    mem::Memory,
+    prelude::*,
+};
+
+//NOTE(joh): In front of every block: its size including the header, then 1 if it is in use
+pub const HEADER_SIZE: u32 = 8;
+pub const ALIGN: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    //NOTE(joh): The memory has to be at least `needed` bytes long for the allocation
+    OutOfMemory { needed: usize },
+    InvalidPointer(u32),
+    //NOTE(joh): The program overwrote the header at the address
+    Corrupted(u32),
+}
+
+impl core::fmt::Display for HeapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HeapError::OutOfMemory { needed } => write!(f, "heap needs {needed} bytes of memory"),
+            HeapError::InvalidPointer(addr) => write!(f, "0x{addr:04x} is not an allocated block"),
+            HeapError::Corrupted(addr) => write!(f, "heap block header at 0x{addr:04x} is corrupted"),
+        }
+    }
+}
+
+impl core::error::Error for HeapError {}
+
+//NOTE(joh): First fit allocator for the memory behind the loaded image, i.e. behind the code, the
+//data and the trailing sections. All of its state are the block headers in the memory itself, so
+//snapshots, stepping back and replays cover it without extra work. The blocks follow each other
+//from `start`, the first header with size 0 or past the end of the memory ends the heap. Free
+//neighbours are merged while searching.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Heap {
+    pub start: u32,
+}
+
+fn header(memory: &Memory, addr: u32) -> Option<(u32, bool)> {
+    let size = memory.read_le::<u32>(addr as usize)?;
+    let used = memory.read_le::<u32>(addr as usize + 4)?;
+    Some((size, used != 0)).filter(|(size, _)| *size != 0)
+}
+
+fn set_header(memory: &mut Memory, addr: u32, size: u32, used: bool) {
+    _ = memory.write_le(addr as usize, size);
+    _ = memory.write_le(addr as usize + 4, used as u32);
+}
+
+impl Heap {
+    pub fn new(start: u32) -> Self {
+        Self { start: start.next_multiple_of(ALIGN) }
+    }
+
+    //NOTE(joh): Returns the address of `size` zeroed bytes, aligned to `ALIGN`
+    pub fn alloc(&self, memory: &mut Memory, size: u32) -> Result<u32, HeapError> {
+        let needed = size
+            .max(1)
+            .checked_next_multiple_of(ALIGN)
+            .and_then(|s| s.checked_add(HEADER_SIZE))
+            .ok_or(HeapError::OutOfMemory { needed: usize::MAX })?;
+        let mut addr = self.start;
+        loop {
+            let Some((mut block, used)) = header(memory, addr) else {
+                return self.place(memory, addr, needed);
+            };
+            if block < HEADER_SIZE || !block.is_multiple_of(ALIGN) {
+                return Err(HeapError::Corrupted(addr));
+            }
+            if !used {
+                let mut next = addr.checked_add(block).ok_or(HeapError::Corrupted(addr))?;
+                while let Some((size, false)) = header(memory, next) {
+                    if size < HEADER_SIZE || !size.is_multiple_of(ALIGN) {
+                        return Err(HeapError::Corrupted(next));
+                    }
+                    block = block.checked_add(size).ok_or(HeapError::Corrupted(next))?;
+                    next = next.checked_add(size).ok_or(HeapError::Corrupted(next))?;
+                }
+                if header(memory, next).is_none() {
+                    return self.place(memory, addr, needed);
+                }
+                set_header(memory, addr, block, false);
+                if block >= needed {
+                    if block - needed >= HEADER_SIZE + ALIGN {
+                        set_header(memory, addr + needed, block - needed, false);
+                        block = needed;
+                    }
+                    set_header(memory, addr, block, true);
+                    memory.fill((addr + HEADER_SIZE) as usize, (block - HEADER_SIZE) as usize, 0);
+                    return Ok(addr + HEADER_SIZE);
+                }
+            }
+            addr = addr.checked_add(block).ok_or(HeapError::Corrupted(addr))?;
+        }
+    }
+
+    //NOTE(joh): A new last block, the header after it marks the end again
+    fn place(&self, memory: &mut Memory, addr: u32, size: u32) -> Result<u32, HeapError> {
+        let end = addr as usize + size as usize;
+        if end + HEADER_SIZE as usize > memory.len() {
+            return Err(HeapError::OutOfMemory { needed: end + HEADER_SIZE as usize });
+        }
+        set_header(memory, addr, size, true);
+        set_header(memory, end as u32, 0, false);
+        memory.fill((addr + HEADER_SIZE) as usize, (size - HEADER_SIZE) as usize, 0);
+        Ok(addr + HEADER_SIZE)
+    }
+
+    pub fn free(&self, memory: &mut Memory, ptr: u32) -> Result<(), HeapError> {
+        let mut addr = self.start;
+        while let Some((block, used)) = header(memory, addr) {
+            if block < HEADER_SIZE || !block.is_multiple_of(ALIGN) {
+                return Err(HeapError::Corrupted(addr));
+            }
+            let next = addr.checked_add(block).ok_or(HeapError::Corrupted(addr))?;
+            if addr + HEADER_SIZE == ptr && used {
+                //NOTE(joh): The last block gives its memory back to the end of the heap
+                let size = if header(memory, next).is_none() { 0 } else { block };
+                set_header(memory, addr, size, false);
+                return Ok(());
+            }
+            if addr + HEADER_SIZE >= ptr {
+                break;
+            }
+            addr = next;
+        }
+        Err(HeapError::InvalidPointer(ptr))
+    }
+
+    //NOTE(joh): Addresses and sizes of the blocks in use, e.g. to show them in a debugger
+    pub fn allocations(&self, memory: &Memory) -> Vec<(u32, u32)> {
+        let mut allocations = Vec::new();
+        let mut addr = self.start;
+        while let Some((block, used)) = header(memory, addr) {
+            if block < HEADER_SIZE || !block.is_multiple_of(ALIGN) {
+                break;
+            }
+            if used {
+                allocations.push((addr + HEADER_SIZE, block - HEADER_SIZE));
+            }
+            let Some(next) = addr.checked_add(block) else {
+                break;
+            };
+            addr = next;
+        }
+        allocations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, interpreter::Interpreter, syscall::*};
+
+    #[test]
+    fn alloc_and_free() {
+        let mut memory = Memory::zeroed(256);
+        let heap = Heap::new(61);
+        assert_eq!(heap.start, 64);
+        let a = heap.alloc(&mut memory, 5).unwrap();
+        let b = heap.alloc(&mut memory, 16).unwrap();
+        let c = heap.alloc(&mut memory, 8).unwrap();
+        assert_eq!([a, b, c], [72, 88, 112]);
+        memory.fill(b as usize, 16, 0xff).unwrap();
+
+        heap.free(&mut memory, b).unwrap();
+        assert_eq!(heap.free(&mut memory, b), Err(HeapError::InvalidPointer(b)));
+        assert_eq!(heap.free(&mut memory, b + 4), Err(HeapError::InvalidPointer(b + 4)));
+        //NOTE(joh): First fit splits the freed block, the memory is zeroed again
+        assert_eq!(heap.alloc(&mut memory, 4).unwrap(), b);
+        assert_eq!(memory.read_le::<u32>(b as usize), Some(0));
+        assert_eq!(heap.allocations(&memory), [(a, 8), (b, 16), (c, 8)]);
+
+        heap.free(&mut memory, c).unwrap();
+        heap.free(&mut memory, b).unwrap();
+        assert_eq!(heap.allocations(&memory), [(a, 8)]);
+        assert_eq!(heap.alloc(&mut memory, 200), Err(HeapError::OutOfMemory { needed: 296 }));
+        assert_eq!(heap.alloc(&mut memory, 24).unwrap(), b);
+
+        memory.write_le(a as usize - 8, 3u32).unwrap();
+        assert_eq!(heap.alloc(&mut memory, 4), Err(HeapError::Corrupted(a - 8)));
+    }
+
+    #[test]
+    fn alloc_syscalls() {
+        let code = format!(
+            "
+            .local a; .local b;
+            #64; push_arg; #{Alloc}; syscall; local_set a;
+            local_get a; #7; store_32 0;
+            local_get a; push_arg; #{Free}; syscall; drop;
+            #100000; push_arg; #{Alloc}; syscall; local_set b;
+            local_get b; #99996; add; #9; store_32 0;
+            local_get a; #4; add; push_arg; #{Free}; syscall;
+            local_get b; local_get a; eq;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        let len = interpreter.memory.len();
+        let result = interpreter.run(&mut StdHandler::new(String::new())).unwrap();
+        assert_eq!(result, [SyscallError::InvalidArgument as u32, 1]);
+        //NOTE(joh): The heap grew the memory for the second block
+        assert!(interpreter.memory.len() > len);
+
+        let buffer = interpreter.alloc_bytes(b"from the host").unwrap();
+        assert!(buffer >= interpreter.heap.start);
+        assert_eq!(interpreter.read_str(buffer, 13).unwrap(), "from the host");
+        interpreter.free(buffer).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use alloc::collections::VecDeque;
+
+use smallvec::SmallVec;
+
+use crate::{
+    interpreter::{Frame, Interpreter, MAX_ARGS},
+    mem::Memory,
+    prelude::*,
+};
+
+//NOTE(joh): Everything needed to undo a single op. Values are only recorded when they
+//are overwritten, so most steps stay small.
+pub struct Step {
+    pc: u32,
+    running: bool,
+    assertion_failed: bool,
+    executed_ops: u64,
+    gas_used: u64,
+    syscall_count: u64,
+    output_bytes: u64,
+    args: SmallVec<[u32; MAX_ARGS]>,
+    //NOTE(joh): Lowest stack height during the op and the values popped below the starting
+    //height, in pop order. Values the op pushed and popped again don't need to be restored.
+    stack_low_water: usize,
+    popped: SmallVec<[u32; 4]>,
+    frame_depth: usize,
+    popped_frame: Option<Frame>,
+    local: Option<(u8, u32)>,
+    global: Option<(u8, u32)>,
+    memory: Vec<(u32, SmallVec<[u8; 8]>)>,
+    //NOTE(joh): A store into one of the memories added by the host
+    other_memory: Option<(u8, u32, SmallVec<[u8; 8]>)>,
+    //NOTE(joh): Syscalls may write anywhere, so the whole memory is kept. Cloning only
+    //shares the pages.
+    memory_snapshot: Option<Memory>,
+}
+
+pub struct History {
+    steps: VecDeque<Step>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            steps: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    pub(crate) fn begin_step(&mut self, interpreter: &Interpreter) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(Step {
+            pc: interpreter.pc,
+            running: interpreter.running,
+            assertion_failed: interpreter.assertion_failed,
+            executed_ops: interpreter.executed_ops,
+            gas_used: interpreter.gas_used,
+            syscall_count: interpreter.syscall_count,
+            output_bytes: interpreter.output_bytes,
+            args: interpreter.args.clone(),
+            stack_low_water: interpreter.value_stack.len(),
+            popped: SmallVec::new(),
+            frame_depth: interpreter.return_stack.len(),
+            popped_frame: None,
+            local: None,
+            global: None,
+            memory: Vec::new(),
+            other_memory: None,
+            memory_snapshot: None,
+        });
+    }
+
+    pub(crate) fn record_pop(&mut self, value: u32, stack_height: usize) {
+        if let Some(step) = self.steps.back_mut()
+            && stack_height < step.stack_low_water
+        {
+            step.stack_low_water = stack_height;
+            step.popped.push(value);
+        }
+    }
+
+    pub(crate) fn record_frame_pop(&mut self, frame: &Frame) {
+        if let Some(step) = self.steps.back_mut() {
+            step.popped_frame = Some(frame.clone());
+        }
+    }
+
+    pub(crate) fn record_local(&mut self, id: u8, old: u32) {
+        if let Some(step) = self.steps.back_mut() {
+            step.local.get_or_insert((id, old));
+        }
+    }
+
+    pub(crate) fn record_global(&mut self, id: u8, old: u32) {
+        if let Some(step) = self.steps.back_mut() {
+            step.global.get_or_insert((id, old));
+        }
+    }
+
+    pub(crate) fn record_memory(&mut self, addr: u32, old: &[u8]) {
+        if let Some(step) = self.steps.back_mut() {
+            step.memory.push((addr, SmallVec::from_slice(old)));
+        }
+    }
+
+    pub(crate) fn record_other_memory(&mut self, index: u8, addr: u32, old: &[u8]) {
+        if let Some(step) = self.steps.back_mut() {
+            step.other_memory = Some((index, addr, SmallVec::from_slice(old)));
+        }
+    }
+
+    pub(crate) fn record_memory_snapshot(&mut self, memory: &Memory) {
+        if let Some(step) = self.steps.back_mut() {
+            step.memory_snapshot.get_or_insert_with(|| memory.clone());
+        }
+    }
+
+    pub(crate) fn pop_step(&mut self) -> Option<Step> {
+        self.steps.pop_back()
+    }
+}
+
+impl Step {
+    pub(crate) fn undo(self, interpreter: &mut Interpreter) {
+        interpreter.value_stack.truncate(self.stack_low_water);
+        interpreter.value_stack.extend(self.popped.iter().rev());
+
+        //NOTE(joh): Locals belong to the frame that was active during the op, so they are
+        //restored after the frame stack is back to its old shape
+        interpreter.return_stack.truncate(self.frame_depth);
+        if let Some(frame) = self.popped_frame {
+            interpreter.return_stack.push(frame);
+        }
+        if let Some((id, old)) = self.local
+            && let Some(frame) = interpreter.return_stack.last_mut()
+        {
+            frame.locals[id as usize] = old;
+        }
+        if let Some((id, old)) = self.global {
+            interpreter.globals[id as usize] = old;
+        }
+
+        if let Some(memory) = self.memory_snapshot {
+            interpreter.memory = memory;
+        }
+        for (addr, old) in self.memory.into_iter().rev() {
+            interpreter.memory.write(addr as usize, &old);
+        }
+        if let Some((index, addr, old)) = self.other_memory
+            && let Some(memory) = interpreter.memory_at_mut(index)
+        {
+            memory.write(addr as usize, &old);
+        }
+
+        interpreter.pc = self.pc;
+        interpreter.running = self.running;
+        interpreter.assertion_failed = self.assertion_failed;
+        interpreter.executed_ops = self.executed_ops;
+        interpreter.gas_used = self.gas_used;
+        interpreter.syscall_count = self.syscall_count;
+        interpreter.output_bytes = self.output_bytes;
+        interpreter.args = self.args;
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{
+    interpreter::{Interpreter, MAX_ARGS},
+    mem::push_le,
+    prelude::*,
+    section::split_section,
+    symbols::{decode_symbols, push_symbols},
+};
+
+pub const HOST_IMPORTS_MAGIC: [u8; 4] = *b"mhst";
+
+//NOTE(joh): Declared with `.host <name> <arity>;`, `call_host` refers to it by its index in the
+//import table. The host provides it with `Interpreter::register_host_fn`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostImport {
+    pub name: String,
+    pub arity: u8,
+}
+
+//NOTE(joh): Gets the args passed with `push_arg`, its result is pushed like the one of a syscall
+pub type HostFn = Box<dyn FnMut(&mut Interpreter, &[u32]) -> u32 + Send>;
+
+//NOTE(joh): The section sits between the global initializers and the exports. It holds the
+//import count, then the arity, name length and name of every import, followed by their size and
+//`HOST_IMPORTS_MAGIC`.
+pub fn encode_host_imports(imports: &[HostImport], buffer: &mut Vec<u8>) {
+    let start = buffer.len();
+    let entries = imports
+        .iter()
+        .map(|import| (import.name.clone(), import.arity as u32))
+        .collect::<Vec<_>>();
+    push_symbols(buffer, &entries);
+    let size = (buffer.len() - start) as u32;
+    push_le(buffer, size);
+    buffer.extend_from_slice(&HOST_IMPORTS_MAGIC);
+}
+
+pub fn split_host_imports(bytecode: &[u8]) -> (&[u8], Option<Box<[HostImport]>>) {
+    split_section(bytecode, HOST_IMPORTS_MAGIC, decode_host_imports)
+}
+
+pub(crate) fn decode_host_imports(mut table: &[u8]) -> Option<Box<[HostImport]>> {
+    let entries = decode_symbols(&mut table).ok()?;
+    if !table.is_empty() || entries.iter().any(|(_, arity)| *arity as usize > MAX_ARGS) {
+        return None;
+    }
+    Some(
+        entries
+            .into_iter()
+            .map(|(name, arity)| HostImport { name, arity: arity as u8 })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        asm::{AssembleErrorKind, Parser},
+        function::split_function_table,
+        interpreter::InterpreterErrorType,
+        syscall::StdHandler,
+        validate::{validate, ValidationError},
+    };
+
+    #[test]
+    fn host_functions() {
+        let code = "
+            .host print 2;
+            .host answer 0;
+            .func twice 0 1;
+            call_host answer; #2; mul;
+            return;
+            :__ENTRY__:
+            #@twice.index; call_indirect; push_arg; #7; push_arg; call_host print;
+            end;
+        ";
+        let bytecode = Parser::parse(code).unwrap().code;
+        let imports = split_host_imports(&bytecode).1.unwrap();
+        assert_eq!(imports[0], HostImport { name: "print".to_string(), arity: 2 });
+        assert_eq!(imports.len(), 2);
+        //NOTE(joh): The function table in front of it is still found
+        assert_eq!(split_function_table(&bytecode).1.map(|f| f.len()), Some(1));
+        assert!(validate(&bytecode).is_ok());
+
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        assert_eq!(interpreter.unbound_host_fns().collect::<Vec<_>>(), ["print", "answer"]);
+        let result = interpreter.run(&mut StdHandler::new(String::new()));
+        assert!(matches!(result, Err(InterpreterErrorType::UnboundHostFn(name)) if name == "answer"));
+
+        let mismatch = interpreter.register_host_fn("print", 1, |_, _| 0);
+        assert!(matches!(mismatch, Err(InterpreterErrorType::HostFnSignatureMismatch { registered: 1, imported: 2, .. })));
+        let sink = printed.clone();
+        interpreter
+            .register_host_fn("print", 2, move |_, args| {
+                sink.lock().unwrap().push(args.to_vec());
+                args.len() as u32
+            })
+            .unwrap();
+        interpreter.register_host_fn("answer", 0, |_, _| 21).unwrap();
+        assert_eq!(interpreter.unbound_host_fns().count(), 0);
+        interpreter.reset_all(&bytecode).unwrap();
+        assert_eq!(interpreter.run(&mut StdHandler::new(String::new())).unwrap(), [2]);
+        assert_eq!(*printed.lock().unwrap(), [vec![42, 7]]);
+
+        //NOTE(joh): The binding is checked again when another program is loaded
+        let other = Parser::parse(".host answer 1; end;").unwrap().code;
+        assert!(matches!(
+            interpreter.reset_all(&other),
+            Err(InterpreterErrorType::HostFnSignatureMismatch { .. })
+        ));
+        let mut corrupted = Parser::parse(".host answer 0; call_host answer; end;").unwrap().code.to_vec();
+        corrupted[crate::asm::BytecodeInfo::total_header_size() + 1] = 1;
+        assert!(matches!(validate(&corrupted), Err(ValidationError::InvalidHostFnIndex { index: 1, .. })));
+
+        let error = |code| Parser::parse(code).map(|_| ()).unwrap_err().kind;
+        assert!(matches!(error("call_host missing;"), AssembleErrorKind::UnknownHostFn(_)));
+        assert!(matches!(error(".host a 1; .host a 1;"), AssembleErrorKind::HostFnAlreadyDefined(_)));
+        let object = Parser::new().assemble_object(".host a 0; end;");
+        assert!(matches!(object.map(|_| ()).unwrap_err().kind, AssembleErrorKind::HostFnInObject));
+    }
+}
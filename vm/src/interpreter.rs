@@ -1,19 +1,37 @@
-use std::str::Utf8Error;
+use alloc::{borrow::Cow, sync::Arc};
+use core::{ops::Range, str::Utf8Error};
 
 use smallvec::SmallVec;
 
-use crate::asm::{self, opcode::{self, StoreArgs}, DATA_START, CODE_START_ADDR_POS};
+use crate::{
+    asm::{opcode::{self, StoreArgs}, BYTECODE_HEADER, CODE_START_ADDR_POS, DATA_START, ENTRY_LABEL_NAME},
+    config::{InterpreterConfig, QuotaKind, SyscallGroup, DEFAULT_GLOBALS, MAX_GLOBALS, STACK_POINTER_GLOBAL},
+    decoded::DecodedCode,
+    function::Function,
+    heap::{Heap, HeapError},
+    history::History,
+    host::{HostFn, HostImport},
+    prelude::*,
+    profile::{CodeSymbols, Profile, StackSamples},
+    trace::{ExecutionTrace, TraceEvent, TraceSink},
+    mem::{mapped_slice, MappedBytes, Memory, PAGE_SIZE},
+    shared::SharedMemory,
+    link::{LinkError, Object},
+    module::{Bytecode, InstructionMap, Module},
+    signing::SignaturePolicy,
+    trap::Trap,
+    validate::{validate, ValidationError},
+};
 
 const INITAL_VALUE_STACK_SIZE: usize = 65536 / 4;
 const INITAL_RETURN_STACK_SIZE: usize = 20;
-const MIN_HEAP_SIZE: usize = 65536;
-const MAX_GLOBALS: usize = 64;
-const MAX_LOCALS: usize = 64;
-const MAX_ARGS: usize = 12;
+pub(crate) const MIN_HEAP_SIZE: usize = 65536;
+pub const MAX_LOCALS: usize = 64;
+pub const MAX_ARGS: usize = 12;
 
 #[derive(Debug)]
 pub enum InterpreterErrorType {
-    IOError(std::io::Error),
+    IOError(crate::io::Error),
     InvalidStringData(Utf8Error),
     InvalidBytecodeHeader,
     AddrOutOfBounds(u32),
@@ -22,71 +40,287 @@ pub enum InterpreterErrorType {
     InvalidJumpAddr(u32),
     InvalidLocalId(u8),
     InvalidGlobalId(u8),
+    InvalidConstPoolIndex(u8),
     ArgStackFull,
     UnexpectedEmptyFrameStack,
-
+    PermissionDenied(u32),
+    QuotaExceeded { kind: QuotaKind, limit: u64 },
+    UntrustedModule,
+    UnknownLabel(String),
+    //NOTE(joh): Nothing of the next op has been executed, running again resumes there
+    OutOfFuel,
+    InvalidOpcode(u8),
+    DivideByZero,
+    IntegerOverflow,
+    InvalidFunctionIndex(u32),
+    ArityMismatch { function: u32, expected: u8, found: u8 },
+    //NOTE(joh): `found` is negative if the function popped values of its caller
+    ReturnArityMismatch { expected: u8, found: i32 },
+    ValueStackOverflow(usize),
+    CallStackOverflow(usize),
+    InvalidBytecode(ValidationError),
+    Link(LinkError),
+    InvalidHostFnIndex(u8),
+    UnboundHostFn(String),
+    //NOTE(joh): The program imports the host function with another arity than it was registered with
+    HostFnSignatureMismatch { name: String, registered: u8, imported: u8 },
+    HostFnArityMismatch { name: String, expected: u8, found: u8 },
+    Heap(HeapError),
+    //NOTE(joh): `push_mem` of the size would leave the memory stack
+    MemoryStackOverflow(u32),
+    MemoryStackUnderflow(u32),
+    WriteToProtectedMemory(u32),
+    //NOTE(joh): `load_mem_32` and friends used a memory the host did not add
+    InvalidMemoryIndex(u8),
+    InvalidSharedMemoryIndex(u8),
+    //NOTE(joh): Atomics only work on addresses that are a multiple of 4, also the ones on `memory`
+    UnalignedAtomic(u32),
+    //NOTE(joh): The module initializes more globals than `InterpreterConfig::globals`
+    TooManyGlobals { count: usize, limit: usize },
+}
+impl core::fmt::Display for InterpreterErrorType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::IOError(e) => write!(f, "io error: {e}"),
+            Self::InvalidStringData(e) => write!(f, "invalid string data: {e}"),
+            Self::InvalidBytecodeHeader => write!(f, "invalid bytecode header"),
+            Self::AddrOutOfBounds(addr) => write!(f, "address 0x{addr:04x} is out of bounds"),
+            Self::UnexpectedValStackEmpty => write!(f, "value stack is empty"),
+            Self::ReachedUnreachable => write!(f, "reached unreachable"),
+            Self::InvalidJumpAddr(addr) => write!(f, "invalid jump address 0x{addr:04x}"),
+            Self::InvalidLocalId(id) => write!(f, "invalid local id {id}"),
+            Self::InvalidGlobalId(id) => write!(f, "invalid global id {id}"),
+            Self::InvalidConstPoolIndex(index) => write!(f, "invalid constant pool index {index}"),
+            Self::ArgStackFull => write!(f, "argument stack is full"),
+            Self::UnexpectedEmptyFrameStack => write!(f, "frame stack is empty"),
+            Self::PermissionDenied(id) => write!(f, "permission denied for syscall {id}"),
+            Self::QuotaExceeded { kind, limit } => write!(f, "{kind:?} quota of {limit} exceeded"),
+            Self::UntrustedModule => write!(f, "module is not signed by a trusted key"),
+            Self::UnknownLabel(label) => write!(f, "unknown label `{label}`"),
+            Self::OutOfFuel => write!(f, "out of fuel"),
+            Self::InvalidOpcode(op) => write!(f, "invalid opcode 0x{op:02x}"),
+            Self::DivideByZero => write!(f, "division by zero"),
+            Self::IntegerOverflow => write!(f, "integer overflow"),
+            Self::InvalidFunctionIndex(index) => write!(f, "invalid function index {index}"),
+            Self::ArityMismatch { function, expected, found } => {
+                write!(f, "function {function} takes {expected} arguments but {found} were pushed")
+            }
+            Self::ReturnArityMismatch { expected, found } if *found < 0 => {
+                write!(f, "function returns {expected} values but popped {} of its caller", -found)
+            }
+            Self::ReturnArityMismatch { expected, found } => {
+                write!(f, "function returns {expected} values but left {found} on the stack")
+            }
+            Self::ValueStackOverflow(limit) => write!(f, "value stack exceeded {limit} values"),
+            Self::CallStackOverflow(limit) => write!(f, "call stack exceeded {limit} frames"),
+            Self::InvalidBytecode(e) => write!(f, "invalid bytecode: {e}"),
+            Self::Link(e) => write!(f, "link error: {e}"),
+            Self::InvalidHostFnIndex(index) => write!(f, "invalid host function index {index}"),
+            Self::UnboundHostFn(name) => write!(f, "host function `{name}` is not registered"),
+            Self::HostFnSignatureMismatch { name, registered, imported } => {
+                write!(f, "host function `{name}` takes {registered} arguments but is imported with {imported}")
+            }
+            Self::HostFnArityMismatch { name, expected, found } => {
+                write!(f, "host function `{name}` takes {expected} arguments but {found} were pushed")
+            }
+            Self::Heap(e) => write!(f, "heap error: {e}"),
+            Self::MemoryStackOverflow(size) => write!(f, "memory stack overflow reserving {size} bytes"),
+            Self::MemoryStackUnderflow(size) => write!(f, "memory stack underflow releasing {size} bytes"),
+            Self::WriteToProtectedMemory(addr) => write!(f, "address 0x{addr:04x} is write protected"),
+            Self::InvalidMemoryIndex(index) => write!(f, "invalid memory index {index}"),
+            Self::InvalidSharedMemoryIndex(index) => write!(f, "invalid shared memory index {index}"),
+            Self::UnalignedAtomic(addr) => write!(f, "atomic access to unaligned address 0x{addr:04x}"),
+            Self::TooManyGlobals { count, limit } => write!(f, "module has {count} globals but only {limit} are available"),
+        }
+    }
+}
+impl core::error::Error for InterpreterErrorType {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            Self::IOError(e) => Some(e),
+            Self::InvalidStringData(e) => Some(e),
+            Self::InvalidBytecode(e) => Some(e),
+            Self::Link(e) => Some(e),
+            Self::Heap(e) => Some(e),
+            _ => None,
+        }
+    }
 }
-impl From<std::io::Error>  for InterpreterErrorType {
-    fn from(value: std::io::Error) -> Self {
+impl From<crate::io::Error>  for InterpreterErrorType {
+    fn from(value: crate::io::Error) -> Self {
         Self::IOError(value)
     }
 }
+impl From<HeapError> for InterpreterErrorType {
+    fn from(value: HeapError) -> Self {
+        Self::Heap(value)
+    }
+}
 impl From<Utf8Error> for InterpreterErrorType {
     fn from(value: Utf8Error) -> Self {
         Self::InvalidStringData(value)
     }
 }
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Frame {
+    #[cfg_attr(feature = "serde", serde(with = "serde_locals"))]
     pub locals: [u32; MAX_LOCALS],
     pub return_addr: u32,
+    //NOTE(joh): Height of the value stack when the frame was created. Functions that declare
+    //`returns` have to leave exactly that many values above it.
+    pub stack_base: u32,
+    pub returns: Option<u8>,
+}
+//NOTE(joh): serde only implements arrays up to 32 elements
+#[cfg(feature = "serde")]
+mod serde_locals {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::MAX_LOCALS;
+
+    pub fn serialize<S: Serializer>(locals: &[u32; MAX_LOCALS], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(locals)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u32; MAX_LOCALS], D::Error> {
+        let locals = Vec::<u32>::deserialize(deserializer)?;
+        locals
+            .try_into()
+            .map_err(|locals: Vec<u32>| D::Error::invalid_length(locals.len(), &"one value per local"))
+    }
 }
+
 impl Frame {
     pub fn empty() -> Self {
         Self {
             locals: [0; _],
             return_addr: CODE_START_ADDR_POS,
+            stack_base: 0,
+            returns: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Yielded(u32),
+    //NOTE(joh): Ended, returned from the bottom frame or halted at a `dbg_halt`
+    Stopped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallTarget<'a> {
+    Addr(u32),
+    Label(&'a str),
+}
+
+impl From<u32> for CallTarget<'_> {
+    fn from(value: u32) -> Self {
+        Self::Addr(value)
+    }
+}
+
+impl<'a> From<&'a str> for CallTarget<'a> {
+    fn from(value: &'a str) -> Self {
+        Self::Label(value)
+    }
+}
+
 pub trait SyscallHandler {
     fn on_syscall(&mut self, interpreter: &mut Interpreter, syscall_id: u32, args: &[u32]) -> u32;
+
+    //NOTE(joh): Syscalls without a group are not restricted by the capabilities
+    fn syscall_group(&self, _syscall_id: u32) -> Option<SyscallGroup> {
+        None
+    }
 }
 
 pub struct Interpreter {
     pub value_stack: Vec<u32>,
     pub return_stack: Vec<Frame>,
-    pub memory: Vec<u8>,
+    pub memory: Memory,
+    //NOTE(joh): Added by the host next to `memory`, which has index 0. Kept when another program
+    //is loaded, see `add_memory`.
+    pub memories: Vec<Memory>,
+    //NOTE(joh): Accessed with the atomic ops, also kept when another program is loaded
+    pub shared: Vec<SharedMemory>,
     pub pc: u32,
-    pub globals: [u32; MAX_GLOBALS],
+    //NOTE(joh): `InterpreterConfig::globals` long
+    pub globals: Box<[u32]>,
     pub args: SmallVec<[u32; MAX_ARGS]>,
     pub start_pc_addr: u32,
-    pub bytecode_len: usize,
+    pub bytecode: Bytecode,
+    pub const_pool: Arc<[u32]>,
+    //NOTE(joh): Targets of `call_indirect`
+    pub functions: Arc<[Function]>,
+    //NOTE(joh): Valid jump and call targets, shared with the module until code is reloaded
+    pub instructions: Arc<InstructionMap>,
+    //NOTE(joh): Targets of `call_host`, bound to `host_fns` by name when the program is loaded
+    pub host_imports: Arc<[HostImport]>,
+    host_bindings: Box<[Option<usize>]>,
+    //NOTE(joh): Kept when another program is loaded, see `register_host_fn`
+    host_fns: Vec<(String, u8, HostFn)>,
+    //NOTE(joh): Reserved behind the image of the loaded program, the stack pointer starts at the
+    //end and moves down, see `push_mem`
+    pub memory_stack: Range<u32>,
+    //NOTE(joh): Stores, `mem_copy` and `mem_fill` into these trap. Holds the code of the loaded
+    //program unless `InterpreterConfig::protect_code` is off, see `set_code_writable`.
+    pub protected: Vec<Range<u32>>,
+    code_range: Range<u32>,
+    //NOTE(joh): Behind the memory stack, see `alloc`
+    pub heap: Heap,
     pub running: bool,
+    //NOTE(joh): Set by `yield`, taken by `run_until_yield`
+    pub yielded: Option<u32>,
     pub assertion_failed: bool,
+    pub executed_ops: u64,
+    //NOTE(joh): Sum of the costs of the executed ops, stays 0 without `InterpreterConfig::gas_table`
+    pub gas_used: u64,
+    pub syscall_count: u64,
+    pub output_bytes: u64,
+    pub config: InterpreterConfig,
+    //NOTE(joh): Absolute addresses, only needed to call functions by name
+    pub labels: HashMap<String, u32>,
+    //NOTE(joh): Only recorded while debugging, see `start_recording`
+    pub history: Option<History>,
+    //NOTE(joh): Remaining ops before `OutOfFuel`, `None` means no metering. Unlike the fuel
+    //quota this can be refilled and the program resumed.
+    fuel: Option<u64>,
+    trace: Option<Box<dyn TraceSink + Send>>,
+    //NOTE(joh): Only collected while profiling, see `start_profiling`
+    profile: Option<Box<Profile>>,
+    //NOTE(joh): Addresses of the ops executed since `start_coverage`
+    coverage: Option<InstructionMap>,
+    //NOTE(joh): Only recorded between `start_trace` and `stop_trace`
+    execution_trace: Option<Box<ExecutionTrace>>,
+    //NOTE(joh): Only collected while sampling, see `start_sampling`
+    stack_samples: Option<Box<StackSamples>>,
+    //NOTE(joh): Kept between runs of `run_decoded`, rebuilt once the code changed
+    pub(crate) decoded: Option<Arc<DecodedCode>>,
 }
 
 macro_rules! interpreter_impl_read_op {
     ($name: ident, $t: tt) => {
         pub fn $name(&self, addr: u32) -> Result<$t, InterpreterErrorType> {
-            Ok($t::from_le_bytes(
-                self.memory
-                    .get(addr as usize..addr as usize + size_of::<$t>())
-                    .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))?
-                    .try_into()
-                    .unwrap(),
-            ))
+            self.memory
+                .read_le::<$t>(addr as usize)
+                .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))
         }
     };
 }
 macro_rules! interpreter_impl_store {
     ($name: ident, $t: tt) => {
         pub fn $name(&mut self, addr: u32, value: $t) -> Result<(), InterpreterErrorType> {
+            self.check_writable(addr, size_of::<$t>() as u32)?;
+            if let Some(history) = &mut self.history
+                && let Some(old) = self.memory.slice(addr as usize, size_of::<$t>())
+            {
+                history.record_memory(addr, &old);
+            }
             self.memory
-                .get_mut(addr as usize..addr as usize + size_of::<$t>())
-                .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))?
-                .copy_from_slice(&$t::to_le_bytes(value));
-            Ok(())
+                .write_le(addr as usize, value)
+                .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))
         }
     };
 }
@@ -99,8 +333,62 @@ macro_rules! do_binop {
     };
 }
 
+macro_rules! do_binop_f32 {
+    ($self: ident, $a: ident, $b: ident, $op: expr) => {
+        let $b = f32::from_bits($self.pop()?);
+        let $a = f32::from_bits($self.pop()?);
+        $self.push($op);
+        $self.pc += 1;
+    };
+}
+macro_rules! do_binop_64 {
+    ($self: ident, $a: ident, $b: ident, $op: expr) => {
+        let $b = $self.pop_u64()?;
+        let $a = $self.pop_u64()?;
+        $self.push_u64($op as u64);
+        $self.pc += 1;
+    };
+}
+macro_rules! do_cmp_64 {
+    ($self: ident, $a: ident, $b: ident, $op: expr) => {
+        let $b = $self.pop_u64()?;
+        let $a = $self.pop_u64()?;
+        $self.push($op as u32);
+        $self.pc += 1;
+    };
+}
+
+//NOTE(joh): Signed division overflows for MIN / -1, everything else only fails on a zero divisor
+macro_rules! impl_checked_div {
+    ($name: ident, $unsigned: ty, $signed: ty) => {
+        fn $name(a: $unsigned, b: $unsigned, signed: bool) -> Result<$unsigned, InterpreterErrorType> {
+            if b == 0 {
+                return Err(InterpreterErrorType::DivideByZero);
+            }
+            match signed {
+                true => (a as $signed)
+                    .checked_div(b as $signed)
+                    .map(|r| r as $unsigned)
+                    .ok_or(InterpreterErrorType::IntegerOverflow),
+                false => Ok(a / b),
+            }
+        }
+    };
+}
+impl_checked_div!(checked_div_32, u32, i32);
+impl_checked_div!(checked_div_64, u64, i64);
+
+//NOTE(joh): Unlike the division `MIN rem_s -1` does not overflow, the remainder is just 0
+fn checked_rem_32(a: u32, b: u32, signed: bool) -> Result<u32, InterpreterErrorType> {
+    match (b, signed) {
+        (0, _) => Err(InterpreterErrorType::DivideByZero),
+        (_, true) => Ok((a as i32).wrapping_rem(b as i32) as u32),
+        (_, false) => Ok(a % b),
+    }
+}
+
 pub fn is_bytecode_header_valid(bytecode: &[u8]) -> Result<(), InterpreterErrorType> {
-    if bytecode[0..4].eq(&[b'm', b'a', b'l', b'u']) {
+    if bytecode.starts_with(&BYTECODE_HEADER) {
         Ok(())
     } else {
         Err(InterpreterErrorType::InvalidBytecodeHeader)
@@ -110,88 +398,494 @@ pub fn is_bytecode_header_valid(bytecode: &[u8]) -> Result<(), InterpreterErrorT
 impl Default for Interpreter {
     fn default() -> Self {
         Self {
-            value_stack: Default::default(),
-            return_stack: Default::default(),
+            value_stack: Vec::with_capacity(INITAL_VALUE_STACK_SIZE),
+            return_stack: Vec::with_capacity(INITAL_RETURN_STACK_SIZE),
             memory: Default::default(),
+            memories: Vec::new(),
+            shared: Vec::new(),
             pc: Default::default(),
-            globals: [0; _],
+            globals: vec![0; DEFAULT_GLOBALS].into_boxed_slice(),
             args: Default::default(),
             running: Default::default(),
+            yielded: None,
             assertion_failed: Default::default(),
             start_pc_addr: 0,
-            bytecode_len: 0,
-            
+            bytecode: Bytecode::default(),
+            const_pool: Arc::new([]),
+            functions: Arc::new([]),
+            instructions: Arc::default(),
+            host_imports: Arc::new([]),
+            host_bindings: Box::new([]),
+            host_fns: Vec::new(),
+            memory_stack: 0..0,
+            protected: Vec::new(),
+            code_range: 0..0,
+            heap: Heap::default(),
+            executed_ops: 0,
+            gas_used: 0,
+            syscall_count: 0,
+            output_bytes: 0,
+            config: Default::default(),
+            labels: HashMap::new(),
+            history: None,
+            fuel: None,
+            trace: None,
+            profile: None,
+            coverage: None,
+            execution_trace: None,
+            stack_samples: None,
+            decoded: None,
         }
     }
 }
 impl Interpreter {
     interpreter_impl_read_op!(read_u16, u16);
     interpreter_impl_read_op!(read_u32, u32);
+    interpreter_impl_read_op!(read_u64, u64);
 
     interpreter_impl_read_op!(read_i16, i16);
     interpreter_impl_read_op!(read_i32, i32);
 
     interpreter_impl_store!(store_u16, u16);
     interpreter_impl_store!(store_u32, u32);
+    interpreter_impl_store!(store_u64, u64);
 
     interpreter_impl_store!(store_i16, i16);
     interpreter_impl_store!(store_i32, i32);
 
-    pub fn read_str(&mut self, addr: u32, len: u32) -> Result<&str, InterpreterErrorType> {
+    pub fn read_str(&self, addr: u32, len: u32) -> Result<Cow<'_, str>, InterpreterErrorType> {
         //TODO: Kommuniziere dass addr + länge out of bounds ist
-        let slice = self.memory.get(addr as usize .. addr as usize + len as usize)
+        let slice = self.memory.slice(addr as usize, len as usize)
             .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))?;
             
-        Ok(str::from_utf8(slice)?)
+        match slice {
+            Cow::Borrowed(bytes) => Ok(Cow::Borrowed(str::from_utf8(bytes)?)),
+            Cow::Owned(bytes) => Ok(Cow::Owned(String::from_utf8(bytes).map_err(|e| e.utf8_error())?)),
+        }
     } 
 
+    //NOTE(joh): For syscalls and hosts that write into the memory of the program, the bytes go
+    //through the same checks as the stores of the program
+    pub fn write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<(), InterpreterErrorType> {
+        let len = u32::try_from(bytes.len()).map_err(|_| InterpreterErrorType::AddrOutOfBounds(addr))?;
+        self.check_writable(addr, len)?;
+        if let Some(history) = &mut self.history
+            && let Some(old) = self.memory.slice(addr as usize, bytes.len())
+        {
+            history.record_memory(addr, &old);
+        }
+        self.memory
+            .write(addr as usize, bytes)
+            .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))
+    }
+
     pub fn from_bytecode(bytecode: &[u8]) -> Result<Self, InterpreterErrorType> {
-        is_bytecode_header_valid(bytecode)?;
+        Self::from_bytecode_with_config(bytecode, InterpreterConfig::default())
+    }
+
+    pub fn from_bytecode_with_config(bytecode: &[u8], config: InterpreterConfig) -> Result<Self, InterpreterErrorType> {
+        if config.validate {
+            validate(bytecode).map_err(InterpreterErrorType::InvalidBytecode)?;
+        }
+        Self::instantiate_with_config(&Module::from_bytecode(bytecode)?, config)
+    }
+
+    //NOTE(joh): Runs the program straight from `bytecode`, see `Module::from_static`
+    pub fn from_static_bytecode(bytecode: &'static [u8]) -> Result<Self, InterpreterErrorType> {
+        Self::from_static_bytecode_with_config(bytecode, InterpreterConfig::default())
+    }
+
+    pub fn from_static_bytecode_with_config(
+        bytecode: &'static [u8],
+        config: InterpreterConfig,
+    ) -> Result<Self, InterpreterErrorType> {
+        if config.validate {
+            validate(bytecode).map_err(InterpreterErrorType::InvalidBytecode)?;
+        }
+        Self::instantiate_with_config(&Module::from_static(bytecode)?, config)
+    }
+
+    //NOTE(joh): Runs the program straight from bytecode with an owner, see `Module::from_mapped`
+    pub fn from_mapped_bytecode(bytes: MappedBytes) -> Result<Self, InterpreterErrorType> {
+        Self::from_mapped_bytecode_with_config(bytes, InterpreterConfig::default())
+    }
 
-        let mut interpreter = Interpreter::default(); 
-        interpreter.memory = vec![0; MIN_HEAP_SIZE + bytecode.len()];
-        interpreter.init_memory(bytecode);
-        interpreter.return_stack.push(Frame::empty());
-        let start_code_addr = interpreter.read_u32(CODE_START_ADDR_POS)?;
-        interpreter.start_pc_addr = start_code_addr;
-        interpreter.bytecode_len = bytecode.len();
+    pub fn from_mapped_bytecode_with_config(
+        bytes: MappedBytes,
+        config: InterpreterConfig,
+    ) -> Result<Self, InterpreterErrorType> {
+        if config.validate {
+            validate(mapped_slice(&bytes)).map_err(InterpreterErrorType::InvalidBytecode)?;
+        }
+        Self::instantiate_with_config(&Module::from_mapped(bytes)?, config)
+    }
+
+    //NOTE(joh): Links the objects when the program is loaded, see `Module::link`
+    pub fn link(objects: &[Object], entry: Option<&str>) -> Result<Self, InterpreterErrorType> {
+        Self::instantiate(&Module::link(objects, entry)?)
+    }
 
-        interpreter.pc = start_code_addr;
-        println!("code start addr: {}", interpreter.pc);
+    pub fn instantiate(module: &Module) -> Result<Self, InterpreterErrorType> {
+        Self::instantiate_with_config(module, InterpreterConfig::default())
+    }
 
+    //NOTE(joh): The memory image is shared with the module and only copied page by page
+    //once the instance writes to it
+    pub fn instantiate_with_config(module: &Module, config: InterpreterConfig) -> Result<Self, InterpreterErrorType> {
+        let mut interpreter = Self::with_config(config);
+        interpreter.load_module(module)?;
         Ok(interpreter)
     }
 
-    pub fn init_memory(&mut self, bytecode: &[u8]) {
-        self.memory[..bytecode.len() - 4].copy_from_slice(&bytecode[4..]);
+    //NOTE(joh): No module is loaded yet, see `reset_all` and `reset_to_module`
+    pub fn with_config(config: InterpreterConfig) -> Self {
+        Self {
+            globals: vec![0; config.globals.min(MAX_GLOBALS)].into_boxed_slice(),
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn load_module(&mut self, module: &Module) -> Result<(), InterpreterErrorType> {
+        if let SignaturePolicy::RequireSignedBy(keys) = &self.config.signature_policy
+            && !module.is_signed_by(keys)
+        {
+            return Err(InterpreterErrorType::UntrustedModule);
+        }
+        let stack_start = module.image().len().next_multiple_of(8);
+        let stack_end = stack_start + self.config.memory_stack_size.next_multiple_of(8);
+        let memory_size = stack_end.max(self.config.memory_size);
+        self.check_quota(QuotaKind::Memory, memory_size as u64)?;
+        //NOTE(joh): The stack pointer needs a slot of its own behind the initialized globals
+        let inits = module.global_inits();
+        let limit = match stack_start == stack_end {
+            true => self.config.globals.min(MAX_GLOBALS),
+            false => self.config.globals.min(MAX_GLOBALS - 1),
+        };
+        if inits.len() > limit {
+            return Err(InterpreterErrorType::TooManyGlobals { count: inits.len(), limit });
+        }
+        self.memory = module.image().clone();
+        self.memory.grow(memory_size - self.memory.len());
+        self.bytecode = module.shared_bytecode();
+        self.const_pool = module.const_pool_arc();
+        self.functions = module.functions_arc();
+        self.instructions = module.instructions_arc();
+        self.host_imports = module.host_imports_arc();
+        self.bind_host_fns()?;
+        self.memory_stack = stack_start as u32..stack_end as u32;
+        self.code_range = module.code_range();
+        self.protected.clear();
+        if self.config.protect_code {
+            self.protected.push(self.code_range.clone());
+        }
+        self.heap = Heap::new(stack_end as u32);
+        let mut count = self.config.globals.min(MAX_GLOBALS);
+        if !self.memory_stack.is_empty() {
+            count = (count + 1).min(MAX_GLOBALS);
+        }
+        if self.globals.len() != count {
+            self.globals = vec![0; count].into_boxed_slice();
+        }
+        self.globals[..inits.len()].copy_from_slice(inits);
+        if let Some(sp) = self.stack_pointer_slot() {
+            self.globals[sp] = self.memory_stack.end;
+        }
+        if let Some(symbols) = module.symbols() {
+            self.set_labels(&symbols.labels);
+        }
+        self.return_stack.push(Frame::empty());
+
+        self.start_pc_addr = module.start_pc_addr();
+        self.pc = self.start_pc_addr;
+
+        Ok(())
+    }
+
+    //NOTE(joh): Makes `f` callable by `call_host` under `name`, replacing an earlier one of that
+    //name. Fails if the loaded program imports it with another arity, every program loaded later
+    //is checked the same way. Imports that are never registered only fail once they are called.
+    pub fn register_host_fn(
+        &mut self,
+        name: impl Into<String>,
+        arity: u8,
+        f: impl FnMut(&mut Interpreter, &[u32]) -> u32 + Send + 'static,
+    ) -> Result<(), InterpreterErrorType> {
+        let name = name.into();
+        if let Some(import) = self.host_imports.iter().find(|import| import.name == name)
+            && import.arity != arity
+        {
+            return Err(InterpreterErrorType::HostFnSignatureMismatch { name, registered: arity, imported: import.arity });
+        }
+        match self.host_fns.iter().position(|(n, ..)| *n == name) {
+            Some(index) => self.host_fns[index] = (name, arity, Box::new(f)),
+            None => self.host_fns.push((name, arity, Box::new(f))),
+        }
+        self.bind_host_fns()
+    }
+
+    fn bind_host_fns(&mut self) -> Result<(), InterpreterErrorType> {
+        let mut bindings = Vec::with_capacity(self.host_imports.len());
+        for import in self.host_imports.iter() {
+            let binding = self.host_fns.iter().position(|(name, ..)| *name == import.name);
+            if let Some(index) = binding
+                && self.host_fns[index].1 != import.arity
+            {
+                return Err(InterpreterErrorType::HostFnSignatureMismatch {
+                    name: import.name.clone(),
+                    registered: self.host_fns[index].1,
+                    imported: import.arity,
+                });
+            }
+            bindings.push(binding);
+        }
+        self.host_bindings = bindings.into_boxed_slice();
+        Ok(())
+    }
+
+    //NOTE(joh): Imports of the loaded program that no host function was registered for
+    pub fn unbound_host_fns(&self) -> impl Iterator<Item = &str> {
+        self.host_imports
+            .iter()
+            .zip(&self.host_bindings)
+            .filter(|(_, binding)| binding.is_none())
+            .map(|(import, _)| import.name.as_str())
+    }
+
+    fn exec_call_host(&mut self) -> Result<(), InterpreterErrorType> {
+        let index = self.read_imm_u8(1)?;
+        let import = self
+            .host_imports
+            .get(index as usize)
+            .ok_or(InterpreterErrorType::InvalidHostFnIndex(index))?;
+        let Some(binding) = self.host_bindings[index as usize] else {
+            return Err(InterpreterErrorType::UnboundHostFn(import.name.clone()));
+        };
+        if self.args.len() != import.arity as usize {
+            return Err(InterpreterErrorType::HostFnArityMismatch {
+                name: import.name.clone(),
+                expected: import.arity,
+                found: self.args.len() as u8,
+            });
+        }
+        if let Some(history) = &mut self.history {
+            history.record_memory_snapshot(&self.memory);
+        }
+        let args = core::mem::take(&mut self.args);
+        //NOTE(joh): Taken out while it runs so it can get the interpreter, it can't register host
+        //functions itself
+        let mut host_fns = core::mem::take(&mut self.host_fns);
+        let ret = (host_fns[binding].2)(self, &args);
+        self.host_fns = host_fns;
+        self.push(ret);
+        self.pc += 2;
+        Ok(())
+    }
+
+    //NOTE(joh): Allocates zeroed memory on the heap of the program like the `Alloc` syscall,
+    //growing the memory if needed. Hosts use it to hand buffers to the guest.
+    pub fn alloc(&mut self, size: u32) -> Result<u32, InterpreterErrorType> {
+        match self.heap.alloc(&mut self.memory, size) {
+            Err(HeapError::OutOfMemory { needed }) if needed <= u32::MAX as usize => {
+                let additional = (needed - self.memory.len()).next_multiple_of(PAGE_SIZE);
+                self.check_quota(QuotaKind::Memory, (self.memory.len() + additional) as u64)?;
+                self.memory.grow(additional);
+                Ok(self.heap.alloc(&mut self.memory, size)?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    pub fn alloc_bytes(&mut self, data: &[u8]) -> Result<u32, InterpreterErrorType> {
+        let len = u32::try_from(data.len()).map_err(|_| HeapError::OutOfMemory { needed: usize::MAX })?;
+        let addr = self.alloc(len)?;
+        self.memory.write(addr as usize, data);
+        Ok(addr)
+    }
+
+    pub fn free(&mut self, addr: u32) -> Result<(), InterpreterErrorType> {
+        Ok(self.heap.free(&mut self.memory, addr)?)
+    }
+
+    //NOTE(joh): Returns the index the program uses to access it, `None` once all are taken. A
+    //buffer can be shared with other instances by adding clones of it, their pages are only
+    //copied once someone writes to them. Writes show up in the other instances once the host
+    //adds the memory again, e.g. with `set_memory`.
+    pub fn add_memory(&mut self, memory: Memory) -> Option<u8> {
+        let index = u8::try_from(self.memories.len() + 1).ok()?;
+        self.memories.push(memory);
+        Some(index)
+    }
+
+    pub fn memory_at(&self, index: u8) -> Option<&Memory> {
+        match index {
+            0 => Some(&self.memory),
+            _ => self.memories.get(index as usize - 1),
+        }
+    }
+
+    pub fn memory_at_mut(&mut self, index: u8) -> Option<&mut Memory> {
+        match index {
+            0 => Some(&mut self.memory),
+            _ => self.memories.get_mut(index as usize - 1),
+        }
+    }
+
+    //NOTE(joh): Replaces the memory and returns the old one
+    pub fn set_memory(&mut self, index: u8, memory: Memory) -> Result<Memory, InterpreterErrorType> {
+        let slot = self.memory_at_mut(index).ok_or(InterpreterErrorType::InvalidMemoryIndex(index))?;
+        Ok(core::mem::replace(slot, memory))
+    }
+
+    //NOTE(joh): Returns the index the atomic ops use, `None` once all are taken. Add clones of the
+    //same memory to several instances to let them work on it together, see `fork_worker`.
+    pub fn add_shared_memory(&mut self, shared: SharedMemory) -> Option<u8> {
+        let index = u8::try_from(self.shared.len()).ok()?;
+        self.shared.push(shared);
+        Some(index)
+    }
+
+    fn shared_memory(&self, index: u8, addr: u32) -> Result<&SharedMemory, InterpreterErrorType> {
+        let shared = self
+            .shared
+            .get(index as usize)
+            .ok_or(InterpreterErrorType::InvalidSharedMemoryIndex(index))?;
+        match addr % 4 {
+            0 if (addr as usize) < shared.len() => Ok(shared),
+            0 => Err(InterpreterErrorType::AddrOutOfBounds(addr)),
+            _ => Err(InterpreterErrorType::UnalignedAtomic(addr)),
+        }
+    }
+
+    //NOTE(joh): A new instance of the loaded program, e.g. for another thread. It starts with a
+    //copy of the memories and the globals, the pages are shared until either side writes to them,
+    //and with the same shared memories. Host functions are not taken along, they have to be
+    //registered again. The quota counters and the fuel are taken along, so a worker can only use
+    //what this instance has left.
+    pub fn fork_worker(&self) -> Interpreter {
+        let mut worker = Self::with_config(self.config.clone());
+        worker.memory = self.memory.clone();
+        worker.memories = self.memories.clone();
+        worker.shared = self.shared.clone();
+        worker.globals = self.globals.clone();
+        worker.start_pc_addr = self.start_pc_addr;
+        worker.pc = self.start_pc_addr;
+        worker.bytecode = self.bytecode.clone();
+        worker.const_pool = self.const_pool.clone();
+        worker.functions = self.functions.clone();
+        worker.instructions = self.instructions.clone();
+        worker.host_imports = self.host_imports.clone();
+        worker.host_bindings = vec![None; self.host_imports.len()].into_boxed_slice();
+        worker.memory_stack = self.memory_stack.clone();
+        worker.protected = self.protected.clone();
+        worker.code_range = self.code_range.clone();
+        worker.heap = self.heap;
+        worker.labels = self.labels.clone();
+        worker.executed_ops = self.executed_ops;
+        worker.gas_used = self.gas_used;
+        worker.syscall_count = self.syscall_count;
+        worker.output_bytes = self.output_bytes;
+        worker.fuel = self.fuel;
+        worker.return_stack.push(Frame::empty());
+        worker
+    }
+
+    fn load_from_memory(&mut self, index: u8, size: usize) -> Result<u32, InterpreterErrorType> {
+        let addr = self.pop()?;
+        let memory = self.memory_at(index).ok_or(InterpreterErrorType::InvalidMemoryIndex(index))?;
+        let value = match size {
+            1 => memory.get(addr as usize).map(u32::from),
+            _ => memory.read_le::<u32>(addr as usize),
+        };
+        value.ok_or(InterpreterErrorType::AddrOutOfBounds(addr))
+    }
+
+    //NOTE(joh): The memory of the program goes through the checks of the other stores
+    fn store_to_memory(&mut self, index: u8, size: usize) -> Result<(), InterpreterErrorType> {
+        let value = self.pop()?;
+        let addr = self.pop()?;
+        let bytes = &value.to_le_bytes()[..size];
+        if index == 0 {
+            return match size {
+                1 => self.store_u8(addr, value as u8),
+                _ => self.store_u32(addr, value),
+            };
+        }
+        let memory = self
+            .memories
+            .get_mut(index as usize - 1)
+            .ok_or(InterpreterErrorType::InvalidMemoryIndex(index))?;
+        if let Some(history) = &mut self.history
+            && let Some(old) = memory.slice(addr as usize, size)
+        {
+            history.record_other_memory(index, addr, &old);
+        }
+        memory.write(addr as usize, bytes).ok_or(InterpreterErrorType::AddrOutOfBounds(addr))
+    }
+
+    pub fn is_writable(&self, addr: u32, len: u32) -> bool {
+        let end = addr.saturating_add(len);
+        !self.protected.iter().any(|region| addr < region.end && end > region.start)
+    }
+
+    fn check_writable(&self, addr: u32, len: u32) -> Result<(), InterpreterErrorType> {
+        match self.is_writable(addr, len) {
+            true => Ok(()),
+            false => Err(InterpreterErrorType::WriteToProtectedMemory(addr)),
+        }
+    }
+
+    //NOTE(joh): For programs that patch their own code on purpose. Other protected regions stay.
+    pub fn set_code_writable(&mut self, writable: bool) {
+        self.protected.retain(|region| *region != self.code_range);
+        if !writable {
+            self.protected.push(self.code_range.clone());
+        }
     }
 
     pub fn reset_all(&mut self, bytecode: &[u8]) -> Result<(), InterpreterErrorType> {
-        is_bytecode_header_valid(bytecode)?;
+        self.reset_to_module(&Module::from_bytecode(bytecode)?)
+    }
 
+    pub fn reset_to_module(&mut self, module: &Module) -> Result<(), InterpreterErrorType> {
         self.value_stack.clear();
         self.return_stack.clear();
-        self.memory.fill(0);
         self.globals.fill(0);
         self.running = false;
+        self.yielded = None;
         self.args.clear();
         self.assertion_failed = false;
-        
-        self.init_memory(bytecode);
-        self.return_stack.push(Frame::empty());
+        self.executed_ops = 0;
+        self.gas_used = 0;
+        self.syscall_count = 0;
+        self.output_bytes = 0;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        if let Some(trace) = &mut self.execution_trace {
+            trace.clear();
+        }
 
-        let start_code_addr = self.read_u32(CODE_START_ADDR_POS)?;
-        self.pc = start_code_addr;
-        self.start_pc_addr = self.pc;
-        self.bytecode_len = bytecode.len();
+        self.load_module(module)
+    }
 
-        println!("code start addr: {}", self.pc);
+    pub fn check_quota(&self, kind: QuotaKind, used: u64) -> Result<(), InterpreterErrorType> {
+        match self.config.quota.limit(kind) {
+            Some(limit) if used > limit => Err(InterpreterErrorType::QuotaExceeded { kind, limit }),
+            _ => Ok(()),
+        }
+    }
 
-        Ok(())
+    //NOTE(joh): Called by syscall handlers before writing output. Returns false if the
+    //output budget is blown, the interpreter traps once the syscall returns.
+    pub fn consume_output(&mut self, bytes: u32) -> bool {
+        self.output_bytes += bytes as u64;
+        self.config.quota.allows(QuotaKind::OutputBytes, self.output_bytes)
     }
 
     pub fn inital_bytecode(&self) -> &[u8] {
-        &self.memory[DATA_START as usize .. DATA_START as usize + self.bytecode_len as usize]
+        let code_start = (BYTECODE_HEADER.len() + DATA_START as usize).min(self.bytecode.len());
+        &self.bytecode[code_start..]
     }
 
     pub fn reset_pc(&mut self) {
@@ -202,19 +896,35 @@ impl Interpreter {
         self.memory
             .get(addr as usize)
             .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))
-            .cloned()
     }
 
     fn store_u8(&mut self, addr: u32, value: u8) -> Result<(), InterpreterErrorType> {
-        *self
-            .memory
-            .get_mut(addr as usize)
-            .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))? = value;
-        Ok(())
+        self.check_writable(addr, 1)?;
+        self.memory
+            .set(addr as usize, value)
+            .ok_or(InterpreterErrorType::AddrOutOfBounds(addr))
+    }
+
+    pub fn set_trace_sink(&mut self, sink: impl TraceSink + Send + 'static) {
+        self.trace = Some(Box::new(sink));
+    }
+
+    pub fn clear_trace_sink(&mut self) {
+        self.trace = None;
+    }
+
+    pub fn has_trace_sink(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    fn trace(&mut self, event: TraceEvent<'_>) {
+        if let Some(trace) = &mut self.trace {
+            trace.on_event(event);
+        }
     }
 
     fn push(&mut self, val: u32) {
-        println!("push {val}");
+        self.trace(TraceEvent::Push(val));
         self.value_stack.push(val);
     }
 
@@ -223,10 +933,25 @@ impl Interpreter {
             .value_stack
             .pop()
             .ok_or(InterpreterErrorType::UnexpectedValStackEmpty)?;
-        println!("pop {val}");
+        if let Some(history) = &mut self.history {
+            history.record_pop(val, self.value_stack.len());
+        }
+        self.trace(TraceEvent::Pop(val));
         Ok(val)
     }
 
+    //NOTE(joh): The low half is pushed first so the high half ends up on top
+    fn push_u64(&mut self, val: u64) {
+        self.push(val as u32);
+        self.push((val >> 32) as u32);
+    }
+
+    fn pop_u64(&mut self) -> Result<u64, InterpreterErrorType> {
+        let high = self.pop()? as u64;
+        let low = self.pop()? as u64;
+        Ok(high << 32 | low)
+    }
+
     fn pop_bool(&mut self) -> Result<bool, InterpreterErrorType> {
         match self.pop()? {
             0x00 => Ok(false),
@@ -241,23 +966,64 @@ impl Interpreter {
             .copied()
     }
 
+    pub(crate) fn is_jump_target(&self, addr: u32) -> bool {
+        addr < self.memory.len() as u32 && self.instructions.contains(addr)
+    }
+
     pub fn try_jump_to(&mut self, addr: u32) -> Result<(), InterpreterErrorType> {
-        if addr >= self.memory.len() as u32 {
+        if !self.is_jump_target(addr) {
             Err(InterpreterErrorType::InvalidJumpAddr(addr))
         } else {
+            self.trace(TraceEvent::Jump { from: self.pc, to: addr });
             self.pc = addr;
             Ok(())
         }
     }
 
     pub fn exec_jmp(&mut self) -> Result<(), InterpreterErrorType> {
-        println!("jmp!");
         let addr = self.pop()?;
         self.try_jump_to(addr)
     }
 
+    //NOTE(joh): Only functions that declare their return count are checked, calling any other
+    //address passes the args unchecked like before
+    fn declared_returns(&self, addr: u32, args: usize) -> Result<Option<u8>, InterpreterErrorType> {
+        let Some((index, function)) = self
+            .functions
+            .iter()
+            .enumerate()
+            .find(|(_, f)| f.addr == addr && f.returns.is_some())
+        else {
+            return Ok(None);
+        };
+        if function.arity as usize != args {
+            return Err(InterpreterErrorType::ArityMismatch {
+                function: index as u32,
+                expected: function.arity,
+                found: args as u8,
+            });
+        }
+        Ok(function.returns)
+    }
+
+    fn call_addr(&mut self, addr: u32) -> Result<(), InterpreterErrorType> {
+        if !self.is_jump_target(addr) {
+            return Err(InterpreterErrorType::InvalidJumpAddr(addr));
+        }
+        let returns = self.declared_returns(addr, self.args.len())?;
+        self.create_frame()?;
+        self.current_frame_mut().returns = returns;
+        self.trace(TraceEvent::Call { from: self.pc, to: addr });
+        if let Some(profile) = &mut self.profile {
+            profile.enter(addr);
+        }
+        self.pc = addr;
+        self.args.clear();
+        Ok(())
+    }
+
     pub fn exec_branch(&mut self) -> Result<(), InterpreterErrorType> {
-        let addr = self.pop()? + self.pc;
+        let addr = self.pop()?.wrapping_add(self.pc);
         self.try_jump_to(addr)
     }
 
@@ -278,10 +1044,25 @@ impl Interpreter {
         self.read_u32(addr)
     }
 
+    //NOTE(joh): Base address plus the immediate offset. An overflow is out of bounds like any
+    //other address past the end of memory.
+    fn pop_addr(&mut self, offset: u32) -> Result<u32, InterpreterErrorType> {
+        let base = self.pop()?;
+        base.checked_add(offset).ok_or(InterpreterErrorType::AddrOutOfBounds(base))
+    }
+
+    fn pop_aligned_addr(&mut self, offset: u32) -> Result<u32, InterpreterErrorType> {
+        let addr = self.pop_addr(offset)?;
+        match addr % 4 {
+            0 => Ok(addr),
+            _ => Err(InterpreterErrorType::UnalignedAtomic(addr)),
+        }
+    }
+
     pub fn read_store_args(&mut self) -> Result<StoreArgs, InterpreterErrorType> {
         let offset = self.read_imm_u32(1)?;
         let value = self.pop()?;
-        let addr = self.pop()? + offset;
+        let addr = self.pop_addr(offset)?;
 
         Ok(StoreArgs { addr, value })
     }
@@ -292,77 +1073,459 @@ impl Interpreter {
         self.current_frame()
             .locals
             .get(id as usize)
-            .ok_or(InterpreterErrorType::InvalidLocalId(id as u8))
+            .ok_or(InterpreterErrorType::InvalidLocalId(id))
             .copied()
     }
 
+    //NOTE(joh): `__SP__` is the last slot, the ids of the program's own globals end in front of it
+    fn stack_pointer_slot(&self) -> Option<usize> {
+        Some(self.globals.len().checked_sub(1)?).filter(|_| !self.memory_stack.is_empty())
+    }
+
+    pub(crate) fn global_slot(&self, id: u8) -> Result<usize, InterpreterErrorType> {
+        let sp = self.stack_pointer_slot();
+        match sp {
+            Some(sp) if id == STACK_POINTER_GLOBAL => Ok(sp),
+            _ if (id as usize) < sp.unwrap_or(self.globals.len()) => Ok(id as usize),
+            _ => Err(InterpreterErrorType::InvalidGlobalId(id)),
+        }
+    }
+
     fn read_global(&self, id_arg_offset: u32) -> Result<u32, InterpreterErrorType> {
         let id = self.read_imm_u8(id_arg_offset)?;
-        self.globals
-            .get(id as usize)
-            .ok_or(InterpreterErrorType::InvalidLocalId(id as u8))
-            .copied()
+        Ok(self.globals[self.global_slot(id)?])
     }
 
     fn set_local(&mut self, id_arg_offset: u32, value: u32) -> Result<u32, InterpreterErrorType> {
         let id = self.read_imm_u8(id_arg_offset)?;
-        *self
-            .current_frame_mut()
+        let local = self
+            .return_stack
+            .last_mut()
+            .unwrap()
             .locals
             .get_mut(id as usize)
-            .ok_or(InterpreterErrorType::InvalidLocalId(id as u8))? = value;
+            .ok_or(InterpreterErrorType::InvalidLocalId(id))?;
+        if let Some(history) = &mut self.history {
+            history.record_local(id, *local);
+        }
+        *local = value;
         Ok(value)
     }
 
     fn set_global(&mut self, id_arg_offset: u32, value: u32) -> Result<u32, InterpreterErrorType> {
         let id = self.read_imm_u8(id_arg_offset)?;
-        *self
-            .globals
-            .get_mut(id as usize)
-            .ok_or(InterpreterErrorType::InvalidGlobalId(id as u8))? = value;
+        let slot = self.global_slot(id)?;
+        if let Some(history) = &mut self.history {
+            history.record_global(slot as u8, self.globals[slot]);
+        }
+        self.globals[slot] = value;
         Ok(value)
     }
 
-    pub fn create_frame(&mut self) {
+    //NOTE(joh): The stack pointer is a plain global so the program can read it with `__SP__` and
+    //stepping back covers it. The stack grows down towards the image, overflowing it would
+    //overwrite the data segment.
+    fn move_stack_pointer(&mut self, size: u32, reserve: bool) -> Result<u32, InterpreterErrorType> {
+        let stack = self.memory_stack.clone();
+        let Some(slot) = self.stack_pointer_slot() else {
+            return Err(InterpreterErrorType::MemoryStackOverflow(size));
+        };
+        let sp = self.globals[slot];
+        let new_sp = match reserve {
+            true => size
+                .checked_next_multiple_of(8)
+                .and_then(|size| sp.checked_sub(size))
+                .filter(|new_sp| *new_sp >= stack.start)
+                .ok_or(InterpreterErrorType::MemoryStackOverflow(size))?,
+            false => size
+                .checked_next_multiple_of(8)
+                .and_then(|size| sp.checked_add(size))
+                .filter(|new_sp| *new_sp <= stack.end)
+                .ok_or(InterpreterErrorType::MemoryStackUnderflow(size))?,
+        };
+        if let Some(history) = &mut self.history {
+            history.record_global(slot as u8, sp);
+        }
+        self.globals[slot] = new_sp;
+        Ok(new_sp)
+    }
+
+    //NOTE(joh): Keeps the last `capacity` ops so they can be undone with `step_back`
+    pub fn start_recording(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.history = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.history.is_some()
+    }
+
+    //NOTE(joh): Starts over if already profiling
+    pub fn start_profiling(&mut self) {
+        self.profile = Some(Box::default());
+    }
+
+    pub fn stop_profiling(&mut self) -> Option<Profile> {
+        self.profile.take().map(|p| *p)
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_deref()
+    }
+
+    //NOTE(joh): Starts over if already tracking
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(InstructionMap::default());
+    }
+
+    pub fn stop_coverage(&mut self) -> Option<InstructionMap> {
+        self.coverage.take()
+    }
+
+    //NOTE(joh): Compare with `instructions` for the ops that never ran
+    pub fn coverage(&self) -> Option<&InstructionMap> {
+        self.coverage.as_ref()
+    }
+
+    //NOTE(joh): Starts over if already tracing, see `ExecutionTrace`
+    pub fn start_trace(&mut self) {
+        self.execution_trace = Some(Box::default());
+    }
+
+    pub fn stop_trace(&mut self) -> Option<ExecutionTrace> {
+        self.execution_trace.take().map(|t| *t)
+    }
+
+    pub fn execution_trace(&self) -> Option<&ExecutionTrace> {
+        self.execution_trace.as_deref()
+    }
+
+    //NOTE(joh): Samples the return stack every `interval` ops, starts over if already sampling
+    pub fn start_sampling(&mut self, interval: u64) {
+        self.stack_samples = Some(Box::new(StackSamples::new(interval)));
+    }
+
+    pub fn stop_sampling(&mut self) -> Option<StackSamples> {
+        self.stack_samples.take().map(|s| *s)
+    }
+
+    pub fn stack_samples(&self) -> Option<&StackSamples> {
+        self.stack_samples.as_deref()
+    }
+
+    //NOTE(joh): The functions of the function table and the entry, so the code behind the last
+    //function does not count as part of it. Without a function table every label is used.
+    pub fn code_symbols(&self) -> CodeSymbols {
+        if self.functions.is_empty() {
+            return CodeSymbols::new(self.labels.iter().map(|(name, addr)| (name.clone(), *addr)));
+        }
+        let functions = self.functions.iter().map(|f| (f.name.clone(), f.addr));
+        CodeSymbols::new(functions.chain([(ENTRY_LABEL_NAME.to_string(), self.start_pc_addr)]))
+    }
+
+    pub fn can_step_back(&self) -> bool {
+        self.history.as_ref().is_some_and(|h| !h.is_empty())
+    }
+
+    //NOTE(joh): Undoes the last executed op. Changes a syscall handler made outside of
+    //memory are not restored.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.as_mut().and_then(|h| h.pop_step()) {
+            Some(step) => {
+                step.undo(self);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn create_frame(&mut self) -> Result<(), InterpreterErrorType> {
+        self.check_call_depth()?;
         self.return_stack.push(Frame::empty());
         let frame = self.return_stack.last_mut().unwrap();
         //TODO: (joh): Check here if pc + 1 might be out of bounds?
         frame.return_addr = self.pc + 1;
+        frame.stack_base = self.value_stack.len() as u32;
 
         frame.locals[..self.args.len()].copy_from_slice(&self.args);
+        Ok(())
     }
 
-    pub fn exec_next_op(&mut self, syscall_handler: &mut impl SyscallHandler) -> Result<(), InterpreterErrorType> {
+    fn check_call_depth(&self) -> Result<(), InterpreterErrorType> {
+        if self.return_stack.len() >= self.config.max_call_depth {
+            return Err(InterpreterErrorType::CallStackOverflow(self.config.max_call_depth));
+        }
+        Ok(())
+    }
+
+    pub fn exec_next_op(&mut self, syscall_handler: &mut impl SyscallHandler) -> Result<(), InterpreterErrorType> {
         let op = self.read_u8(self.pc)?;
-        println!("op: {:0x}", op);
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.checked_sub(1).ok_or(InterpreterErrorType::OutOfFuel)?;
+        }
+        if let Some(mut history) = self.history.take() {
+            history.begin_step(self);
+            self.history = Some(history);
+        }
+        self.trace(TraceEvent::Op { pc: self.pc, opcode: op });
+        self.check_quota(QuotaKind::Fuel, self.executed_ops + 1)?;
+        self.check_quota(QuotaKind::Memory, self.memory.len() as u64)?;
+        if let Some(table) = &self.config.gas_table {
+            let gas_used = self.gas_used.saturating_add(table.cost(op) as u64);
+            self.check_quota(QuotaKind::Gas, gas_used)?;
+            self.gas_used = gas_used;
+        }
+        if self.value_stack.len() > self.config.max_value_stack {
+            return Err(InterpreterErrorType::ValueStackOverflow(self.config.max_value_stack));
+        }
+        self.executed_ops += 1;
+        if let Some(profile) = &mut self.profile {
+            profile.record_op(self.pc, op);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.insert(self.pc);
+        }
+        if let Some(trace) = &mut self.execution_trace {
+            trace.record_op(self.pc, op, self.value_stack.len(), self.return_stack.len());
+        }
+        if let Some(samples) = &mut self.stack_samples {
+            samples.tick(self.pc, &self.return_stack);
+        }
         match op {
-            opcode::Nop => Ok(self.pc += 1),
+            opcode::Nop => {
+                self.pc += 1;
+                Ok(())
+            }
             opcode::End => {
                 self.running = false;
                 Ok(())
             }
+            //NOTE(joh): Pauses like a breakpoint, running again continues after it
+            opcode::DbgHalt => {
+                self.running = false;
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::PushMem => {
+                let size = self.pop()?;
+                let sp = self.move_stack_pointer(size, true)?;
+                self.push(sp);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::PopMem => {
+                let size = self.pop()?;
+                self.move_stack_pointer(size, false)?;
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Yield => {
+                let value = self.pop()?;
+                self.yield_to_host(value);
+                self.pc += 1;
+                Ok(())
+            }
             opcode::Unreachable => Err(InterpreterErrorType::ReachedUnreachable),
             opcode::Drop => {
                 _ = self.pop()?;
                 self.pc += 1;
                 Ok(())
             }
+            opcode::Dup => {
+                let val = self.pop()?;
+                self.push(val);
+                self.push(val);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Swap => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b);
+                self.push(a);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Select => {
+                let cond = self.pop()?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(if cond != 0 { a } else { b });
+                self.pc += 1;
+                Ok(())
+            }
             opcode::Const => {
                 let arg = self.read_i32(self.pc + 1)?;
                 self.push(arg as u32);
                 self.pc += 1_u32 + size_of::<i32>() as u32;
                 Ok(())
             }
+            opcode::ConstPool => {
+                let index = self.read_imm_u8(1)?;
+                let value = *self
+                    .const_pool
+                    .get(index as usize)
+                    .ok_or(InterpreterErrorType::InvalidConstPoolIndex(index))?;
+                self.push(value);
+                self.pc += 2;
+                Ok(())
+            }
+            opcode::Const64 => {
+                let arg = self.read_u64(self.pc + 1)?;
+                self.push_u64(arg);
+                self.pc += 1_u32 + size_of::<u64>() as u32;
+                Ok(())
+            }
+            opcode::Add64 => {
+                do_binop_64!(self, a, b, a.wrapping_add(b));
+                Ok(())
+            }
+            opcode::Sub64 => {
+                do_binop_64!(self, a, b, a.wrapping_sub(b));
+                Ok(())
+            }
+            opcode::Mul64 => {
+                do_binop_64!(self, a, b, (a as i64).checked_mul(b as i64).ok_or(InterpreterErrorType::IntegerOverflow)?);
+                Ok(())
+            }
+            opcode::MulWrap64 => {
+                do_binop_64!(self, a, b, a.wrapping_mul(b));
+                Ok(())
+            }
+            opcode::Divu64 => {
+                do_binop_64!(self, a, b, checked_div_64(a, b, false)?);
+                Ok(())
+            }
+            opcode::Divs64 => {
+                do_binop_64!(self, a, b, checked_div_64(a, b, true)?);
+                Ok(())
+            }
+            opcode::Eq64 => {
+                do_cmp_64!(self, a, b, a == b);
+                Ok(())
+            }
+            opcode::Ltu64 => {
+                do_cmp_64!(self, a, b, a < b);
+                Ok(())
+            }
+            opcode::Lts64 => {
+                do_cmp_64!(self, a, b, (a as i64) < (b as i64));
+                Ok(())
+            }
+            opcode::Gtu64 => {
+                do_cmp_64!(self, a, b, a > b);
+                Ok(())
+            }
+            opcode::Gts64 => {
+                do_cmp_64!(self, a, b, (a as i64) > (b as i64));
+                Ok(())
+            }
+            opcode::And64 => {
+                do_binop_64!(self, a, b, a & b);
+                Ok(())
+            }
+            opcode::Or64 => {
+                do_binop_64!(self, a, b, a | b);
+                Ok(())
+            }
+            opcode::Xor64 => {
+                do_binop_64!(self, a, b, a ^ b);
+                Ok(())
+            }
+            opcode::Shiftl64 => {
+                do_binop_64!(self, a, b, a.wrapping_shl(b as u32));
+                Ok(())
+            }
+            opcode::Shiftr64 => {
+                do_binop_64!(self, a, b, a.wrapping_shr(b as u32));
+                Ok(())
+            }
+            opcode::ExtendU32 => {
+                let val = self.pop()?;
+                self.push_u64(val as u64);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::ExtendS32 => {
+                let val = self.pop()?;
+                self.push_u64(val as i32 as i64 as u64);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Wrap64 => {
+                let val = self.pop_u64()?;
+                self.push(val as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Load64 => {
+                let offset = self.read_imm_u32(1)?;
+                let addr = self.pop_addr(offset)?;
+                self.push_u64(self.read_u64(addr)?);
+                self.pc += 5;
+                Ok(())
+            }
+            opcode::Store64 => {
+                let offset = self.read_imm_u32(1)?;
+                let value = self.pop_u64()?;
+                let addr = self.pop_addr(offset)?;
+                self.store_u64(addr, value)?;
+                self.pc += 5;
+                Ok(())
+            }
+            opcode::FConst => {
+                let arg = self.read_u32(self.pc + 1)?;
+                self.push(arg);
+                self.pc += 1_u32 + size_of::<f32>() as u32;
+                Ok(())
+            }
+            opcode::FAdd => {
+                do_binop_f32!(self, a, b, (a + b).to_bits());
+                Ok(())
+            }
+            opcode::FSub => {
+                do_binop_f32!(self, a, b, (a - b).to_bits());
+                Ok(())
+            }
+            opcode::FMul => {
+                do_binop_f32!(self, a, b, (a * b).to_bits());
+                Ok(())
+            }
+            opcode::FDiv => {
+                do_binop_f32!(self, a, b, (a / b).to_bits());
+                Ok(())
+            }
+            opcode::FLt => {
+                do_binop_f32!(self, a, b, (a < b) as u32);
+                Ok(())
+            }
+            opcode::FGt => {
+                do_binop_f32!(self, a, b, (a > b) as u32);
+                Ok(())
+            }
+            opcode::FFromI32 => {
+                let val = self.pop()? as i32;
+                self.push((val as f32).to_bits());
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::I32FromF => {
+                //NOTE(joh): Saturates, NaN becomes 0
+                let val = f32::from_bits(self.pop()?);
+                self.push(val as i32 as u32);
+                self.pc += 1;
+                Ok(())
+            }
             opcode::Jmp => {
-                println!("jmp");
-                Ok(_ = self.exec_jmp()?)
+                self.exec_jmp()
             }
             opcode::JmpIf => {
-                println!("jmp if");
                 let addr = self.pop()?;
 
                 if self.pop_bool()? {
-                    println!("addr: {}", addr);
                     self.try_jump_to(addr)?;
                 } else {
                     self.pc += 1;
@@ -370,9 +1533,21 @@ impl Interpreter {
 
                 Ok(())
             }
+            opcode::BrTable => {
+                let index = self.pop()?;
+                let count = self.read_imm_u32(1)?;
+                //NOTE(joh): The default target directly follows the targets
+                let slot = index.min(count);
+                let slot_addr = slot
+                    .checked_mul(size_of::<u32>() as u32)
+                    .and_then(|offset| offset.checked_add(self.pc + 1 + size_of::<u32>() as u32))
+                    .ok_or(InterpreterErrorType::AddrOutOfBounds(self.pc))?;
+                let addr = self.read_u32(slot_addr)?;
+                self.try_jump_to(addr)
+            }
             opcode::Branch => Ok(_ = self.exec_branch()),
             opcode::BranchIf => {
-                let addr = self.pop()? + self.pc;
+                let addr = self.pop()?.wrapping_add(self.pc);
                 if self.pop_bool()? {
                     self.try_jump_to(addr)?;
                 } else {
@@ -382,42 +1557,35 @@ impl Interpreter {
             }
 
             opcode::LocalGet => {
-                println!("local get");
                 self.push(self.read_local(1)?);
                 self.pc += 2;
                 Ok(())
             }
             opcode::LocalSet => {
-                println!("local set");
                 let val = self.pop()?;
                 self.set_local(1, val)?;
                 self.pc += 2;
                 Ok(())
             }
             opcode::LocalTee => {
-                println!("local tee");
                 let val = self.peek()?;
                 self.set_local(1, val)?;
                 self.pc += 2;
                 Ok(())
             }
             opcode::GlobalGet => {
-                println!("global get");
                 let global = self.read_global(1)?;
-                println!("globals {:?}", self.globals);
                 self.push(global);
                 self.pc += 2;
                 Ok(())
             }
             opcode::GlobalSet => {
-                println!("global set");
                 let val = self.pop()?;
                 _ = self.set_global(1, val)?;
                 self.pc += 2;
                 Ok(())
             }
             opcode::GlobalTee => {
-                println!("global tee");
                 let val = self.peek()?;
                 self.set_global(1, val)?;
                 self.pc += 2;
@@ -427,8 +1595,73 @@ impl Interpreter {
                 do_binop!(self, a, b, a == b);
                 Ok(())
             }
+            opcode::Eqz => {
+                let val = self.pop()?;
+                self.push((val == 0) as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Neg => {
+                let val = self.pop()? as i32;
+                self.push(val.wrapping_neg() as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Clz => {
+                let val = self.pop()?;
+                self.push(val.leading_zeros());
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Ctz => {
+                let val = self.pop()?;
+                self.push(val.trailing_zeros());
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Popcnt => {
+                let val = self.pop()?;
+                self.push(val.count_ones());
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Bswap => {
+                let val = self.pop()?;
+                self.push(val.swap_bytes());
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Bswap16 => {
+                let val = self.pop()? as u16;
+                self.push(val.swap_bytes() as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Extend8S32 => {
+                let val = self.pop()?;
+                self.push(val as i8 as i32 as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Extend8U32 => {
+                let val = self.pop()?;
+                self.push(val as u8 as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Extend16S32 => {
+                let val = self.pop()?;
+                self.push(val as i16 as i32 as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::Extend16U32 => {
+                let val = self.pop()?;
+                self.push(val as u16 as u32);
+                self.pc += 1;
+                Ok(())
+            }
             opcode::Add => {
-                println!("add");
                 do_binop!(self, a, b, a.wrapping_add(b));
                 Ok(())
             }
@@ -437,15 +1670,36 @@ impl Interpreter {
                 Ok(())
             }
             opcode::Mul => {
-                do_binop!(self, a, b, a * b);
+                do_binop!(self, a, b, (a as i32).checked_mul(b as i32).ok_or(InterpreterErrorType::IntegerOverflow)?);
+                Ok(())
+            }
+            opcode::MulWrap => {
+                //NOTE(joh): The low 32 bits are the same for signed and unsigned operands
+                do_binop!(self, a, b, a.wrapping_mul(b));
                 Ok(())
             }
             opcode::Divu => {
-                do_binop!(self, a, b, a / b);
+                do_binop!(self, a, b, checked_div_32(a, b, false)?);
                 Ok(())
             }
             opcode::Divs => {
-                do_binop!(self, a, b, a as i32 / b as i32);
+                do_binop!(self, a, b, checked_div_32(a, b, true)?);
+                Ok(())
+            }
+            opcode::Rotl => {
+                do_binop!(self, a, b, a.rotate_left(b % 32));
+                Ok(())
+            }
+            opcode::Rotr => {
+                do_binop!(self, a, b, a.rotate_right(b % 32));
+                Ok(())
+            }
+            opcode::RemU => {
+                do_binop!(self, a, b, checked_rem_32(a, b, false)?);
+                Ok(())
+            }
+            opcode::RemS => {
+                do_binop!(self, a, b, checked_rem_32(a, b, true)?);
                 Ok(())
             }
             opcode::Lt => {
@@ -482,7 +1736,11 @@ impl Interpreter {
                 Ok(())
             }
             opcode::Shiftr => {
-                do_binop!(self, a, b, a >> b);
+                do_binop!(self, a, b, a.wrapping_shr(b));
+                Ok(())
+            }
+            opcode::ShiftrS => {
+                do_binop!(self, a, b, (a as i32).wrapping_shr(b));
                 Ok(())
             }
 
@@ -509,16 +1767,36 @@ impl Interpreter {
 
             opcode::Load8u => {
                 let offset = self.read_imm_u32(1)?;
-                let addr = offset + self.pop()?;
+                let addr = self.pop_addr(offset)?;
                 let val = self.read_u8(addr)? as u32;
-                println!("reading: {}, offset: {offset}", val);
                 self.push(val);
                 self.pc += 5;
                 Ok(())
             }
+            opcode::Load8s => {
+                let offset = self.read_imm_u32(1)?;
+                let addr = self.pop_addr(offset)?;
+                self.push(self.read_u8(addr)? as i8 as i32 as u32);
+                self.pc += 5;
+                Ok(())
+            }
+            opcode::Load16s => {
+                let offset = self.read_imm_u32(1)?;
+                let addr = self.pop_addr(offset)?;
+                self.push(self.read_i16(addr)? as i32 as u32);
+                self.pc += 5;
+                Ok(())
+            }
+            opcode::Load32s => {
+                let offset = self.read_imm_u32(1)?;
+                let addr = self.pop_addr(offset)?;
+                self.push(self.read_i32(addr)? as u32);
+                self.pc += 5;
+                Ok(())
+            }
             opcode::Load16u => {
                 let offset = self.read_imm_u32(1)?;
-                let addr = offset + self.pop()?;
+                let addr = self.pop_addr(offset)?;
                 self.push(self.read_u16(addr)? as u32);
                 self.pc += 5;
                 Ok(())
@@ -526,11 +1804,103 @@ impl Interpreter {
 
             opcode::Load32u => {
                 let offset = self.read_imm_u32(1)?;
-                let addr = offset + self.pop()?;
+                let addr = self.pop_addr(offset)?;
+                self.push(self.read_u32(addr)?);
+                self.pc += 5;
+                Ok(())
+            }
+
+            opcode::LoadMem8u | opcode::LoadMem32 => {
+                let index = self.read_imm_u8(1)?;
+                let size = if op == opcode::LoadMem8u { 1 } else { 4 };
+                let value = self.load_from_memory(index, size)?;
+                self.push(value);
+                self.pc += 2;
+                Ok(())
+            }
+            opcode::StoreMem8 | opcode::StoreMem32 => {
+                let index = self.read_imm_u8(1)?;
+                let size = if op == opcode::StoreMem8 { 1 } else { 4 };
+                self.store_to_memory(index, size)?;
+                self.pc += 2;
+                Ok(())
+            }
+
+            opcode::AtomicLoad => {
+                let index = self.read_imm_u8(1)?;
+                let addr = self.pop()?;
+                let value = self.shared_memory(index, addr)?.load(addr);
+                self.push(value.ok_or(InterpreterErrorType::AddrOutOfBounds(addr))?);
+                self.pc += 2;
+                Ok(())
+            }
+            opcode::AtomicStore => {
+                let index = self.read_imm_u8(1)?;
+                let value = self.pop()?;
+                let addr = self.pop()?;
+                let stored = self.shared_memory(index, addr)?.store(addr, value);
+                stored.ok_or(InterpreterErrorType::AddrOutOfBounds(addr))?;
+                self.pc += 2;
+                Ok(())
+            }
+            opcode::AtomicAdd => {
+                let index = self.read_imm_u8(1)?;
+                let value = self.pop()?;
+                let addr = self.pop()?;
+                let old = self.shared_memory(index, addr)?.fetch_add(addr, value);
+                self.push(old.ok_or(InterpreterErrorType::AddrOutOfBounds(addr))?);
+                self.pc += 2;
+                Ok(())
+            }
+            opcode::Cmpxchg => {
+                let index = self.read_imm_u8(1)?;
+                let new = self.pop()?;
+                let expected = self.pop()?;
+                let addr = self.pop()?;
+                let old = self.shared_memory(index, addr)?.compare_exchange(addr, expected, new);
+                self.push(old.ok_or(InterpreterErrorType::AddrOutOfBounds(addr))?);
+                self.pc += 2;
+                Ok(())
+            }
+
+            opcode::AtomicLoad32 => {
+                let offset = self.read_imm_u32(1)?;
+                let addr = self.pop_aligned_addr(offset)?;
                 self.push(self.read_u32(addr)?);
                 self.pc += 5;
                 Ok(())
             }
+            opcode::AtomicStore32 => {
+                let offset = self.read_imm_u32(1)?;
+                let value = self.pop()?;
+                let addr = self.pop_aligned_addr(offset)?;
+                self.store_u32(addr, value)?;
+                self.pc += 5;
+                Ok(())
+            }
+            opcode::AtomicRmwAdd => {
+                let offset = self.read_imm_u32(1)?;
+                let value = self.pop()?;
+                let addr = self.pop_aligned_addr(offset)?;
+                let old = self.read_u32(addr)?;
+                self.store_u32(addr, old.wrapping_add(value))?;
+                self.push(old);
+                self.pc += 5;
+                Ok(())
+            }
+            opcode::AtomicCmpxchg => {
+                let offset = self.read_imm_u32(1)?;
+                let new = self.pop()?;
+                let expected = self.pop()?;
+                let addr = self.pop_aligned_addr(offset)?;
+                let old = self.read_u32(addr)?;
+                if old == expected {
+                    self.store_u32(addr, new)?;
+                }
+                self.push(old);
+                self.pc += 5;
+                Ok(())
+            }
 
             opcode::PushArg => {
                 if self.args.len() >= MAX_ARGS {
@@ -544,22 +1914,106 @@ impl Interpreter {
             }
             opcode::Call => {
                 let addr = self.pop()?;
-                if addr >= self.memory.len() as u32 {
-                    Err(InterpreterErrorType::InvalidJumpAddr(addr))
+                self.call_addr(addr)
+            }
+            opcode::CallIf => {
+                let addr = self.pop()?;
+                if self.pop_bool()? {
+                    self.call_addr(addr)
                 } else {
-                    self.create_frame();
-                    self.pc = addr;
                     self.args.clear();
-
+                    self.pc += 1;
                     Ok(())
                 }
             }
+            opcode::CallIndirect => {
+                let index = self.pop()?;
+                let function = self
+                    .functions
+                    .get(index as usize)
+                    .ok_or(InterpreterErrorType::InvalidFunctionIndex(index))?;
+                if function.arity as usize != self.args.len() {
+                    return Err(InterpreterErrorType::ArityMismatch {
+                        function: index,
+                        expected: function.arity,
+                        found: self.args.len() as u8,
+                    });
+                }
+                self.call_addr(function.addr)
+            }
+            opcode::MemSize => {
+                self.push(self.memory.len() as u32);
+                self.pc += 1;
+                Ok(())
+            }
+            opcode::MemGrow => {
+                let pages = self.pop()?;
+                let old_len = self.memory.len();
+                if old_len as u64 + pages as u64 * PAGE_SIZE as u64 > u32::MAX as u64 + 1 {
+                    self.push(u32::MAX);
+                    self.pc += 1;
+                    return Ok(());
+                }
+                let additional = pages as usize * PAGE_SIZE;
+                self.check_quota(QuotaKind::Memory, (old_len + additional) as u64)?;
+                if let Some(history) = &mut self.history {
+                    history.record_memory_snapshot(&self.memory);
+                }
+                self.memory.grow(additional);
+                self.push(old_len as u32);
+                self.pc += 1;
+                Ok(())
+            }
+
+            opcode::MemCopy | opcode::MemFill => {
+                let len = self.pop()?;
+                let src = self.pop()?;
+                let dst = self.pop()?;
+                let in_bounds = |addr: u32| {
+                    (addr as usize)
+                        .checked_add(len as usize)
+                        .is_some_and(|end| end <= self.memory.len())
+                };
+                if op == opcode::MemCopy && !in_bounds(src) {
+                    return Err(InterpreterErrorType::AddrOutOfBounds(src));
+                }
+                if !in_bounds(dst) {
+                    return Err(InterpreterErrorType::AddrOutOfBounds(dst));
+                }
+                self.check_writable(dst, len)?;
+                if let Some(history) = &mut self.history {
+                    history.record_memory_snapshot(&self.memory);
+                }
+                let (dst, len) = (dst as usize, len as usize);
+                match op {
+                    opcode::MemCopy => self.memory.copy_within(src as usize, dst, len),
+                    _ => self.memory.fill(dst, len, src as u8),
+                };
+                self.pc += 1;
+                Ok(())
+            }
 
             opcode::Return => {
+                if let Some(frame) = self.return_stack.last()
+                    && let Some(expected) = frame.returns
+                {
+                    let base = frame.stack_base as usize;
+                    if self.value_stack.len() != base + expected as usize {
+                        let found = self.value_stack.len() as i32 - base as i32;
+                        return Err(InterpreterErrorType::ReturnArityMismatch { expected, found });
+                    }
+                }
                 let last_frame = self
                     .return_stack
                     .pop()
                     .ok_or(InterpreterErrorType::UnexpectedEmptyFrameStack)?;
+                if let Some(history) = &mut self.history {
+                    history.record_frame_pop(&last_frame);
+                }
+                self.trace(TraceEvent::Return { from: self.pc, to: last_frame.return_addr });
+                if let Some(profile) = &mut self.profile {
+                    profile.leave();
+                }
                 match last_frame.return_addr {
                     0 => {
                         self.running = false;
@@ -575,11 +2029,9 @@ impl Interpreter {
                 let cond = self.pop_bool()?;
                 match cond {
                     true => {
-                        println!("assertion ok");
                         self.pc += 1;
                     }
                     false => {
-                        println!("Assertion failed at: {}", self.pc);
                         self.running = false;
                         self.assertion_failed = true;
                     }
@@ -588,19 +2040,148 @@ impl Interpreter {
             }
             opcode::Syscall => {
                 let id = self.pop()?;
+                if let Some(group) = syscall_handler.syscall_group(id)
+                    && !self.config.capabilities.allows(group)
+                {
+                    return Err(InterpreterErrorType::PermissionDenied(id));
+                }
+                self.syscall_count += 1;
+                self.check_quota(QuotaKind::Syscalls, self.syscall_count)?;
                 let args = self.args.clone(); 
-                println!("syscall args {:?}", args);
+                self.trace(TraceEvent::Syscall { id, args: &args });
+                if let Some(history) = &mut self.history {
+                    history.record_memory_snapshot(&self.memory);
+                }
                 let ret = syscall_handler.on_syscall(self, id, args.as_slice());       
                 self.args.clear(); 
+                self.check_quota(QuotaKind::OutputBytes, self.output_bytes)?;
 
                 self.push(ret);
                 self.pc += 1;
                 Ok(())
             }
-            _ => todo!(),
+            opcode::CallHost => self.exec_call_host(),
+            _ => Err(InterpreterErrorType::InvalidOpcode(op)),
         }
     }
 
+    //NOTE(joh): The bottom frame has no call site, neither do frames entered through `call`
+    pub fn backtrace(&self) -> Vec<u32> {
+        let call_sites = self
+            .return_stack
+            .iter()
+            .skip(1)
+            .rev()
+            .filter_map(|frame| frame.return_addr.checked_sub(1));
+        core::iter::once(self.pc).chain(call_sites).collect()
+    }
+
+    pub fn set_labels(&mut self, labels: &[(String, u32)]) {
+        self.labels = labels.iter().cloned().collect();
+    }
+
+    pub fn resolve_call_target(&self, target: CallTarget<'_>) -> Result<u32, InterpreterErrorType> {
+        match target {
+            CallTarget::Addr(addr) => Ok(addr),
+            CallTarget::Label(name) => self
+                .labels
+                .get(name)
+                .copied()
+                .ok_or_else(|| InterpreterErrorType::UnknownLabel(name.to_string())),
+        }
+    }
+
+    //NOTE(joh): Runs a single function until it returns and hands back the values it left on
+    //the stack. The state of the regular program (pc, stacks, pending args) is restored
+    //afterwards, also when the call traps.
+    pub fn call<'a>(
+        &mut self,
+        target: impl Into<CallTarget<'a>>,
+        args: &[u32],
+        syscall_handler: &mut impl SyscallHandler,
+    ) -> Result<Vec<u32>, Trap> {
+        let saved_pc = self.pc;
+        let saved_running = self.running;
+        let saved_args = core::mem::take(&mut self.args);
+        let stack_height = self.value_stack.len();
+        let frame_depth = self.return_stack.len();
+        let open_calls = self.profile.as_ref().map_or(0, |p| p.open_calls());
+
+        let result = self.exec_call(target.into(), args, syscall_handler);
+        let result = match result {
+            Ok(()) => Ok(self.value_stack.split_off(stack_height)),
+            Err(error) => Err(Trap::capture(self, error)),
+        };
+
+        self.value_stack.truncate(stack_height);
+        self.return_stack.truncate(frame_depth);
+        if let Some(profile) = &mut self.profile {
+            profile.unwind(open_calls);
+        }
+        self.pc = saved_pc;
+        self.running = saved_running;
+        self.args = saved_args;
+        result
+    }
+
+    fn exec_call(
+        &mut self,
+        target: CallTarget<'_>,
+        args: &[u32],
+        syscall_handler: &mut impl SyscallHandler,
+    ) -> Result<(), InterpreterErrorType> {
+        let addr = self.resolve_call_target(target)?;
+        if args.len() > MAX_LOCALS {
+            return Err(InterpreterErrorType::ArgStackFull);
+        }
+        self.check_call_depth()?;
+        let returns = self.declared_returns(addr, args.len())?;
+        self.try_jump_to(addr)?;
+
+        //NOTE(joh): A return address of 0 stops the interpreter once the frame returns
+        let mut frame = Frame::empty();
+        frame.return_addr = 0;
+        frame.stack_base = self.value_stack.len() as u32;
+        frame.returns = returns;
+        frame.locals[..args.len()].copy_from_slice(args);
+        self.return_stack.push(frame);
+        if let Some(profile) = &mut self.profile {
+            profile.enter(addr);
+        }
+
+        self.running = true;
+        while self.running {
+            self.exec_next_op(syscall_handler)?;
+        }
+        Ok(())
+    }
+
+    pub fn run_with_backtrace(&mut self, syscall_handler: &mut impl SyscallHandler) -> Result<&[u32], Trap> {
+        if let Err(error) = self.run(syscall_handler) {
+            return Err(Trap::capture(self, error));
+        }
+        Ok(&self.value_stack)
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    //NOTE(joh): Runs at most `max_ops` ops. The remaining fuel stays set afterwards, so
+    //later runs are metered as well until `set_fuel(None)`.
+    pub fn run_with_fuel(
+        &mut self,
+        syscall_handler: &mut impl SyscallHandler,
+        max_ops: u64,
+    ) -> Result<&[u32], InterpreterErrorType> {
+        self.fuel = Some(max_ops);
+        self.run(syscall_handler)
+    }
+
     pub fn run(&mut self, syscall_handler: &mut impl SyscallHandler) -> Result<&[u32], InterpreterErrorType> {
         self.running = true;
         loop {
@@ -611,27 +2192,100 @@ impl Interpreter {
         }
         Ok(&self.value_stack)
     }
+
+    //NOTE(joh): Stops the run once the current op is done, `run_until_yield` hands `value` to the
+    //host. Also meant for syscall handlers that need the host to wait for something.
+    pub fn yield_to_host(&mut self, value: u32) {
+        self.yielded = Some(value);
+        self.running = false;
+    }
+
+    //NOTE(joh): Runs until the program yields or stops. After a yield the state is kept as is, the
+    //next call continues after the `yield`. A `dbg_halt` stops the run like the end does.
+    pub fn run_until_yield(&mut self, syscall_handler: &mut impl SyscallHandler) -> Result<RunState, InterpreterErrorType> {
+        self.yielded = None;
+        self.run(syscall_handler)?;
+        Ok(match self.yielded.take() {
+            Some(value) => RunState::Yielded(value),
+            None => RunState::Stopped,
+        })
+    }
+
+    //NOTE(joh): Like `run`, but returns after at most `max_ops` ops so a UI can run long programs
+    //in slices. Returns whether the program is still running.
+    pub fn run_for(
+        &mut self,
+        syscall_handler: &mut impl SyscallHandler,
+        max_ops: u64,
+    ) -> Result<bool, InterpreterErrorType> {
+        self.running = true;
+        for _ in 0..max_ops {
+            if !self.running {
+                break;
+            }
+            self.exec_next_op(syscall_handler)?;
+        }
+        Ok(self.running)
+    }
+
+    //NOTE(joh): Executes the next op. If it is a call, runs until that call returned. `stop` is
+    //checked before every further op and ends the run early, e.g. on a breakpoint.
+    pub fn step_over(
+        &mut self,
+        syscall_handler: &mut impl SyscallHandler,
+        stop: impl FnMut(&Self) -> bool,
+    ) -> Result<(), InterpreterErrorType> {
+        let depth = self.return_stack.len();
+        self.run_while_deeper(syscall_handler, depth, stop)
+    }
+
+    //NOTE(joh): Runs until the current frame returned, in the outermost frame that is the end of
+    //the program. See `step_over` for `stop`.
+    pub fn step_out(
+        &mut self,
+        syscall_handler: &mut impl SyscallHandler,
+        stop: impl FnMut(&Self) -> bool,
+    ) -> Result<(), InterpreterErrorType> {
+        let depth = self.return_stack.len().saturating_sub(1);
+        self.run_while_deeper(syscall_handler, depth, stop)
+    }
+
+    //NOTE(joh): Always executes at least one op so it doesn't get stuck on the current breakpoint
+    fn run_while_deeper(
+        &mut self,
+        syscall_handler: &mut impl SyscallHandler,
+        depth: usize,
+        mut stop: impl FnMut(&Self) -> bool,
+    ) -> Result<(), InterpreterErrorType> {
+        self.running = true;
+        self.exec_next_op(syscall_handler)?;
+        while self.running && self.return_stack.len() > depth && !stop(self) {
+            self.exec_next_op(syscall_handler)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::asm;
+    use crate::{asm, config::{Capabilities, Quota, DEFAULT_MEMORY_STACK_SIZE}, gas::GasTable};
 
     struct DummySyscallHandler();
     impl SyscallHandler for DummySyscallHandler {
         fn on_syscall(&mut self, _: &mut Interpreter, id: u32, args: &[u32]) -> u32 {
             match id {
-                1 => {
-                    if args[0] == 1 && args[1] == 2 {
-                        1
-                    } else {
-                        0
-                    }
-                } 
+                1 if args[0] == 1 && args[1] == 2 => 1,
                 _ => 0,
             }
         }
+
+        fn syscall_group(&self, id: u32) -> Option<SyscallGroup> {
+            match id {
+                1 => Some(SyscallGroup::Console),
+                _ => None,
+            }
+        }
     }
     macro_rules! assert_code_result {
         ($code: expr, $expected: expr) => {
@@ -786,6 +2440,22 @@ mod tests {
         "; 
         assert_code_result!(code, &[1]);
     }
+
+    #[test]
+    fn syscall_without_capability() {
+        let code = "
+            #1; push_arg;
+            #2; push_arg;
+            #1; syscall;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.config.capabilities = Capabilities::all().with(SyscallGroup::Console, false);
+
+        let result = interpreter.run(&mut DummySyscallHandler());
+        assert!(matches!(result, Err(InterpreterErrorType::PermissionDenied(1))));
+    }
     
     #[test]
     fn strings() {
@@ -818,4 +2488,893 @@ mod tests {
         assert_code_result!(code, &[9]);
 
     }
+
+    #[test]
+    fn quota_exceeded() {
+        let code = "
+            :loop:
+            #2; syscall; drop;
+            #@loop; jmp;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+
+        let quota = Quota { fuel: Some(100), ..Quota::unlimited() };
+        let config = InterpreterConfig { quota, ..Default::default() };
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+        let result = interpreter.run(&mut DummySyscallHandler());
+        assert!(matches!(result, Err(InterpreterErrorType::QuotaExceeded { kind: QuotaKind::Fuel, limit: 100 })));
+        assert_eq!(interpreter.executed_ops, 100);
+
+        let quota = Quota { syscalls: Some(3), ..Quota::unlimited() };
+        let config = InterpreterConfig { quota, ..Default::default() };
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+        let result = interpreter.run(&mut DummySyscallHandler());
+        assert!(matches!(result, Err(InterpreterErrorType::QuotaExceeded { kind: QuotaKind::Syscalls, limit: 3 })));
+
+        let quota = Quota { memory_bytes: Some(1024), ..Quota::unlimited() };
+        let config = InterpreterConfig { quota, ..Default::default() };
+        let result = Interpreter::from_bytecode_with_config(&bytecode.code, config);
+        assert!(matches!(result, Err(InterpreterErrorType::QuotaExceeded { kind: QuotaKind::Memory, .. })));
+    }
+
+    #[test]
+    fn gas() {
+        let bytecode = asm::Parser::parse("#8; #2; add; load_32_u 0; #3; div_u; end;").unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        interpreter.run(&mut DummySyscallHandler()).unwrap();
+        //NOTE(joh): Nothing is counted without a table
+        assert_eq!(interpreter.gas_used, 0);
+
+        let config = InterpreterConfig::default().with_gas_table(GasTable::default());
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode, config.clone()).unwrap();
+        interpreter.run(&mut DummySyscallHandler()).unwrap();
+        assert_eq!(interpreter.gas_used, 1 + 1 + 1 + 3 + 1 + 4 + 1);
+
+        let config = InterpreterConfig::default().with_gas_table(GasTable::uniform(1).with_cost(opcode::Add, 7));
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode, config.clone()).unwrap();
+        interpreter.run(&mut DummySyscallHandler()).unwrap();
+        assert_eq!(interpreter.gas_used, interpreter.executed_ops + 6);
+
+        let config = InterpreterConfig { quota: Quota { gas: Some(5), ..Quota::unlimited() }, ..config };
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode, config).unwrap();
+        let result = interpreter.run(&mut DummySyscallHandler());
+        assert!(matches!(result, Err(InterpreterErrorType::QuotaExceeded { kind: QuotaKind::Gas, limit: 5 })));
+        //NOTE(joh): The add would go past the limit, so it is not counted
+        assert_eq!(interpreter.gas_used, 2);
+    }
+
+    #[test]
+    fn const_pool() {
+        let code = r#"
+            #"abc"; load_32_u 0;
+            #0x1000; #0x1000; add;
+            #@func; call;
+            end;
+            :func: #0x1000; return;
+        "#;
+        let bytecode = asm::Parser::parse_with_const_pool(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        assert_eq!(interpreter.const_pool.len(), 3);
+
+        let result = interpreter.run(&mut DummySyscallHandler()).unwrap();
+        assert_eq!(result, &[3, 0x2000, 0x1000]);
+    }
+
+    #[test]
+    fn br_table() {
+        let code = "
+            local_set 0;
+            local_get 0; br_table @zero @one @other;
+            :zero: #10; end;
+            :one: #11; end;
+            :other: #12; end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        for (index, expected) in [(0, 10), (1, 11), (2, 12), (100, 12)] {
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.value_stack.push(index);
+            let result = interpreter.run(&mut DummySyscallHandler()).unwrap();
+            assert_eq!(result, &[expected]);
+        }
+
+        let alias = code.replace("br_table @zero @one @other", "jump_table @zero @one default @other");
+        assert_eq!(asm::Parser::parse(&alias).unwrap().code, bytecode.code);
+        let error = |args: &str| asm::Parser::parse(&format!("jump_table {args};")).map(|_| ()).unwrap_err().kind;
+        assert!(matches!(error("@a default"), asm::AssembleErrorKind::MissingArgument));
+        assert!(matches!(error("@a default @b @c"), asm::AssembleErrorKind::TooManyArguments));
+    }
+
+    #[test]
+    fn call_function() {
+        let code = "
+            :add:
+            local_get 0; local_get 1; add;
+            return;
+            :__ENTRY__:
+            #7;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.set_labels(&bytecode.debug_info.labels);
+        let entry = interpreter.pc;
+
+        assert_eq!(interpreter.call("add", &[40, 2], &mut DummySyscallHandler()).unwrap(), [42]);
+        assert_eq!(interpreter.call(DATA_START, &[1, 2], &mut DummySyscallHandler()).unwrap(), [3]);
+        assert_eq!(interpreter.pc, entry);
+        assert!(interpreter.value_stack.is_empty());
+
+        let trap = interpreter.call("missing", &[], &mut DummySyscallHandler()).unwrap_err();
+        assert!(matches!(trap.error, InterpreterErrorType::UnknownLabel(_)));
+
+        assert_eq!(interpreter.run(&mut DummySyscallHandler()).unwrap(), &[7]);
+    }
+
+    #[test]
+    fn wide_values() {
+        let code = "
+            const_64 0x100000000; const_64 5; add_64;
+            #-1; extend_u_32; #2; extend_u_32; mul_64;
+            #-1; extend_s_32; const_64 -1; eq_64;
+            const_64 -2; const_64 1; lt_s_64;
+            #0x8000; const_64 0x123456789; store_64 0;
+            #0x8000; load_64 0; wrap_64;
+            end;
+        ";
+        assert_code_result!(code, &[5, 1, 0xFFFFFFFE, 1, 1, 1, 0x23456789]);
+    }
+
+    #[test]
+    fn floats() {
+        let code = "
+            f_const 1.5; f_const 2.25; f_add;
+            #-3; f_from_i32; f_mul; i32_from_f;
+            #7; f_from_i32; f_const 2; f_div;
+            f_const 3.4; f_lt;
+            f_const 1e20; i32_from_f;
+            end;
+        ";
+        assert_code_result!(code, &[-11_i32 as u32, 0, i32::MAX as u32]);
+    }
+
+    #[test]
+    fn data_section() {
+        let code = r#"
+            .data msg bytes "hi" 0x21;
+            .data table words 7 @msg;
+            #@table; load_32_u 0;
+            #@table; load_32_u 4; #@msg; eq;
+            #@msg; load_8_u 2;
+            end;
+        "#;
+        assert_code_result!(code, &[7, 1, 0x21]);
+    }
+
+    #[test]
+    fn step_back() {
+        let code = "
+            #0x8000; #7; store_32 0;
+            #3; global_set 1;
+            #@fn; call;
+            #1; #2; add;
+            end;
+            :fn:
+            #5; local_set 0;
+            return;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = DummySyscallHandler();
+        interpreter.start_recording(usize::MAX);
+
+        let mut states = Vec::new();
+        interpreter.running = true;
+        while interpreter.running {
+            states.push((
+                interpreter.pc,
+                interpreter.value_stack.clone(),
+                interpreter.return_stack.iter().map(|f| (f.return_addr, f.locals)).collect::<Vec<_>>(),
+                interpreter.globals.clone(),
+                interpreter.read_u32(0x8000).unwrap(),
+            ));
+            interpreter.exec_next_op(&mut handler).unwrap();
+        }
+        assert_eq!(interpreter.value_stack, [3]);
+
+        while let Some(state) = states.pop() {
+            assert!(interpreter.step_back());
+            assert_eq!(interpreter.pc, state.0);
+            assert_eq!(interpreter.value_stack, state.1);
+            assert_eq!(interpreter.return_stack.iter().map(|f| (f.return_addr, f.locals)).collect::<Vec<_>>(), state.2);
+            assert_eq!(interpreter.globals, state.3);
+            assert_eq!(interpreter.read_u32(0x8000).unwrap(), state.4);
+        }
+        assert!(!interpreter.step_back());
+
+        //NOTE(joh): Only the most recent ops are kept
+        interpreter.start_recording(2);
+        interpreter.running = true;
+        for _ in 0..4 {
+            interpreter.exec_next_op(&mut handler).unwrap();
+        }
+        assert!(interpreter.step_back() && interpreter.step_back());
+        assert!(!interpreter.step_back());
+        assert_eq!(interpreter.pc, asm::DATA_START + 10);
+    }
+
+    #[test]
+    fn fuel() {
+        let code = "
+            #0; local_set 0;
+            :loop:
+            local_get 0; #1; add; local_tee 0;
+            #10; lt; #@loop; jmp_if;
+            local_get 0;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = DummySyscallHandler();
+
+        let mut runs = 0;
+        let result = loop {
+            runs += 1;
+            match interpreter.run_with_fuel(&mut handler, 7) {
+                Ok(result) => break result.to_vec(),
+                Err(InterpreterErrorType::OutOfFuel) => assert_eq!(interpreter.fuel(), Some(0)),
+                Err(e) => panic!("{e}"),
+            }
+        };
+        assert_eq!(result, [10]);
+        assert_eq!(runs, interpreter.executed_ops.div_ceil(7));
+
+        interpreter.reset_pc();
+        interpreter.value_stack.clear();
+        interpreter.set_fuel(None);
+        assert_eq!(interpreter.run(&mut handler).unwrap(), [10]);
+    }
+
+    #[test]
+    fn unary_and_extend_ops() {
+        let code = "
+            #0; eqz; #5; eqz;
+            #5; neg; #-7; neg;
+            #-16; #2; shift_r_s; #-16; #2; shift_r;
+            #0x1ff; extend_8_32_s; #0x180; extend_8_32_u;
+            #0x18000; extend_16_32_s; #0x18000; extend_16_32_u;
+            end;
+        ";
+        assert_code_result!(
+            code,
+            &[1, 0, -5_i32 as u32, 7, -4_i32 as u32, 0x3ffffffc, u32::MAX, 0x80, 0xffff8000, 0x8000]
+        );
+    }
+
+    #[test]
+    fn signed_loads() {
+        let code = "
+            #0x8000; #0x8081ff; store_32 0;
+            #0x8000; load_8_s 0; #0x8000; load_8_u 0;
+            #0x8000; load_16_s 1; #0x8000; load_16_u 1;
+            #0x8000; load_32_s 0;
+            end;
+        ";
+        assert_code_result!(code, &[u32::MAX, 0xff, 0xffff8081, 0x8081, 0x8081ff]);
+    }
+
+    #[test]
+    fn dbg_halt_pauses() {
+        let code = "
+            #1; dbg_halt; #2;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        assert_eq!(interpreter.run(&mut DummySyscallHandler()).unwrap(), [1]);
+        assert_eq!(interpreter.run(&mut DummySyscallHandler()).unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn division_traps() {
+        assert_code_result!(
+            "#-7; #2; div_s; #7; #2; div_u; #-3; #0x7fffffff; mul_wrap; #-3; #5; mul; end;",
+            &[-3_i32 as u32, 3, 0x80000003, -15_i32 as u32]
+        );
+
+        let run = |code: &str| {
+            let bytecode = asm::Parser::parse(code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert!(matches!(run("#1; #0; div_u; end;"), Err(InterpreterErrorType::DivideByZero)));
+        assert!(matches!(run("#1; #0; div_s; end;"), Err(InterpreterErrorType::DivideByZero)));
+        assert!(matches!(run("#-2147483648; #-1; div_s; end;"), Err(InterpreterErrorType::IntegerOverflow)));
+        assert!(matches!(run("#-3; #0x7fffffff; mul; end;"), Err(InterpreterErrorType::IntegerOverflow)));
+        assert!(matches!(run("#0x10000; #0x10000; mul; end;"), Err(InterpreterErrorType::IntegerOverflow)));
+        assert!(matches!(
+            run("const_64 0x100000000; const_64 0x100000000; mul_64; end;"),
+            Err(InterpreterErrorType::IntegerOverflow)
+        ));
+        assert!(matches!(run("const_64 0x100000000; const_64 0x100000000; mul_wrap_64; end;"), Ok(v) if v == [0, 0]));
+        assert!(matches!(run("const_64 -3; const_64 5; mul_64; end;"), Ok(v) if v == [-15_i32 as u32, u32::MAX]));
+        assert!(matches!(run("const_64 1; const_64 0; div_u_64; end;"), Err(InterpreterErrorType::DivideByZero)));
+        assert!(matches!(
+            run("const_64 0x8000000000000000; const_64 -1; div_s_64; end;"),
+            Err(InterpreterErrorType::IntegerOverflow)
+        ));
+    }
+
+    #[test]
+    fn remainder() {
+        assert_code_result!(
+            "#-7; #2; rem_s; #7; #-2; rem_s; #-7; #2; rem_u; #-2147483648; #-1; rem_s; end;",
+            &[-1_i32 as u32, 1, 1, 0]
+        );
+
+        let run = |code: &str| {
+            let bytecode = asm::Parser::parse(code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert!(matches!(run("#1; #0; rem_u; end;"), Err(InterpreterErrorType::DivideByZero)));
+        assert!(matches!(run("#1; #0; rem_s; end;"), Err(InterpreterErrorType::DivideByZero)));
+    }
+
+    #[test]
+    fn bit_ops() {
+        assert_code_result!(
+            "#-2147483647; #1; rot_l; #-2147483647; #33; rot_r; #0x00f00000; clz; #0; clz; #0x00f00000; ctz; #0; ctz;
+             #0x0f0f0f0f; popcnt; #-1; popcnt; end;",
+            &[3, 0xc0000000, 8, 32, 20, 32, 16, 32]
+        );
+        //NOTE(joh): A big endian u32 and u16 as they come from the network
+        assert_code_result!(
+            "#0x4000; #0x78563412; store_32 0; #0x4000; load_32_u 0; bswap;
+             #0x4000; load_16_u 0; bswap_16; #0x12345678; bswap_16; end;",
+            &[0x12345678, 0x1234, 0x7856]
+        );
+    }
+
+    #[test]
+    fn stack_ops() {
+        assert_code_result!(
+            "#1; dup; #2; #3; swap; #4; #5; #1; select; #6; #7; #0; select; end;",
+            &[1, 1, 3, 2, 4, 7]
+        );
+
+        let bytecode = asm::Parser::parse("#1; #2; select; end;").unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        assert!(matches!(
+            interpreter.run(&mut DummySyscallHandler()),
+            Err(InterpreterErrorType::UnexpectedValStackEmpty)
+        ));
+    }
+
+    #[test]
+    fn bulk_memory() {
+        assert_code_result!(
+            "#200; #0x04030201; store_32 0;
+             #202; #200; #4; mem_copy; #202; load_32_u 0;
+             #200; #0xff; #3; mem_fill; #200; load_32_u 0;
+             #203; #0; #0; mem_copy; end;",
+            &[0x04030201, 0x02ffffff]
+        );
+
+        let run = |code: &str| {
+            let bytecode = asm::Parser::parse(code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert!(matches!(
+            run("#0; #-16; #16; mem_copy; end;"),
+            Err(InterpreterErrorType::AddrOutOfBounds(0xfffffff0))
+        ));
+        assert!(matches!(run("#-1; #0; #2; mem_fill; end;"), Err(InterpreterErrorType::AddrOutOfBounds(u32::MAX))));
+    }
+
+    #[test]
+    fn profiling() {
+        let code = "
+            #3; push_arg; #@count; call; #2; push_arg; #@count; call; end;
+            :count: local_get 0; #1; sub; local_tee 0; #@count; jmp_if; return;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.start_profiling();
+        interpreter.run(&mut DummySyscallHandler()).unwrap();
+
+        let profile = interpreter.stop_profiling().unwrap();
+        assert!(interpreter.profile().is_none());
+        assert_eq!(profile.total_ops(), interpreter.executed_ops);
+        let count = bytecode.labels.iter().find(|(name, _)| name == "count").unwrap().1 + DATA_START;
+        assert_eq!(profile.ops_at(count), 5);
+        assert_eq!(profile.max_ops_at(), 5);
+        assert_eq!(profile.hot_instructions()[0], (count, 5));
+        assert_eq!(profile.opcodes()[0], (opcode::Const, 14));
+        assert_eq!(profile.functions().len(), 1);
+        assert_eq!(profile.functions()[0].0, count);
+        assert_eq!(profile.functions()[0].1.calls, 2);
+    }
+
+    #[test]
+    fn stack_sampling() {
+        let code = "
+            .func inner 1 1;
+            local_get 0; #1; add;
+            return;
+            .func outer 0 1;
+            #1; push_arg; #@inner; call;
+            return;
+            :__ENTRY__:
+            #@outer; call; end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.start_sampling(1);
+        interpreter.run(&mut DummySyscallHandler()).unwrap();
+
+        let samples = interpreter.stop_sampling().unwrap();
+        assert_eq!(samples.total_samples(), interpreter.executed_ops);
+        let symbols = interpreter.code_symbols();
+        assert_eq!(
+            samples.folded(&symbols),
+            "__ENTRY__;outer 5\n__ENTRY__;outer;inner 4\n__ENTRY__ 3\n"
+        );
+        let edges = samples.call_graph(&symbols);
+        assert_eq!((edges[0].caller.as_str(), edges[0].callee.as_str(), edges[0].samples), ("__ENTRY__", "outer", 9));
+        assert_eq!((edges[1].caller.as_str(), edges[1].callee.as_str(), edges[1].samples), ("outer", "inner", 4));
+
+        interpreter.reset_all(&bytecode.code).unwrap();
+        interpreter.start_sampling(4);
+        interpreter.run(&mut DummySyscallHandler()).unwrap();
+        assert_eq!(interpreter.stack_samples().unwrap().total_samples(), 3);
+    }
+
+    #[test]
+    fn coverage() {
+        let code = "
+            #1; #@skip; jmp_if; #2; drop;
+            :skip: end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        assert!(interpreter.coverage().is_none());
+        interpreter.start_coverage();
+        interpreter.run(&mut DummySyscallHandler()).unwrap();
+
+        let coverage = interpreter.coverage().unwrap();
+        assert_eq!(coverage.count(), 4);
+        assert_eq!(interpreter.instructions.count(), 6);
+        let uncovered = (0..interpreter.memory.len() as u32)
+            .filter(|addr| interpreter.instructions.contains(*addr) && !coverage.contains(*addr))
+            .collect::<Vec<_>>();
+        assert_eq!(uncovered, [DATA_START + 11, DATA_START + 16]);
+        let covered = [0, 5, 10, 17].map(|offset| 1u64 << (DATA_START + offset));
+        assert_eq!(coverage.bits(), [covered.iter().fold(0, |bits, bit| bits | bit)]);
+    }
+
+    #[test]
+    fn run_in_slices() {
+        let code = "
+            #0; global_set 0;
+            :loop: global_get 0; #1; add; global_tee 0;
+            #100; lt; #@loop; jmp_if;
+            global_get 0; end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut slices = 0;
+        while interpreter.run_for(&mut DummySyscallHandler(), 50).unwrap() {
+            slices += 1;
+            assert_eq!(interpreter.executed_ops, slices * 50);
+        }
+        assert!(slices > 10);
+        assert_eq!(interpreter.value_stack, [100]);
+    }
+
+    #[test]
+    fn yield_to_host() {
+        //NOTE(joh): A game loop that yields once per frame, the host decides when to stop
+        let code = "
+            .global frame = 0;
+            :loop: global_get frame; #1; add; global_tee frame;
+            yield;
+            global_get frame; #3; lt; #@loop; jmp_if;
+            #42; end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut frames = Vec::new();
+        while let RunState::Yielded(frame) = interpreter.run_until_yield(&mut DummySyscallHandler()).unwrap() {
+            assert!(interpreter.value_stack.is_empty());
+            frames.push(frame);
+        }
+        assert_eq!(frames, [1, 2, 3]);
+        assert_eq!(interpreter.value_stack, [42]);
+
+        //NOTE(joh): The decoded runner stops at a yield as well
+        interpreter.reset_all(&bytecode.code).unwrap();
+        interpreter.run_decoded(&mut DummySyscallHandler()).unwrap();
+        assert_eq!(interpreter.yielded, Some(1));
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let code = "
+            #1; push_arg; #@outer; call; #2;
+            end;
+            :outer: #3; #@inner; call; #4; #@inner; call; return;
+            :inner: #5; drop; return;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let labels = bytecode.labels.iter().cloned().collect::<HashMap<_, _>>();
+        let label = |name: &str| labels[name] + DATA_START;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = DummySyscallHandler();
+        let never = |_: &Interpreter| false;
+
+        for _ in 0..3 {
+            interpreter.step_over(&mut handler, never).unwrap();
+        }
+        assert_eq!(interpreter.value_stack, [label("outer")]);
+        interpreter.step_over(&mut handler, never).unwrap();
+        assert_eq!(interpreter.value_stack, [3, 4]);
+        assert_eq!(interpreter.return_stack.len(), 1);
+
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        for _ in 0..4 {
+            interpreter.step_over(&mut handler, |i| i.pc == label("inner")).unwrap();
+        }
+        assert_eq!(interpreter.pc, label("inner"));
+        assert_eq!(interpreter.return_stack.len(), 3);
+
+        interpreter.step_out(&mut handler, never).unwrap();
+        assert_eq!(interpreter.return_stack.len(), 2);
+        assert_eq!(interpreter.value_stack, [3]);
+        interpreter.step_out(&mut handler, never).unwrap();
+        assert_eq!(interpreter.return_stack.len(), 1);
+        assert_eq!(interpreter.value_stack, [3, 4]);
+        interpreter.step_out(&mut handler, never).unwrap();
+        assert!(!interpreter.running);
+        assert_eq!(interpreter.value_stack, [3, 4, 2]);
+    }
+
+    #[test]
+    fn address_overflow() {
+        let run = |code: &str| {
+            let bytecode = asm::Parser::parse(code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert!(matches!(run("#-1; load_32_u 8; end;"), Err(InterpreterErrorType::AddrOutOfBounds(0xffffffff))));
+        assert!(matches!(run("#-4; #1; store_32 8; end;"), Err(InterpreterErrorType::AddrOutOfBounds(0xfffffffc))));
+
+        //NOTE(joh): Relative branches go backwards through a wrapping offset
+        let code = "
+            :loop:
+            local_get 0; #1; add; local_tee 0;
+            #3; lt;
+            #-21; branch_if;
+            local_get 0;
+            end;
+        ";
+        assert_code_result!(code, &[3]);
+    }
+
+    #[test]
+    fn forward_references() {
+        let code = "
+            .data table words @after;
+            #@main; jmp;
+            :double:
+            local_get 0; #2; mul;
+            return;
+            :main:
+            #21; push_arg; #@double; call;
+            #0; #.skip; branch_if;
+            #1; #.skip; branch_if;
+            unreachable;
+            :skip:
+            #@table; load_32_u 0; #@after; eq;
+            :loop:
+            local_get 1; #1; add; local_tee 1;
+            #3; lt; #.loop; branch_if;
+            local_get 1;
+            end;
+            :after:
+            unreachable;
+        ";
+        assert_code_result!(code, &[42, 1, 3]);
+
+        let bytecode = asm::Parser::parse_with_const_pool(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        assert_eq!(interpreter.run(&mut DummySyscallHandler()).unwrap(), [42, 1, 3]);
+    }
+
+    #[test]
+    fn configured_limits() {
+        let run = |code: &str, config: InterpreterConfig| {
+            let bytecode = asm::Parser::parse(code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        let config = InterpreterConfig::default().with_globals(2);
+        assert_eq!(run("#1; global_set 1; global_get 1; end;", config.clone()).unwrap(), [1]);
+        assert!(matches!(
+            run("#1; global_set 2; end;", config.clone()),
+            Err(InterpreterErrorType::InvalidGlobalId(2))
+        ));
+        assert_eq!(run(".global a = 1; .global b = 2; global_get 1; end;", config.clone()).unwrap(), [2]);
+        let bytecode = asm::Parser::parse(".global a = 1; .global b = 2; .global c = 3; end;").unwrap();
+        assert!(matches!(
+            Interpreter::from_bytecode_with_config(&bytecode.code, config),
+            Err(InterpreterErrorType::TooManyGlobals { count: 3, limit: 2 })
+        ));
+
+        let config = InterpreterConfig::default().with_max_value_stack(4);
+        assert_eq!(run("#1; #2; #3; #4; end;", config.clone()).unwrap(), [1, 2, 3, 4]);
+        assert!(matches!(
+            run("#1; #2; #3; #4; #5; #6; end;", config),
+            Err(InterpreterErrorType::ValueStackOverflow(4))
+        ));
+
+        let recursion = ":__ENTRY__: #@f; call; end; :f: #@f; call; return;";
+        let config = InterpreterConfig::default().with_max_call_depth(16);
+        let bytecode = asm::Parser::parse(recursion).unwrap();
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+        assert!(matches!(
+            interpreter.run(&mut DummySyscallHandler()),
+            Err(InterpreterErrorType::CallStackOverflow(16))
+        ));
+        assert_eq!(interpreter.return_stack.len(), 16);
+
+        let config = InterpreterConfig::default().with_memory_size(1 << 20);
+        let bytecode = asm::Parser::parse("mem_size; end;").unwrap();
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+        assert_eq!(interpreter.run(&mut DummySyscallHandler()).unwrap(), &[1 << 20]);
+    }
+
+    #[test]
+    fn memory_stack() {
+        let code = "
+            .local a; .local b;
+            #5; push_mem; local_set a;
+            #16; push_mem; local_set b;
+            local_get a; #7; store_32 0;
+            #16; pop_mem;
+            global_get __SP__; local_get a; sub;
+            local_get a; local_get b; sub;
+            #8; pop_mem;
+            global_get __SP__;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        let stack = interpreter.memory_stack.clone();
+        assert_eq!(stack.len(), DEFAULT_MEMORY_STACK_SIZE);
+        assert!(interpreter.heap.start >= stack.end);
+        let result = interpreter.run(&mut DummySyscallHandler()).unwrap().to_vec();
+        assert_eq!(result, [0, 16, stack.end]);
+
+        let config = InterpreterConfig::default().with_memory_stack_size(32);
+        let run = |code: &str, config: InterpreterConfig| {
+            let bytecode = asm::Parser::parse(code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert!(run("#32; push_mem; end;", config.clone()).is_ok());
+        assert!(matches!(
+            run("#16; push_mem; #17; push_mem; end;", config.clone()),
+            Err(InterpreterErrorType::MemoryStackOverflow(17))
+        ));
+        assert!(matches!(run("#8; pop_mem; end;", config), Err(InterpreterErrorType::MemoryStackUnderflow(8))));
+        let config = InterpreterConfig::default().with_memory_stack_size(0);
+        assert!(matches!(run("#8; push_mem; end;", config.clone()), Err(InterpreterErrorType::MemoryStackOverflow(8))));
+        assert!(matches!(run("global_get __SP__; end;", config), Err(InterpreterErrorType::InvalidGlobalId(255))));
+
+        //NOTE(joh): Stepping back restores the stack pointer
+        interpreter.reset_all(&bytecode).unwrap();
+        interpreter.start_recording(16);
+        interpreter.run_for(&mut DummySyscallHandler(), 3).unwrap();
+        assert_eq!(interpreter.globals[interpreter.globals.len() - 1], stack.end - 8);
+        interpreter.step_back();
+        interpreter.step_back();
+        assert_eq!(interpreter.globals[interpreter.globals.len() - 1], stack.end);
+    }
+
+    #[test]
+    fn code_protection() {
+        let code = "
+            #@code; #0x01; store_8 0;
+            :code: unreachable;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        let result = interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec());
+        assert!(matches!(result, Err(InterpreterErrorType::WriteToProtectedMemory(addr)) if addr > interpreter.pc));
+        interpreter.reset_all(&bytecode).unwrap();
+        assert!(matches!(
+            interpreter.run_decoded(&mut DummySyscallHandler()),
+            Err(InterpreterErrorType::WriteToProtectedMemory(_))
+        ));
+        interpreter.reset_all(&bytecode).unwrap();
+        interpreter.set_code_writable(true);
+        assert!(interpreter.run(&mut DummySyscallHandler()).is_ok());
+
+        let config = InterpreterConfig::default().with_code_protection(false);
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode, config).unwrap();
+        assert!(interpreter.run(&mut DummySyscallHandler()).is_ok());
+
+        //NOTE(joh): Regions of the host apply to `mem_fill` as well
+        let bytecode = asm::Parser::parse("#0x4000; #0; #8; mem_fill; end;").unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        interpreter.protected.push(0x4004..0x4008);
+        assert!(matches!(
+            interpreter.run(&mut DummySyscallHandler()),
+            Err(InterpreterErrorType::WriteToProtectedMemory(0x4000))
+        ));
+        assert!(matches!(interpreter.store_u32(0, 1), Err(InterpreterErrorType::WriteToProtectedMemory(0))));
+    }
+
+    #[test]
+    fn multiple_memories() {
+        let code = "
+            #4; #0x1234; store_mem_32 1;
+            #4; load_mem_32 1; #1; add; #0x4000; swap; store_mem_32 0;
+            #0x4000; load_mem_8_u 0;
+            #9; #7; store_mem_8 2;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        let shared = crate::mem::Memory::zeroed(PAGE_SIZE);
+        assert_eq!(interpreter.add_memory(shared.clone()), Some(1));
+        let result = interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec());
+        assert!(matches!(result, Err(InterpreterErrorType::InvalidMemoryIndex(2))));
+        assert_eq!(interpreter.value_stack, [0x35]);
+        assert_eq!(interpreter.read_u32(0x4000).unwrap(), 0x1235);
+        assert_eq!(interpreter.memory_at(1).unwrap().read_le::<u32>(4), Some(0x1234));
+        //NOTE(joh): The host's copy is untouched until it takes the memory back
+        assert_eq!(shared.read_le::<u32>(4), Some(0));
+        assert!(interpreter.memory_at(2).is_none());
+
+        //NOTE(joh): The memories stay when the program starts over, stores into them are undone
+        interpreter.reset_all(&bytecode).unwrap();
+        let old = interpreter.set_memory(1, shared).unwrap();
+        assert_eq!(old.read_le::<u32>(4), Some(0x1234));
+        assert_eq!(interpreter.add_memory(crate::mem::Memory::zeroed(16)), Some(2));
+        interpreter.start_recording(64);
+        while interpreter.memory_at(2).unwrap().get(9) == Some(0) {
+            interpreter.exec_next_op(&mut DummySyscallHandler()).unwrap();
+        }
+        assert!(interpreter.step_back());
+        assert_eq!(interpreter.memory_at(2).unwrap().get(9), Some(0));
+        assert!(matches!(interpreter.set_memory(3, old), Err(InterpreterErrorType::InvalidMemoryIndex(3))));
+    }
+
+    #[test]
+    fn jumps_into_ops() {
+        let run = |code: &str| {
+            let bytecode = asm::Parser::parse(code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert_eq!(run("#@target; jmp; :target: #7; end;").unwrap(), [7]);
+        let target = asm::DATA_START + 12;
+        assert!(matches!(
+            run("#@target; #1; add; jmp; :target: #7; end;"),
+            Err(InterpreterErrorType::InvalidJumpAddr(addr)) if addr == target + 1
+        ));
+        assert!(matches!(
+            run("#@f; #2; add; call; end; :f: #7; return;"),
+            Err(InterpreterErrorType::InvalidJumpAddr(_))
+        ));
+    }
+
+    #[test]
+    fn memory_grow() {
+        let code = "
+            mem_size;
+            #1; mem_grow; global_set 0;
+            global_get 0; #42; store_32 0;
+            global_get 0; load_32_u 0;
+            mem_size;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let len = interpreter.memory.len() as u32;
+        let result = interpreter.run(&mut DummySyscallHandler()).unwrap();
+        assert_eq!(result, &[len, 42, len + PAGE_SIZE as u32]);
+
+        let bytecode = asm::Parser::parse("#1; mem_grow; #1; mem_grow; end;").unwrap();
+        let len = Interpreter::from_bytecode(&bytecode.code).unwrap().memory.len() as u32;
+        let quota = Quota { memory_bytes: Some(len as u64 + PAGE_SIZE as u64), ..Quota::unlimited() };
+        let config = InterpreterConfig { quota, ..Default::default() };
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+        assert!(matches!(
+            interpreter.run(&mut DummySyscallHandler()),
+            Err(InterpreterErrorType::QuotaExceeded { kind: QuotaKind::Memory, .. })
+        ));
+        assert_eq!(interpreter.memory.len() as u32, len + PAGE_SIZE as u32);
+
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.start_recording(usize::MAX);
+        interpreter.exec_next_op(&mut DummySyscallHandler()).unwrap();
+        interpreter.exec_next_op(&mut DummySyscallHandler()).unwrap();
+        assert!(interpreter.step_back());
+        assert_eq!(interpreter.memory.len() as u32, len);
+
+        //NOTE(joh): Addresses past `u32::MAX` could not be reached, the memory stays as it is
+        let bytecode = asm::Parser::parse("#0x100000; mem_grow; mem_size; end;").unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let len = interpreter.memory.len() as u32;
+        assert_eq!(interpreter.run(&mut DummySyscallHandler()).unwrap(), &[u32::MAX, len]);
+        assert_eq!(interpreter.memory.len() as u32, len);
+    }
+
+    #[test]
+    fn conditional_and_indirect_calls() {
+        let functions = "
+            .func one 0; #1; return;
+            .func add 2; local_get 0; local_get 1; add; return;
+        ";
+        let code = format!(
+            ":__ENTRY__:
+            #0; #@one; call_if;
+            #1; #@one; call_if;
+            #2; push_arg; #3; push_arg; #@add.index; call_indirect;
+            end;
+            {functions}"
+        );
+        assert_code_result!(&code, &[1, 5]);
+
+        let run = |code: String| {
+            let bytecode = asm::Parser::parse(&code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert!(matches!(
+            run(format!(":__ENTRY__: #1; push_arg; #0; call_indirect; end; {functions}")),
+            Err(InterpreterErrorType::ArityMismatch { function: 0, expected: 0, found: 1 })
+        ));
+        assert!(matches!(
+            run(format!(":__ENTRY__: #2; call_indirect; end; {functions}")),
+            Err(InterpreterErrorType::InvalidFunctionIndex(2))
+        ));
+        assert!(matches!(
+            asm::Parser::parse(&format!(":__ENTRY__: #0; #@one; call_if; #@add.index; call_indirect; end; {functions}")).map(|_| ()),
+            Err(asm::AssembleError { kind: asm::AssembleErrorKind::ArityMismatch { .. }, .. })
+        ));
+    }
+
+    #[test]
+    fn return_arity() {
+        let functions = "
+            .func pair 1 2; local_get 0; local_get 0; return;
+            .func leaky 0 1; #1; #2; return;
+            .func greedy 0 0; drop; return;
+        ";
+        assert_code_result!(&format!(":__ENTRY__: #7; #3; push_arg; #@pair; call; end; {functions}"), &[7, 3, 3]);
+
+        let run = |code: String| {
+            let bytecode = asm::Parser::parse(&code).unwrap();
+            let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+            interpreter.run(&mut DummySyscallHandler()).map(|r| r.to_vec())
+        };
+        assert!(matches!(
+            run(format!(":__ENTRY__: #@leaky; call; end; {functions}")),
+            Err(InterpreterErrorType::ReturnArityMismatch { expected: 1, found: 2 })
+        ));
+        assert!(matches!(
+            run(format!(":__ENTRY__: #9; #@greedy; call; end; {functions}")),
+            Err(InterpreterErrorType::ReturnArityMismatch { expected: 0, found: -1 })
+        ));
+        //NOTE(joh): The target is not a constant, so only the interpreter sees the missing arg
+        assert!(matches!(
+            run(format!(":__ENTRY__: #@pair; local_set 0; local_get 0; call; end; {functions}")),
+            Err(InterpreterErrorType::ArityMismatch { function: 0, expected: 1, found: 0 })
+        ));
+    }
 }
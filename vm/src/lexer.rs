@@ -0,0 +1,217 @@
+use alloc::borrow::Cow;
+use core::ops::Range;
+
+use crate::{
+    asm::{opcode, MACRO_PREFIX, OP_ALIASES, STATEMENT_SEP},
+    flow,
+};
+
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Opcode,
+    //NOTE(joh): `.data`, `.string`, `.func` and the `%` macro directives
+    Directive,
+    Label,
+    LabelRef,
+    //NOTE(joh): Only the `#`, its argument is a token of its own
+    Const,
+    Number,
+    String,
+    Comment,
+    Separator,
+    //NOTE(joh): Anything else, e.g. names in directives or macro invocations
+    Ident,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+}
+
+//NOTE(joh): Meant for highlighting source that is still being edited, so it never fails.
+//Whitespace between the tokens is not part of any token.
+pub fn tokenize(code: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut statement_start = true;
+    //NOTE(joh): `%` directives end at the end of the line instead of a separator
+    let mut line_directive = false;
+    let mut i = 0;
+
+    while let Some(c) = code[i..].chars().next() {
+        let rest = &code[i..];
+        if c.is_whitespace() {
+            if c == '\n' && line_directive {
+                statement_start = true;
+                line_directive = false;
+            }
+            i += c.len_utf8();
+            continue;
+        }
+
+        let (kind, len) = if rest.starts_with("//") {
+            (TokenKind::Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") {
+            (TokenKind::Comment, rest.find("*/").map_or(rest.len(), |end| end + 2))
+        } else {
+            match c {
+                STATEMENT_SEP | '{' | '}' => (TokenKind::Separator, 1),
+                '"' => (TokenKind::String, string_len(rest)),
+                '#' => (TokenKind::Const, 1),
+                ':' => (TokenKind::Label, rest[1..].find(':').map_or(rest.len(), |end| end + 2)),
+                MACRO_PREFIX => {
+                    line_directive = true;
+                    (TokenKind::Directive, 1 + word_len(&rest[1..]))
+                }
+                '.' if statement_start => (TokenKind::Directive, 1 + word_len(&rest[1..])),
+                '@' | '.' => (TokenKind::LabelRef, 1 + word_len(&rest[1..])),
+                _ => {
+                    let len = word_len(rest).max(c.len_utf8());
+                    let word = &rest[..len];
+                    let is_op = opcode::Names.contains(&word) || OP_ALIASES.iter().any(|(alias, _)| *alias == word);
+                    let kind = if statement_start && (is_op || flow::KEYWORDS.contains(&word)) {
+                        TokenKind::Opcode
+                    } else if word.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+                        TokenKind::Number
+                    } else {
+                        TokenKind::Ident
+                    };
+                    (kind, len)
+                }
+            }
+        };
+
+        statement_start = match kind {
+            TokenKind::Separator | TokenKind::Label => true,
+            TokenKind::Comment => statement_start,
+            _ => false,
+        };
+        tokens.push(Token { kind, span: i..i + len });
+        i += len;
+    }
+    tokens
+}
+
+//NOTE(joh): Replaces comments with spaces, keeping newlines so offsets and lines stay the same
+pub fn blank_comments(code: &str) -> Cow<'_, str> {
+    let comments: Vec<Range<usize>> = tokenize(code)
+        .into_iter()
+        .filter(|t| t.kind == TokenKind::Comment)
+        .map(|t| t.span)
+        .collect();
+    if comments.is_empty() {
+        return Cow::Borrowed(code);
+    }
+    let mut blanked = String::with_capacity(code.len());
+    let mut end = 0;
+    for span in comments {
+        blanked.push_str(&code[end..span.start]);
+        //NOTE(joh): One space per byte, so multi byte chars don't shift the offsets
+        for c in code[span.clone()].chars() {
+            match c {
+                '\n' => blanked.push('\n'),
+                c => (0..c.len_utf8()).for_each(|_| blanked.push(' ')),
+            }
+        }
+        end = span.end;
+    }
+    blanked.push_str(&code[end..]);
+    Cow::Owned(blanked)
+}
+
+fn word_len(s: &str) -> usize {
+    s.find(|c: char| c.is_whitespace() || matches!(c, STATEMENT_SEP | '"' | '/' | '{' | '}'))
+        .unwrap_or(s.len())
+}
+
+//NOTE(joh): Includes both quotes, an unterminated string runs to the end
+fn string_len(s: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_statements() {
+        let code = "%define N 2;\n:start: #@msg.len; local_get N; // done\n.string msg \"a\\\"b\"; /* x */ call;";
+        let tokens: Vec<(TokenKind, &str)> = tokenize(code)
+            .into_iter()
+            .map(|t| (t.kind, &code[t.span]))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenKind::Directive, "%define"),
+                (TokenKind::Ident, "N"),
+                (TokenKind::Number, "2"),
+                (TokenKind::Separator, ";"),
+                (TokenKind::Label, ":start:"),
+                (TokenKind::Const, "#"),
+                (TokenKind::LabelRef, "@msg.len"),
+                (TokenKind::Separator, ";"),
+                (TokenKind::Opcode, "local_get"),
+                (TokenKind::Ident, "N"),
+                (TokenKind::Separator, ";"),
+                (TokenKind::Comment, "// done"),
+                (TokenKind::Directive, ".string"),
+                (TokenKind::Ident, "msg"),
+                (TokenKind::String, "\"a\\\"b\""),
+                (TokenKind::Separator, ";"),
+                (TokenKind::Comment, "/* x */"),
+                (TokenKind::Opcode, "call"),
+                (TokenKind::Separator, ";"),
+            ]
+        );
+
+        let kinds = |code| tokenize(code).into_iter().map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds("if {drop;} else"),
+            [TokenKind::Opcode, TokenKind::Separator, TokenKind::Opcode, TokenKind::Separator, TokenKind::Separator, TokenKind::Opcode]
+        );
+
+        let unterminated = tokenize("#\"open");
+        assert_eq!(unterminated[1].span, 1..6);
+    }
+
+    //NOTE(joh): The assembler has its own parser, this checks that the highlighting of the GUI
+    //and the LSP agrees with it on where the ops are. Each `#` pushes a constant.
+    #[test]
+    fn tokens_match_assembler() {
+        let corpus = [
+            include_str!("../../gui/assets/asm/code_example.malu"),
+            ":a: :b: nop; #1; #@a; jmp;\n.string s \"x; nop; #2\";\nend; // add; #3;",
+            ".data d bytes 1 2;\n.func f 1 1;\n/* #4;\nnop; */ local_get 0;\nreturn;\n:__ENTRY__: #@d.len; jump_table 0;\nend;",
+            ".global g = 4;\n#-1; global_set 0; #0x10;\n  mul_wrap; nop; end;",
+        ];
+        for code in corpus {
+            let mut parser = crate::asm::Parser::new();
+            parser.set_emit_debug_info(true);
+            let result = parser.assemble(code).unwrap();
+            let mut expected = vec![0; code.lines().count()];
+            for entry in &result.debug_info.lines {
+                expected[entry.line as usize - 1] += 1;
+            }
+
+            let mut ops = vec![0; code.lines().count()];
+            for token in tokenize(code) {
+                if matches!(token.kind, TokenKind::Opcode | TokenKind::Const) {
+                    ops[code[..token.span.start].matches('\n').count()] += 1;
+                }
+            }
+            assert_eq!(ops, expected, "{code}");
+        }
+    }
+}
@@ -1,4 +1,52 @@
+//NOTE(joh): Without the `std` feature only `alloc` is needed, see `io` and `prelude`
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod abi;
+pub mod analyze;
 pub mod asm;
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod clock;
+pub mod config;
+pub mod debug;
+pub mod decoded;
+pub mod debuginfo;
+pub mod disasm;
+pub mod exports;
+pub mod expr;
+pub mod flow;
+pub mod framebuffer;
+pub mod function;
+pub mod gas;
+pub mod globals;
+pub mod heap;
+pub mod history;
+pub mod host;
 pub mod interpreter;
+pub mod io;
+pub mod lexer;
+pub mod link;
+pub mod mem;
+pub mod module;
 pub mod op;
 pub mod parse;
+mod prelude;
+pub mod profile;
+pub mod reload;
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod sandbox;
+pub mod section;
+pub mod shared;
+pub mod signing;
+pub mod snapshot;
+pub mod symbols;
+pub mod syscall;
+pub mod testing;
+pub mod trace;
+pub mod trap;
+pub mod validate;
+#[cfg(feature = "std")]
+pub mod worker;
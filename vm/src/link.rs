@@ -0,0 +1,454 @@
+use core::fmt::Display;
+
+use crate::{
+    asm::{BytecodeInfo, DATA_START, ENTRY_LABEL_NAME},
+    exports::encode_exports,
+    function::{decode_returns, encode_function_table, encode_returns, Function},
+    mem::{push_le, read_le_from},
+    prelude::*,
+};
+
+pub const OBJECT_MAGIC: [u8; 4] = *b"mobj";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Code,
+    Data,
+}
+
+//NOTE(joh): Only `.func` declarations, `.data`/`.string` entries and the entry label are
+//visible to other objects. Plain code labels are resolved inside the object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: u32,
+}
+
+//NOTE(joh): What gets added to the 32 bit word at a relocation. Local targets were already
+//resolved as if the object was linked on its own (code at `DATA_START`, data right after it),
+//so only the distance the section moved is added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelocationTarget {
+    Code,
+    Data,
+    Function,
+    Symbol(String),
+    //NOTE(joh): `@name.index` of a function declared in another object
+    SymbolIndex(String),
+}
+
+//NOTE(joh): `offset` counts from the start of the code, words in the data section come after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u32,
+    pub target: RelocationTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Object {
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    pub instruction_count: u32,
+    pub symbols: Vec<Symbol>,
+    pub functions: Vec<(String, u8, Option<u8>)>,
+    pub relocations: Vec<Relocation>,
+    //NOTE(joh): Names declared with `.export`, they end up in the export section of the program
+    pub exports: Vec<String>,
+    //NOTE(joh): Names declared with `.import`, they have to be defined by another object even if
+    //nothing refers to them
+    pub imports: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    InvalidObject,
+    DuplicateSymbol(String),
+    //NOTE(joh): Every symbol that is missing, not just the first one
+    UnresolvedSymbols(Vec<String>),
+    RelocationOutOfBounds(u32),
+    //NOTE(joh): The sizes, offsets or counts of the objects add up past `u32::MAX`
+    TooLarge,
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LinkError::InvalidObject => write!(f, "invalid object file"),
+            LinkError::DuplicateSymbol(name) => write!(f, "symbol `{name}` is defined more than once"),
+            LinkError::UnresolvedSymbols(names) => {
+                let names = names.iter().map(|n| format!("`{n}`")).collect::<Vec<_>>();
+                match names.len() {
+                    1 => write!(f, "undefined symbol {}", names[0]),
+                    _ => write!(f, "undefined symbols {}", names.join(", ")),
+                }
+            }
+            LinkError::RelocationOutOfBounds(offset) => write!(f, "relocation at 0x{offset:04x} is out of bounds"),
+            LinkError::TooLarge => write!(f, "the linked program does not fit into the address space"),
+        }
+    }
+}
+
+impl core::error::Error for LinkError {}
+
+fn push_str(buffer: &mut Vec<u8>, s: &str) {
+    push_le(buffer, s.len() as u32);
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+fn push_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    push_le(buffer, bytes.len() as u32);
+    buffer.extend_from_slice(bytes);
+}
+
+fn read_bytes<'a>(reader: &mut &'a [u8]) -> Result<&'a [u8], LinkError> {
+    let len: u32 = read_le_from(reader).map_err(|_| LinkError::InvalidObject)?;
+    reader.split_off(..len as usize).ok_or(LinkError::InvalidObject)
+}
+
+fn read_str(reader: &mut &[u8]) -> Result<String, LinkError> {
+    String::from_utf8(read_bytes(reader)?.to_vec()).map_err(|_| LinkError::InvalidObject)
+}
+
+fn read_u32(reader: &mut &[u8]) -> Result<u32, LinkError> {
+    read_le_from(reader).map_err(|_| LinkError::InvalidObject)
+}
+
+fn read_u8(reader: &mut &[u8]) -> Result<u8, LinkError> {
+    read_le_from(reader).map_err(|_| LinkError::InvalidObject)
+}
+
+//NOTE(joh): Sizes and offsets come from the object files, they may add up to anything
+fn add(a: u32, b: impl TryInto<u32>) -> Result<u32, LinkError> {
+    b.try_into().ok().and_then(|b| a.checked_add(b)).ok_or(LinkError::TooLarge)
+}
+
+impl Object {
+    //NOTE(joh): Symbols the object imports or refers to without defining them, other objects
+    //have to define them
+    pub fn unresolved(&self) -> Vec<&str> {
+        let referenced = self.relocations.iter().filter_map(|r| match &r.target {
+            RelocationTarget::Symbol(name) | RelocationTarget::SymbolIndex(name) => Some(name.as_str()),
+            _ => None,
+        });
+        let mut unresolved = Vec::new();
+        for name in self.imports.iter().map(String::as_str).chain(referenced) {
+            if !unresolved.contains(&name) && self.symbols.iter().all(|s| s.name != name) {
+                unresolved.push(name);
+            }
+        }
+        unresolved
+    }
+
+    //NOTE(joh): `OBJECT_MAGIC`, the instruction count, code and data, then the symbols,
+    //functions, relocations, exports and imports, each prefixed with their count
+    pub fn encode(&self) -> Box<[u8]> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&OBJECT_MAGIC);
+        push_le(&mut buffer, self.instruction_count);
+        push_bytes(&mut buffer, &self.code);
+        push_bytes(&mut buffer, &self.data);
+
+        push_le(&mut buffer, self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            push_str(&mut buffer, &symbol.name);
+            push_le(&mut buffer, symbol.section as u8);
+            push_le(&mut buffer, symbol.offset);
+        }
+        push_le(&mut buffer, self.functions.len() as u32);
+        for (name, arity, returns) in &self.functions {
+            push_str(&mut buffer, name);
+            push_le(&mut buffer, *arity);
+            push_le(&mut buffer, encode_returns(*returns));
+        }
+        push_le(&mut buffer, self.relocations.len() as u32);
+        for relocation in &self.relocations {
+            push_le(&mut buffer, relocation.offset);
+            let (kind, name) = match &relocation.target {
+                RelocationTarget::Code => (0u8, None),
+                RelocationTarget::Data => (1, None),
+                RelocationTarget::Function => (2, None),
+                RelocationTarget::Symbol(name) => (3, Some(name)),
+                RelocationTarget::SymbolIndex(name) => (4, Some(name)),
+            };
+            push_le(&mut buffer, kind);
+            if let Some(name) = name {
+                push_str(&mut buffer, name);
+            }
+        }
+        for names in [&self.exports, &self.imports] {
+            push_le(&mut buffer, names.len() as u32);
+            names.iter().for_each(|name| push_str(&mut buffer, name));
+        }
+        buffer.into_boxed_slice()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, LinkError> {
+        let mut reader = bytes
+            .strip_prefix(&OBJECT_MAGIC)
+            .ok_or(LinkError::InvalidObject)?;
+        let reader = &mut reader;
+        let instruction_count = read_u32(reader)?;
+        let code = read_bytes(reader)?.to_vec();
+        let data = read_bytes(reader)?.to_vec();
+
+        let mut symbols = Vec::new();
+        for _ in 0..read_u32(reader)? {
+            let name = read_str(reader)?;
+            let section = match read_u8(reader)? {
+                0 => Section::Code,
+                1 => Section::Data,
+                _ => return Err(LinkError::InvalidObject),
+            };
+            let offset = read_u32(reader)?;
+            symbols.push(Symbol { name, section, offset });
+        }
+        let mut functions = Vec::new();
+        for _ in 0..read_u32(reader)? {
+            functions.push((read_str(reader)?, read_u8(reader)?, decode_returns(read_u8(reader)?)));
+        }
+        let mut relocations = Vec::new();
+        for _ in 0..read_u32(reader)? {
+            let offset = read_u32(reader)?;
+            let target = match read_u8(reader)? {
+                0 => RelocationTarget::Code,
+                1 => RelocationTarget::Data,
+                2 => RelocationTarget::Function,
+                3 => RelocationTarget::Symbol(read_str(reader)?),
+                4 => RelocationTarget::SymbolIndex(read_str(reader)?),
+                _ => return Err(LinkError::InvalidObject),
+            };
+            relocations.push(Relocation { offset, target });
+        }
+        let mut read_names = || -> Result<Vec<String>, LinkError> {
+            (0..read_u32(reader)?).map(|_| read_str(reader)).collect()
+        };
+        let exports = read_names()?;
+        let imports = read_names()?;
+        if !reader.is_empty() {
+            return Err(LinkError::InvalidObject);
+        }
+
+        Ok(Self {
+            code,
+            data,
+            instruction_count,
+            symbols,
+            functions,
+            relocations,
+            exports,
+            imports,
+        })
+    }
+}
+
+//NOTE(joh): Code of all objects comes first, in order, followed by their data. The entry
+//point is the `__ENTRY__` label of whichever object defines it, or the start of the code.
+//Objects are placed back to back, so `.align` only holds relative to the start of each object.
+pub fn link(objects: &[Object]) -> Result<Box<[u8]>, LinkError> {
+    link_with_entry(objects, None)
+}
+
+//NOTE(joh): Like `link`, but starts at the exported symbol `entry` if there is one, e.g. to pick
+//one of several programs linked against the same library
+pub fn link_with_entry(objects: &[Object], entry: Option<&str>) -> Result<Box<[u8]>, LinkError> {
+    let code_size = objects.iter().try_fold(0, |size, o| add(size, o.code.len()))?;
+
+    let mut code_base = DATA_START;
+    let mut data_base = add(DATA_START, code_size)?;
+    let mut function_base = 0;
+    let mut bases = Vec::with_capacity(objects.len());
+    for object in objects {
+        bases.push((code_base, data_base, function_base));
+        code_base = add(code_base, object.code.len())?;
+        data_base = add(data_base, object.data.len())?;
+        function_base = add(function_base, object.functions.len())?;
+    }
+
+    let mut symbols = HashMap::new();
+    let mut functions = Vec::new();
+    for (object, &(code_base, data_base, _)) in objects.iter().zip(&bases) {
+        for symbol in &object.symbols {
+            let base = match symbol.section {
+                Section::Code => code_base,
+                Section::Data => data_base,
+            };
+            if symbols.insert(symbol.name.as_str(), add(base, symbol.offset)?).is_some() {
+                return Err(LinkError::DuplicateSymbol(symbol.name.clone()));
+            }
+        }
+        for (name, arity, returns) in &object.functions {
+            let addr = object
+                .symbols
+                .iter()
+                .find(|s| s.name == *name)
+                .map_or(Ok(code_base), |s| add(code_base, s.offset))?;
+            functions.push(Function { name: name.clone(), addr, arity: *arity, returns: *returns });
+        }
+    }
+
+    let mut unresolved = Vec::new();
+    for name in objects.iter().flat_map(Object::unresolved) {
+        if !symbols.contains_key(name) && !unresolved.iter().any(|n| n == name) {
+            unresolved.push(name.to_string());
+        }
+    }
+    if !unresolved.is_empty() {
+        return Err(LinkError::UnresolvedSymbols(unresolved));
+    }
+
+    let mut code = Vec::with_capacity(code_size as usize);
+    let mut data = Vec::new();
+    for (object, &(code_base, data_base, function_base)) in objects.iter().zip(&bases) {
+        let mut content = object.code.clone();
+        content.extend_from_slice(&object.data);
+        let own_data_start = DATA_START + object.code.len() as u32;
+
+        for relocation in &object.relocations {
+            let delta = match &relocation.target {
+                RelocationTarget::Code => code_base - DATA_START,
+                RelocationTarget::Data => data_base.wrapping_sub(own_data_start),
+                RelocationTarget::Function => function_base,
+                RelocationTarget::Symbol(name) => *symbols
+                    .get(name.as_str())
+                    .ok_or_else(|| LinkError::UnresolvedSymbols(vec![name.clone()]))?,
+                RelocationTarget::SymbolIndex(name) => functions
+                    .iter()
+                    .position(|f| f.name == *name)
+                    .ok_or_else(|| LinkError::UnresolvedSymbols(vec![format!("{name}.index")]))?
+                    as u32,
+            };
+            let offset = relocation.offset as usize;
+            let word = content
+                .get_mut(offset..)
+                .and_then(|rest| rest.first_chunk_mut::<4>())
+                .ok_or(LinkError::RelocationOutOfBounds(relocation.offset))?;
+            *word = u32::from_le_bytes(*word).wrapping_add(delta).to_le_bytes();
+        }
+
+        let (object_code, object_data) = content.split_at(object.code.len());
+        code.extend_from_slice(object_code);
+        data.extend_from_slice(object_data);
+    }
+
+    let info = BytecodeInfo {
+        code_size_bytes: code_size,
+        instruction_count: objects.iter().try_fold(0, |count, o| add(count, o.instruction_count))?,
+        code_start_offset: match entry {
+            Some(entry) => *symbols
+                .get(entry)
+                .ok_or_else(|| LinkError::UnresolvedSymbols(vec![entry.to_string()]))?,
+            None => symbols.get(ENTRY_LABEL_NAME).copied().unwrap_or(DATA_START),
+        },
+        data_section_size: data.len() as u32,
+    };
+    let mut buffer = info.to_bytecode();
+    buffer.extend_from_slice(&code);
+    buffer.extend_from_slice(&data);
+    if !functions.is_empty() {
+        encode_function_table(&functions, &mut buffer);
+    }
+    let exports = objects
+        .iter()
+        .flat_map(|o| &o.exports)
+        .filter_map(|name| Some((name.clone(), *symbols.get(name.as_str())?)))
+        .collect::<Vec<_>>();
+    if !exports.is_empty() {
+        encode_exports(&exports, &mut buffer);
+    }
+    Ok(buffer.into_boxed_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::Parser,
+        interpreter::{Interpreter, InterpreterErrorType},
+        testing::NoSyscalls,
+    };
+
+    const MAIN: &str = "
+        :__ENTRY__:
+        #2; push_arg; #3; push_arg; #@add; call;
+        #@pointer; load_32_u 0; load_8_u 0; add;
+        #4; push_arg; #@double.index; call_indirect;
+        end;
+    ";
+    const LIB: &str = "
+        .string greeting \"A\";
+        .data pointer words @greeting;
+        .func add 2;
+        local_get 0; local_get 1; add;
+        return;
+        .func double 1;
+        :again:
+        local_get 0; local_get 0; add;
+        return;
+    ";
+
+    fn run(bytecode: &[u8]) -> Vec<u32> {
+        let mut interpreter = Interpreter::from_bytecode(bytecode).unwrap();
+        interpreter.run(&mut NoSyscalls()).unwrap().to_vec()
+    }
+
+    #[test]
+    fn link_objects() {
+        let main = Parser::new().assemble_object(MAIN).unwrap();
+        let lib = Parser::new().assemble_object(LIB).unwrap();
+        assert!(main.relocations.contains(&Relocation {
+            offset: 1 + 5 + 1 + 5 + 1,
+            target: RelocationTarget::Symbol("add".to_string())
+        }));
+        assert_eq!(Object::decode(&lib.encode()), Ok(lib.clone()));
+
+        for objects in [[main.clone(), lib.clone()], [lib.clone(), main.clone()]] {
+            assert_eq!(run(&link(&objects).unwrap()), [5 + b'A' as u32, 8]);
+        }
+
+        let flat = Parser::parse(&format!("{MAIN}{LIB}")).unwrap();
+        assert_eq!(run(&flat.code), run(&link(&[main.clone(), lib.clone()]).unwrap()));
+
+        let len = (main.code.len() + main.data.len()) as u32;
+        for offset in [len - 2, u32::MAX] {
+            let mut broken = main.clone();
+            broken.relocations[0].offset = offset;
+            let error = link(&[broken, lib.clone()]);
+            assert_eq!(error, Err(LinkError::RelocationOutOfBounds(offset)));
+        }
+
+        //NOTE(joh): Crafted objects whose offsets and counts add up past `u32::MAX`
+        let mut far = lib.clone();
+        far.symbols[0].offset = u32::MAX;
+        assert_eq!(link(&[main.clone(), far]), Err(LinkError::TooLarge));
+        let mut counted = lib.clone();
+        counted.instruction_count = u32::MAX;
+        assert_eq!(link(&[main.clone(), counted]), Err(LinkError::TooLarge));
+
+        let missing = ["add", "pointer", "double"].map(str::to_string).to_vec();
+        assert_eq!(link(std::slice::from_ref(&main)), Err(LinkError::UnresolvedSymbols(missing)));
+        assert_eq!(link(&[lib.clone(), lib]), Err(LinkError::DuplicateSymbol("add".to_string())));
+        assert_eq!(Object::decode(b"mbc"), Err(LinkError::InvalidObject));
+    }
+
+    #[test]
+    fn load_time_linking() {
+        let main = Parser::new().assemble_object(MAIN).unwrap();
+        let lib = Parser::new().assemble_object(LIB).unwrap();
+        let other = Parser::new()
+            .assemble_object(".func other 0; #@add; drop; #10; push_arg; #@double.index; call_indirect; end;")
+            .unwrap();
+        assert_eq!(main.unresolved(), ["add", "pointer", "double"]);
+        assert_eq!(other.unresolved(), ["add", "double"]);
+        assert!(lib.unresolved().is_empty());
+
+        //NOTE(joh): One library, two programs linked against it
+        let mut interpreter = Interpreter::link(&[main, lib.clone()], None).unwrap();
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), [5 + b'A' as u32, 8]);
+        let mut interpreter = Interpreter::link(&[lib.clone(), other.clone()], Some("other")).unwrap();
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), [20]);
+
+        let error = Interpreter::link(&[lib, other], Some("missing")).err();
+        assert!(matches!(error, Some(InterpreterErrorType::Link(LinkError::UnresolvedSymbols(_)))));
+    }
+}
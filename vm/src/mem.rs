@@ -0,0 +1,464 @@
+use alloc::{borrow::Cow, sync::Arc};
+use core::ops::{Deref, Range};
+
+use crate::{
+    io::{Read, Write},
+    prelude::*,
+};
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub type Page = [u8; PAGE_SIZE];
+
+//NOTE(joh): Bytes some other value owns, e.g. a memory mapped file. `as_ref` has to return the
+//same bytes every time and they must not change while anything holds on to them.
+pub type MappedBytes = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+pub(crate) fn mapped_slice(bytes: &MappedBytes) -> &[u8] {
+    (**bytes).as_ref()
+}
+
+//NOTE(joh): Shared by the zeroed pages of all memories. Without std every call makes a new one,
+//the pages of a memory still share it until they are written to.
+#[cfg(feature = "std")]
+fn zero_page() -> Arc<Page> {
+    static ZERO_PAGE: std::sync::LazyLock<Arc<Page>> = std::sync::LazyLock::new(|| Arc::new([0; PAGE_SIZE]));
+    ZERO_PAGE.clone()
+}
+
+#[cfg(not(feature = "std"))]
+fn zero_page() -> Arc<Page> {
+    Arc::new([0; PAGE_SIZE])
+}
+
+//NOTE(joh): Either shared with other memories or borrowed from an image that lives as long as
+//the program or its owner, see `Memory::from_static` and `Memory::from_mapped`. All of them are
+//copied on the first write. `Mapped` has the offset of the page in the bytes.
+#[derive(Clone)]
+enum PageRef {
+    Shared(Arc<Page>),
+    Static(&'static Page),
+    Mapped(MappedBytes, usize),
+}
+
+impl PageRef {
+    fn make_mut(&mut self) -> &mut Page {
+        if !matches!(self, PageRef::Shared(_)) {
+            *self = PageRef::Shared(Arc::new(**self));
+        }
+        match self {
+            PageRef::Shared(page) => Arc::make_mut(page),
+            PageRef::Static(_) | PageRef::Mapped(..) => unreachable!("borrowed pages are copied above"),
+        }
+    }
+
+    fn ptr_eq(&self, other: &PageRef) -> bool {
+        core::ptr::eq::<Page>(&**self, &**other)
+    }
+}
+
+impl Deref for PageRef {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        match self {
+            PageRef::Shared(page) => page,
+            PageRef::Static(page) => page,
+            PageRef::Mapped(bytes, offset) => mapped_slice(bytes)[*offset..*offset + PAGE_SIZE]
+                .try_into()
+                .expect("mapped pages are page sized"),
+        }
+    }
+}
+
+//NOTE(joh): All multi-byte values in bytecode, memory and cache files are little-endian,
+//independent of the host. Every conversion goes through this trait so there is exactly
+//one place where byte order is decided.
+pub trait LeBytes: Sized + Copy {
+    const SIZE: usize;
+
+    //NOTE(joh): `bytes` must be exactly `SIZE` long, use `read_le` for unchecked input
+    fn from_le_slice(bytes: &[u8]) -> Self;
+    fn write_le_slice(self, dest: &mut [u8]);
+}
+
+macro_rules! impl_le_bytes {
+    ($($t: ty),+) => {
+        $(impl LeBytes for $t {
+            const SIZE: usize = size_of::<$t>();
+
+            fn from_le_slice(bytes: &[u8]) -> Self {
+                let mut buf = [0; size_of::<$t>()];
+                buf.copy_from_slice(bytes);
+                <$t>::from_le_bytes(buf)
+            }
+
+            fn write_le_slice(self, dest: &mut [u8]) {
+                dest.copy_from_slice(&self.to_le_bytes());
+            }
+        })+
+    };
+}
+impl_le_bytes!(u8, i8, u16, i16, u32, i32, u64);
+
+pub fn read_le<T: LeBytes>(bytes: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(T::SIZE)?;
+    bytes.get(offset..end).map(T::from_le_slice)
+}
+
+pub fn push_le<T: LeBytes>(buffer: &mut Vec<u8>, value: T) {
+    let start = buffer.len();
+    buffer.resize(start + T::SIZE, 0);
+    value.write_le_slice(&mut buffer[start..]);
+}
+
+pub fn read_le_from<T: LeBytes>(reader: &mut impl Read) -> Result<T, crate::io::Error> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf[..T::SIZE])?;
+    Ok(T::from_le_slice(&buf[..T::SIZE]))
+}
+
+pub fn write_le_to<T: LeBytes>(writer: &mut impl Write, value: T) -> Result<(), crate::io::Error> {
+    let mut buf = [0; 8];
+    value.write_le_slice(&mut buf[..T::SIZE]);
+    writer.write_all(&buf[..T::SIZE])
+}
+
+//NOTE(joh): Pages are reference counted and only copied when they are written to,
+//so cloning a Memory (e.g. for a new instance of the same module) is cheap.
+#[derive(Clone, Default)]
+pub struct Memory {
+    pages: Vec<PageRef>,
+    len: usize,
+}
+
+//NOTE(joh): Stored as plain bytes, a deserialized Memory owns all of its pages
+#[cfg(feature = "serde")]
+impl serde::Serialize for Memory {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_vec())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Memory {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        Ok(Self::from_image(&bytes, bytes.len()))
+    }
+}
+
+impl core::fmt::Debug for Memory {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Memory")
+            .field("len", &self.len)
+            .field("pages", &self.pages.len())
+            .field("owned_pages", &self.owned_pages())
+            .finish()
+    }
+}
+
+impl Memory {
+    pub fn zeroed(len: usize) -> Self {
+        Self {
+            pages: vec![PageRef::Shared(zero_page()); len.div_ceil(PAGE_SIZE)],
+            len,
+        }
+    }
+
+    pub fn from_image(image: &[u8], len: usize) -> Self {
+        let mut memory = Self::zeroed(len.max(image.len()));
+        memory.write(0, image);
+        memory
+    }
+
+    //NOTE(joh): Like `from_image`, but the full pages of the image are borrowed instead of
+    //copied, e.g. from `include_bytes!` or a leaked mmap. Only the pages that are written to and
+    //the last partial one end up on the heap.
+    pub fn from_static(image: &'static [u8], len: usize) -> Self {
+        let mut memory = Self::zeroed(len.max(image.len()));
+        let mut chunks = image.chunks_exact(PAGE_SIZE);
+        for (page, chunk) in memory.pages.iter_mut().zip(chunks.by_ref()) {
+            *page = PageRef::Static(chunk.try_into().expect("chunks are page sized"));
+        }
+        let rest = chunks.remainder();
+        memory.write(image.len() - rest.len(), rest);
+        memory
+    }
+
+    //NOTE(joh): Like `from_static` for the `image` range of bytes with an owner, the full pages
+    //keep the owner alive
+    pub fn from_mapped(bytes: MappedBytes, image: Range<usize>, len: usize) -> Self {
+        let mut memory = Self::zeroed(len.max(image.len()));
+        let full_pages = image.len() / PAGE_SIZE;
+        for (i, page) in memory.pages.iter_mut().take(full_pages).enumerate() {
+            *page = PageRef::Mapped(bytes.clone(), image.start + i * PAGE_SIZE);
+        }
+        let rest = &mapped_slice(&bytes)[image.start + full_pages * PAGE_SIZE..image.end];
+        memory.write(full_pages * PAGE_SIZE, rest);
+        memory
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    //NOTE(joh): Pages not shared with any other memory or the zero page
+    pub fn owned_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .filter(|p| matches!(p, PageRef::Shared(page) if Arc::strong_count(page) == 1))
+            .count()
+    }
+
+    fn in_bounds(&self, addr: usize, len: usize) -> bool {
+        addr.checked_add(len).is_some_and(|end| end <= self.len)
+    }
+
+    pub fn get(&self, addr: usize) -> Option<u8> {
+        if addr < self.len {
+            Some(self.pages[addr / PAGE_SIZE][addr % PAGE_SIZE])
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, addr: usize, value: u8) -> Option<()> {
+        if addr < self.len {
+            self.pages[addr / PAGE_SIZE].make_mut()[addr % PAGE_SIZE] = value;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    pub fn read(&self, addr: usize, buf: &mut [u8]) -> Option<()> {
+        if !self.in_bounds(addr, buf.len()) {
+            return None;
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = addr + done;
+            let offset = pos % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(buf.len() - done);
+            buf[done..done + n].copy_from_slice(&self.pages[pos / PAGE_SIZE][offset..offset + n]);
+            done += n;
+        }
+        Some(())
+    }
+
+    pub fn write(&mut self, addr: usize, data: &[u8]) -> Option<()> {
+        if !self.in_bounds(addr, data.len()) {
+            return None;
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let pos = addr + done;
+            let offset = pos % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(data.len() - done);
+            self.pages[pos / PAGE_SIZE].make_mut()[offset..offset + n]
+                .copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        Some(())
+    }
+
+    pub fn read_array<const N: usize>(&self, addr: usize) -> Option<[u8; N]> {
+        let mut buf = [0; N];
+        self.read(addr, &mut buf)?;
+        Some(buf)
+    }
+
+    pub fn read_le<T: LeBytes>(&self, addr: usize) -> Option<T> {
+        let mut buf = [0; 8];
+        self.read(addr, &mut buf[..T::SIZE])?;
+        Some(T::from_le_slice(&buf[..T::SIZE]))
+    }
+
+    pub fn write_le<T: LeBytes>(&mut self, addr: usize, value: T) -> Option<()> {
+        let mut buf = [0; 8];
+        value.write_le_slice(&mut buf[..T::SIZE]);
+        self.write(addr, &buf[..T::SIZE])
+    }
+
+    //NOTE(joh): Ranges may overlap, the copy goes backwards if the destination is behind the source
+    pub fn copy_within(&mut self, src: usize, dst: usize, len: usize) -> Option<()> {
+        if !self.in_bounds(src, len) || !self.in_bounds(dst, len) {
+            return None;
+        }
+        let mut buf = [0; PAGE_SIZE];
+        let mut done = 0;
+        while done < len {
+            let n = PAGE_SIZE.min(len - done);
+            let offset = if dst <= src { done } else { len - done - n };
+            self.read(src + offset, &mut buf[..n])?;
+            self.write(dst + offset, &buf[..n])?;
+            done += n;
+        }
+        Some(())
+    }
+
+    //NOTE(joh): Whole pages filled with zeros go back to being the shared zero page
+    pub fn fill(&mut self, addr: usize, len: usize, value: u8) -> Option<()> {
+        if !self.in_bounds(addr, len) {
+            return None;
+        }
+        let mut done = 0;
+        while done < len {
+            let pos = addr + done;
+            let offset = pos % PAGE_SIZE;
+            let n = (PAGE_SIZE - offset).min(len - done);
+            let page = &mut self.pages[pos / PAGE_SIZE];
+            if value == 0 && n == PAGE_SIZE {
+                *page = PageRef::Shared(zero_page());
+            } else {
+                page.make_mut()[offset..offset + n].fill(value);
+            }
+            done += n;
+        }
+        Some(())
+    }
+
+    //NOTE(joh): Borrows if the range lies in a single page, copies otherwise
+    pub fn slice(&self, addr: usize, len: usize) -> Option<Cow<'_, [u8]>> {
+        if !self.in_bounds(addr, len) {
+            return None;
+        }
+        let offset = addr % PAGE_SIZE;
+        if offset + len <= PAGE_SIZE {
+            let page = &self.pages[addr / PAGE_SIZE];
+            Some(Cow::Borrowed(&page[offset..offset + len]))
+        } else {
+            let mut buf = vec![0; len];
+            self.read(addr, &mut buf)?;
+            Some(Cow::Owned(buf))
+        }
+    }
+
+    //NOTE(joh): New pages are shared zero pages until written
+    pub fn grow(&mut self, additional: usize) {
+        self.len += additional;
+        self.pages.resize(self.len.div_ceil(PAGE_SIZE), PageRef::Shared(zero_page()));
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.fill(PageRef::Shared(zero_page()));
+    }
+
+    //NOTE(joh): Runs of bytes that differ from `older`, as (addr, new bytes). Pages still shared
+    //with it are skipped, so this is cheap right after cloning `older` from this memory.
+    pub fn changes_since(&self, older: &Memory) -> Vec<(usize, Vec<u8>)> {
+        let mut changes = Vec::new();
+        let zero = PageRef::Shared(zero_page());
+        for (i, page) in self.pages.iter().enumerate() {
+            let old = older.pages.get(i).unwrap_or(&zero);
+            if page.ptr_eq(old) {
+                continue;
+            }
+            let end = (self.len - i * PAGE_SIZE).min(PAGE_SIZE);
+            let mut offset = 0;
+            while offset < end {
+                let Some(start) = (offset..end).find(|&j| page[j] != old[j]) else {
+                    break;
+                };
+                let run_end = (start..end).find(|&j| page[j] == old[j]).unwrap_or(end);
+                changes.push((i * PAGE_SIZE + start, page[start..run_end].to_vec()));
+                offset = run_end;
+            }
+        }
+        changes
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.len];
+        self.read(0, &mut buf);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write_across_pages() {
+        let mut memory = Memory::zeroed(3 * PAGE_SIZE);
+        let addr = PAGE_SIZE - 2;
+        memory.write(addr, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(memory.read_array::<4>(addr), Some([1, 2, 3, 4]));
+        assert!(matches!(memory.slice(addr, 4), Some(Cow::Owned(_))));
+        assert!(matches!(memory.slice(0, 4), Some(Cow::Borrowed(_))));
+        assert_eq!(memory.owned_pages(), 2);
+
+        assert!(memory.write(3 * PAGE_SIZE - 1, &[1, 2]).is_none());
+        assert!(memory.get(3 * PAGE_SIZE).is_none());
+    }
+
+    #[test]
+    fn copy_and_fill() {
+        let mut memory = Memory::zeroed(3 * PAGE_SIZE);
+        let data = (0..2 * PAGE_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        memory.write(10, &data).unwrap();
+
+        memory.copy_within(10, 20, data.len()).unwrap();
+        assert_eq!(memory.slice(20, data.len()).unwrap(), &data[..]);
+        memory.copy_within(20, 10, data.len()).unwrap();
+        assert_eq!(memory.slice(10, data.len()).unwrap(), &data[..]);
+        assert!(memory.copy_within(0, PAGE_SIZE, 2 * PAGE_SIZE + 1).is_none());
+
+        memory.fill(PAGE_SIZE - 1, PAGE_SIZE + 2, 0xab).unwrap();
+        assert_eq!(memory.read_array::<4>(PAGE_SIZE - 2), Some([data[PAGE_SIZE - 12], 0xab, 0xab, 0xab]));
+        assert_eq!(memory.get(2 * PAGE_SIZE + 1), Some(data[2 * PAGE_SIZE - 9]));
+        memory.fill(0, 3 * PAGE_SIZE, 0).unwrap();
+        assert_eq!(memory.owned_pages(), 0);
+        assert!(memory.fill(1, 3 * PAGE_SIZE, 0).is_none());
+    }
+
+    #[test]
+    fn little_endian_helpers() {
+        let mut buffer = Vec::new();
+        push_le(&mut buffer, 0x11223344_u32);
+        push_le(&mut buffer, -2_i16);
+        assert_eq!(buffer, [0x44, 0x33, 0x22, 0x11, 0xfe, 0xff]);
+
+        assert_eq!(read_le::<u32>(&buffer, 0), Some(0x11223344));
+        assert_eq!(read_le::<i16>(&buffer, 4), Some(-2));
+        assert_eq!(read_le::<u16>(&buffer, 1), Some(0x2233));
+        assert_eq!(read_le::<u32>(&buffer, 3), None);
+        assert_eq!(read_le::<u32>(&buffer, usize::MAX), None);
+
+        let mut reader = &buffer[..5];
+        assert_eq!(read_le_from::<u32>(&mut reader).unwrap(), 0x11223344);
+        assert!(read_le_from::<u16>(&mut reader).is_err());
+
+        let mut memory = Memory::zeroed(PAGE_SIZE + 4);
+        memory.write_le(PAGE_SIZE - 1, 0xaabbccdd_u32).unwrap();
+        assert_eq!(memory.read_le::<u32>(PAGE_SIZE - 1), Some(0xaabbccdd));
+        assert_eq!(memory.get(PAGE_SIZE - 1), Some(0xdd));
+        assert_eq!(memory.read_le::<u32>(PAGE_SIZE + 1), None);
+        assert!(memory.write_le(PAGE_SIZE + 1, 0_u32).is_none());
+    }
+
+    #[test]
+    fn clones_copy_on_write() {
+        let image = Memory::from_image(&[0xaa; 10], 4 * PAGE_SIZE);
+        let mut a = image.clone();
+        let b = image.clone();
+
+        a.set(2 * PAGE_SIZE, 7).unwrap();
+        assert_eq!(a.get(2 * PAGE_SIZE), Some(7));
+        assert_eq!(b.get(2 * PAGE_SIZE), Some(0));
+        assert_eq!(a.get(0), Some(0xaa));
+        assert_eq!(a.owned_pages(), 1);
+        assert_eq!(b.owned_pages(), 0);
+    }
+}
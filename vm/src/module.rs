@@ -0,0 +1,698 @@
+use alloc::sync::Arc;
+use core::ops::{Deref, Range};
+#[cfg(feature = "std")]
+use std::{
+    fs,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "std")]
+use crate::{
+    asm::{RawArg, RawOp},
+    mem::{read_le_from, write_le_to},
+    parse::try_parse_op,
+};
+
+use crate::{
+    io::Cursor,
+    asm::{BytecodeInfo, BYTECODE_HEADER, CODE_START_ADDR_POS, DATA_START},
+    exports::{decode_exports, EXPORTS_MAGIC},
+    function::{decode_function_table, Function, FUNCTION_TABLE_MAGIC},
+    globals::{decode_global_inits, GLOBALS_MAGIC},
+    host::{decode_host_imports, HostImport, HOST_IMPORTS_MAGIC},
+    interpreter::{is_bytecode_header_valid, InterpreterErrorType, MIN_HEAP_SIZE},
+    link::{link_with_entry, Object},
+    mem::{mapped_slice, MappedBytes, Memory},
+    parse::{decode_const_pool, try_parse_ops_from_bytecode, MaybeRawOp},
+    prelude::*,
+    section::Sections,
+    signing::{self, split_signature, Signature, VerifyingKey},
+    symbols::{decode_symbol_table, SymbolTable, SYMBOL_TABLE_MAGIC},
+};
+
+pub const CACHE_EXTENSION: &str = "maluc";
+#[cfg(feature = "std")]
+const CACHE_MAGIC: [u8; 4] = *b"mluc";
+//NOTE(joh): Bumped when the layout of the cache file changes, changes to the decoder are
+//picked up by `cache_version`
+#[cfg(feature = "std")]
+const CACHE_FORMAT: u32 = 8;
+
+//NOTE(joh): Decoded ops together with their address in memory
+pub type DecodedOps = Vec<(MaybeRawOp, u32)>;
+
+//NOTE(joh): One bit per address, set where an op starts. Jumps and calls may only land on
+//these so they can never continue in the middle of an immediate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InstructionMap {
+    bits: Vec<u64>,
+}
+
+impl InstructionMap {
+    pub fn from_ops(ops: &[(MaybeRawOp, u32)]) -> Self {
+        let mut map = Self::default();
+        ops.iter().for_each(|(_, addr)| map.insert(*addr));
+        map
+    }
+
+    pub fn insert(&mut self, addr: u32) {
+        let word = addr as usize / u64::BITS as usize;
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= 1 << (addr % u64::BITS);
+    }
+
+    pub fn remove(&mut self, addr: u32) {
+        if let Some(word) = self.bits.get_mut(addr as usize / u64::BITS as usize) {
+            *word &= !(1 << (addr % u64::BITS));
+        }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        self.bits
+            .get(addr as usize / u64::BITS as usize)
+            .is_some_and(|word| word & (1 << (addr % u64::BITS)) != 0)
+    }
+
+    //NOTE(joh): Bit `addr % 64` of word `addr / 64` is set for a marked address
+    pub fn bits(&self) -> &[u64] {
+        &self.bits
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    //NOTE(joh): Replaces the marks in `range` with the ops of `code`, which is placed at its start
+    pub fn mark_code(&mut self, range: Range<u32>, code: &[u8]) {
+        range.clone().for_each(|addr| self.remove(addr));
+        let mut addr = range.start;
+        for op in try_parse_ops_from_bytecode(&mut Cursor::new(code)) {
+            let Ok(op) = op else {
+                break;
+            };
+            self.insert(addr);
+            addr += match &op {
+                MaybeRawOp::Op(raw_op) => raw_op.size_bytes() as u32,
+                MaybeRawOp::Unknown(_) => 1,
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleInfo {
+    pub hash: u64,
+    pub verified: bool,
+    pub code_size_bytes: u32,
+    pub instruction_count: u32,
+}
+
+//NOTE(joh): The bytecode of a module, either owned by the modules and instances sharing it or
+//borrowed for the whole run of the host or from an owner, see `Module::from_static` and
+//`Module::from_mapped`. `Mapped` has the length of the bytecode without the signature.
+#[derive(Clone)]
+pub enum Bytecode {
+    Shared(Arc<[u8]>),
+    Static(&'static [u8]),
+    Mapped(MappedBytes, usize),
+}
+
+impl core::fmt::Debug for Bytecode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Bytecode::Shared(bytes) => f.debug_tuple("Shared").field(bytes).finish(),
+            Bytecode::Static(bytes) => f.debug_tuple("Static").field(bytes).finish(),
+            Bytecode::Mapped(_, len) => f.debug_tuple("Mapped").field(&&self[..*len]).finish(),
+        }
+    }
+}
+
+impl Default for Bytecode {
+    fn default() -> Self {
+        Bytecode::Static(&[])
+    }
+}
+
+impl Deref for Bytecode {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Bytecode::Shared(bytes) => bytes,
+            Bytecode::Static(bytes) => bytes,
+            Bytecode::Mapped(bytes, len) => &mapped_slice(bytes)[..*len],
+        }
+    }
+}
+
+impl AsRef<[u8]> for Bytecode {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+//NOTE(joh): A loaded program that can be instantiated many times. All instances share
+//the bytecode, the decoded ops and the initial memory image. `bytecode` never contains
+//the signature section.
+#[derive(Clone, Debug)]
+pub struct Module {
+    bytecode: Bytecode,
+    signature: Option<Signature>,
+    image: Memory,
+    start_pc_addr: u32,
+    ops: Arc<[(MaybeRawOp, u32)]>,
+    instructions: Arc<InstructionMap>,
+    const_pool: Arc<[u32]>,
+    functions: Arc<[Function]>,
+    global_inits: Arc<[u32]>,
+    host_imports: Arc<[HostImport]>,
+    exports: Arc<[(String, u32)]>,
+    symbols: Option<Arc<SymbolTable>>,
+    info: ModuleInfo,
+}
+
+//NOTE(joh): FNV-1a, only used as a cache key
+pub fn module_hash(bytecode: &[u8]) -> u64 {
+    bytecode.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(feature = "std")]
+pub fn cache_path(bytecode_path: &Path) -> PathBuf {
+    bytecode_path.with_extension(CACHE_EXTENSION)
+}
+
+//NOTE(joh): The cache stores decoded ops, so its version also covers which argument the decoder
+//reads for every opcode. Adding an opcode or changing an argument invalidates old caches.
+#[cfg(feature = "std")]
+fn cache_version() -> u32 {
+    static VERSION: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+    *VERSION.get_or_init(|| {
+        let mut signature = CACHE_FORMAT.to_le_bytes().to_vec();
+        for opcode in 0..=u8::MAX {
+            let mut probe = [0; 16];
+            probe[0] = opcode;
+            let kind = match try_parse_op(&mut Cursor::new(&probe[..])) {
+                Ok(MaybeRawOp::Op(RawOp { arg: None, .. })) => 1,
+                Ok(MaybeRawOp::Op(RawOp { arg: Some(RawArg::Register(_)), .. })) => 2,
+                Ok(MaybeRawOp::Op(RawOp { arg: Some(RawArg::Num(_)), .. })) => 3,
+                Ok(MaybeRawOp::Op(RawOp { arg: Some(RawArg::Wide(_)), .. })) => 4,
+                Ok(MaybeRawOp::Op(RawOp { arg: Some(RawArg::Table { .. }), .. })) => 5,
+                Ok(MaybeRawOp::Unknown(_)) | Err(_) => 0,
+            };
+            signature.push(kind);
+        }
+        module_hash(&signature) as u32
+    })
+}
+
+fn decode_ops(bytecode: &[u8]) -> Result<(DecodedOps, ModuleInfo), InterpreterErrorType> {
+    let header = BytecodeInfo::decode(&mut Cursor::new(bytecode))?;
+    if bytecode.len() < header.total_size() {
+        return Err(InterpreterErrorType::InvalidBytecodeHeader);
+    }
+    let code = bytecode
+        .get(BytecodeInfo::total_header_size()..)
+        .and_then(|c| c.get(..header.code_size_bytes as usize))
+        .ok_or(InterpreterErrorType::InvalidBytecodeHeader)?;
+
+    //NOTE(joh): Every op takes at least one byte, the header alone is not trusted for the size
+    let mut ops = Vec::with_capacity((header.instruction_count as usize).min(code.len()));
+    let mut offset = DATA_START;
+    for op in try_parse_ops_from_bytecode(&mut Cursor::new(code)) {
+        let op = op?;
+        let size = match &op {
+            MaybeRawOp::Op(raw_op) => raw_op.size_bytes() as u32,
+            MaybeRawOp::Unknown(_) => 1,
+        };
+        ops.push((op, offset));
+        offset += size;
+    }
+
+    let info = module_info(bytecode, &header, &ops);
+    Ok((ops, info))
+}
+
+//NOTE(joh): Verified if the ops follow each other from `DATA_START` on, are all known and cover
+//exactly the code the header announces. Also used for cached ops, which are not trusted.
+fn module_info(bytecode: &[u8], header: &BytecodeInfo, ops: &DecodedOps) -> ModuleInfo {
+    let mut offset = DATA_START as u64;
+    let contiguous = ops.iter().all(|(op, addr)| {
+        let at = *addr as u64 == offset;
+        offset += match op {
+            MaybeRawOp::Op(raw_op) => raw_op.size_bytes() as u64,
+            MaybeRawOp::Unknown(_) => 1,
+        };
+        at && matches!(op, MaybeRawOp::Op(_))
+    });
+    let verified = contiguous
+        && offset - DATA_START as u64 == header.code_size_bytes as u64
+        && ops.len() == header.instruction_count as usize;
+
+    ModuleInfo {
+        hash: module_hash(bytecode),
+        verified,
+        code_size_bytes: header.code_size_bytes,
+        instruction_count: header.instruction_count,
+    }
+}
+
+impl Module {
+    pub fn from_bytecode(bytecode: &[u8]) -> Result<Self, InterpreterErrorType> {
+        let (bytecode, signature) = split_signature(bytecode);
+        let (ops, info) = decode_ops(bytecode)?;
+        Self::from_parts(Bytecode::Shared(bytecode.into()), signature, ops, info)
+    }
+
+    //NOTE(joh): Neither the bytecode nor the memory image are copied, the image borrows its pages
+    //from `bytecode` until an instance writes to them. Meant for hosts that start many short-lived
+    //instances, e.g. of a program embedded with `include_bytes!`.
+    pub fn from_static(bytecode: &'static [u8]) -> Result<Self, InterpreterErrorType> {
+        let (bytecode, signature) = split_signature(bytecode);
+        let (ops, info) = decode_ops(bytecode)?;
+        Self::from_parts(Bytecode::Static(bytecode), signature, ops, info)
+    }
+
+    //NOTE(joh): Like `from_static` for bytecode with an owner, e.g. a memory mapped file. The module
+    //and its instances keep the owner alive, the bytes must not change until the last one is dropped.
+    pub fn from_mapped(bytes: MappedBytes) -> Result<Self, InterpreterErrorType> {
+        let (bytecode, signature) = split_signature(mapped_slice(&bytes));
+        let len = bytecode.len();
+        let (ops, info) = decode_ops(bytecode)?;
+        Self::from_parts(Bytecode::Mapped(bytes, len), signature, ops, info)
+    }
+
+    //NOTE(joh): Lays out the objects like `link` and loads the result. The same objects, e.g. a
+    //runtime library, can be linked into any number of programs this way without writing the
+    //linked bytecode anywhere. `entry` names an exported symbol to start at.
+    pub fn link(objects: &[Object], entry: Option<&str>) -> Result<Self, InterpreterErrorType> {
+        let bytecode = link_with_entry(objects, entry).map_err(InterpreterErrorType::Link)?;
+        Self::from_bytecode(&bytecode)
+    }
+
+    fn from_parts(
+        bytecode: Bytecode,
+        signature: Option<Signature>,
+        ops: DecodedOps,
+        info: ModuleInfo,
+    ) -> Result<Self, InterpreterErrorType> {
+        is_bytecode_header_valid(&bytecode)?;
+
+        let len = MIN_HEAP_SIZE + bytecode.len();
+        let image = match bytecode {
+            Bytecode::Shared(ref bytes) => Memory::from_image(&bytes[BYTECODE_HEADER.len()..], len),
+            Bytecode::Static(bytes) => Memory::from_static(&bytes[BYTECODE_HEADER.len()..], len),
+            Bytecode::Mapped(ref bytes, end) => Memory::from_mapped(bytes.clone(), BYTECODE_HEADER.len()..end, len),
+        };
+        let start_pc_addr = image
+            .read_le::<u32>(CODE_START_ADDR_POS as usize)
+            .ok_or(InterpreterErrorType::InvalidBytecodeHeader)?;
+        let const_pool = decode_const_pool(&bytecode)?;
+        let sections = Sections::read(&bytecode);
+        let functions = sections.get(FUNCTION_TABLE_MAGIC).and_then(decode_function_table);
+        let global_inits = sections.get(GLOBALS_MAGIC).and_then(decode_global_inits);
+        let host_imports = sections.get(HOST_IMPORTS_MAGIC).and_then(decode_host_imports);
+        let exports = sections.get(EXPORTS_MAGIC).and_then(decode_exports);
+        let symbols = sections.get(SYMBOL_TABLE_MAGIC).and_then(decode_symbol_table);
+
+        Ok(Self {
+            bytecode,
+            signature,
+            image,
+            start_pc_addr,
+            instructions: InstructionMap::from_ops(&ops).into(),
+            ops: ops.into(),
+            const_pool: const_pool.into(),
+            functions: functions.unwrap_or_default().into(),
+            global_inits: global_inits.unwrap_or_default().into(),
+            host_imports: host_imports.unwrap_or_default().into(),
+            exports: exports.unwrap_or_default().into(),
+            symbols: symbols.map(Arc::new),
+            info,
+        })
+    }
+
+    //NOTE(joh): Loads a bytecode file and reuses the decoded ops from the `.maluc` file next to it
+    //if its hash matches, otherwise the cache is (re)written.
+    #[cfg(feature = "std")]
+    pub fn load_cached(path: impl AsRef<Path>) -> Result<Self, InterpreterErrorType> {
+        let path = path.as_ref();
+        let file = fs::read(path)?;
+        let (bytecode, signature) = split_signature(&file);
+        let cache_path = cache_path(path);
+
+        if let Ok(cache) = fs::read(&cache_path)
+            && let Ok(Some((ops, info))) = read_cache(&cache, bytecode)
+        {
+            return Self::from_parts(Bytecode::Shared(bytecode.into()), signature, ops, info);
+        }
+
+        let module = Self::from_bytecode(&file)?;
+        //NOTE(joh): A cache that can't be written is not an error, it just won't be used
+        _ = module.write_cache(&cache_path);
+        Ok(module)
+    }
+
+    //NOTE(joh): The rest of `ModuleInfo` is derived from the bytecode and the ops again when the
+    //cache is read. The cache ends with the hash of everything before it.
+    #[cfg(feature = "std")]
+    pub fn write_cache(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut writer = Vec::new();
+        writer.extend_from_slice(&CACHE_MAGIC);
+        write_le_to(&mut writer, cache_version())?;
+        write_le_to(&mut writer, self.info.hash)?;
+
+        write_le_to(&mut writer, self.ops.len() as u32)?;
+        for (op, offset) in self.ops.iter() {
+            write_le_to(&mut writer, *offset)?;
+            let (kind, opcode, value): (u8, u8, u32) = match op {
+                MaybeRawOp::Unknown(byte) => (0, *byte, 0),
+                MaybeRawOp::Op(RawOp { opcode, arg: None }) => (1, *opcode, 0),
+                MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Register(r)) }) => (2, *opcode, *r as u32),
+                MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Num(n)) }) => (3, *opcode, *n),
+                MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Table { targets, .. }) }) => {
+                    (4, *opcode, targets.len() as u32)
+                }
+                MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Wide(n)) }) => (5, *opcode, *n as u32),
+            };
+            write_le_to(&mut writer, kind)?;
+            write_le_to(&mut writer, opcode)?;
+            write_le_to(&mut writer, value)?;
+            //NOTE(joh): Tables store their target count as value, followed by the targets and the default
+            if let MaybeRawOp::Op(RawOp { arg: Some(RawArg::Table { targets, default }), .. }) = op {
+                for target in targets.iter().chain(core::iter::once(default)) {
+                    write_le_to(&mut writer, *target)?;
+                }
+            }
+            //NOTE(joh): Wide values store the low half as value, followed by the high half
+            if let MaybeRawOp::Op(RawOp { arg: Some(RawArg::Wide(n)), .. }) = op {
+                write_le_to(&mut writer, (n >> 32) as u32)?;
+            }
+        }
+        let checksum = module_hash(&writer);
+        write_le_to(&mut writer, checksum)?;
+        fs::write(path, writer)
+    }
+
+    pub fn signature(&self) -> Option<&Signature> {
+        self.signature.as_ref()
+    }
+
+    pub fn is_signed_by(&self, keys: &[VerifyingKey]) -> bool {
+        self.signature
+            .as_ref()
+            .is_some_and(|signature| signing::is_signed_by(&self.bytecode, signature, keys))
+    }
+
+    pub fn ops(&self) -> &[(MaybeRawOp, u32)] {
+        &self.ops
+    }
+
+    pub fn instructions(&self) -> &InstructionMap {
+        &self.instructions
+    }
+
+    pub fn instructions_arc(&self) -> Arc<InstructionMap> {
+        self.instructions.clone()
+    }
+
+    pub fn const_pool(&self) -> &[u32] {
+        &self.const_pool
+    }
+
+    pub fn const_pool_arc(&self) -> Arc<[u32]> {
+        self.const_pool.clone()
+    }
+
+    //NOTE(joh): Empty if the bytecode has no function table
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    pub fn functions_arc(&self) -> Arc<[Function]> {
+        self.functions.clone()
+    }
+
+    //NOTE(joh): Initial values of the first globals, empty if the bytecode has no `.global`s
+    pub fn global_inits(&self) -> &[u32] {
+        &self.global_inits
+    }
+
+    //NOTE(joh): Host functions the program calls, empty if it has no `.host` declarations
+    pub fn host_imports(&self) -> &[HostImport] {
+        &self.host_imports
+    }
+
+    pub fn host_imports_arc(&self) -> Arc<[HostImport]> {
+        self.host_imports.clone()
+    }
+
+    //NOTE(joh): Names declared with `.export` and their addresses, e.g. for `Interpreter::call`
+    pub fn exports(&self) -> &[(String, u32)] {
+        &self.exports
+    }
+
+    pub fn export(&self, name: &str) -> Option<u32> {
+        self.exports.iter().find(|(n, _)| n == name).map(|(_, addr)| *addr)
+    }
+
+    //NOTE(joh): `None` if the bytecode was assembled without a symbol table
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbols.as_deref()
+    }
+
+    pub fn info(&self) -> &ModuleInfo {
+        &self.info
+    }
+
+    pub fn bytecode(&self) -> &[u8] {
+        &self.bytecode
+    }
+
+    pub fn shared_bytecode(&self) -> Bytecode {
+        self.bytecode.clone()
+    }
+
+    pub fn image(&self) -> &Memory {
+        &self.image
+    }
+
+    //NOTE(joh): The header and the code in the image
+    pub fn code_range(&self) -> Range<u32> {
+        0..DATA_START + self.info.code_size_bytes
+    }
+
+    pub fn start_pc_addr(&self) -> u32 {
+        self.start_pc_addr
+    }
+}
+
+//NOTE(joh): Size of an op in the cache without the extra words of tables and wide values
+#[cfg(feature = "std")]
+const CACHED_OP_SIZE: usize = 10;
+
+#[cfg(feature = "std")]
+fn read_cache(cache: &[u8], bytecode: &[u8]) -> Result<Option<(DecodedOps, ModuleInfo)>, std::io::Error> {
+    let Some((payload, checksum)) = cache.split_last_chunk::<8>() else {
+        return Ok(None);
+    };
+    if module_hash(payload) != u64::from_le_bytes(*checksum) {
+        return Ok(None);
+    }
+    let reader = &mut &payload[..];
+    let mut magic = [0; CACHE_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != CACHE_MAGIC || read_le_from::<u32>(reader)? != cache_version() {
+        return Ok(None);
+    }
+    if read_le_from::<u64>(reader)? != module_hash(bytecode) {
+        return Ok(None);
+    }
+    let Ok(header) = BytecodeInfo::decode(&mut Cursor::new(bytecode)) else {
+        return Ok(None);
+    };
+
+    let op_count = read_le_from::<u32>(reader)?;
+    let mut ops = Vec::with_capacity((op_count as usize).min(reader.len() / CACHED_OP_SIZE));
+    for _ in 0..op_count {
+        let offset = read_le_from::<u32>(reader)?;
+        let kind = read_le_from::<u8>(reader)?;
+        let opcode = read_le_from::<u8>(reader)?;
+        let value = read_le_from::<u32>(reader)?;
+        let op = match kind {
+            0 => MaybeRawOp::Unknown(opcode),
+            1 => MaybeRawOp::Op(RawOp { opcode, arg: None }),
+            2 => MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Register(value as u8)) }),
+            3 => MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Num(value)) }),
+            4 => {
+                let targets = (0..value).map(|_| read_le_from(reader)).collect::<Result<_, _>>()?;
+                let default = read_le_from(reader)?;
+                MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Table { targets, default }) })
+            }
+            5 => {
+                let high = read_le_from::<u32>(reader)?;
+                MaybeRawOp::Op(RawOp { opcode, arg: Some(RawArg::Wide((high as u64) << 32 | value as u64)) })
+            }
+            _ => return Err(std::io::Error::new(ErrorKind::InvalidData, "invalid op kind in cache")),
+        };
+        ops.push((op, offset));
+    }
+    let info = module_info(bytecode, &header, &ops);
+    Ok(Some((ops, info)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{self, opcode},
+        interpreter::Interpreter,
+        mem::PAGE_SIZE,
+        testing::NoSyscalls,
+    };
+
+    #[test]
+    fn instances_share_memory_until_written() {
+        let code = "
+            #0x8000; #42; store_32 0;
+            #0x8000; load_32_u 0;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let module = Module::from_bytecode(&bytecode.code).unwrap();
+
+        let mut a = Interpreter::instantiate(&module).unwrap();
+        let b = Interpreter::instantiate(&module).unwrap();
+        assert_eq!(a.memory.owned_pages(), 0);
+
+        assert_eq!(a.run(&mut NoSyscalls()).unwrap(), &[42]);
+        assert_eq!(a.memory.owned_pages(), 1);
+        assert_eq!(b.read_u32(0x8000).unwrap(), 0);
+        assert_eq!(b.memory.owned_pages(), 0);
+    }
+
+    #[test]
+    fn run_from_static_bytecode() {
+        let code = format!(
+            ".data table bytes {}; #@table; #42; store_32 0; #@table; load_32_u 0; end;",
+            "7 ".repeat(3 * PAGE_SIZE)
+        );
+        let bytecode: &'static [u8] = Box::leak(asm::Parser::parse(&code).unwrap().code);
+        let module = Module::from_static(bytecode).unwrap();
+        assert!(matches!(module.shared_bytecode(), Bytecode::Static(b) if b.as_ptr() == bytecode.as_ptr()));
+        //NOTE(joh): Only the last page of the image is copied
+        assert_eq!(module.image().owned_pages(), 1);
+        assert_eq!(module.image().to_vec(), Module::from_bytecode(bytecode).unwrap().image().to_vec());
+
+        let mut interpreter = Interpreter::instantiate(&module).unwrap();
+        assert_eq!(interpreter.memory.owned_pages(), 0);
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), &[42]);
+        assert_eq!(interpreter.memory.owned_pages(), 1);
+        let mut interpreter = Interpreter::from_static_bytecode(bytecode).unwrap();
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), &[42]);
+    }
+
+    #[test]
+    fn run_from_mapped_bytecode() {
+        let code = format!(
+            ".data table bytes {}; #@table; #42; store_32 0; #@table; load_32_u 0; end;",
+            "7 ".repeat(3 * PAGE_SIZE)
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap().code.into_vec();
+        let bytes: MappedBytes = Arc::new(bytecode.clone());
+        let module = Module::from_mapped(bytes.clone()).unwrap();
+        assert!(matches!(module.shared_bytecode(), Bytecode::Mapped(ref b, _) if Arc::ptr_eq(b, &bytes)));
+        assert_eq!(module.image().owned_pages(), 1);
+        assert_eq!(module.image().to_vec(), Module::from_bytecode(&bytecode).unwrap().image().to_vec());
+
+        let mut interpreter = Interpreter::instantiate(&module).unwrap();
+        drop(bytes);
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), &[42]);
+        assert_eq!(interpreter.memory.owned_pages(), 1);
+        let mut interpreter = Interpreter::from_mapped_bytecode(Arc::new(bytecode)).unwrap();
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), &[42]);
+    }
+
+    //NOTE(joh): Replaces the checksum at the end
+    fn reseal(cache: &mut Vec<u8>) {
+        cache.truncate(cache.len() - 8);
+        let checksum = module_hash(cache);
+        cache.extend_from_slice(&checksum.to_le_bytes());
+    }
+
+    #[test]
+    fn load_from_cache() {
+        let bytecode = asm::Parser::parse("#1; #2; add; local_set 0; end;").unwrap();
+        let dir = std::env::temp_dir().join(format!("maluvm_cache_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prog.mbc");
+        fs::write(&path, &bytecode.code).unwrap();
+
+        let module = Module::load_cached(&path).unwrap();
+        assert!(module.info().verified);
+        assert_eq!(module.ops().len(), 5);
+        assert!(cache_path(&path).exists());
+
+        let cached = Module::load_cached(&path).unwrap();
+        assert_eq!(cached.ops(), module.ops());
+        assert_eq!(cached.info(), module.info());
+
+        //NOTE(joh): A cache written by another decoder is decoded again and replaced
+        let mut cache = fs::read(cache_path(&path)).unwrap();
+        cache[CACHE_MAGIC.len()..][..4].copy_from_slice(&CACHE_FORMAT.to_le_bytes());
+        reseal(&mut cache);
+        assert!(read_cache(&cache, &bytecode.code).unwrap().is_none());
+        fs::write(cache_path(&path), &cache).unwrap();
+        assert_eq!(Module::load_cached(&path).unwrap().ops(), module.ops());
+        let cache = fs::read(cache_path(&path)).unwrap();
+        assert_eq!(cache[CACHE_MAGIC.len()..][..4], cache_version().to_le_bytes());
+
+        //NOTE(joh): Damaged caches are not used, ops that don't cover the code are not verified
+        let mut damaged = cache.clone();
+        damaged[CACHE_MAGIC.len() + 12 + 4] ^= 1;
+        assert!(read_cache(&damaged, &bytecode.code).unwrap().is_none());
+        let op_count = CACHE_MAGIC.len() + 4 + 8;
+        let mut huge = cache[..op_count].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge.extend_from_slice(&[0; 8]);
+        reseal(&mut huge);
+        assert!(read_cache(&huge, &bytecode.code).is_err());
+        let mut short = cache[..op_count].to_vec();
+        short.extend_from_slice(&1u32.to_le_bytes());
+        short.extend_from_slice(&cache[op_count + 4..][..CACHED_OP_SIZE]);
+        short.extend_from_slice(&[0; 8]);
+        reseal(&mut short);
+        let (ops, info) = read_cache(&short, &bytecode.code).unwrap().unwrap();
+        assert_eq!(ops.len(), 1);
+        assert!(!info.verified);
+
+        let bytecode = asm::Parser::parse("nop; end;").unwrap();
+        fs::write(&path, &bytecode.code).unwrap();
+        let changed = Module::load_cached(&path).unwrap();
+        assert_eq!(changed.ops().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_bytecode() {
+        let code = r#"#"abc"; #0x1000; store_32 4; end;"#;
+        let bytecode = asm::Parser::parse(code).unwrap();
+        assert!(Module::from_bytecode(&bytecode.code).is_ok());
+        for len in 0..bytecode.code.len() {
+            assert!(Module::from_bytecode(&bytecode.code[..len]).is_err(), "accepted {len} bytes");
+        }
+
+        let pooled = asm::Parser::parse_with_const_pool(code).unwrap();
+        let len = pooled.code.len();
+        assert!(Module::from_bytecode(&pooled.code[..len - 1]).is_err());
+
+        let mut reader = Cursor::new(&[opcode::Const, 1, 2][..]);
+        let result = crate::parse::try_parse_op(&mut reader);
+        assert!(matches!(result, Err(e) if e.kind() == ErrorKind::InvalidData));
+    }
+}
@@ -1,29 +1,80 @@
-use byteorder::{LittleEndian, ReadBytesExt};
-use core::fmt;
-use std::io::{ErrorKind, Read};
+use crate::{
+    asm::{opcode, BytecodeInfo, RawArg, RawOp, BYTECODE_HEADER},
+    io::{Cursor, ErrorKind, Read},
+    mem::read_le_from,
+    prelude::*,
+    section::Sections,
+};
 
-use crate::asm::{opcode, RawArg, RawOp};
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MaybeRawOp {
     Op(RawOp),
     Unknown(u8)
 }
-pub fn try_parse_ops_from_bytecode(reader: &mut impl Read) -> impl Iterator<Item = Result<MaybeRawOp, std::io::Error>> {
+pub fn try_parse_ops_from_bytecode(reader: &mut impl Read) -> impl Iterator<Item = Result<MaybeRawOp, crate::io::Error>> {
     (0..).map_while(|_| {
         match try_parse_op(reader) {
             Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
-            v @ _ => Some(v)
+            v => Some(v)
         }
     })   
 }
 
+impl BytecodeInfo {
+    pub fn decode(reader: &mut impl Read) -> Result<Self, crate::io::Error> {
+        let mut magic = [0; BYTECODE_HEADER.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != BYTECODE_HEADER {
+            return Err(crate::io::Error::new(ErrorKind::InvalidData, "invalid bytecode header"));
+        }
+
+        Ok(Self {
+            code_size_bytes: read_le_from(reader)?,
+            instruction_count: read_le_from(reader)?,
+            code_start_offset: read_le_from(reader)?,
+            data_section_size: read_le_from(reader)?,
+        })
+    }
+}
+
+//NOTE(joh): The constant pool is optional, bytecode without one has an empty pool
+pub fn decode_const_pool(bytecode: &[u8]) -> Result<Box<[u32]>, crate::io::Error> {
+    BytecodeInfo::decode(&mut Cursor::new(bytecode))?;
+    let mut pool = Sections::read(bytecode).const_pool();
+    if pool.is_empty() {
+        return Ok(Box::new([]));
+    }
+    let count: u32 = read_le_from(&mut pool)?;
+    (0..count).map(|_| read_le_from(&mut pool)).collect()
+}
+
+//NOTE(joh): Running out of bytes in the middle of an op means the code is truncated,
+//only an EOF before the opcode ends the op stream
+fn truncated(e: crate::io::Error) -> crate::io::Error {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => crate::io::Error::new(ErrorKind::InvalidData, "truncated immediate"),
+        _ => e,
+    }
+}
+
 impl RawArg {
-    pub fn decode_num(reader: &mut impl Read) -> Result<Self, std::io::Error> {
-        Ok(Self::Num(reader.read_u32::<LittleEndian>()?))
+    pub fn decode_num(reader: &mut impl Read) -> Result<Self, crate::io::Error> {
+        Ok(Self::Num(read_le_from(reader).map_err(truncated)?))
+    }
+    pub fn decode_register(reader: &mut impl Read) -> Result<Self, crate::io::Error> {
+        Ok(Self::Register(read_le_from(reader).map_err(truncated)?))
+    }
+    pub fn decode_wide(reader: &mut impl Read) -> Result<Self, crate::io::Error> {
+        Ok(Self::Wide(read_le_from(reader).map_err(truncated)?))
     }
-    pub fn decode_register(reader: &mut impl Read) -> Result<Self, std::io::Error> {
-        Ok(Self::Register(reader.read_u8()?))
+    pub fn decode_table(reader: &mut impl Read) -> Result<Self, crate::io::Error> {
+        let count: u32 = read_le_from(reader).map_err(truncated)?;
+        let targets = (0..count)
+            .map(|_| read_le_from(reader).map_err(truncated))
+            .collect::<Result<_, _>>()?;
+        let default = read_le_from(reader).map_err(truncated)?;
+        Ok(Self::Table { targets, default })
     }
 } 
 
@@ -37,15 +88,24 @@ macro_rules! make_op {
         let arg = RawArg::decode_register($code)?;
         Ok(MaybeRawOp::Op(RawOp {opcode: $op, arg: Some(arg)}))
     };
+    ($code: expr, $op: expr, Wide) => {
+        let arg = RawArg::decode_wide($code)?;
+        Ok(MaybeRawOp::Op(RawOp {opcode: $op, arg: Some(arg)}))
+    };
+    ($code: expr, $op: expr, Table) => {
+        let arg = RawArg::decode_table($code)?;
+        Ok(MaybeRawOp::Op(RawOp {opcode: $op, arg: Some(arg)}))
+    };
     ($op: expr) => {
         Ok(MaybeRawOp::Op(RawOp {opcode: $op, arg: None}))
     }
      
 }
-pub fn try_parse_op(reader: &mut impl Read) -> Result<MaybeRawOp, std::io::Error> {
-    let opcode = reader.read_u8()?;
+pub fn try_parse_op(reader: &mut impl Read) -> Result<MaybeRawOp, crate::io::Error> {
+    let opcode: u8 = read_le_from(reader)?;
     match opcode {
-          opcode::Nop 
+          opcode::DbgHalt
+        | opcode::Nop 
         | opcode::Unreachable
         | opcode::Drop 
         | opcode::Jmp
@@ -53,16 +113,129 @@ pub fn try_parse_op(reader: &mut impl Read) -> Result<MaybeRawOp, std::io::Error
         | opcode::Branch 
         | opcode::BranchIf
         | opcode::Eq..=opcode::Return
-        | opcode::End..=opcode::DbgAssert
+        | opcode::End..=opcode::Syscall
         => make_op!(opcode),
-        opcode::LocalGet..=opcode::GlobalTee => {
+        opcode::LocalGet..=opcode::GlobalTee
+        | opcode::ConstPool
+        | opcode::CallHost
+        | opcode::LoadMem8u..=opcode::Cmpxchg => {
             make_op! {reader, opcode, Register}
         },
         opcode::Const 
         | opcode::Store8..=opcode::Load32u => {
             make_op! {reader, opcode, Num}
         }
+        opcode::BrTable => {
+            make_op! {reader, opcode, Table}
+        }
+        opcode::Const64 => {
+            make_op! {reader, opcode, Wide}
+        }
+        opcode::Load64 | opcode::Store64 | opcode::AtomicLoad32..=opcode::AtomicCmpxchg => {
+            make_op! {reader, opcode, Num}
+        }
+        opcode::Add64..=opcode::Wrap64 => make_op!(opcode),
+        opcode::FConst => {
+            make_op! {reader, opcode, Num}
+        }
+        opcode::FAdd..=opcode::Yield | opcode::PushMem..=opcode::Bswap16 | opcode::MulWrap | opcode::MulWrap64 => {
+            make_op!(opcode)
+        }
         _ => Ok(MaybeRawOp::Unknown(opcode))
     }   
 
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::asm::Parser;
+
+    //NOTE(joh): The decoder decides which argument an opcode takes, the assembler has to accept
+    //exactly that argument and encode it to the same bytes
+    fn arg_kind(opcode: u8) -> Option<RawArg> {
+        let mut probe = vec![opcode];
+        probe.resize(16, 0);
+        match try_parse_op(&mut Cursor::new(probe)).unwrap() {
+            MaybeRawOp::Op(op) => op.arg,
+            MaybeRawOp::Unknown(opcode) => panic!("opcode 0x{opcode:02x} is not decodable"),
+        }
+    }
+
+    //NOTE(joh): Every opcode the decoder knows, the same range as `opcode::Names`
+    fn arb_op() -> impl Strategy<Value = RawOp> {
+        (0..opcode::Names.len() as u8).prop_flat_map(|opcode| {
+            let arg = match arg_kind(opcode) {
+                None => Just(None).boxed(),
+                Some(RawArg::Register(_)) => any::<u8>().prop_map(|r| Some(RawArg::Register(r))).boxed(),
+                Some(RawArg::Num(_)) if opcode == opcode::FConst => {
+                    proptest::num::f32::NORMAL
+                        .prop_map(|f| Some(RawArg::Num(f.to_bits())))
+                        .boxed()
+                }
+                Some(RawArg::Num(_)) => any::<u32>().prop_map(|n| Some(RawArg::Num(n))).boxed(),
+                Some(RawArg::Wide(_)) => any::<u64>().prop_map(|n| Some(RawArg::Wide(n))).boxed(),
+                Some(RawArg::Table { .. }) => (prop::collection::vec(any::<u32>(), 0..8), any::<u32>())
+                    .prop_map(|(targets, default)| {
+                        Some(RawArg::Table { targets: targets.into(), default })
+                    })
+                    .boxed(),
+            };
+            arg.prop_map(move |arg| RawOp { opcode, arg })
+        })
+    }
+
+    fn source(op: &RawOp) -> String {
+        //NOTE(joh): The label ends the `push_arg`s before it, so the arity of the import isn't checked
+        if op.opcode == opcode::CallHost
+            && let Some(RawArg::Register(r)) = op.arg
+        {
+            return format!(":0: call_host host_{r};");
+        }
+        let arg = match (op.opcode, &op.arg) {
+            (_, None) => String::new(),
+            (_, Some(RawArg::Register(r))) => format!(" {r}"),
+            (opcode::FConst, Some(RawArg::Num(n))) => format!(" {:?}", f32::from_bits(*n)),
+            (_, Some(RawArg::Num(n))) => format!(" {}", *n as i32),
+            (_, Some(RawArg::Wide(n))) => format!(" {}", *n as i64),
+            (_, Some(RawArg::Table { targets, default })) => targets
+                .iter()
+                .chain([default])
+                .map(|t| format!(" {}", *t as i32))
+                .collect(),
+        };
+        format!("{}{arg};", op.name())
+    }
+
+    proptest! {
+        #[test]
+        fn ops_round_trip(ops in prop::collection::vec(arb_op(), 1..64)) {
+            let mut expected = Vec::new();
+            ops.iter().for_each(|op| op.encode(&mut expected));
+
+            //NOTE(joh): `call_host` refers to the host functions by name
+            let hosts = (0..=u8::MAX).map(|i| format!(".host host_{i} 0;")).collect::<String>();
+            let src = hosts + &ops.iter().map(source).collect::<Vec<_>>().join("\n");
+            let bytecode = Parser::parse(&src).unwrap().code;
+            let info = BytecodeInfo::decode(&mut Cursor::new(&bytecode[..])).unwrap();
+            let code = &bytecode[BytecodeInfo::total_header_size()..][..info.code_size_bytes as usize];
+            prop_assert_eq!(code, &expected[..]);
+
+            let decoded = try_parse_ops_from_bytecode(&mut Cursor::new(code))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            prop_assert_eq!(&decoded, &ops.iter().cloned().map(MaybeRawOp::Op).collect::<Vec<_>>());
+
+            let mut encoded = Vec::new();
+            for op in &decoded {
+                let MaybeRawOp::Op(op) = op else { unreachable!() };
+                op.encode(&mut encoded);
+            }
+            prop_assert_eq!(encoded, expected);
+        }
+    }
+}
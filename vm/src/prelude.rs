@@ -0,0 +1,15 @@
+//NOTE(joh): What the std prelude has but `no_std` doesn't, every module that needs any of it
+//imports all of it. `HashMap` comes from hashbrown there, it has the same interface.
+pub(crate) use alloc::{
+    borrow::ToOwned,
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::HashMap;
+#[cfg(feature = "std")]
+pub(crate) use std::collections::HashMap;
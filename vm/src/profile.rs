@@ -0,0 +1,253 @@
+use core::time::Duration;
+
+#[cfg(feature = "std")]
+pub(crate) use crate::clock::Instant;
+use crate::{interpreter::Frame, prelude::*};
+
+//NOTE(joh): Without std there is no clock, functions are only counted
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instant;
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    pub(crate) fn now() -> Self {
+        Instant
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    //NOTE(joh): Includes the time spent in callees and syscalls. Recursive calls are only
+    //counted once, by the outermost one.
+    pub time: Duration,
+}
+
+//NOTE(joh): Collected while profiling is on, see `Interpreter::start_profiling`. Functions are
+//identified by the address they were called at.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    ops_by_addr: HashMap<u32, u64>,
+    ops_by_opcode: [u64; 256],
+    total_ops: u64,
+    functions: HashMap<u32, FunctionProfile>,
+    //NOTE(joh): Calls that have not returned yet, innermost last
+    open_calls: Vec<(u32, Instant)>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            ops_by_addr: HashMap::new(),
+            ops_by_opcode: [0; 256],
+            total_ops: 0,
+            functions: HashMap::new(),
+            open_calls: Vec::new(),
+        }
+    }
+}
+
+impl Profile {
+    pub(crate) fn record_op(&mut self, pc: u32, opcode: u8) {
+        *self.ops_by_addr.entry(pc).or_default() += 1;
+        self.ops_by_opcode[opcode as usize] += 1;
+        self.total_ops += 1;
+    }
+
+    pub(crate) fn enter(&mut self, addr: u32) {
+        self.functions.entry(addr).or_default().calls += 1;
+        self.open_calls.push((addr, Instant::now()));
+    }
+
+    //NOTE(joh): Returns from frames entered before profiling started are ignored
+    pub(crate) fn leave(&mut self) {
+        if let Some((addr, start)) = self.open_calls.pop()
+            && !self.open_calls.iter().any(|(a, _)| *a == addr)
+        {
+            self.functions.entry(addr).or_default().time += start.elapsed();
+        }
+    }
+
+    pub(crate) fn open_calls(&self) -> usize {
+        self.open_calls.len()
+    }
+
+    //NOTE(joh): Closes the calls dropped without returning, e.g. when `Interpreter::call` traps
+    pub(crate) fn unwind(&mut self, open_calls: usize) {
+        while self.open_calls.len() > open_calls {
+            self.leave();
+        }
+    }
+
+    pub fn total_ops(&self) -> u64 {
+        self.total_ops
+    }
+
+    pub fn ops_at(&self, addr: u32) -> u64 {
+        self.ops_by_addr.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn max_ops_at(&self) -> u64 {
+        self.ops_by_addr.values().copied().max().unwrap_or(0)
+    }
+
+    //NOTE(joh): Most executed first
+    pub fn hot_instructions(&self) -> Vec<(u32, u64)> {
+        let mut hot = self.ops_by_addr.iter().map(|(addr, n)| (*addr, *n)).collect::<Vec<_>>();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot
+    }
+
+    //NOTE(joh): Only opcodes that were executed, most executed first
+    pub fn opcodes(&self) -> Vec<(u8, u64)> {
+        let mut opcodes = (0..=u8::MAX)
+            .map(|op| (op, self.ops_by_opcode[op as usize]))
+            .filter(|(_, n)| *n > 0)
+            .collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        opcodes
+    }
+
+    //NOTE(joh): Slowest first
+    pub fn functions(&self) -> Vec<(u32, FunctionProfile)> {
+        let mut functions = self.functions.iter().map(|(addr, f)| (*addr, *f)).collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+        functions
+    }
+}
+
+//NOTE(joh): Names code addresses after the symbol in front of them, see
+//`Interpreter::code_symbols`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CodeSymbols {
+    //NOTE(joh): Sorted by address
+    symbols: Vec<(String, u32)>,
+}
+
+impl CodeSymbols {
+    pub fn new(symbols: impl IntoIterator<Item = (String, u32)>) -> Self {
+        let mut symbols = symbols.into_iter().collect::<Vec<_>>();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        Self { symbols }
+    }
+
+    pub fn resolve(&self, addr: u32) -> Option<&str> {
+        let index = self.symbols.partition_point(|(_, a)| *a <= addr).checked_sub(1)?;
+        Some(self.symbols[index].0.as_str())
+    }
+
+    //NOTE(joh): Addresses in front of every symbol are shown in hex
+    pub fn name(&self, addr: u32) -> String {
+        self.resolve(addr).map_or_else(|| format!("0x{addr:04x}"), str::to_string)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallEdge {
+    pub caller: String,
+    pub callee: String,
+    //NOTE(joh): Samples that had the call on the stack, recursion counts once per sample
+    pub samples: u64,
+}
+
+//NOTE(joh): Collected while sampling, see `Interpreter::start_sampling`. Every `interval` ops the
+//return stack is sampled, a stack is the call sites of the frames outermost first followed by the
+//pc. The addresses only get names when the samples are exported, so sampling stays cheap.
+#[derive(Debug, Clone)]
+pub struct StackSamples {
+    interval: u64,
+    until_sample: u64,
+    stacks: HashMap<Vec<u32>, u64>,
+    total: u64,
+}
+
+impl StackSamples {
+    pub fn new(interval: u64) -> Self {
+        let interval = interval.max(1);
+        Self {
+            interval,
+            until_sample: interval,
+            stacks: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    pub(crate) fn tick(&mut self, pc: u32, frames: &[Frame]) {
+        self.until_sample -= 1;
+        if self.until_sample > 0 {
+            return;
+        }
+        self.until_sample = self.interval;
+        //NOTE(joh): Like `Interpreter::backtrace`, the bottom frame has no call site
+        let stack = frames
+            .iter()
+            .skip(1)
+            .filter_map(|frame| frame.return_addr.checked_sub(1))
+            .chain(core::iter::once(pc))
+            .collect();
+        *self.stacks.entry(stack).or_default() += 1;
+        self.total += 1;
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.total
+    }
+
+    //NOTE(joh): The stacks with their names and how often they were sampled, most sampled first.
+    //Addresses in the same function end up as the same stack.
+    pub fn resolve(&self, symbols: &CodeSymbols) -> Vec<(Vec<String>, u64)> {
+        let mut resolved = HashMap::<Vec<String>, u64>::new();
+        for (stack, samples) in &self.stacks {
+            let names = stack.iter().map(|addr| symbols.name(*addr)).collect();
+            *resolved.entry(names).or_default() += samples;
+        }
+        let mut resolved = resolved.into_iter().collect::<Vec<_>>();
+        resolved.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        resolved
+    }
+
+    //NOTE(joh): One `outer;inner count` line per stack, the input format of flamegraph.pl and
+    //inferno
+    pub fn folded(&self, symbols: &CodeSymbols) -> String {
+        let mut out = String::new();
+        for (names, samples) in self.resolve(symbols) {
+            out.push_str(&names.join(";"));
+            out.push_str(&format!(" {samples}\n"));
+        }
+        out
+    }
+
+    //NOTE(joh): Who called whom in the samples, most sampled first
+    pub fn call_graph(&self, symbols: &CodeSymbols) -> Vec<CallEdge> {
+        let mut edges = HashMap::<(String, String), u64>::new();
+        for (names, samples) in self.resolve(symbols) {
+            let mut seen = Vec::new();
+            for pair in names.windows(2) {
+                let edge = (pair[0].clone(), pair[1].clone());
+                if !seen.contains(&edge) {
+                    *edges.entry(edge.clone()).or_default() += samples;
+                    seen.push(edge);
+                }
+            }
+        }
+        let mut edges = edges
+            .into_iter()
+            .map(|((caller, callee), samples)| CallEdge { caller, callee, samples })
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| {
+            b.samples
+                .cmp(&a.samples)
+                .then_with(|| (&a.caller, &a.callee).cmp(&(&b.caller, &b.callee)))
+        });
+        edges
+    }
+}
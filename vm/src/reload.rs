@@ -0,0 +1,352 @@
+use alloc::sync::Arc;
+use core::ops::Range;
+
+use crate::{
+    asm::{opcode, AssembleError, BytecodeInfo, Parser, RawArg, RawOp},
+    config::QuotaKind,
+    debug::{DebugInfo, LineEntry},
+    interpreter::{Interpreter, InterpreterErrorType},
+    prelude::*,
+};
+
+#[derive(Debug)]
+pub enum HotReloadError {
+    Assemble(AssembleError),
+    Interpreter(InterpreterErrorType),
+    UnknownFunction(String),
+    //NOTE(joh): The new code has to start with the function label and must not define other labels
+    LabelMismatch,
+    FunctionActive,
+    TooSmallToRedirect,
+}
+
+impl core::fmt::Display for HotReloadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HotReloadError::Assemble(e) => write!(f, "{e}"),
+            HotReloadError::Interpreter(e) => write!(f, "{e}"),
+            HotReloadError::UnknownFunction(name) => write!(f, "unknown function `{name}`"),
+            HotReloadError::LabelMismatch => write!(f, "the new code has to start with the function label and define no other labels"),
+            HotReloadError::FunctionActive => write!(f, "the function is active on the call stack"),
+            HotReloadError::TooSmallToRedirect => write!(f, "the function is too small to redirect"),
+        }
+    }
+}
+
+impl core::error::Error for HotReloadError {}
+
+impl From<AssembleError> for HotReloadError {
+    fn from(value: AssembleError) -> Self {
+        Self::Assemble(value)
+    }
+}
+
+impl From<InterpreterErrorType> for HotReloadError {
+    fn from(value: InterpreterErrorType) -> Self {
+        Self::Interpreter(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Patch {
+    InPlace { addr: u32 },
+    //NOTE(joh): The old address now jumps to the new code, so existing references stay valid
+    Relocated { from: u32, to: u32 },
+}
+
+//NOTE(joh): `#addr; jmp;`
+const REDIRECT_SIZE: u32 = 6;
+//NOTE(joh): Labels added by relocations, they only mark where the redirect of a relocated function
+//and where its copy end, so the functions around them keep their boundaries
+pub const RELOAD_LABEL_PREFIX: &str = "__reload";
+
+//NOTE(joh): A function is everything from its label up to the next label
+pub fn function_range(debug_info: &DebugInfo, name: &str) -> Option<(u32, u32)> {
+    let (_, start) = debug_info.labels.iter().find(|(n, _)| n == name)?;
+    let end = debug_info
+        .labels
+        .iter()
+        .map(|(_, addr)| *addr)
+        .filter(|addr| addr > start)
+        .min()
+        .unwrap_or(debug_info.code_end);
+    Some((*start, end))
+}
+
+//NOTE(joh): Whether a label marks a function or was added by a relocation
+pub fn is_function_label(name: &str) -> bool {
+    !name.starts_with(RELOAD_LABEL_PREFIX)
+}
+
+//NOTE(joh): The source of a function including its label, using the same boundaries as `function_range`
+pub fn function_source<'src>(source: &'src str, name: &str) -> Option<&'src str> {
+    let label = format!(":{name}:");
+    let start = source.find(&label)?;
+    let body_start = start + label.len();
+    let body = &source[body_start..];
+
+    let mut prev = ' ';
+    let end = body
+        .char_indices()
+        .find(|(_, c)| {
+            let is_label = *c == ':' && prev.is_whitespace();
+            prev = *c;
+            is_label
+        })
+        .map_or(body.len(), |(i, _)| i);
+    Some(&source[start..body_start + end])
+}
+
+struct Assembled {
+    payload: Box<[u8]>,
+    //NOTE(joh): Only the first `code_size` bytes of the payload are ops, literal data may follow them
+    code_size: usize,
+    lines: Vec<LineEntry>,
+}
+
+fn assemble(
+    source: &str,
+    name: &str,
+    origin: u32,
+    externals: &HashMap<String, u32>,
+) -> Result<Assembled, HotReloadError> {
+    let result = Parser::with_origin(origin, externals.clone()).assemble(source)?;
+    match &result.labels[..] {
+        [(label, 0)] if label == name => {}
+        _ => return Err(HotReloadError::LabelMismatch),
+    }
+    let info = BytecodeInfo::decode(&mut &result.code[..]).map_err(InterpreterErrorType::from)?;
+    Ok(Assembled {
+        payload: result.code[BytecodeInfo::total_header_size()..].into(),
+        code_size: info.code_size_bytes as usize,
+        lines: result.debug_info.lines,
+    })
+}
+
+fn write(interpreter: &mut Interpreter, addr: u32, bytes: &[u8]) -> Result<(), HotReloadError> {
+    interpreter
+        .memory
+        .write(addr as usize, bytes)
+        .ok_or(HotReloadError::Interpreter(InterpreterErrorType::AddrOutOfBounds(addr)))
+}
+
+fn mark_code(interpreter: &mut Interpreter, range: Range<u32>, code: &[u8]) {
+    Arc::make_mut(&mut interpreter.instructions).mark_code(range, code);
+}
+
+fn first_line(debug_info: &DebugInfo, range: &Range<u32>) -> Option<LineEntry> {
+    debug_info.lines.iter().find(|e| range.contains(&e.addr)).copied()
+}
+
+//NOTE(joh): The lines of the new code are relative to its source, they are moved so its first op
+//keeps the line and file of the old first op
+fn move_lines(lines: &mut [LineEntry], first: Option<LineEntry>) {
+    if let (Some(old), Some(new)) = (first, lines.first().copied()) {
+        for entry in lines {
+            entry.line = (entry.line + old.line).saturating_sub(new.line);
+            entry.file = old.file;
+        }
+    }
+}
+
+fn replace_lines(debug_info: &mut DebugInfo, range: Range<u32>, lines: Vec<LineEntry>) {
+    debug_info.lines.retain(|e| !range.contains(&e.addr));
+    let index = debug_info.lines.partition_point(|e| e.addr < range.start);
+    debug_info.lines.splice(index..index, lines);
+}
+
+fn write_in_place(
+    interpreter: &mut Interpreter,
+    debug_info: &mut DebugInfo,
+    start: u32,
+    end: u32,
+    assembled: Assembled,
+) -> Result<Patch, HotReloadError> {
+    let mut patch = assembled.payload.into_vec();
+    patch.resize((end - start) as usize, opcode::Nop);
+    write(interpreter, start, &patch)?;
+    mark_code(interpreter, start..end, &patch[..assembled.code_size]);
+    let mut lines = assembled.lines;
+    move_lines(&mut lines, first_line(debug_info, &(start..end)));
+    replace_lines(debug_info, start..end, lines);
+    Ok(Patch::InPlace { addr: start })
+}
+
+//NOTE(joh): Replaces the code of the function `name` in a running instance with `source` and
+//updates its labels and lines in `debug_info`. The function must not be on the call stack. If
+//the new code doesn't fit into the old space it is appended to memory, the old address jumps to
+//it and the label of the function moves along.
+pub fn reload_function(
+    interpreter: &mut Interpreter,
+    debug_info: &mut DebugInfo,
+    name: &str,
+    source: &str,
+) -> Result<Patch, HotReloadError> {
+    let (start, end) =
+        function_range(debug_info, name).ok_or_else(|| HotReloadError::UnknownFunction(name.to_string()))?;
+
+    let in_function = |addr: u32| (start..end).contains(&addr);
+    if in_function(interpreter.pc) || interpreter.return_stack.iter().any(|f| in_function(f.return_addr)) {
+        return Err(HotReloadError::FunctionActive);
+    }
+
+    let externals: HashMap<String, u32> = debug_info.labels.iter().chain(&debug_info.data_labels).cloned().collect();
+    let size = (end - start) as usize;
+
+    //NOTE(joh): Without literal data the code can fall through to the next function directly
+    let assembled = assemble(source, name, start, &externals)?;
+    if assembled.payload.len() == assembled.code_size && assembled.payload.len() <= size {
+        return write_in_place(interpreter, debug_info, start, end, Assembled { code_size: size, ..assembled });
+    }
+
+    let source = format!("{source}\n#{end}; jmp;");
+    let assembled = assemble(&source, name, start, &externals)?;
+    if assembled.payload.len() <= size {
+        return write_in_place(interpreter, debug_info, start, end, assembled);
+    }
+
+    if size < REDIRECT_SIZE as usize {
+        return Err(HotReloadError::TooSmallToRedirect);
+    }
+    let to = interpreter.memory.len() as u32;
+    let Assembled { payload, code_size, lines } = assemble(&source, name, to, &externals)?;
+    interpreter.check_quota(QuotaKind::Memory, (interpreter.memory.len() + payload.len()) as u64)?;
+    interpreter.memory.grow(payload.len());
+    write(interpreter, to, &payload)?;
+    mark_code(interpreter, to..to + code_size as u32, &payload[..code_size]);
+
+    let mut redirect = Vec::with_capacity(REDIRECT_SIZE as usize);
+    RawOp { opcode: opcode::Const, arg: Some(RawArg::Num(to)) }.encode(&mut redirect);
+    RawOp { opcode: opcode::Jmp, arg: None }.encode(&mut redirect);
+    write(interpreter, start, &redirect)?;
+    mark_code(interpreter, start..end, &redirect);
+
+    relocate_debug_info(debug_info, name, start..end, to, to + payload.len() as u32, lines);
+    Ok(Patch::Relocated { from: start, to })
+}
+
+//NOTE(joh): The label of the function moves to the copy. Labels mark the redirect, the end of the
+//copy and, the first time, the end of the original code, so no other function grows into them.
+fn relocate_debug_info(
+    debug_info: &mut DebugInfo,
+    name: &str,
+    old: Range<u32>,
+    to: u32,
+    copy_end: u32,
+    mut lines: Vec<LineEntry>,
+) {
+    let first = first_line(debug_info, &old);
+    move_lines(&mut lines, first);
+    let redirect = first.map(|first| LineEntry { addr: old.start, ..first });
+    replace_lines(debug_info, old.clone(), redirect.into_iter().collect());
+    replace_lines(debug_info, to..copy_end, lines);
+
+    let code_end = debug_info.code_end;
+    for (label, addr) in &mut debug_info.labels {
+        if label == name {
+            *addr = to;
+        }
+    }
+    for addr in [old.start, copy_end, code_end] {
+        if !debug_info.labels.iter().any(|(_, a)| *a == addr) {
+            debug_info.labels.push((format!("{RELOAD_LABEL_PREFIX}{addr}"), addr));
+        }
+    }
+    debug_info.labels.sort_by_key(|(_, addr)| *addr);
+    debug_info.code_end = debug_info.code_end.max(copy_end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, testing::NoSyscalls};
+
+    const CODE: &str = "
+        :fn:
+        #1; #2; add; return;
+        :__ENTRY__:
+        #@fn; call;
+        end;
+    ";
+
+    fn reload_and_run(source: &str) -> (Result<Patch, HotReloadError>, Option<Vec<u32>>) {
+        let mut bytecode = asm::Parser::parse(CODE).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let patch = reload_function(&mut interpreter, &mut bytecode.debug_info, "fn", source);
+        let result = interpreter.run(&mut NoSyscalls()).ok().map(|r| r.to_vec());
+        (patch, result)
+    }
+
+    #[test]
+    fn function_source_boundaries() {
+        assert_eq!(function_source(CODE, "fn").unwrap().trim(), ":fn:\n        #1; #2; add; return;");
+        assert_eq!(function_source("  :a: nop; :b: end;", "a"), Some(":a: nop; "));
+        assert_eq!(function_source(CODE, "missing"), None);
+    }
+
+    #[test]
+    fn reload_in_place() {
+        let (patch, result) = reload_and_run(":fn: #40; #2; add; return;");
+        assert!(matches!(patch, Ok(Patch::InPlace { .. })));
+        assert_eq!(result.unwrap(), [42]);
+    }
+
+    #[test]
+    fn reload_relocated() {
+        let (patch, result) = reload_and_run(r#":fn: #"abc"; load_32_u 0; #39; add; return;"#);
+        assert!(matches!(patch, Ok(Patch::Relocated { .. })));
+        assert_eq!(result.unwrap(), [42]);
+    }
+
+    #[test]
+    fn reject_incompatible() {
+        let (patch, _) = reload_and_run(":other: #1; return;");
+        assert!(matches!(patch, Err(HotReloadError::LabelMismatch)));
+
+        let mut bytecode = asm::Parser::parse(CODE).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.pc = asm::DATA_START + 2;
+        let patch = reload_function(&mut interpreter, &mut bytecode.debug_info, "fn", ":fn: #1; return;");
+        assert!(matches!(patch, Err(HotReloadError::FunctionActive)));
+    }
+
+    #[test]
+    fn relocation_updates_debug_info() {
+        let mut bytecode = asm::Parser::parse(CODE).unwrap();
+        let debug_info = &mut bytecode.debug_info;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let (start, end) = function_range(debug_info, "fn").unwrap();
+        let entry = function_range(debug_info, "__ENTRY__").unwrap();
+
+        let source = ":fn:\n#1; #2; #3; #4; #5; #6;\nadd; add; add; add; add; return;";
+        let Ok(Patch::Relocated { from, to }) = reload_function(&mut interpreter, debug_info, "fn", source) else {
+            panic!("not relocated");
+        };
+        assert_eq!(from, start);
+        let (moved, moved_end) = function_range(debug_info, "fn").unwrap();
+        assert_eq!(moved, to);
+        assert_eq!(function_range(debug_info, "__ENTRY__"), Some(entry));
+        assert!(debug_info.labels.is_sorted_by_key(|(_, addr)| *addr));
+        //NOTE(joh): The redirect keeps the line of the function, the copy gets the lines of the new code
+        assert_eq!(debug_info.resolve_line(start).unwrap().line, 3);
+        assert_eq!(debug_info.resolve_line(start + 2), debug_info.resolve_line(start));
+        assert_eq!(debug_info.resolve_line(to).unwrap().line, 3);
+        assert_eq!(debug_info.resolve_line(to + 30).unwrap().line, 4);
+        assert!(debug_info.lines.iter().all(|e| !(start + 1..end).contains(&e.addr)));
+        assert!(debug_info.lines.is_sorted_by_key(|e| e.addr));
+
+        //NOTE(joh): A frame inside the copy keeps the function from being reloaded again
+        interpreter.pc = to + 5;
+        let patch = reload_function(&mut interpreter, debug_info, "fn", ":fn: #1; return;");
+        assert!(matches!(patch, Err(HotReloadError::FunctionActive)));
+
+        //NOTE(joh): The copy has room for smaller code, nothing new is appended
+        interpreter.pc = entry.0;
+        let memory_len = interpreter.memory.len();
+        let patch = reload_function(&mut interpreter, debug_info, "fn", ":fn: #40; #2; add; return;");
+        assert!(matches!(patch, Ok(Patch::InPlace { addr }) if addr == moved));
+        assert_eq!(interpreter.memory.len(), memory_len);
+        assert_eq!(function_range(debug_info, "fn"), Some((moved, moved_end)));
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), [42]);
+    }
+}
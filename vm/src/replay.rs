@@ -0,0 +1,324 @@
+use crate::{
+    config::{QuotaKind, SyscallGroup},
+    interpreter::{Interpreter, SyscallHandler},
+    io::{Cursor, Read},
+    mem::{push_le, read_le_from},
+    prelude::*,
+};
+
+pub const REPLAY_MAGIC: [u8; 4] = *b"mrpl";
+
+//NOTE(joh): Everything a syscall did to the program: its result, the bytes it wrote into the
+//memory, the length of the memory afterwards, e.g. after `Alloc` grew it, and whether it yielded
+//to the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyscallRecord {
+    pub id: u32,
+    pub args: Vec<u32>,
+    pub result: u32,
+    pub writes: Vec<(u32, Vec<u8>)>,
+    pub memory_len: u32,
+    pub yielded: Option<u32>,
+}
+
+//NOTE(joh): Passes the syscalls on to `inner` and records what they did, see `encode_records`
+pub struct Recorder<H> {
+    pub inner: H,
+    pub records: Vec<SyscallRecord>,
+}
+
+impl<H: SyscallHandler> Recorder<H> {
+    pub fn new(inner: H) -> Self {
+        Self { inner, records: Vec::new() }
+    }
+}
+
+impl<H: SyscallHandler> SyscallHandler for Recorder<H> {
+    fn on_syscall(&mut self, interpreter: &mut Interpreter, syscall_id: u32, args: &[u32]) -> u32 {
+        //NOTE(joh): The pages stay shared with the clone until the syscall writes to them
+        let before = interpreter.memory.clone();
+        let yielded = interpreter.yielded;
+        let result = self.inner.on_syscall(interpreter, syscall_id, args);
+        let writes = interpreter
+            .memory
+            .changes_since(&before)
+            .into_iter()
+            .map(|(addr, bytes)| (addr as u32, bytes))
+            .collect();
+        self.records.push(SyscallRecord {
+            id: syscall_id,
+            args: args.to_vec(),
+            result,
+            writes,
+            memory_len: interpreter.memory.len() as u32,
+            yielded: interpreter.yielded.filter(|_| interpreter.yielded != yielded),
+        });
+        result
+    }
+
+    fn syscall_group(&self, syscall_id: u32) -> Option<SyscallGroup> {
+        self.inner.syscall_group(syscall_id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    //NOTE(joh): The program made a different syscall than the recorded one
+    Mismatch { index: usize, id: u32, args: Vec<u32> },
+    //NOTE(joh): The program made more syscalls than were recorded
+    Exhausted { index: usize },
+    //NOTE(joh): The recorded memory length or write does not fit the memory of the program
+    Grow { index: usize, len: u32 },
+    Write { index: usize, addr: u32 },
+}
+
+impl core::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReplayError::Mismatch { index, id, args } => {
+                write!(f, "syscall {index} ({id} {args:?}) does not match the recording")
+            }
+            ReplayError::Exhausted { index } => write!(f, "syscall {index} is past the end of the recording"),
+            ReplayError::Grow { index, len } => write!(f, "syscall {index} can not grow the memory to {len} bytes"),
+            ReplayError::Write { index, addr } => write!(f, "syscall {index} can not write to 0x{addr:04x}"),
+        }
+    }
+}
+
+impl core::error::Error for ReplayError {}
+
+//NOTE(joh): Answers the syscalls from a recording instead of calling the host, `inner` is only
+//asked for the syscall groups so the capabilities apply like in the recorded run. A program that
+//leaves the recording is stopped and `error` says where.
+pub struct Replayer<H> {
+    pub inner: H,
+    records: Vec<SyscallRecord>,
+    position: usize,
+    pub error: Option<ReplayError>,
+}
+
+impl<H: SyscallHandler> Replayer<H> {
+    pub fn new(inner: H, records: Vec<SyscallRecord>) -> Self {
+        Self {
+            inner,
+            records,
+            position: 0,
+            error: None,
+        }
+    }
+
+    //NOTE(joh): Syscalls that were not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.records.len() - self.position
+    }
+}
+
+impl<H: SyscallHandler> SyscallHandler for Replayer<H> {
+    fn on_syscall(&mut self, interpreter: &mut Interpreter, syscall_id: u32, args: &[u32]) -> u32 {
+        let index = self.position;
+        let record = match self.records.get(index) {
+            Some(record) if record.id == syscall_id && record.args == args => record,
+            Some(_) => {
+                self.error = Some(ReplayError::Mismatch { index, id: syscall_id, args: args.to_vec() });
+                interpreter.running = false;
+                return 0;
+            }
+            None => {
+                self.error = Some(ReplayError::Exhausted { index });
+                interpreter.running = false;
+                return 0;
+            }
+        };
+        self.position += 1;
+        let len = interpreter.memory.len();
+        if let Some(additional) = (record.memory_len as usize).checked_sub(len).filter(|n| *n > 0) {
+            if interpreter.check_quota(QuotaKind::Memory, record.memory_len as u64).is_err() {
+                self.error = Some(ReplayError::Grow { index, len: record.memory_len });
+                interpreter.running = false;
+                return 0;
+            }
+            interpreter.memory.grow(additional);
+        }
+        for (addr, bytes) in &record.writes {
+            if interpreter.write_bytes(*addr, bytes).is_err() {
+                self.error = Some(ReplayError::Write { index, addr: *addr });
+                interpreter.running = false;
+                return 0;
+            }
+        }
+        if let Some(value) = record.yielded {
+            interpreter.yield_to_host(value);
+        }
+        record.result
+    }
+
+    fn syscall_group(&self, syscall_id: u32) -> Option<SyscallGroup> {
+        self.inner.syscall_group(syscall_id)
+    }
+}
+
+//NOTE(joh): `REPLAY_MAGIC` and the record count, then per record the id, the args, the result,
+//the yielded value behind a flag byte, the writes and the memory length, everything little endian
+//with u32 lengths
+pub fn encode_records(records: &[SyscallRecord], buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&REPLAY_MAGIC);
+    push_le(buffer, records.len() as u32);
+    for record in records {
+        push_le(buffer, record.id);
+        push_le(buffer, record.args.len() as u32);
+        record.args.iter().for_each(|arg| push_le(buffer, *arg));
+        push_le(buffer, record.result);
+        match record.yielded {
+            Some(value) => {
+                buffer.push(1);
+                push_le(buffer, value);
+            }
+            None => buffer.push(0),
+        }
+        push_le(buffer, record.writes.len() as u32);
+        for (addr, bytes) in &record.writes {
+            push_le(buffer, *addr);
+            push_le(buffer, bytes.len() as u32);
+            buffer.extend_from_slice(bytes);
+        }
+        push_le(buffer, record.memory_len);
+    }
+}
+
+pub fn decode_records(bytes: &[u8]) -> Result<Vec<SyscallRecord>, crate::io::Error> {
+    let invalid = || crate::io::Error::from(crate::io::ErrorKind::InvalidData);
+    let mut reader = Cursor::new(bytes);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != REPLAY_MAGIC {
+        return Err(invalid());
+    }
+    //NOTE(joh): Lengths are checked against what is left so a broken file can not allocate much
+    let len = |reader: &mut Cursor<&[u8]>, size: usize| -> Result<usize, crate::io::Error> {
+        let len = read_le_from::<u32>(reader)? as usize;
+        let left = bytes.len() - reader.position() as usize;
+        match len.checked_mul(size).is_some_and(|n| n <= left) {
+            true => Ok(len),
+            false => Err(invalid()),
+        }
+    };
+    let count = len(&mut reader, 4)?;
+    let mut records = Vec::with_capacity(count);
+    for _ in 0..count {
+        let id = read_le_from(&mut reader)?;
+        let args = (0..len(&mut reader, 4)?)
+            .map(|_| read_le_from(&mut reader))
+            .collect::<Result<_, _>>()?;
+        let result = read_le_from(&mut reader)?;
+        let yielded = match read_le_from::<u8>(&mut reader)? {
+            0 => None,
+            1 => Some(read_le_from(&mut reader)?),
+            _ => return Err(invalid()),
+        };
+        let mut writes = Vec::new();
+        for _ in 0..len(&mut reader, 8)? {
+            let addr = read_le_from(&mut reader)?;
+            let mut bytes = vec![0; len(&mut reader, 1)?];
+            reader.read_exact(&mut bytes)?;
+            writes.push((addr, bytes));
+        }
+        let memory_len = read_le_from(&mut reader)?;
+        records.push(SyscallRecord { id, args, result, writes, memory_len, yielded });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm,
+        syscall::{Alloc, ClockMonotonic, Random, ReadLine, StdHandler, YieldFor},
+    };
+
+    struct Scripted;
+    impl crate::syscall::Console for Scripted {
+        fn write(&mut self, _: &str) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+            line.push_str("typed\n");
+            Ok(6)
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let code = format!(
+            "
+            .data buffer bytes 0 0 0 0 0 0 0 0;
+            #0; push_arg; #{Random}; syscall;
+            #{ClockMonotonic}; syscall; drop;
+            #@buffer; push_arg; #8; push_arg; #{ReadLine}; syscall;
+            #5; push_arg; #{YieldFor}; syscall; drop;
+            #@buffer; load_32_u 0;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        let mut recorder = Recorder::new(StdHandler::new(Scripted));
+        interpreter.run_until_yield(&mut recorder).unwrap();
+        let recorded = interpreter.run(&mut recorder).unwrap().to_vec();
+        assert_eq!(recorder.records.len(), 4);
+        assert_eq!(recorder.records[2].writes.len(), 1);
+        assert_eq!(recorder.records[3].yielded, Some(5));
+
+        let mut trace = Vec::new();
+        encode_records(&recorder.records, &mut trace);
+        let records = decode_records(&trace).unwrap();
+        assert_eq!(records, recorder.records);
+        assert!(decode_records(&trace[..trace.len() - 1]).is_err());
+
+        //NOTE(joh): Another seed and no input, all of it comes from the recording
+        let mut replayer = Replayer::new(StdHandler::new(String::new()).with_seed(1), records);
+        interpreter.reset_all(&bytecode).unwrap();
+        let replay = interpreter.run_until_yield(&mut replayer).unwrap();
+        assert_eq!(replay, crate::interpreter::RunState::Yielded(5));
+        assert_eq!(interpreter.run(&mut replayer).unwrap(), recorded);
+        assert_eq!((replayer.remaining(), replayer.error), (0, None));
+
+        let mut replayer = Replayer::new(StdHandler::new(String::new()), Vec::new());
+        interpreter.reset_all(&bytecode).unwrap();
+        interpreter.run(&mut replayer).unwrap();
+        assert_eq!(replayer.error, Some(ReplayError::Exhausted { index: 0 }));
+    }
+
+    #[test]
+    fn replay_memory_growth() {
+        let code = format!(
+            "
+            .local ptr;
+            #200000; push_arg; #{Alloc}; syscall; local_tee ptr;
+            #199996; add; #42; store_32 0;
+            local_get ptr; #199996; add; load_32_u 0;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        let mut recorder = Recorder::new(StdHandler::new(String::new()));
+        assert_eq!(interpreter.run(&mut recorder).unwrap(), [42]);
+        let len = interpreter.memory.len();
+        assert_eq!(recorder.records[0].memory_len as usize, len);
+
+        let mut replayer = Replayer::new(StdHandler::new(String::new()), recorder.records.clone());
+        interpreter.reset_all(&bytecode).unwrap();
+        assert_eq!(interpreter.run(&mut replayer).unwrap(), [42]);
+        assert_eq!((interpreter.memory.len(), replayer.error), (len, None));
+
+        //NOTE(joh): A recording that writes into the code is refused like the syscall would be
+        let mut records = recorder.records;
+        records[0].writes = vec![(asm::DATA_START, vec![0])];
+        let mut replayer = Replayer::new(StdHandler::new(String::new()), records);
+        interpreter.reset_all(&bytecode).unwrap();
+        interpreter.run(&mut replayer).unwrap();
+        assert_eq!(replayer.error, Some(ReplayError::Write { index: 0, addr: asm::DATA_START }));
+    }
+}
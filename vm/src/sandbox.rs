@@ -0,0 +1,174 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::syscall::SyscallError;
+
+//NOTE(joh): Flags of `FileOpen`, without `Write` or `Append` a file is opened for reading
+#[allow(non_upper_case_globals)]
+pub mod open_flags {
+    pub const Read: u32 = 1;
+    pub const Write: u32 = 2;
+    pub const Create: u32 = 4;
+    pub const Truncate: u32 = 8;
+    pub const Append: u32 = 16;
+}
+
+pub const MAX_OPEN_FILES: usize = 64;
+
+//NOTE(joh): Backs the file syscalls. Programs name files by paths relative to `root` and never
+//see anything outside of it, `..`, absolute paths and symlinks leading out are refused. The fd is
+//the index into `files`, closed slots are reused.
+#[derive(Debug)]
+pub struct Sandbox {
+    root: PathBuf,
+    files: Vec<Option<File>>,
+}
+
+impl Sandbox {
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+            files: Vec::new(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn resolve(&self, path: &str) -> Result<PathBuf, SyscallError> {
+        let path = Path::new(path);
+        let relative = path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        let name = path.file_name().ok_or(SyscallError::PermissionDenied)?;
+        if !relative {
+            return Err(SyscallError::PermissionDenied);
+        }
+        let full = self.root.join(path);
+        //NOTE(joh): A file that does not exist yet is checked by its directory. A dangling symlink
+        //does not resolve either, but opening it would create its target wherever it points.
+        let resolved = match full.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) if full.symlink_metadata().is_ok() => return Err(SyscallError::PermissionDenied),
+            Err(_) => full.parent().unwrap_or(&self.root).canonicalize()?.join(name),
+        };
+        match resolved.starts_with(&self.root) {
+            true => Ok(resolved),
+            false => Err(SyscallError::PermissionDenied),
+        }
+    }
+
+    fn file(&mut self, fd: u32) -> Result<&mut File, SyscallError> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::as_mut)
+            .ok_or(SyscallError::BadFd)
+    }
+
+    pub fn open(&mut self, path: &str, flags: u32) -> Result<u32, SyscallError> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_OPEN_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(SyscallError::TooManyFiles),
+        };
+        let path = self.resolve(path)?;
+        let has = |flag| flags & flag != 0;
+        let file = OpenOptions::new()
+            .read(has(open_flags::Read) || !(has(open_flags::Write) || has(open_flags::Append)))
+            .write(has(open_flags::Write))
+            .append(has(open_flags::Append))
+            .create(has(open_flags::Create))
+            .truncate(has(open_flags::Truncate))
+            .open(path)?;
+        self.files[fd] = Some(file);
+        Ok(fd as u32)
+    }
+
+    //NOTE(joh): Returns 0 at the end of the file
+    pub fn read(&mut self, fd: u32, buf: &mut [u8]) -> Result<usize, SyscallError> {
+        Ok(self.file(fd)?.read(buf)?)
+    }
+
+    pub fn write(&mut self, fd: u32, data: &[u8]) -> Result<usize, SyscallError> {
+        Ok(self.file(fd)?.write(data)?)
+    }
+
+    //NOTE(joh): whence is 0 for the start, 1 for the current position and 2 for the end
+    pub fn seek(&mut self, fd: u32, offset: i64, whence: u32) -> Result<u64, SyscallError> {
+        let from = match whence {
+            0 => SeekFrom::Start(offset.try_into().map_err(|_| SyscallError::InvalidArgument)?),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(SyscallError::InvalidArgument),
+        };
+        Ok(self.file(fd)?.seek(from)?)
+    }
+
+    pub fn close(&mut self, fd: u32) -> Result<(), SyscallError> {
+        self.file(fd)?;
+        self.files[fd as usize] = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, interpreter::Interpreter, syscall::*};
+
+    #[test]
+    fn sandboxed_files() {
+        let root = std::env::temp_dir().join(format!("maluvm-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let code = format!(
+            "
+            .string name \"notes.txt\";
+            .string text \"hello\";
+            .data buffer bytes 0 0 0 0 0 0 0 0;
+            .local fd;
+            #@name; push_arg; #@name.len; push_arg; #{}; push_arg; #{FileOpen}; syscall; local_set fd;
+            local_get fd; push_arg; #@text; push_arg; #@text.len; push_arg; #{FileWrite}; syscall;
+            local_get fd; push_arg; #1; push_arg; #0; push_arg; #{FileSeek}; syscall;
+            local_get fd; push_arg; #@buffer; push_arg; #8; push_arg; #{FileRead}; syscall;
+            local_get fd; push_arg; #{FileClose}; syscall;
+            local_get fd; push_arg; #{FileClose}; syscall;
+            end;
+            ",
+            open_flags::Read | open_flags::Write | open_flags::Create | open_flags::Truncate,
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = StdHandler::new(String::new()).with_sandbox(Sandbox::new(&root).unwrap());
+        let result = interpreter.run(&mut handler).unwrap();
+        let bad_fd = SyscallError::as_negated_code(Err(SyscallError::BadFd));
+        assert_eq!(result, &[5, 1, 4, 0, bad_fd]);
+        assert_eq!(std::fs::read_to_string(root.join("notes.txt")).unwrap(), "hello");
+
+        let sandbox = handler.sandbox.as_mut().unwrap();
+        for escape in ["../notes.txt", "/etc/passwd", "", "a/../../notes.txt"] {
+            assert_eq!(sandbox.open(escape, open_flags::Read), Err(SyscallError::PermissionDenied));
+        }
+        assert_eq!(sandbox.open("missing.txt", open_flags::Read), Err(SyscallError::NotFound));
+        #[cfg(unix)]
+        {
+            let outside = std::env::temp_dir().join(format!("maluvm-escape-{}", std::process::id()));
+            std::os::unix::fs::symlink(&outside, root.join("dangling")).unwrap();
+            let flags = open_flags::Write | open_flags::Create;
+            assert_eq!(sandbox.open("dangling", flags), Err(SyscallError::PermissionDenied));
+            assert!(!outside.exists());
+        }
+
+        let mut handler = StdHandler::new(String::new());
+        interpreter.reset_all(&bytecode.code).unwrap();
+        let denied = SyscallError::as_negated_code(Err(SyscallError::PermissionDenied));
+        assert_eq!(interpreter.run(&mut handler).unwrap()[0], denied);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    asm::BytecodeInfo,
+    debuginfo::DEBUG_INFO_MAGIC,
+    exports::EXPORTS_MAGIC,
+    function::FUNCTION_TABLE_MAGIC,
+    globals::GLOBALS_MAGIC,
+    host::HOST_IMPORTS_MAGIC,
+    io::Cursor,
+    mem::read_le_from,
+    prelude::*,
+    signing::{SIGNATURE_MAGIC, SIGNATURE_SECTION_SIZE},
+    symbols::SYMBOL_TABLE_MAGIC,
+};
+
+//NOTE(joh): The optional sections after the code, data and const pool, in the order they are
+//written. Each one ends in the size of its content and its magic, only the signature has a fixed
+//size and no size field.
+pub const SECTION_ORDER: [[u8; 4]; 7] = [
+    FUNCTION_TABLE_MAGIC,
+    GLOBALS_MAGIC,
+    HOST_IMPORTS_MAGIC,
+    EXPORTS_MAGIC,
+    SYMBOL_TABLE_MAGIC,
+    DEBUG_INFO_MAGIC,
+    SIGNATURE_MAGIC,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Section<'a> {
+    magic: [u8; 4],
+    //NOTE(joh): Offset of the section in the bytecode
+    start: usize,
+    content: &'a [u8],
+}
+
+//NOTE(joh): The sections are read from the end of the bytecode backwards and have to come in
+//`SECTION_ORDER`. Only the bytes after the data section can hold one, so data that happens to end
+//like a section is never taken for one. The bytes between the data and the first section are the
+//const pool, a section that would cut into it is not one either.
+#[derive(Debug, Clone)]
+pub struct Sections<'a> {
+    bytecode: &'a [u8],
+    data_end: usize,
+    //NOTE(joh): In the order they appear in the bytecode
+    sections: Vec<Section<'a>>,
+}
+
+impl<'a> Sections<'a> {
+    pub fn read(bytecode: &'a [u8]) -> Self {
+        let data_end = BytecodeInfo::decode(&mut Cursor::new(bytecode))
+            .map(|info| info.total_size())
+            .ok()
+            .filter(|end| *end <= bytecode.len());
+        let Some(data_end) = data_end else {
+            return Self { bytecode, data_end: bytecode.len(), sections: Vec::new() };
+        };
+
+        let mut sections = Vec::new();
+        let mut end = bytecode.len();
+        let mut allowed = SECTION_ORDER.len();
+        while let Some(section) = read_section(&bytecode[data_end..end], &SECTION_ORDER[..allowed]) {
+            let section = Section { start: data_end + section.start, ..section };
+            allowed = SECTION_ORDER.iter().position(|m| *m == section.magic).unwrap();
+            end = section.start;
+            sections.push(section);
+        }
+        sections.reverse();
+        let mut sections = Self { bytecode, data_end, sections };
+        while !sections.sections.is_empty() && !is_const_pool(sections.const_pool()) {
+            sections.sections.remove(0);
+        }
+        sections
+    }
+
+    pub fn get(&self, magic: [u8; 4]) -> Option<&'a [u8]> {
+        self.sections.iter().find(|s| s.magic == magic).map(|s| s.content)
+    }
+
+    //NOTE(joh): The bytecode in front of the section, without the sections that follow it. Without
+    //the section that is the bytecode in front of the first section that would follow it.
+    pub fn before(&self, magic: [u8; 4]) -> &'a [u8] {
+        let order = |magic| SECTION_ORDER.iter().position(|m| *m == magic);
+        let end = self
+            .sections
+            .iter()
+            .find(|s| order(s.magic) >= order(magic))
+            .map_or(self.bytecode.len(), |s| s.start);
+        &self.bytecode[..end]
+    }
+
+    pub fn const_pool(&self) -> &'a [u8] {
+        let end = self.sections.first().map_or(self.bytecode.len(), |s| s.start);
+        &self.bytecode[self.data_end..end]
+    }
+}
+
+//NOTE(joh): Returns the bytecode in front of the section and its decoded content. A missing or
+//malformed section decodes to `None`.
+pub fn split_section<'a, T>(
+    bytecode: &'a [u8],
+    magic: [u8; 4],
+    decode: impl FnOnce(&'a [u8]) -> Option<T>,
+) -> (&'a [u8], Option<T>) {
+    let sections = Sections::read(bytecode);
+    (sections.before(magic), sections.get(magic).and_then(decode))
+}
+
+//NOTE(joh): The last section in `tail` if its magic is one of `allowed`, `start` is relative to
+//`tail`
+fn read_section<'a>(tail: &'a [u8], allowed: &[[u8; 4]]) -> Option<Section<'a>> {
+    let (rest, magic) = tail.split_last_chunk::<4>()?;
+    if !allowed.contains(magic) {
+        return None;
+    }
+    let (trailer, size) = match *magic {
+        SIGNATURE_MAGIC => (SIGNATURE_MAGIC.len(), SIGNATURE_SECTION_SIZE - SIGNATURE_MAGIC.len()),
+        _ => (size_of::<u32>() + magic.len(), u32::from_le_bytes(*rest.last_chunk::<4>()?) as usize),
+    };
+    let end = tail.len().checked_sub(trailer)?;
+    let start = end.checked_sub(size)?;
+    let content = &tail[start..end];
+    Some(Section { magic: *magic, start, content })
+}
+
+//NOTE(joh): Either no pool at all or the entry count followed by exactly that many entries
+fn is_const_pool(mut pool: &[u8]) -> bool {
+    let len = pool.len();
+    match read_le_from::<u32>(&mut pool) {
+        Ok(count) => (count as usize).checked_mul(size_of::<u32>()) == Some(len - size_of::<u32>()),
+        Err(_) => len == 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::Parser,
+        function::split_function_table,
+        parse::decode_const_pool,
+        globals::split_global_inits,
+        signing::{sign_bytecode, split_signature, SigningKey},
+    };
+
+    #[test]
+    fn section_walker() {
+        let mut parser = Parser::with_const_pool();
+        parser.set_emit_symbols(true);
+        parser.set_emit_debug_info(true);
+        let code = "
+            .global counter = 40;
+            #0x12345678; drop;
+            global_get counter; #@f; call; end;
+            .func f 0; return;
+        ";
+        let bytecode = sign_bytecode(&parser.assemble(code).unwrap().code, &SigningKey::from_bytes(&[7; 32]));
+        let sections = Sections::read(&bytecode);
+        let magics = sections.sections.iter().map(|s| s.magic).collect::<Vec<_>>();
+        assert_eq!(
+            magics,
+            [FUNCTION_TABLE_MAGIC, GLOBALS_MAGIC, SYMBOL_TABLE_MAGIC, DEBUG_INFO_MAGIC, SIGNATURE_MAGIC]
+        );
+        assert_eq!(decode_const_pool(&bytecode).unwrap()[0], 0x12345678);
+        assert_eq!(sections.const_pool().len(), 3 * size_of::<u32>());
+        assert_eq!(split_global_inits(&bytecode).1.as_deref(), Some(&[40][..]));
+        assert_eq!(sections.before(HOST_IMPORTS_MAGIC), sections.before(SYMBOL_TABLE_MAGIC));
+        assert!(split_signature(&bytecode).1.is_some());
+
+        //NOTE(joh): Data that ends like a section is still data
+        let fake = "end; .data d bytes 1 0 0 0 1 2 0 0 0 0 10 0 0 0 109 102 117 110;";
+        let bytecode = Parser::parse(fake).unwrap().code;
+        assert_eq!(split_function_table(&bytecode), (&bytecode[..], None));
+    }
+}
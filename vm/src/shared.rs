@@ -0,0 +1,70 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::prelude::*;
+
+//NOTE(joh): Memory that several instances access at the same time, also from other threads, see
+//`Interpreter::add_shared_memory` and `Spawner`. Unlike `Memory` it is not copied on write, all
+//clones see the same words. Every access is a sequentially consistent atomic on an aligned u32,
+//so the program never sees a torn value. Snapshots and stepping back leave it alone.
+#[derive(Debug, Clone)]
+pub struct SharedMemory {
+    words: Arc<[AtomicU32]>,
+}
+
+impl SharedMemory {
+    //NOTE(joh): `len` is rounded up to whole words
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: (0..len.div_ceil(4)).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len() * 4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    //NOTE(joh): `None` if the address is out of bounds or not a multiple of 4
+    fn word(&self, addr: u32) -> Option<&AtomicU32> {
+        match addr % 4 {
+            0 => self.words.get(addr as usize / 4),
+            _ => None,
+        }
+    }
+
+    pub fn load(&self, addr: u32) -> Option<u32> {
+        Some(self.word(addr)?.load(Ordering::SeqCst))
+    }
+
+    pub fn store(&self, addr: u32, value: u32) -> Option<()> {
+        self.word(addr)?.store(value, Ordering::SeqCst);
+        Some(())
+    }
+
+    //NOTE(joh): Returns the old value, the sum wraps
+    pub fn fetch_add(&self, addr: u32, value: u32) -> Option<u32> {
+        Some(self.word(addr)?.fetch_add(value, Ordering::SeqCst))
+    }
+
+    //NOTE(joh): Stores `new` if the word is `expected`. Returns the old value either way, it is
+    //`expected` if the store happened.
+    pub fn compare_exchange(&self, addr: u32, expected: u32, new: u32) -> Option<u32> {
+        let result = self
+            .word(addr)?
+            .compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst);
+        Some(result.unwrap_or_else(|old| old))
+    }
+
+    //NOTE(joh): The words at the time they are read, others may change them meanwhile
+    pub fn to_vec(&self) -> Vec<u32> {
+        self.words.iter().map(|word| word.load(Ordering::SeqCst)).collect()
+    }
+
+    pub fn ptr_eq(&self, other: &SharedMemory) -> bool {
+        Arc::ptr_eq(&self.words, &other.words)
+    }
+}
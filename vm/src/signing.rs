@@ -0,0 +1,85 @@
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+use ed25519_dalek::{Signer, Verifier, SIGNATURE_LENGTH};
+
+use crate::{prelude::*, section::split_section};
+
+pub const SIGNATURE_MAGIC: [u8; 4] = *b"msig";
+pub const SIGNATURE_SECTION_SIZE: usize = SIGNATURE_LENGTH + SIGNATURE_MAGIC.len();
+
+//NOTE(joh): The signature section is appended to the end of the bytecode and covers
+//everything before it: the signature followed by `SIGNATURE_MAGIC`
+pub fn split_signature(bytecode: &[u8]) -> (&[u8], Option<Signature>) {
+    split_section(bytecode, SIGNATURE_MAGIC, |signature| Signature::from_slice(signature).ok())
+}
+
+//NOTE(joh): Replaces an existing signature
+pub fn sign_bytecode(bytecode: &[u8], key: &SigningKey) -> Vec<u8> {
+    let (content, _) = split_signature(bytecode);
+    let signature = key.sign(content);
+
+    let mut signed = Vec::with_capacity(content.len() + SIGNATURE_SECTION_SIZE);
+    signed.extend_from_slice(content);
+    signed.extend_from_slice(&signature.to_bytes());
+    signed.extend_from_slice(&SIGNATURE_MAGIC);
+    signed
+}
+
+pub fn is_signed_by(content: &[u8], signature: &Signature, keys: &[VerifyingKey]) -> bool {
+    keys.iter().any(|key| key.verify(content, signature).is_ok())
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum SignaturePolicy {
+    //NOTE(joh): Signatures are not checked at all
+    #[default]
+    AllowUnsigned,
+    RequireSignedBy(Vec<VerifyingKey>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{self, BytecodeInfo},
+        config::InterpreterConfig,
+        interpreter::{Interpreter, InterpreterErrorType},
+        module::Module,
+        testing::NoSyscalls,
+    };
+
+    #[test]
+    fn only_trusted_modules_are_instantiated() {
+        let bytecode = asm::Parser::parse("#1; #2; add; end;").unwrap();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other_key = SigningKey::from_bytes(&[8; 32]);
+        let signed = sign_bytecode(&bytecode.code, &key);
+
+        let (content, signature) = split_signature(&signed);
+        assert_eq!(content, &bytecode.code[..]);
+        assert!(signature.is_some());
+        assert_eq!(sign_bytecode(&signed, &key), signed);
+
+        let config = InterpreterConfig {
+            signature_policy: SignaturePolicy::RequireSignedBy(vec![key.verifying_key()]),
+            ..Default::default()
+        };
+        let instantiate = |bytecode: &[u8]| {
+            let module = Module::from_bytecode(bytecode).unwrap();
+            Interpreter::instantiate_with_config(&module, config.clone())
+        };
+
+        assert!(instantiate(&signed).is_ok());
+        assert!(matches!(instantiate(&bytecode.code), Err(InterpreterErrorType::UntrustedModule)));
+        let foreign = sign_bytecode(&bytecode.code, &other_key);
+        assert!(matches!(instantiate(&foreign), Err(InterpreterErrorType::UntrustedModule)));
+
+        let mut tampered = signed.clone();
+        tampered[BytecodeInfo::total_header_size() + 1] ^= 1;
+        assert!(matches!(instantiate(&tampered), Err(InterpreterErrorType::UntrustedModule)));
+
+        let module = Module::from_bytecode(&signed).unwrap();
+        let mut interpreter = Interpreter::instantiate(&module).unwrap();
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), &[3]);
+    }
+}
@@ -0,0 +1,146 @@
+use alloc::sync::Arc;
+
+use smallvec::SmallVec;
+
+use crate::{
+    interpreter::{Frame, Interpreter, MAX_ARGS},
+    mem::Memory,
+    module::InstructionMap,
+    prelude::*,
+};
+
+//NOTE(joh): The complete execution state of an instance. It does not contain the module, a
+//snapshot can only be restored into an instance of the same program. Taking one is cheap,
+//memory pages are shared until either side writes to them.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VmSnapshot {
+    pub memory: Memory,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub memories: Vec<Memory>,
+    pub value_stack: Vec<u32>,
+    pub return_stack: Vec<Frame>,
+    pub globals: Box<[u32]>,
+    pub pc: u32,
+    pub args: SmallVec<[u32; MAX_ARGS]>,
+    pub running: bool,
+    pub assertion_failed: bool,
+    pub executed_ops: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub gas_used: u64,
+    pub syscall_count: u64,
+    pub output_bytes: u64,
+    //NOTE(joh): Hot reloading changes where ops start
+    pub instructions: Arc<InstructionMap>,
+}
+
+impl Interpreter {
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            memory: self.memory.clone(),
+            memories: self.memories.clone(),
+            value_stack: self.value_stack.clone(),
+            return_stack: self.return_stack.clone(),
+            globals: self.globals.clone(),
+            pc: self.pc,
+            args: self.args.clone(),
+            running: self.running,
+            assertion_failed: self.assertion_failed,
+            executed_ops: self.executed_ops,
+            gas_used: self.gas_used,
+            syscall_count: self.syscall_count,
+            output_bytes: self.output_bytes,
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    //NOTE(joh): The recorded history belongs to the replaced state and is dropped
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.memory = snapshot.memory.clone();
+        self.memories = snapshot.memories.clone();
+        snapshot.value_stack.clone_into(&mut self.value_stack);
+        snapshot.return_stack.clone_into(&mut self.return_stack);
+        self.globals = snapshot.globals.clone();
+        self.pc = snapshot.pc;
+        self.args = snapshot.args.clone();
+        self.running = snapshot.running;
+        self.assertion_failed = snapshot.assertion_failed;
+        self.executed_ops = snapshot.executed_ops;
+        self.gas_used = snapshot.gas_used;
+        self.syscall_count = snapshot.syscall_count;
+        self.output_bytes = snapshot.output_bytes;
+        self.instructions = snapshot.instructions.clone();
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm,
+        interpreter::Interpreter,
+        testing::NoSyscalls,
+    };
+
+    #[test]
+    fn snapshot_and_restore() {
+        let code = "
+            .data counter words 0;
+            :__ENTRY__:
+            #1; global_set 0;
+            :loop:
+            #@counter; #@counter; load_32_u 0; #1; add; store_32 0;
+            global_get 0; #2; mul; global_tee 0;
+            #64; lt; #@loop; jmp_if;
+            #@counter; load_32_u 0; global_get 0;
+            end;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.running = true;
+        for _ in 0..20 {
+            interpreter.exec_next_op(&mut NoSyscalls()).unwrap();
+        }
+        let snapshot = interpreter.snapshot();
+
+        let expected = interpreter.run(&mut NoSyscalls()).unwrap().to_vec();
+        assert_eq!(expected, [6, 64]);
+        let executed_ops = interpreter.executed_ops;
+
+        interpreter.restore(&snapshot);
+        assert_eq!(interpreter.pc, snapshot.pc);
+        assert_eq!(interpreter.executed_ops, 20);
+        assert_eq!(interpreter.run(&mut NoSyscalls()).unwrap(), expected);
+        assert_eq!(interpreter.executed_ops, executed_ops);
+
+        let mut other = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        other.restore(&snapshot);
+        assert_eq!(other.run(&mut NoSyscalls()).unwrap(), expected);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_snapshot() {
+        let code = "#@f; call; end; :f: #3; local_set 1; local_get 1; return;";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let json = serde_json::to_string(&bytecode).unwrap();
+        let parsed: asm::ParseResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.code, bytecode.code);
+        assert_eq!(parsed.debug_info, bytecode.debug_info);
+
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.running = true;
+        while interpreter.return_stack.len() < 2 || interpreter.current_frame().locals[1] == 0 {
+            interpreter.exec_next_op(&mut NoSyscalls()).unwrap();
+        }
+        let json = serde_json::to_string(&interpreter.snapshot()).unwrap();
+        let snapshot: super::VmSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.memory.to_vec(), interpreter.memory.to_vec());
+
+        let mut restored = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        restored.restore(&snapshot);
+        assert_eq!(restored.run(&mut NoSyscalls()).unwrap(), &[3]);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{
+    mem::{push_le, read_le_from},
+    prelude::*,
+    section::split_section,
+};
+
+pub const SYMBOL_TABLE_MAGIC: [u8; 4] = *b"msym";
+
+//NOTE(joh): Label names of a program, addresses are absolute like in `DebugInfo`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SymbolTable {
+    pub labels: Vec<(String, u32)>,
+    pub data_labels: Vec<(String, u32)>,
+}
+
+pub(crate) fn push_symbols(buffer: &mut Vec<u8>, symbols: &[(String, u32)]) {
+    push_le(buffer, symbols.len() as u32);
+    for (name, addr) in symbols {
+        push_le(buffer, *addr);
+        push_le(buffer, name.len() as u32);
+        buffer.extend_from_slice(name.as_bytes());
+    }
+}
+
+//NOTE(joh): The table is optional and sits between the exports and the debug info section. It
+//holds the code labels and the data labels, each prefixed with their count, followed by the size
+//of the content and `SYMBOL_TABLE_MAGIC`.
+pub fn encode_symbol_table(symbols: &SymbolTable, buffer: &mut Vec<u8>) {
+    let start = buffer.len();
+    push_symbols(buffer, &symbols.labels);
+    push_symbols(buffer, &symbols.data_labels);
+    let size = (buffer.len() - start) as u32;
+    push_le(buffer, size);
+    buffer.extend_from_slice(&SYMBOL_TABLE_MAGIC);
+}
+
+pub fn split_symbol_table(bytecode: &[u8]) -> (&[u8], Option<SymbolTable>) {
+    split_section(bytecode, SYMBOL_TABLE_MAGIC, decode_symbol_table)
+}
+
+pub(crate) fn decode_symbol_table(mut table: &[u8]) -> Option<SymbolTable> {
+    let labels = decode_symbols(&mut table).ok()?;
+    let data_labels = decode_symbols(&mut table).ok()?;
+    table.is_empty().then_some(SymbolTable { labels, data_labels })
+}
+
+pub(crate) fn decode_symbols(reader: &mut &[u8]) -> Result<Vec<(String, u32)>, crate::io::Error> {
+    let count: u32 = read_le_from(reader)?;
+    let mut symbols = Vec::new();
+    for _ in 0..count {
+        let addr = read_le_from(reader)?;
+        let len: u32 = read_le_from(reader)?;
+        let name = reader
+            .split_off(..len as usize)
+            .ok_or(crate::io::ErrorKind::UnexpectedEof)?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| crate::io::ErrorKind::InvalidData)?;
+        symbols.push((name, addr));
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, debuginfo::split_debug_info, disasm::disassemble, interpreter::Interpreter, syscall::StdHandler};
+
+    #[test]
+    fn embedded_symbols() {
+        let code = "
+            .string greeting \"hi\";
+            :__ENTRY__:
+            #20; push_arg; #@triple; call;
+            end;
+            :triple:
+            local_get 0; #3; mul;
+            :done:
+            return;
+        ";
+        let mut parser = asm::Parser::new();
+        parser.set_emit_symbols(true);
+        parser.set_emit_debug_info(true);
+        let result = parser.assemble(code).unwrap();
+
+        let (rest, symbols) = split_symbol_table(&result.code);
+        let symbols = symbols.unwrap();
+        assert_eq!(symbols.labels, result.debug_info.labels);
+        assert_eq!(symbols.data_labels, result.debug_info.data_labels);
+        assert!(split_debug_info(&result.code).1.is_some());
+        assert_eq!(rest, &asm::Parser::parse(code).unwrap().code[..]);
+
+        let mut interpreter = Interpreter::from_bytecode(&result.code).unwrap();
+        assert_eq!(interpreter.call("triple", &[5], &mut StdHandler::new(String::new())).unwrap(), [15]);
+
+        let source = disassemble(&result.code).unwrap();
+        assert!(source.contains(":triple:"));
+        assert!(source.contains(":done:"));
+        assert!(source.contains(".data greeting bytes 104 105;"));
+        assert_eq!(asm::Parser::parse(&source).unwrap().code, asm::Parser::parse(code).unwrap().code);
+    }
+}
@@ -0,0 +1,610 @@
+#![allow(non_upper_case_globals)]
+
+#[cfg(feature = "std")]
+use std::io::{BufRead, Write};
+
+use crate::{heap::HeapError, interpreter::InterpreterErrorType};
+#[cfg(feature = "std")]
+use crate::{
+    clock::{Clock, ClockMode, SystemTime, UNIX_EPOCH},
+    config::SyscallGroup,
+    framebuffer::Framebuffer,
+    interpreter::{Interpreter, SyscallHandler},
+    sandbox::Sandbox,
+};
+
+//NOTE(joh): The ids are part of the bytecode ABI, never renumber them. Arguments are passed
+//with `push_arg`, the result is pushed onto the value stack.
+
+//NOTE(joh): args: addr, len. Returns 0 or a `SyscallError`
+pub const PrintDebugString: u32 = 0x00;
+//NOTE(joh): Milliseconds since the program started
+pub const ClockMonotonic: u32 = 0x01;
+//NOTE(joh): Seconds since the unix epoch
+pub const ClockWallTime: u32 = 0x02;
+//NOTE(joh): args: addr, max len. Returns the number of bytes read, 0 on EOF. The rest of a line
+//longer than max len is returned by the next call.
+pub const ReadLine: u32 = 0x03;
+//NOTE(joh): args: bound. Returns a random number below bound, or any u32 if bound is 0
+pub const Random: u32 = 0x04;
+//NOTE(joh): Size of the linear memory in bytes
+pub const MemorySize: u32 = 0x05;
+//NOTE(joh): args: millis. Blocks for the duration, a virtual clock just advances. Returns 0.
+pub const Sleep: u32 = 0x06;
+//NOTE(joh): args: millis. Suspends the program like `yield` with the duration as the value, the
+//host decides when to continue. Returns 0 once it does.
+pub const YieldFor: u32 = 0x07;
+//NOTE(joh): args: key code from `key`. Returns 1 while the key is held down, else 0
+pub const KeyDown: u32 = 0x08;
+//NOTE(joh): Returns the next typed character as a unicode scalar value, 0 if there is none. Input
+//that comes in lines, like stdin, is handed out one character at a time and blocks for the next
+//line.
+pub const ReadChar: u32 = 0x09;
+//NOTE(joh): args: addr, width, height. Makes the memory at addr the framebuffer the host shows, see
+//`Framebuffer`. A width or height of 0 removes it. Returns 0 or a `SyscallError`.
+pub const SetFramebuffer: u32 = 0x0a;
+//NOTE(joh): args: src addr, x, y, width, height. Copies the pixels at src into the framebuffer,
+//x and y are signed. Returns 0 or a `SyscallError`.
+pub const Blit: u32 = 0x0b;
+
+//NOTE(joh): The file syscalls work on the `Sandbox` of the handler and are refused without one.
+//They return the negated `SyscallError` on failure, see `SyscallError::as_negated_code`.
+
+//NOTE(joh): args: path addr, path len, flags from `open_flags`. Returns the fd
+pub const FileOpen: u32 = 0x0c;
+//NOTE(joh): args: fd, addr, max len. Returns the number of bytes read, 0 at the end of the file
+pub const FileRead: u32 = 0x0d;
+//NOTE(joh): args: fd, addr, len. Returns the number of bytes written
+pub const FileWrite: u32 = 0x0e;
+//NOTE(joh): args: fd, signed offset, whence (0 start, 1 current, 2 end). Returns the new position
+pub const FileSeek: u32 = 0x0f;
+//NOTE(joh): args: fd. Returns 0
+pub const FileClose: u32 = 0x10;
+//NOTE(joh): args: size. Returns the address of that many zeroed bytes on the heap behind the
+//program, aligned to 8, or 0 if there is no memory left. See `Heap`.
+pub const Alloc: u32 = 0x11;
+//NOTE(joh): args: addr returned by `Alloc`. Returns 0 or a `SyscallError`
+pub const Free: u32 = 0x12;
+//NOTE(joh): args: function addr, arg. Calls the function with the arg on a worker thread, see
+//`Spawner`. Returns the worker id, 0 if the host runs no workers or the addr is no op.
+pub const Spawn: u32 = 0x13;
+//NOTE(joh): args: worker id. Blocks until the worker is done. Returns the first value it returned,
+//0 if it trapped, returned nothing or was already joined.
+pub const Join: u32 = 0x14;
+
+//NOTE(joh): Key codes of `KeyDown`. Letters, digits and the keys with a control character use its
+//ASCII value, letters in lower case. Also part of the ABI.
+pub mod key {
+    pub const Backspace: u32 = 0x08;
+    pub const Tab: u32 = 0x09;
+    pub const Enter: u32 = 0x0a;
+    pub const Escape: u32 = 0x1b;
+    pub const Space: u32 = 0x20;
+    pub const Left: u32 = 0x100;
+    pub const Right: u32 = 0x101;
+    pub const Up: u32 = 0x102;
+    pub const Down: u32 = 0x103;
+}
+
+//NOTE(joh): Error codes returned to the program, 0 means success
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyscallError {
+    InvalidMemAddr = 1,
+    InvalidStringData = 2,
+    Io = 3,
+    NoFramebuffer = 4,
+    NotFound = 5,
+    PermissionDenied = 6,
+    BadFd = 7,
+    TooManyFiles = 8,
+    InvalidArgument = 9,
+
+    Unknown = 99,
+}
+
+impl SyscallError {
+    pub fn as_return_code(result: Result<(), SyscallError>) -> u32 {
+        match result {
+            Ok(_) => 0,
+            Err(e) => e as u32,
+        }
+    }
+
+    //NOTE(joh): For syscalls that return a count, the count can not be mistaken for an error as
+    //long as it stays below 2^31
+    pub fn as_negated_code(result: Result<u32, SyscallError>) -> u32 {
+        match result {
+            Ok(value) => value,
+            Err(e) => (e as i32).wrapping_neg() as u32,
+        }
+    }
+}
+
+impl From<InterpreterErrorType> for SyscallError {
+    fn from(value: InterpreterErrorType) -> Self {
+        match value {
+            InterpreterErrorType::InvalidStringData(_) => Self::InvalidStringData,
+            InterpreterErrorType::AddrOutOfBounds(_) | InterpreterErrorType::WriteToProtectedMemory(_) => {
+                Self::InvalidMemAddr
+            }
+            InterpreterErrorType::Heap(HeapError::InvalidPointer(_)) => Self::InvalidArgument,
+            _ => SyscallError::Unknown,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for SyscallError {
+    fn from(value: std::io::Error) -> Self {
+        match value.kind() {
+            std::io::ErrorKind::NotFound => SyscallError::NotFound,
+            std::io::ErrorKind::PermissionDenied => SyscallError::PermissionDenied,
+            std::io::ErrorKind::InvalidInput => SyscallError::InvalidArgument,
+            _ => SyscallError::Io,
+        }
+    }
+}
+
+//NOTE(joh): Where `PrintDebugString` and `ReadLine` go. A `String` collects the output and
+//never has any input.
+#[cfg(feature = "std")]
+pub trait Console {
+    fn write(&mut self, s: &str) -> std::io::Result<()>;
+    //NOTE(joh): Appends a line including its newline, returns 0 on EOF
+    fn read_line(&mut self, line: &mut String) -> std::io::Result<usize>;
+    //NOTE(joh): Consoles without a keyboard never have a key down
+    fn key_down(&mut self, _key: u32) -> bool {
+        false
+    }
+    //NOTE(joh): A character typed since the last call. Consoles returning `None` are read by line.
+    fn read_char(&mut self) -> Option<char> {
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdConsole;
+
+#[cfg(feature = "std")]
+impl Console for StdConsole {
+    fn write(&mut self, s: &str) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(s.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+        std::io::stdin().lock().read_line(line)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Console for String {
+    fn write(&mut self, s: &str) -> std::io::Result<()> {
+        self.push_str(s);
+        Ok(())
+    }
+
+    fn read_line(&mut self, _: &mut String) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+#[cfg(feature = "std")]
+pub struct StdHandler<C: Console = StdConsole> {
+    pub console: C,
+    pub clock: Clock,
+    //NOTE(joh): Set by the program with `SetFramebuffer`, hosts without a display ignore it
+    pub framebuffer: Option<Framebuffer>,
+    //NOTE(joh): Where the file syscalls go, `None` refuses them
+    pub sandbox: Option<Sandbox>,
+    //NOTE(joh): The seed the generator was last started with, see `restart_random`
+    seed: u64,
+    //NOTE(joh): xorshift64*, never 0
+    rng_state: u64,
+    //NOTE(joh): Rest of the last line that did not fit into the `ReadLine` buffer
+    pending_input: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl<C: Console + Default> Default for StdHandler<C> {
+    fn default() -> Self {
+        Self::new(C::default())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: Console> StdHandler<C> {
+    pub fn new(console: C) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self {
+            console,
+            clock: Clock::default(),
+            framebuffer: None,
+            sandbox: None,
+            seed: 0,
+            rng_state: 0,
+            pending_input: Vec::new(),
+        }
+        .with_seed(seed)
+    }
+
+    //NOTE(joh): Together with a virtual clock this makes runs reproducible
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed(seed);
+        self
+    }
+
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng_state = seed.max(1);
+    }
+
+    pub fn random_seed(&self) -> u64 {
+        self.seed
+    }
+
+    //NOTE(joh): Hands out the same numbers again, for running a program again from the start
+    pub fn restart_random(&mut self) {
+        self.seed(self.seed);
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        self.rng_state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn random(&mut self, bound: u32) -> u32 {
+        let value = (self.next_random() >> 32) as u32;
+        match bound {
+            0 => value,
+            bound => ((value as u64 * bound as u64) >> 32) as u32,
+        }
+    }
+
+    fn print(&mut self, interpreter: &mut Interpreter, addr: u32, len: u32) -> Result<(), SyscallError> {
+        if !interpreter.consume_output(len) {
+            return Ok(());
+        }
+        let string = interpreter.read_str(addr, len)?;
+        self.console.write(&string)?;
+        Ok(())
+    }
+
+    fn read_line(&mut self, interpreter: &mut Interpreter, addr: u32, len: u32) -> u32 {
+        if self.pending_input.is_empty() {
+            let mut line = String::new();
+            if self.console.read_line(&mut line).is_err() {
+                return 0;
+            }
+            self.pending_input = line.into_bytes();
+        }
+        let read = self.pending_input.len().min(len as usize);
+        match interpreter.write_bytes(addr, &self.pending_input[..read]) {
+            Ok(()) => {
+                self.pending_input.drain(..read);
+                read as u32
+            }
+            Err(_) => 0,
+        }
+    }
+
+    fn set_framebuffer(&mut self, interpreter: &Interpreter, addr: u32, width: u32, height: u32) -> Result<(), SyscallError> {
+        if width == 0 || height == 0 {
+            self.framebuffer = None;
+            return Ok(());
+        }
+        let framebuffer = Framebuffer::new(&interpreter.memory, addr, width, height)
+            .filter(|f| interpreter.is_writable(f.addr, f.len_bytes() as u32))
+            .ok_or(SyscallError::InvalidMemAddr)?;
+        self.framebuffer = Some(framebuffer);
+        Ok(())
+    }
+
+    fn blit(&mut self, interpreter: &mut Interpreter, args: [u32; 5]) -> Result<(), SyscallError> {
+        let [src, x, y, width, height] = args;
+        let framebuffer = self.framebuffer.ok_or(SyscallError::NoFramebuffer)?;
+        //NOTE(joh): The code may have become write protected since the framebuffer was set
+        if !interpreter.is_writable(framebuffer.addr, framebuffer.len_bytes() as u32) {
+            return Err(SyscallError::InvalidMemAddr);
+        }
+        framebuffer
+            .blit(&mut interpreter.memory, src, x as i32, y as i32, width, height)
+            .ok_or(SyscallError::InvalidMemAddr)
+    }
+
+    fn sandbox(&mut self) -> Result<&mut Sandbox, SyscallError> {
+        self.sandbox.as_mut().ok_or(SyscallError::PermissionDenied)
+    }
+
+    fn file_open(&mut self, interpreter: &Interpreter, addr: u32, len: u32, flags: u32) -> Result<u32, SyscallError> {
+        let path = interpreter.read_str(addr, len)?;
+        self.sandbox()?.open(&path, flags)
+    }
+
+    fn file_read(&mut self, interpreter: &mut Interpreter, fd: u32, addr: u32, len: u32) -> Result<u32, SyscallError> {
+        let sandbox = self.sandbox()?;
+        //NOTE(joh): Checked up front, nothing may be read from the file that can't be stored
+        if (addr as usize).checked_add(len as usize).is_none_or(|end| end > interpreter.memory.len())
+            || !interpreter.is_writable(addr, len)
+        {
+            return Err(SyscallError::InvalidMemAddr);
+        }
+        let mut buf = vec![0; len as usize];
+        let read = sandbox.read(fd, &mut buf)?;
+        interpreter.write_bytes(addr, &buf[..read])?;
+        Ok(read as u32)
+    }
+
+    fn file_write(&mut self, interpreter: &Interpreter, fd: u32, addr: u32, len: u32) -> Result<u32, SyscallError> {
+        let sandbox = self.sandbox()?;
+        let data = interpreter.memory.slice(addr as usize, len as usize).ok_or(SyscallError::InvalidMemAddr)?;
+        Ok(sandbox.write(fd, &data)? as u32)
+    }
+
+    fn read_char(&mut self) -> u32 {
+        if self.pending_input.is_empty() {
+            if let Some(c) = self.console.read_char() {
+                return c as u32;
+            }
+            let mut line = String::new();
+            if self.console.read_line(&mut line).is_err() {
+                return 0;
+            }
+            self.pending_input = line.into_bytes();
+        }
+        //NOTE(joh): `ReadLine` may have split a character, its bytes are handed out one by one
+        let len = self.pending_input.len().min(4);
+        let c = (1..=len)
+            .find_map(|n| str::from_utf8(&self.pending_input[..n]).ok())
+            .and_then(|s| s.chars().next());
+        match c {
+            Some(c) => {
+                self.pending_input.drain(..c.len_utf8());
+                c as u32
+            }
+            None if len > 0 => self.pending_input.remove(0) as u32,
+            None => 0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: Console> SyscallHandler for StdHandler<C> {
+    fn on_syscall(&mut self, interpreter: &mut Interpreter, id: u32, args: &[u32]) -> u32 {
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        match id {
+            PrintDebugString => SyscallError::as_return_code(self.print(interpreter, arg(0), arg(1))),
+            ClockMonotonic => self.clock.monotonic_millis(interpreter) as u32,
+            ClockWallTime => self.clock.wall_clock_secs(interpreter) as u32,
+            ReadLine => self.read_line(interpreter, arg(0), arg(1)),
+            Random => self.random(arg(0)),
+            MemorySize => interpreter.memory.len() as u32,
+            //NOTE(joh): Blocking would freeze the page in the browser, so the host gets to wait there
+            Sleep if cfg!(target_arch = "wasm32") && self.clock.mode() == ClockMode::Real => {
+                interpreter.yield_to_host(arg(0));
+                0
+            }
+            Sleep => {
+                self.clock.sleep(arg(0) as u64);
+                0
+            }
+            YieldFor => {
+                interpreter.yield_to_host(arg(0));
+                0
+            }
+            KeyDown => self.console.key_down(arg(0)) as u32,
+            ReadChar => self.read_char(),
+            SetFramebuffer => {
+                SyscallError::as_return_code(self.set_framebuffer(interpreter, arg(0), arg(1), arg(2)))
+            }
+            Blit => SyscallError::as_return_code(self.blit(interpreter, [arg(0), arg(1), arg(2), arg(3), arg(4)])),
+            FileOpen => SyscallError::as_negated_code(self.file_open(interpreter, arg(0), arg(1), arg(2))),
+            FileRead => SyscallError::as_negated_code(self.file_read(interpreter, arg(0), arg(1), arg(2))),
+            FileWrite => SyscallError::as_negated_code(self.file_write(interpreter, arg(0), arg(1), arg(2))),
+            FileSeek => SyscallError::as_negated_code(
+                self.sandbox()
+                    .and_then(|sandbox| sandbox.seek(arg(0), arg(1) as i32 as i64, arg(2)))
+                    .map(|position| position as u32),
+            ),
+            FileClose => SyscallError::as_negated_code(self.sandbox().and_then(|sandbox| sandbox.close(arg(0))).map(|_| 0)),
+            Alloc => interpreter.alloc(arg(0)).unwrap_or(0),
+            Free => SyscallError::as_return_code(interpreter.free(arg(0)).map_err(SyscallError::from)),
+            _ => 0,
+        }
+    }
+
+    fn syscall_group(&self, id: u32) -> Option<SyscallGroup> {
+        match id {
+            PrintDebugString | ReadLine | KeyDown | ReadChar => Some(SyscallGroup::Console),
+            ClockMonotonic | ClockWallTime | Sleep | YieldFor => Some(SyscallGroup::Clock),
+            Random => Some(SyscallGroup::Random),
+            SetFramebuffer | Blit => Some(SyscallGroup::Display),
+            Alloc | Free | MemorySize => Some(SyscallGroup::Memory),
+            FileOpen | FileRead | FileWrite | FileSeek | FileClose => Some(SyscallGroup::Files),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{self, opcode},
+        config::{Capabilities, InterpreterConfig},
+        interpreter::RunState,
+    };
+
+    #[derive(Default)]
+    struct Scripted {
+        output: String,
+        input: Vec<&'static str>,
+        keys: Vec<u32>,
+    }
+
+    impl Console for Scripted {
+        fn write(&mut self, s: &str) -> std::io::Result<()> {
+            self.output.push_str(s);
+            Ok(())
+        }
+
+        fn read_line(&mut self, line: &mut String) -> std::io::Result<usize> {
+            match self.input.pop() {
+                Some(input) => {
+                    line.push_str(input);
+                    Ok(input.len())
+                }
+                None => Ok(0),
+            }
+        }
+
+        fn key_down(&mut self, key: u32) -> bool {
+            self.keys.contains(&key)
+        }
+    }
+
+    #[test]
+    fn std_handler() {
+        let code = format!(
+            "
+            .string msg \"hi\";
+            .data buffer bytes 0 0 0;
+            #@msg; push_arg; #@msg.len; push_arg; #{PrintDebugString}; syscall;
+            #@buffer; push_arg; #3; push_arg; #{ReadLine}; syscall;
+            #@buffer; push_arg; #3; push_arg; #{ReadLine}; syscall;
+            #@buffer; load_8_u 0;
+            #@buffer; push_arg; #3; push_arg; #{ReadLine}; syscall;
+            #10; push_arg; #{Random}; syscall; #10; lt;
+            #{MemorySize}; syscall;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let memory_size = interpreter.memory.len() as u32;
+        let mut handler = StdHandler::new(Scripted {
+            input: vec!["abcd\n"],
+            ..Default::default()
+        });
+
+        let result = interpreter.run(&mut handler).unwrap();
+        assert_eq!(result, &[0, 3, 2, b'd' as u32, 0, 1, memory_size]);
+        assert_eq!(handler.console.output, "hi");
+
+        let mut a = StdHandler::new(String::new()).with_seed(7);
+        let mut b = StdHandler::new(String::new()).with_seed(7);
+        let numbers = (0..8).map(|_| a.random(0)).collect::<Vec<_>>();
+        assert_eq!(numbers, (0..8).map(|_| b.random(0)).collect::<Vec<_>>());
+        a.restart_random();
+        assert_eq!(numbers, (0..8).map(|_| a.random(0)).collect::<Vec<_>>());
+        assert_eq!(a.random_seed(), 7);
+
+        let denied = Capabilities::all().with(SyscallGroup::Memory, false);
+        interpreter.reset_all(&bytecode.code).unwrap();
+        interpreter.config.capabilities = denied;
+        let result = interpreter.run(&mut StdHandler::new(String::new()));
+        assert!(matches!(result, Err(InterpreterErrorType::PermissionDenied(MemorySize))));
+    }
+
+    #[test]
+    fn keys_and_chars() {
+        let code = format!(
+            "
+            #{}; push_arg; #{KeyDown}; syscall;
+            #{}; push_arg; #{KeyDown}; syscall;
+            #{ReadChar}; syscall; #{ReadChar}; syscall; #{ReadChar}; syscall; #{ReadChar}; syscall;
+            end;
+            ",
+            key::Left,
+            'a' as u32,
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = StdHandler::new(Scripted {
+            input: vec!["é!\n"],
+            keys: vec![key::Left],
+            ..Default::default()
+        });
+        let result = interpreter.run(&mut handler).unwrap();
+        assert_eq!(result, &[1, 0, 'é' as u32, '!' as u32, '\n' as u32, 0]);
+    }
+
+    #[test]
+    fn framebuffer() {
+        let code = format!(
+            "
+            #0x2000; push_arg; #0; push_arg; #0; push_arg; #1; push_arg; #1; push_arg; #{Blit}; syscall;
+            #0x1000; push_arg; #2; push_arg; #2; push_arg; #{SetFramebuffer}; syscall;
+            #0x2000; #0x7f0000ff; store_32 0;
+            #0x2000; push_arg; #1; push_arg; #1; push_arg; #1; push_arg; #1; push_arg; #{Blit}; syscall;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = StdHandler::new(String::new());
+        let result = interpreter.run(&mut handler).unwrap();
+        assert_eq!(result, &[SyscallError::NoFramebuffer as u32, 0, 0]);
+
+        let framebuffer = handler.framebuffer.unwrap();
+        let pixels = framebuffer.pixels(&interpreter.memory).unwrap();
+        assert_eq!(pixels[12..], [0xff, 0, 0, 0x7f]);
+        assert!(pixels[..12].iter().all(|b| *b == 0));
+
+        //NOTE(joh): Blitting into the code would patch it without the checks of the stores
+        let code = format!(
+            "
+            #@fb; push_arg; #1; push_arg; #1; push_arg; #{SetFramebuffer}; syscall;
+            end;
+            :fb: unreachable;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = StdHandler::new(String::new());
+        let invalid = SyscallError::as_return_code(Err(SyscallError::InvalidMemAddr));
+        assert_eq!(interpreter.run(&mut handler).unwrap(), &[invalid]);
+        assert_eq!(handler.framebuffer, None);
+
+        let config = InterpreterConfig::default().with_code_protection(false);
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode.code, config).unwrap();
+        assert_eq!(interpreter.run(&mut handler).unwrap(), &[0]);
+        interpreter.set_code_writable(false);
+        interpreter.memory.write(0x2000, &[1, 1, 1, 1]).unwrap();
+        assert_eq!(handler.on_syscall(&mut interpreter, Blit, &[0x2000, 0, 0, 1, 1]), invalid);
+        let fb = handler.framebuffer.unwrap().addr;
+        assert_eq!(interpreter.memory.get(fb as usize), Some(opcode::Unreachable));
+    }
+
+    #[test]
+    fn sleep_and_yield_for() {
+        let code = format!(
+            "
+            #{ClockMonotonic}; syscall;
+            #30; push_arg; #{Sleep}; syscall; drop;
+            #{ClockMonotonic}; syscall; swap; sub;
+            #16; push_arg; #{YieldFor}; syscall;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        let mut handler = StdHandler::new(String::new());
+        handler.clock = Clock::virtualized(0, 0);
+
+        assert_eq!(interpreter.run_until_yield(&mut handler).unwrap(), RunState::Yielded(16));
+        assert_eq!(interpreter.value_stack, [30, 0]);
+        assert_eq!(interpreter.run_until_yield(&mut handler).unwrap(), RunState::Stopped);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    config::InterpreterConfig,
+    debug::{DebugInfo, LineEntry},
+    interpreter::{Interpreter, InterpreterErrorType, SyscallHandler},
+    module::Module,
+    prelude::*,
+    trap::Trap,
+};
+
+//NOTE(joh): Every code label starting with this is run as a test, see `run_tests`
+pub const TEST_PREFIX: &str = "test_";
+
+#[derive(Debug)]
+pub enum TestOutcome {
+    Passed,
+    //NOTE(joh): `pc` is the failing `dbg_assert`, `line` is only known with debug info
+    AssertionFailed { pc: u32, line: Option<LineEntry> },
+    Trapped(Trap),
+}
+
+//NOTE(joh): For code that does not make syscalls, every syscall returns 0. Only for tests, other
+//crates get it with the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoSyscalls();
+
+#[cfg(any(test, feature = "testing"))]
+impl SyscallHandler for NoSyscalls {
+    fn on_syscall(&mut self, _: &mut Interpreter, _: u32, _: &[u32]) -> u32 {
+        0
+    }
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub addr: u32,
+    pub outcome: TestOutcome,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        matches!(self.outcome, TestOutcome::Passed)
+    }
+
+    pub fn render(&self, debug_info: Option<&DebugInfo>) -> String {
+        match &self.outcome {
+            TestOutcome::Passed => format!("{} ... ok", self.name),
+            TestOutcome::AssertionFailed { pc, .. } => {
+                let location = debug_info
+                    .and_then(|d| d.resolve_pc(*pc))
+                    .map_or_else(|| format!("0x{pc:04x}"), |loc| loc.to_string());
+                format!("{} ... FAILED\n    assertion failed at {location}", self.name)
+            }
+            TestOutcome::Trapped(trap) => {
+                format!("{} ... FAILED\n    {}", self.name, trap.render(debug_info).replace('\n', "\n    "))
+            }
+        }
+    }
+}
+
+//NOTE(joh): Tests in the order they appear in the code. `labels` are absolute addresses like in
+//`DebugInfo::labels` or the symbol table.
+pub fn discover_tests(labels: &[(String, u32)]) -> Vec<(String, u32)> {
+    let mut tests = labels
+        .iter()
+        .filter(|(name, _)| name.starts_with(TEST_PREFIX))
+        .cloned()
+        .collect::<Vec<_>>();
+    tests.sort_by_key(|(_, addr)| *addr);
+    tests
+}
+
+//NOTE(joh): Each test runs in a fresh instance of `module`, starting at its label and ending
+//once it returns. The labels come from `debug_info` if given, else from the symbol table of the
+//module; without either there are no tests to find.
+pub fn run_tests(
+    module: &Module,
+    debug_info: Option<&DebugInfo>,
+    config: &InterpreterConfig,
+    syscall_handler: &mut impl SyscallHandler,
+) -> Result<Vec<TestResult>, InterpreterErrorType> {
+    let labels = match (debug_info, module.symbols()) {
+        (Some(debug_info), _) => &debug_info.labels[..],
+        (None, Some(symbols)) => &symbols.labels[..],
+        (None, None) => &[],
+    };
+
+    let mut results = Vec::new();
+    for (name, addr) in discover_tests(labels) {
+        let outcome = run_test(module, addr, debug_info, config, syscall_handler)?;
+        results.push(TestResult { name, addr, outcome });
+    }
+    Ok(results)
+}
+
+//NOTE(joh): Only an instance that can't be created is an error, everything after that is the
+//outcome of the test
+fn run_test(
+    module: &Module,
+    addr: u32,
+    debug_info: Option<&DebugInfo>,
+    config: &InterpreterConfig,
+    syscall_handler: &mut impl SyscallHandler,
+) -> Result<TestOutcome, InterpreterErrorType> {
+    let mut interpreter = Interpreter::instantiate_with_config(module, config.clone())?;
+    //NOTE(joh): Like `Interpreter::call`, returning from the test stops the interpreter. The pc
+    //is not restored afterwards, it still points at a failed assertion.
+    if let Some(frame) = interpreter.return_stack.last_mut() {
+        frame.return_addr = 0;
+    }
+    if let Err(e) = interpreter.try_jump_to(addr) {
+        return Ok(TestOutcome::Trapped(Trap::capture(&interpreter, e)));
+    }
+    if let Err(e) = interpreter.run(syscall_handler) {
+        return Ok(TestOutcome::Trapped(Trap::capture(&interpreter, e)));
+    }
+    Ok(match interpreter.assertion_failed {
+        true => TestOutcome::AssertionFailed {
+            pc: interpreter.pc,
+            line: debug_info.and_then(|d| d.resolve_line(interpreter.pc)),
+        },
+        false => TestOutcome::Passed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn discover_and_run() {
+        let code = "
+            :main:
+            unreachable;
+            :test_add:
+            #2; #3; add; #5; eq; dbg_assert;
+            return;
+            :helper:
+            return;
+            :test_fails:
+            #1; dbg_assert;
+            #0; dbg_assert;
+            return;
+            :test_traps:
+            #1; #0; div_s;
+            return;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let module = Module::from_bytecode(&bytecode.code).unwrap();
+        let debug_info = &bytecode.debug_info;
+
+        let results = run_tests(&module, Some(debug_info), &InterpreterConfig::default(), &mut NoSyscalls()).unwrap();
+        let names = results.iter().map(|r| (r.name.as_str(), r.passed())).collect::<Vec<_>>();
+        assert_eq!(names, [("test_add", true), ("test_fails", false), ("test_traps", false)]);
+
+        let TestOutcome::AssertionFailed { pc, line } = results[1].outcome else {
+            panic!("expected a failed assertion, got {:?}", results[1].outcome);
+        };
+        assert_eq!(line.map(|l| l.line), Some(11));
+        assert_eq!(debug_info.resolve_line(pc), line);
+        assert!(results[1].render(Some(debug_info)).contains("assertion failed at <source>:11"));
+        assert!(matches!(results[2].outcome, TestOutcome::Trapped(_)));
+
+        //NOTE(joh): Without debug info or symbols there are no labels to find tests by
+        let results = run_tests(&module, None, &InterpreterConfig::default(), &mut NoSyscalls()).unwrap();
+        assert!(results.is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use core::fmt::{Display, Write};
+
+use crate::{asm::opcode, prelude::*, profile::Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent<'a> {
+    //NOTE(joh): Emitted before the op is executed
+    Op { pc: u32, opcode: u8 },
+    Push(u32),
+    Pop(u32),
+    Jump { from: u32, to: u32 },
+    Call { from: u32, to: u32 },
+    //NOTE(joh): `to` is 0 when the outermost frame of `Interpreter::call` returns
+    Return { from: u32, to: u32 },
+    Syscall { id: u32, args: &'a [u32] },
+}
+
+impl Display for TraceEvent<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TraceEvent::Op { pc, opcode } => {
+                let name = opcode::Names.get(*opcode as usize).unwrap_or(&"???");
+                write!(f, "0x{pc:04x}: {name}")
+            }
+            TraceEvent::Push(val) => write!(f, "push {val}"),
+            TraceEvent::Pop(val) => write!(f, "pop {val}"),
+            TraceEvent::Jump { from, to } => write!(f, "jump 0x{from:04x} -> 0x{to:04x}"),
+            TraceEvent::Call { from, to } => write!(f, "call 0x{from:04x} -> 0x{to:04x}"),
+            TraceEvent::Return { from, to } => write!(f, "return 0x{from:04x} -> 0x{to:04x}"),
+            TraceEvent::Syscall { id, args } => write!(f, "syscall {id} {args:?}"),
+        }
+    }
+}
+
+pub trait TraceSink {
+    fn on_event(&mut self, event: TraceEvent<'_>);
+}
+
+impl<F: FnMut(TraceEvent<'_>)> TraceSink for F {
+    fn on_event(&mut self, event: TraceEvent<'_>) {
+        self(event)
+    }
+}
+
+//NOTE(joh): Prints every event to stderr so it does not mix with program output
+#[cfg(feature = "std")]
+pub struct PrintTracer;
+
+#[cfg(feature = "std")]
+impl TraceSink for PrintTracer {
+    fn on_event(&mut self, event: TraceEvent<'_>) {
+        eprintln!("{event}");
+    }
+}
+
+//NOTE(joh): `time_nanos` is counted from `Interpreter::start_trace`, without std it is always 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TracedOp {
+    pub pc: u32,
+    pub opcode: u8,
+    //NOTE(joh): The values and the frames before the op is executed, the outermost frame counts too
+    pub stack_depth: u32,
+    pub call_depth: u32,
+    pub time_nanos: u64,
+}
+
+//NOTE(joh): Every op executed since `Interpreter::start_trace`, resetting the interpreter starts it
+//over so it holds the last run. It grows with every op, for long runs a `TraceSink` that only
+//keeps what is needed is the better choice.
+#[derive(Debug, Clone)]
+pub struct ExecutionTrace {
+    start: Instant,
+    ops: Vec<TracedOp>,
+}
+
+impl Default for ExecutionTrace {
+    fn default() -> Self {
+        Self { start: Instant::now(), ops: Vec::new() }
+    }
+}
+
+impl ExecutionTrace {
+    pub(crate) fn record_op(&mut self, pc: u32, opcode: u8, stack_depth: usize, call_depth: usize) {
+        self.ops.push(TracedOp {
+            pc,
+            opcode,
+            stack_depth: stack_depth as u32,
+            call_depth: call_depth as u32,
+            time_nanos: self.start.elapsed().as_nanos() as u64,
+        });
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn ops(&self) -> &[TracedOp] {
+        &self.ops
+    }
+
+    //NOTE(joh): The JSON object format of chrome://tracing and Perfetto. Every op is a complete
+    //event that lasts until the next one starts, the last one has no duration.
+    pub fn to_chrome_json(&self) -> String {
+        let micros = |nanos: u64| format!("{}.{:03}", nanos / 1000, nanos % 1000);
+        let mut out = String::from("{\"traceEvents\":[");
+        for (i, op) in self.ops.iter().enumerate() {
+            let end = self.ops.get(i + 1).map_or(op.time_nanos, |next| next.time_nanos);
+            let name = opcode::Names.get(op.opcode as usize).unwrap_or(&"???");
+            if i > 0 {
+                out.push(',');
+            }
+            _ = write!(
+                out,
+                "\n{{\"name\":\"{name}\",\"cat\":\"op\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":0,\"tid\":0,\
+                 \"args\":{{\"pc\":{},\"stack_depth\":{},\"call_depth\":{}}}}}",
+                micros(op.time_nanos),
+                micros(end - op.time_nanos),
+                op.pc,
+                op.stack_depth,
+                op.call_depth,
+            );
+        }
+        out.push_str("\n],\"displayTimeUnit\":\"ns\"}\n");
+        out
+    }
+
+    //NOTE(joh): One line per op after a header, the pc in hex like in the disassembly
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("pc,op,stack_depth,call_depth,time_ns\n");
+        for op in &self.ops {
+            let name = opcode::Names.get(op.opcode as usize).unwrap_or(&"???");
+            _ = writeln!(
+                out,
+                "0x{:04x},{name},{},{},{}",
+                op.pc, op.stack_depth, op.call_depth, op.time_nanos
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        asm,
+        interpreter::Interpreter,
+        testing::NoSyscalls,
+    };
+
+    #[test]
+    fn trace_events() {
+        let code = "
+            #@fn; call;
+            end;
+            :fn:
+            #1; push_arg; #3; syscall;
+            return;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        interpreter.set_trace_sink(move |event: TraceEvent<'_>| sink.lock().unwrap().push(event.to_string()));
+        interpreter.run(&mut NoSyscalls()).unwrap();
+
+        let fn_addr = asm::DATA_START + 7;
+        assert_eq!(
+            events.lock().unwrap()[..],
+            [
+                "0x0010: const".to_string(),
+                "push 23".to_string(),
+                "0x0015: call".to_string(),
+                "pop 23".to_string(),
+                format!("call 0x0015 -> 0x{fn_addr:04x}"),
+                "0x0017: const".to_string(),
+                "push 1".to_string(),
+                "0x001c: push_arg".to_string(),
+                "pop 1".to_string(),
+                "0x001d: const".to_string(),
+                "push 3".to_string(),
+                "0x0022: syscall".to_string(),
+                "pop 3".to_string(),
+                "syscall 3 [1]".to_string(),
+                "push 0".to_string(),
+                "0x0023: return".to_string(),
+                "return 0x0023 -> 0x0016".to_string(),
+                "0x0016: end".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn execution_trace() {
+        let code = "
+            #@fn; call;
+            end;
+            :fn:
+            #1; push_arg; #3; syscall;
+            return;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+        interpreter.start_trace();
+        interpreter.run(&mut NoSyscalls()).unwrap();
+        let trace = interpreter.execution_trace().unwrap();
+        assert_eq!(trace.ops().len() as u64, interpreter.executed_ops);
+        let depths = trace.ops().iter().map(|op| (op.stack_depth, op.call_depth)).collect::<Vec<_>>();
+        assert_eq!(depths, [(0, 1), (1, 1), (0, 2), (1, 2), (0, 2), (1, 2), (1, 2), (1, 1)]);
+        assert!(trace.ops().windows(2).all(|w| w[0].time_nanos <= w[1].time_nanos));
+
+        let csv = trace.to_csv();
+        let line = format!("0x0017,const,0,2,{}", trace.ops()[2].time_nanos);
+        assert_eq!(csv.lines().nth(3), Some(line.as_str()));
+        let json: serde_json::Value = serde_json::from_str(&trace.to_chrome_json()).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[1]["name"], "call");
+        assert_eq!(events[1]["args"]["pc"], 0x15);
+
+        //NOTE(joh): Resetting starts the trace over
+        interpreter.reset_all(&bytecode.code).unwrap();
+        assert!(interpreter.execution_trace().unwrap().ops().is_empty());
+        assert!(interpreter.stop_trace().is_some());
+        assert!(interpreter.execution_trace().is_none());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{
+    debug::DebugInfo,
+    interpreter::{Interpreter, InterpreterErrorType},
+    prelude::*,
+};
+
+//NOTE(joh): An error together with where it happened. `backtrace` starts with the pc of
+//the faulting op, followed by the call sites of all active frames, innermost first.
+#[derive(Debug)]
+pub struct Trap {
+    pub error: InterpreterErrorType,
+    pub pc: u32,
+    pub backtrace: Vec<u32>,
+}
+
+impl Trap {
+    pub fn capture(interpreter: &Interpreter, error: InterpreterErrorType) -> Self {
+        Self {
+            error,
+            pc: interpreter.pc,
+            backtrace: interpreter.backtrace(),
+        }
+    }
+
+    pub fn symbolicate(&self, debug_info: Option<&DebugInfo>) -> Vec<String> {
+        self.backtrace
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| match debug_info.and_then(|d| d.resolve_label(*addr)) {
+                Some((label, offset)) => format!("#{i} :{label}+0x{offset:02x}"),
+                None => format!("#{i} 0x{addr:04x}"),
+            })
+            .collect()
+    }
+
+    pub fn render(&self, debug_info: Option<&DebugInfo>) -> String {
+        let location = debug_info
+            .and_then(|d| d.resolve_pc(self.pc))
+            .map_or_else(|| format!("0x{:04x}", self.pc), |loc| loc.to_string());
+
+        let mut out = format!("trap at {location}: {}", self.error);
+        for frame in self.symbolicate(debug_info) {
+            out.push_str("\n    ");
+            out.push_str(&frame);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        asm,
+        interpreter::{Interpreter, InterpreterErrorType},
+        testing::NoSyscalls,
+    };
+
+    #[test]
+    fn backtrace_on_trap() {
+        let code = "
+            :main:
+            #@fn; call;
+            end;
+            :fn:
+            #@inner; call;
+            return;
+            :inner:
+            unreachable;
+        ";
+        let bytecode = asm::Parser::parse(code).unwrap();
+        let mut interpreter = Interpreter::from_bytecode(&bytecode.code).unwrap();
+
+        let trap = interpreter.run_with_backtrace(&mut NoSyscalls()).unwrap_err();
+        assert!(matches!(trap.error, InterpreterErrorType::ReachedUnreachable));
+        assert_eq!(
+            trap.symbolicate(Some(&bytecode.debug_info)),
+            ["#0 :inner+0x00", "#1 :fn+0x05", "#2 :main+0x05"]
+        );
+        assert!(trap.render(Some(&bytecode.debug_info)).starts_with("trap at <source>:9 (:inner+0x00)"));
+    }
+}
@@ -0,0 +1,335 @@
+use alloc::collections::BTreeSet;
+use core::fmt::Display;
+
+use crate::{
+    io::Cursor,
+    asm::{opcode, BytecodeInfo, RawArg, RawOp, DATA_START},
+    function::split_function_table,
+    host::split_host_imports,
+    interpreter::MAX_LOCALS,
+    parse::{decode_const_pool, try_parse_op, MaybeRawOp},
+    prelude::*,
+    signing::split_signature,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub instruction_count: usize,
+    pub block_count: usize,
+    //NOTE(joh): Highest stack height on the paths whose height is known statically
+    pub max_stack_height: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    InvalidHeader,
+    //NOTE(joh): The header claims more code or data than the bytecode contains
+    SectionOutOfBounds,
+    UnknownOpcode { addr: u32, opcode: u8 },
+    TruncatedOp(u32),
+    InvalidLocalId { addr: u32, id: u8 },
+    InvalidConstPoolIndex { addr: u32, index: u8 },
+    InvalidHostFnIndex { addr: u32, index: u8 },
+    InvalidEntryPoint(u32),
+    //NOTE(joh): A jump, call or table target that is not the start of an op
+    InvalidTarget { addr: u32, target: u32 },
+    StackUnderflow(u32),
+    //NOTE(joh): Two paths reach `addr` with different stack heights
+    UnbalancedStack { addr: u32, expected: usize, found: usize },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidHeader => write!(f, "invalid bytecode header"),
+            Self::SectionOutOfBounds => write!(f, "code or data section exceeds the bytecode"),
+            Self::UnknownOpcode { addr, opcode } => write!(f, "unknown opcode 0x{opcode:02x} at 0x{addr:04x}"),
+            Self::TruncatedOp(addr) => write!(f, "op at 0x{addr:04x} is truncated"),
+            Self::InvalidLocalId { addr, id } => write!(f, "invalid local id {id} at 0x{addr:04x}"),
+            Self::InvalidConstPoolIndex { addr, index } => {
+                write!(f, "invalid constant pool index {index} at 0x{addr:04x}")
+            }
+            Self::InvalidHostFnIndex { addr, index } => {
+                write!(f, "invalid host function index {index} at 0x{addr:04x}")
+            }
+            Self::InvalidEntryPoint(addr) => write!(f, "entry point 0x{addr:04x} is not the start of an op"),
+            Self::InvalidTarget { addr, target } => {
+                write!(f, "target 0x{target:04x} of the op at 0x{addr:04x} is not the start of an op")
+            }
+            Self::StackUnderflow(addr) => write!(f, "stack underflow at 0x{addr:04x}"),
+            Self::UnbalancedStack { addr, expected, found } => {
+                write!(f, "stack height at 0x{addr:04x} is {found} on one path and {expected} on another")
+            }
+        }
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+//NOTE(joh): Values popped and pushed. `None` for calls, whose effect depends on the callee.
+#[allow(non_upper_case_globals)]
+pub fn stack_effect(opcode: u8) -> Option<(usize, usize)> {
+    use opcode::*;
+    let effect = match opcode {
+        DbgHalt | Nop | Jmp | Branch | Return | End | Unreachable => (0, 0),
+        Const | ConstPool | LocalGet | GlobalGet | FConst | MemSize | CallHost => (0, 1),
+        Const64 => (0, 2),
+        Drop | LocalSet | GlobalSet | PushArg | DbgAssert | BrTable | Yield | PopMem => (1, 0),
+        JmpIf | BranchIf => (2, 0),
+        LocalTee | GlobalTee | Eqz | Neg | Load8u | Load8s | Load16s | Load16u | Load32s | Load32u
+        | Syscall | FFromI32 | I32FromF | Extend8S32 | Extend8U32 | Extend16S32 | Extend16U32 | MemGrow | Clz | Ctz
+        | Popcnt | PushMem | Bswap | Bswap16 | LoadMem8u | LoadMem32 | AtomicLoad
+        | AtomicLoad32 => (1, 1),
+        Eq | Add | Sub | Divs | Divu | RemS | RemU | Mul | MulWrap | Gt | Lt | Ge | Le | Shiftr | Shiftl | ShiftrS | And | Or
+        | Xor | Rotl | Rotr | FAdd | FSub | FMul | FDiv | FLt | FGt => (2, 1),
+        Store8 | Store16 | Store32 | StoreMem8 | StoreMem32 | AtomicStore | AtomicStore32 => (2, 0),
+        AtomicAdd | AtomicRmwAdd => (2, 1),
+        Cmpxchg | AtomicCmpxchg => (3, 1),
+        Dup => (1, 2),
+        Swap => (2, 2),
+        Select => (3, 1),
+        MemCopy | MemFill => (3, 0),
+        Add64 | Sub64 | Mul64 | MulWrap64 | Divs64 | Divu64 | And64 | Or64 | Xor64 | Shiftl64 | Shiftr64 => (4, 2),
+        Eq64 | Lts64 | Ltu64 | Gts64 | Gtu64 => (4, 1),
+        ExtendU32 | ExtendS32 | Load64 => (1, 2),
+        Wrap64 => (2, 1),
+        Store64 => (3, 0),
+        _ => return None,
+    };
+    Some(effect)
+}
+
+//NOTE(joh): Ops after which the next op is not reached by falling through
+pub(crate) fn ends_flow(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        opcode::Jmp | opcode::Branch | opcode::BrTable | opcode::Return | opcode::End | opcode::Unreachable
+    )
+}
+
+pub(crate) fn is_control_flow(opcode: u8) -> bool {
+    ends_flow(opcode)
+        || matches!(
+            opcode,
+            opcode::JmpIf | opcode::BranchIf | opcode::Call | opcode::CallIf | opcode::CallIndirect
+        )
+}
+
+pub(crate) struct Code {
+    pub(crate) ops: Vec<(u32, RawOp)>,
+    //NOTE(joh): Index into `ops` by address
+    pub(crate) index: HashMap<u32, usize>,
+}
+
+//NOTE(joh): The header, the const pool and the ops of bytecode without the trailing sections
+pub(crate) fn decode_code(bytecode: &[u8]) -> Result<(BytecodeInfo, Box<[u32]>, Code), ValidationError> {
+    let info = BytecodeInfo::decode(&mut Cursor::new(bytecode)).map_err(|_| ValidationError::InvalidHeader)?;
+    let const_pool = decode_const_pool(bytecode).map_err(|_| ValidationError::SectionOutOfBounds)?;
+    if bytecode.len() < info.total_size() {
+        return Err(ValidationError::SectionOutOfBounds);
+    }
+    let code_start = BytecodeInfo::total_header_size();
+    let code = decode_ops(&bytecode[code_start..code_start + info.code_size_bytes as usize])?;
+    Ok((info, const_pool, code))
+}
+
+//NOTE(joh): The value a `const` or `const_pool` op pushes
+pub(crate) fn const_value(op: &RawOp, const_pool: &[u32]) -> Option<u32> {
+    match (op.opcode, &op.arg) {
+        (opcode::Const, Some(RawArg::Num(n))) => Some(*n),
+        (opcode::ConstPool, Some(RawArg::Register(i))) => const_pool.get(*i as usize).copied(),
+        _ => None,
+    }
+}
+
+//NOTE(joh): Where the op at `i` jumps, branches or calls to, as far as it is known statically
+pub(crate) fn op_targets(code: &Code, i: usize, const_pool: &[u32]) -> Vec<u32> {
+    let (addr, op) = &code.ops[i];
+    let pushed = i.checked_sub(1).and_then(|prev| const_value(&code.ops[prev].1, const_pool));
+    match (op.opcode, &op.arg) {
+        (opcode::BrTable, Some(RawArg::Table { targets, default })) => targets.iter().chain([default]).copied().collect(),
+        (opcode::Jmp | opcode::JmpIf | opcode::Call | opcode::CallIf, _) => pushed.into_iter().collect(),
+        (opcode::Branch | opcode::BranchIf, _) => pushed.map(|offset| addr.wrapping_add(offset)).into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+//NOTE(joh): Checks bytecode without running it. Jump and call targets are only known when the
+//address is a constant right before the op, computed targets are checked by the interpreter.
+//Stack heights are tracked from the entry point up to the first call.
+pub fn validate(bytecode: &[u8]) -> Result<ValidationReport, ValidationError> {
+    let (bytecode, _) = split_signature(bytecode);
+    let (_, host_imports) = split_host_imports(bytecode);
+    let (bytecode, functions) = split_function_table(bytecode);
+    let (info, const_pool, code) = decode_code(bytecode)?;
+
+    let is_boundary = |addr: u32| code.index.contains_key(&addr);
+    if info.code_start_offset != DATA_START && !is_boundary(info.code_start_offset) {
+        return Err(ValidationError::InvalidEntryPoint(info.code_start_offset));
+    }
+    for function in functions.iter().flatten() {
+        if !is_boundary(function.addr) {
+            return Err(ValidationError::InvalidTarget { addr: function.addr, target: function.addr });
+        }
+    }
+
+    let mut leaders = BTreeSet::from([info.code_start_offset]);
+    let mut targets = vec![Vec::new(); code.ops.len()];
+    for (i, (addr, op)) in code.ops.iter().enumerate() {
+        match &op.arg {
+            Some(RawArg::Register(id))
+                if (opcode::LocalGet..=opcode::LocalTee).contains(&op.opcode) && *id as usize >= MAX_LOCALS =>
+            {
+                return Err(ValidationError::InvalidLocalId { addr: *addr, id: *id });
+            }
+            Some(RawArg::Register(index)) if op.opcode == opcode::ConstPool && *index as usize >= const_pool.len() => {
+                return Err(ValidationError::InvalidConstPoolIndex { addr: *addr, index: *index });
+            }
+            Some(RawArg::Register(index))
+                if op.opcode == opcode::CallHost && *index as usize >= host_imports.as_ref().map_or(0, |h| h.len()) =>
+            {
+                return Err(ValidationError::InvalidHostFnIndex { addr: *addr, index: *index });
+            }
+            _ => {}
+        }
+
+        let op_targets = op_targets(&code, i, &const_pool);
+        for &target in &op_targets {
+            if !is_boundary(target) {
+                return Err(ValidationError::InvalidTarget { addr: *addr, target });
+            }
+        }
+        if is_control_flow(op.opcode) {
+            leaders.extend(&op_targets);
+            leaders.extend(code.ops.get(i + 1).map(|(next, _)| *next));
+        }
+        targets[i] = op_targets;
+    }
+
+    let max_stack_height = check_stack(&code, &targets, info.code_start_offset)?;
+    Ok(ValidationReport {
+        instruction_count: code.ops.len(),
+        block_count: leaders.iter().filter(|addr| is_boundary(**addr)).count(),
+        max_stack_height,
+    })
+}
+
+fn decode_ops(code: &[u8]) -> Result<Code, ValidationError> {
+    let mut reader = Cursor::new(code);
+    let mut ops = Vec::new();
+    let mut index = HashMap::new();
+    while (reader.position() as usize) < code.len() {
+        let addr = DATA_START + reader.position() as u32;
+        match try_parse_op(&mut reader).map_err(|_| ValidationError::TruncatedOp(addr))? {
+            MaybeRawOp::Op(op) => {
+                index.insert(addr, ops.len());
+                ops.push((addr, op));
+            }
+            MaybeRawOp::Unknown(opcode) => return Err(ValidationError::UnknownOpcode { addr, opcode }),
+        }
+    }
+    Ok(Code { ops, index })
+}
+
+//NOTE(joh): Walks every path from the entry point and records the stack height before each
+//op. A path ends at a call, since the height after it depends on the callee.
+fn check_stack(code: &Code, targets: &[Vec<u32>], entry: u32) -> Result<usize, ValidationError> {
+    let mut heights: HashMap<u32, usize> = HashMap::new();
+    let mut max_height = 0;
+    let mut pending = vec![(entry, 0)];
+
+    while let Some((addr, height)) = pending.pop() {
+        let Some(&i) = code.index.get(&addr) else {
+            continue;
+        };
+        if let Some(&expected) = heights.get(&addr) {
+            if expected != height {
+                return Err(ValidationError::UnbalancedStack { addr, expected, found: height });
+            }
+            continue;
+        }
+        heights.insert(addr, height);
+
+        let op = &code.ops[i].1;
+        let Some((pops, pushes)) = stack_effect(op.opcode) else {
+            continue;
+        };
+        let height = height
+            .checked_sub(pops)
+            .ok_or(ValidationError::StackUnderflow(addr))?
+            + pushes;
+        max_height = max_height.max(height);
+
+        pending.extend(targets[i].iter().map(|t| (*t, height)));
+        if !ends_flow(op.opcode)
+            && let Some((next, _)) = code.ops.get(i + 1)
+        {
+            pending.push((*next, height));
+        }
+    }
+    Ok(max_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::Parser,
+        config::InterpreterConfig,
+        interpreter::{Interpreter, InterpreterErrorType},
+    };
+
+    fn check(code: &str) -> Result<ValidationReport, ValidationError> {
+        validate(&Parser::parse(code).unwrap().code)
+    }
+
+    #[test]
+    fn validation() {
+        let report = check(
+            "
+            #0; global_set 0;
+            :loop:
+            global_get 0; #1; add; global_tee 0;
+            #5; lt; #@loop; jmp_if;
+            #1; br_table @a @b;
+            :a: #1; end;
+            :b: const_64 7; end;
+            ",
+        )
+        .unwrap();
+        assert_eq!(report.block_count, 5);
+        assert_eq!(report.max_stack_height, 2);
+
+        assert_eq!(check("drop; end;"), Err(ValidationError::StackUnderflow(DATA_START)));
+        assert_eq!(
+            check(":loop: #1; #1; #@loop; jmp_if; end;"),
+            Err(ValidationError::UnbalancedStack { addr: DATA_START, expected: 0, found: 1 })
+        );
+        assert_eq!(
+            check("#17; jmp; end;"),
+            Err(ValidationError::InvalidTarget { addr: DATA_START + 5, target: 17 })
+        );
+        assert_eq!(
+            check("local_get 70; end;"),
+            Err(ValidationError::InvalidLocalId { addr: DATA_START, id: 70 })
+        );
+        //NOTE(joh): Heights after a call are unknown, so the drop is not an underflow here
+        assert!(check("#@f; call; drop; end; :f: #1; return;").is_ok());
+
+        let bytecode = Parser::parse("drop; end;").unwrap().code;
+        assert!(Interpreter::from_bytecode(&bytecode).is_ok());
+        assert!(matches!(
+            Interpreter::from_bytecode_with_config(&bytecode, InterpreterConfig::default().with_validation(true)),
+            Err(InterpreterErrorType::InvalidBytecode(ValidationError::StackUnderflow(_)))
+        ));
+
+        let mut bytecode = Parser::parse("#1; end;").unwrap().code.to_vec();
+        bytecode[BytecodeInfo::total_header_size()] = 0xff;
+        assert_eq!(
+            validate(&bytecode),
+            Err(ValidationError::UnknownOpcode { addr: DATA_START, opcode: 0xff })
+        );
+        bytecode.truncate(BytecodeInfo::total_header_size() + 2);
+        assert_eq!(validate(&bytecode), Err(ValidationError::SectionOutOfBounds));
+    }
+}
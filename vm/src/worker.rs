@@ -0,0 +1,182 @@
+use std::thread::{self, JoinHandle};
+
+use crate::{
+    config::SyscallGroup,
+    interpreter::{Interpreter, SyscallHandler},
+    prelude::*,
+    syscall::{Join, Spawn},
+    trap::Trap,
+};
+
+pub type WorkerResult = Result<Vec<u32>, Trap>;
+
+pub const DEFAULT_MAX_WORKERS: usize = 16;
+
+//NOTE(joh): Runs the workers of `Spawn` on threads and passes the other syscalls on to `inner`.
+//Every worker is a `fork_worker` of the instance that spawned it and gets its own handler from
+//`make_handler`, so the workers only share what is in the shared memories. At most `max_workers`
+//run at once, each of them with what is left of the quota of the spawning instance.
+pub struct Spawner<H, F> {
+    pub inner: H,
+    make_handler: F,
+    workers: Vec<Option<JoinHandle<WorkerResult>>>,
+    max_workers: usize,
+}
+
+impl<H, F, W> Spawner<H, F>
+where
+    H: SyscallHandler,
+    F: FnMut() -> W,
+    W: SyscallHandler + Send + 'static,
+{
+    pub fn new(inner: H, make_handler: F) -> Self {
+        Self {
+            inner,
+            make_handler,
+            workers: Vec::new(),
+            max_workers: DEFAULT_MAX_WORKERS,
+        }
+    }
+
+    pub fn with_max_workers(mut self, max_workers: usize) -> Self {
+        self.max_workers = max_workers;
+        self
+    }
+
+    //NOTE(joh): Workers that were spawned and not joined yet
+    pub fn running(&self) -> usize {
+        self.workers.iter().filter(|w| w.is_some()).count()
+    }
+
+    //NOTE(joh): Returns the worker id, 0 while `max_workers` workers are running
+    pub fn spawn(&mut self, interpreter: &Interpreter, addr: u32, arg: u32) -> u32 {
+        if self.running() >= self.max_workers {
+            return 0;
+        }
+        let mut worker = interpreter.fork_worker();
+        let mut handler = (self.make_handler)();
+        let handle = thread::spawn(move || worker.call(addr, &[arg], &mut handler));
+        self.workers.push(Some(handle));
+        self.workers.len() as u32
+    }
+
+    //NOTE(joh): `None` if there is no such worker or it was joined before
+    pub fn join(&mut self, id: u32) -> Option<WorkerResult> {
+        let handle = self.workers.get_mut((id as usize).checked_sub(1)?)?.take()?;
+        Some(handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+    }
+
+    //NOTE(joh): Waits for the workers the program did not join, in the order they were spawned
+    pub fn join_all(&mut self) -> Vec<WorkerResult> {
+        (1..=self.workers.len() as u32).filter_map(|id| self.join(id)).collect()
+    }
+}
+
+impl<H, F, W> SyscallHandler for Spawner<H, F>
+where
+    H: SyscallHandler,
+    F: FnMut() -> W,
+    W: SyscallHandler + Send + 'static,
+{
+    #[allow(non_upper_case_globals)]
+    fn on_syscall(&mut self, interpreter: &mut Interpreter, syscall_id: u32, args: &[u32]) -> u32 {
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        match syscall_id {
+            Spawn if interpreter.is_jump_target(arg(0)) => self.spawn(interpreter, arg(0), arg(1)),
+            Spawn => 0,
+            Join => match self.join(arg(0)) {
+                Some(Ok(values)) => values.first().copied().unwrap_or(0),
+                _ => 0,
+            },
+            _ => self.inner.on_syscall(interpreter, syscall_id, args),
+        }
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn syscall_group(&self, syscall_id: u32) -> Option<SyscallGroup> {
+        match syscall_id {
+            Spawn | Join => Some(SyscallGroup::Threads),
+            _ => self.inner.syscall_group(syscall_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm,
+        config::{Capabilities, InterpreterConfig, Quota, QuotaKind},
+        interpreter::InterpreterErrorType,
+        shared::SharedMemory,
+        syscall::StdHandler,
+    };
+
+    #[test]
+    fn workers_share_memory() {
+        let code = format!(
+            "
+            .func work 1 1;
+            :loop:
+            #0; #1; atomic_add 0; drop;
+            local_get 0; #1; sub; local_tee 0; #@loop; jmp_if;
+            #4; #0; #1; cmpxchg 0;
+            return;
+
+            :__ENTRY__:
+            .local a; .local b;
+            #@work; push_arg; #1000; push_arg; #{Spawn}; syscall; local_set a;
+            #@work; push_arg; #1000; push_arg; #{Spawn}; syscall; local_set b;
+            local_get a; push_arg; #{Join}; syscall;
+            local_get b; push_arg; #{Join}; syscall;
+            add;
+            #0; atomic_load 0;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap().code;
+        let mut interpreter = Interpreter::from_bytecode(&bytecode).unwrap();
+        let shared = SharedMemory::new(8);
+        assert_eq!(interpreter.add_shared_memory(shared.clone()), Some(0));
+        let mut spawner = Spawner::new(StdHandler::new(String::new()), || StdHandler::new(String::new()));
+        //NOTE(joh): Exactly one of the workers swaps the 0 for a 1
+        assert_eq!(interpreter.run(&mut spawner).unwrap(), [1, 2000]);
+        assert_eq!(shared.to_vec(), [2000, 1]);
+        assert!(spawner.join_all().is_empty());
+        assert!(spawner.join(1).is_none());
+    }
+
+    #[test]
+    #[allow(non_upper_case_globals)]
+    fn worker_limits() {
+        let code = format!(
+            "
+            .func work 1 1;
+            :spin: #@spin; jmp;
+
+            :__ENTRY__:
+            #@work; push_arg; #0; push_arg; #{Spawn}; syscall;
+            #@work; push_arg; #0; push_arg; #{Spawn}; syscall;
+            end;
+            "
+        );
+        let bytecode = asm::Parser::parse(&code).unwrap().code;
+        let quota = Quota { fuel: Some(1000), ..Quota::unlimited() };
+        let config = InterpreterConfig { quota, ..Default::default() };
+        let mut interpreter = Interpreter::from_bytecode_with_config(&bytecode, config).unwrap();
+        let make_spawner = || Spawner::new(StdHandler::new(String::new()), || StdHandler::new(String::new()));
+        let mut spawner = make_spawner().with_max_workers(1);
+        assert_eq!(interpreter.run(&mut spawner).unwrap(), [1, 0]);
+        //NOTE(joh): The worker runs out of the fuel the entry point had left
+        let results = spawner.join_all();
+        assert!(matches!(
+            &results[..],
+            [Err(trap)] if matches!(trap.error, InterpreterErrorType::QuotaExceeded { kind: QuotaKind::Fuel, .. })
+        ));
+
+        interpreter.reset_all(&bytecode).unwrap();
+        interpreter.config.capabilities = Capabilities::all().with(SyscallGroup::Threads, false);
+        let result = interpreter.run(&mut make_spawner());
+        assert!(matches!(result, Err(InterpreterErrorType::PermissionDenied(Spawn))));
+    }
+}